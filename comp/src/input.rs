@@ -52,6 +52,8 @@ impl LibinputInterface for NixInterface {
 }
 
 impl State {
+    /// Handles the physical seat's devices. Streaming sessions don't run on this compositor,
+    /// remote input goes to the host's headless compositor instead.
     pub fn process_input_event(&mut self, dh: &DisplayHandle, event: InputEvent<LibinputInputBackend>) {
         match event {
             InputEvent::Keyboard { event, .. } => {
@@ -11,6 +11,9 @@ pub fn gst_video_format_from_drm_fourcc(format: Fourcc) -> Option<VideoFormat> {
         Fourcc::Rgbx8888 => VideoFormat::Xbgr,
         Fourcc::Xbgr8888 => VideoFormat::Rgbx,
         Fourcc::Xrgb8888 => VideoFormat::Bgrx,
+        // 10 bit, for HDR
+        Fourcc::Abgr2101010 => VideoFormat::Rgb10a2Le,
+        Fourcc::Argb2101010 => VideoFormat::Bgr10a2Le,
         _ => return None,
     };
     Some(format)
@@ -26,6 +29,8 @@ pub fn gst_video_format_to_drm_fourcc(format: VideoFormat) -> Option<Fourcc> {
         VideoFormat::Rgbx => Fourcc::Xbgr8888,
         VideoFormat::Xbgr => Fourcc::Rgbx8888,
         VideoFormat::Xrgb => Fourcc::Bgrx8888,
+        VideoFormat::Bgr10a2Le => Fourcc::Argb2101010,
+        VideoFormat::Rgb10a2Le => Fourcc::Abgr2101010,
         _ => return None,
     };
     Some(format)
@@ -76,6 +76,7 @@ mod input;
 mod window;
 
 use self::focus::*;
+pub use self::input::Input;
 use self::input::*;
 use self::window::*;
 
@@ -109,6 +110,7 @@ struct State {
     // management
     output: Option<Output>,
     seat: Seat<Self>,
+    remote_keymap: RemoteKeymap,
     space: Space<Window>,
     popups: PopupManager,
     pointer_location: Point<f64, Logical>,
@@ -641,7 +643,12 @@ impl XwmHandler for Data {
     }
 }
 
-pub fn init(command_src: Channel<Command>, drm_node: DrmNode, seat: impl AsRef<str>) {
+pub fn init(
+    command_src: Channel<Command>,
+    source: ListeningSocketSource,
+    drm_node: DrmNode,
+    seat: impl AsRef<str>,
+) {
     let log = ::slog::Logger::root(super::imp::SlogGstDrain.fuse(), slog::o!());
 
     let mut display = Display::<State>::new().unwrap();
@@ -700,7 +707,9 @@ pub fn init(command_src: Channel<Command>, drm_node: DrmNode, seat: impl AsRef<s
     let space = Space::new(log.clone());
 
     let mut seat = seat_state.new_wl_seat(&dh, "seat-0", log.clone());
-    seat.add_keyboard(XkbConfig::default(), 200, 25)
+    let xkb_config = XkbConfig::default();
+    let remote_keymap = RemoteKeymap::new(&xkb_config).expect("Failed to compile keymap");
+    seat.add_keyboard(xkb_config, 200, 25)
         .expect("Failed to add keyboard to seat");
     seat.add_pointer();
 
@@ -720,6 +729,7 @@ pub fn init(command_src: Channel<Command>, drm_node: DrmNode, seat: impl AsRef<s
         space,
         popups: PopupManager::new(log.clone()),
         seat,
+        remote_keymap,
         output: None,
         pointer_location: (0., 0.).into(),
         cursor_element,
@@ -788,6 +798,7 @@ pub fn init(command_src: Channel<Command>, drm_node: DrmNode, seat: impl AsRef<s
                     }
                     let _ = buffer_ack.send(());
                 }
+                Event::Msg(Command::Input(input)) => data.state.process_remote_input(input),
                 Event::Msg(Command::Quit) | Event::Closed => {
                     data.state.should_quit = true;
                 }
@@ -795,7 +806,6 @@ pub fn init(command_src: Channel<Command>, drm_node: DrmNode, seat: impl AsRef<s
         })
         .unwrap();
 
-    let source = ListeningSocketSource::new_auto(log.clone()).unwrap();
     slog::info!(
         log,
         "Listening on wayland socket: {}",
@@ -2,13 +2,13 @@ use super::{focus::FocusTarget, State};
 use smithay::{
     backend::{
         input::{
-            Axis, AxisSource, Event, InputEvent, KeyboardKeyEvent, PointerAxisEvent,
-            PointerButtonEvent, PointerMotionEvent,
+            Axis, AxisSource, ButtonState, Event, InputEvent, KeyState, KeyboardKeyEvent,
+            PointerAxisEvent, PointerButtonEvent, PointerMotionEvent,
         },
         libinput::LibinputInputBackend,
     },
     input::{
-        keyboard::FilterResult,
+        keyboard::{FilterResult, XkbConfig},
         pointer::{AxisFrame, ButtonEvent, MotionEvent, RelativeMotionEvent},
     },
     reexports::{
@@ -22,6 +22,197 @@ use std::{
     os::{fd::FromRawFd, unix::io::OwnedFd},
     path::Path,
 };
+use xkbcommon::xkb;
+
+/// Name of the structure of the custom upstream events carrying [`Input`].
+const INPUT_STRUCTURE: &str = "remote-input";
+/// Scroll distance libinput reports per wheel notch.
+const DEGREES_PER_NOTCH: f64 = 15.0;
+const KEY_LEFTSHIFT: u32 = 42;
+
+/// Input of a remote seat, sent to the src pad as custom upstream events.
+///
+/// The `remote-input` structure names the kind of input in its `type` field:
+/// - `pointer-motion` with `dx` and `dy` doubles
+/// - `pointer-motion-absolute` with `x` and `y` doubles, normalized to `0.0..=1.0` of the output
+/// - `pointer-button` with an evdev `button` code as uint and `pressed` boolean
+/// - `pointer-axis` with `horizontal` and `vertical` doubles in wheel notches
+/// - `key` with an evdev `keycode` as uint and `pressed` boolean, optionally with the `keysym`
+///   uint it types, typed instead if the seat's layout has no such key
+/// - `keysym` with a `keysym` uint and `pressed` boolean, typed with whichever key of the
+///   seat's layout produces it
+///
+/// Keysyms the layout lacks are put on keycodes it leaves unused, updating the seat's keymap.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    PointerMotion {
+        dx: f64,
+        dy: f64,
+    },
+    PointerMotionAbsolute {
+        x: f64,
+        y: f64,
+    },
+    PointerButton {
+        button: u32,
+        pressed: bool,
+    },
+    PointerAxis {
+        horizontal: f64,
+        vertical: f64,
+    },
+    Key {
+        keycode: u32,
+        keysym: Option<u32>,
+        pressed: bool,
+    },
+    Keysym {
+        keysym: u32,
+        pressed: bool,
+    },
+}
+
+impl Input {
+    pub fn from_structure(structure: &gst::StructureRef) -> Option<Input> {
+        if structure.name() != INPUT_STRUCTURE {
+            return None;
+        }
+        let input = match structure.get::<&str>("type").ok()? {
+            "pointer-motion" => Input::PointerMotion {
+                dx: structure.get("dx").ok()?,
+                dy: structure.get("dy").ok()?,
+            },
+            "pointer-motion-absolute" => Input::PointerMotionAbsolute {
+                x: structure.get("x").ok()?,
+                y: structure.get("y").ok()?,
+            },
+            "pointer-button" => Input::PointerButton {
+                button: structure.get("button").ok()?,
+                pressed: structure.get("pressed").ok()?,
+            },
+            "pointer-axis" => Input::PointerAxis {
+                horizontal: structure.get("horizontal").ok()?,
+                vertical: structure.get("vertical").ok()?,
+            },
+            "key" => Input::Key {
+                keycode: structure.get("keycode").ok()?,
+                keysym: structure.get("keysym").ok(),
+                pressed: structure.get("pressed").ok()?,
+            },
+            "keysym" => Input::Keysym {
+                keysym: structure.get("keysym").ok()?,
+                pressed: structure.get("pressed").ok()?,
+            },
+            _ => return None,
+        };
+        Some(input)
+    }
+}
+
+/// The seat's layout, to type keysyms of remote input it has no keycode for.
+///
+/// Keysyms missing from the layout are put on keycodes it leaves unused, which the seat's
+/// keymap is then updated with.
+pub struct RemoteKeymap {
+    context: xkb::Context,
+    /// The layout as configured, in text form.
+    base: String,
+    keymap: xkb::Keymap,
+    /// Keycodes without symbols in the configured layout, with their names.
+    spare: Vec<(u32, String)>,
+    /// Keysyms put on the spare keycodes, in order.
+    synthesized: Vec<u32>,
+    /// Spare keycode to use next, the oldest once all are taken.
+    next_spare: usize,
+}
+
+impl RemoteKeymap {
+    pub fn new(config: &XkbConfig<'_>) -> Option<RemoteKeymap> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            config.rules,
+            config.model,
+            config.layout,
+            config.variant,
+            config.options.clone(),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+        let spare = (keymap.min_keycode().max(8)..=keymap.max_keycode())
+            .filter(|keycode| keymap.key_get_syms_by_level(*keycode, 0, 0).is_empty())
+            .filter_map(|keycode| Some((keycode, keymap.key_get_name(keycode)?.to_string())))
+            .collect();
+        Some(RemoteKeymap {
+            context,
+            base: keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1),
+            keymap,
+            spare,
+            synthesized: Vec::new(),
+            next_spare: 0,
+        })
+    }
+
+    /// Whether the evdev keycode types anything in this layout.
+    fn has_key(&self, keycode: u32) -> bool {
+        !self
+            .keymap
+            .key_get_syms_by_level(keycode + 8, 0, 0)
+            .is_empty()
+    }
+
+    /// Finds the evdev keycode typing `keysym`, and whether it needs shift held.
+    fn find_keysym(&self, keysym: u32) -> Option<(u32, bool)> {
+        let keycodes = self.keymap.min_keycode().max(8)..=self.keymap.max_keycode();
+        for level in 0..2 {
+            for keycode in keycodes.clone() {
+                if self.keymap.num_levels_for_key(keycode, 0) > level
+                    && self
+                        .keymap
+                        .key_get_syms_by_level(keycode, 0, level)
+                        .contains(&keysym)
+                {
+                    return Some((keycode - 8, level == 1));
+                }
+            }
+        }
+        None
+    }
+
+    /// Puts `keysym` on a spare keycode, returning its evdev keycode and the updated keymap
+    /// to hand to the seat.
+    fn synthesize(&mut self, keysym: u32) -> Option<(u32, String)> {
+        if self.spare.is_empty() {
+            return None;
+        }
+        let index = self.next_spare;
+        self.next_spare = (self.next_spare + 1) % self.spare.len();
+        match self.synthesized.get_mut(index) {
+            Some(synthesized) => *synthesized = keysym,
+            None => self.synthesized.push(keysym),
+        }
+
+        // the symbols section is the last one, closed right before the keymap
+        let keymap_end = self.base.rfind("};")?;
+        let symbols_end = self.base[..keymap_end].rfind("};")?;
+        let mut text = self.base[..symbols_end].to_string();
+        for ((_, name), keysym) in self.spare.iter().zip(&self.synthesized) {
+            text += &format!(
+                "\tkey <{}> {{ [ {} ] }};\n",
+                name,
+                xkb::keysym_get_name(*keysym)
+            );
+        }
+        text += &self.base[symbols_end..];
+
+        self.keymap = xkb::Keymap::new_from_string(
+            &self.context,
+            text.clone(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+        Some((self.spare[index].0 - 8, text))
+    }
+}
 
 pub struct NixInterface;
 
@@ -144,6 +335,146 @@ impl State {
         }
     }
 
+    pub fn process_remote_input(&mut self, input: Input) {
+        let time = self.start_time.elapsed().as_millis() as u32;
+        match input {
+            Input::PointerMotion { dx, dy } => {
+                let location = self.pointer_location + Point::from((dx, dy));
+                self.pointer_motion(location, time);
+            }
+            Input::PointerMotionAbsolute { x, y } => {
+                if let Some(mode) = self
+                    .output
+                    .as_ref()
+                    .and_then(|output| output.current_mode())
+                {
+                    let location = (x * mode.size.w as f64, y * mode.size.h as f64).into();
+                    self.pointer_motion(location, time);
+                }
+            }
+            Input::PointerButton { button, pressed } => {
+                let serial = SERIAL_COUNTER.next_serial();
+                let state = if pressed {
+                    self.update_keyboard_focus(serial);
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
+                };
+                self.seat.get_pointer().unwrap().button(
+                    self,
+                    &ButtonEvent {
+                        button,
+                        state,
+                        serial,
+                        time,
+                    },
+                );
+            }
+            Input::PointerAxis {
+                horizontal,
+                vertical,
+            } => {
+                let mut frame = AxisFrame::new(time).source(AxisSource::Wheel);
+                for (axis, notches) in [(Axis::Horizontal, horizontal), (Axis::Vertical, vertical)]
+                {
+                    if notches != 0.0 {
+                        frame = frame.value(axis, notches * DEGREES_PER_NOTCH);
+                        if notches.fract() == 0.0 {
+                            frame = frame.discrete(axis, notches as i32);
+                        }
+                    }
+                }
+                self.seat.get_pointer().unwrap().axis(self, frame);
+            }
+            Input::Key {
+                keycode,
+                keysym,
+                pressed,
+            } => match keysym {
+                // the layout lacks this key, type its keysym wherever the layout has it instead
+                Some(keysym) if !self.remote_keymap.has_key(keycode) => {
+                    self.remote_keysym(keysym, pressed, time)
+                }
+                _ => self.remote_key(keycode, pressed, time),
+            },
+            Input::Keysym { keysym, pressed } => self.remote_keysym(keysym, pressed, time),
+        }
+    }
+
+    /// Presses or releases the key typing `keysym` in the active layout, with shift if it
+    /// is on the second level. Keysyms the layout lacks are added to it on press.
+    fn remote_keysym(&mut self, keysym: u32, pressed: bool, time: u32) {
+        let key = match self.remote_keymap.find_keysym(keysym) {
+            Some(key) => Some(key),
+            None if pressed => self.synthesize_keysym(keysym),
+            None => None,
+        };
+        match key {
+            Some((keycode, false)) => self.remote_key(keycode, pressed, time),
+            Some((keycode, true)) if pressed => {
+                self.remote_key(KEY_LEFTSHIFT, true, time);
+                self.remote_key(keycode, true, time);
+            }
+            Some((keycode, true)) => {
+                self.remote_key(keycode, false, time);
+                self.remote_key(KEY_LEFTSHIFT, false, time);
+            }
+            None => slog::debug!(
+                self.log,
+                "Keysym {:#x} missing from the active layout",
+                keysym
+            ),
+        }
+    }
+
+    /// Adds `keysym` to the seat's keymap, returning the key typing it.
+    fn synthesize_keysym(&mut self, keysym: u32) -> Option<(u32, bool)> {
+        let (keycode, keymap) = self.remote_keymap.synthesize(keysym)?;
+        let keyboard = self.seat.get_keyboard().unwrap();
+        if let Err(err) = keyboard.set_keymap_from_string(self, keymap) {
+            slog::warn!(self.log, "Failed to update the seat's keymap: {}", err);
+            return None;
+        }
+        Some((keycode, false))
+    }
+
+    fn remote_key(&mut self, keycode: u32, pressed: bool, time: u32) {
+        let state = if pressed {
+            KeyState::Pressed
+        } else {
+            KeyState::Released
+        };
+        let keyboard = self.seat.get_keyboard().unwrap();
+        keyboard.input::<(), _>(
+            self,
+            keycode,
+            state,
+            SERIAL_COUNTER.next_serial(),
+            time,
+            |_data, _modifiers, _handle| FilterResult::Forward,
+        );
+    }
+
+    fn pointer_motion(&mut self, location: Point<f64, Logical>, time: u32) {
+        let serial = SERIAL_COUNTER.next_serial();
+        self.pointer_location = self.clamp_coords(location);
+
+        let pointer = self.seat.get_pointer().unwrap();
+        let under = self
+            .space
+            .element_under(self.pointer_location)
+            .map(|(w, pos)| (w.clone().into(), pos));
+        pointer.motion(
+            self,
+            under,
+            &MotionEvent {
+                location: self.pointer_location,
+                serial,
+                time,
+            },
+        );
+    }
+
     fn clamp_coords(&self, pos: Point<f64, Logical>) -> Point<f64, Logical> {
         if let Some(output) = self.output.as_ref() {
             if let Some(mode) = output.current_mode() {
@@ -11,6 +11,7 @@ use smithay::backend::allocator::dmabuf::Dmabuf;
 use smithay::backend::drm::{DrmNode, NodeType};
 use smithay::backend::egl::{EGLDevice, EGLDisplay};
 use smithay::reexports::calloop::channel::Sender;
+use smithay::wayland::socket::ListeningSocketSource;
 
 use gst::glib;
 use gst::glib::once_cell::sync::Lazy;
@@ -82,6 +83,7 @@ impl Default for WaylandDisplaySrc {
 pub struct Settings {
     render_node: Option<DrmNode>,
     input_seat: Option<String>,
+    socket_name: Option<String>,
 }
 
 pub struct State {
@@ -92,6 +94,7 @@ pub struct State {
 pub enum Command {
     VideoInfo(VideoInfo),
     Buffer(Dmabuf, SyncSender<()>),
+    Input(super::comp::Input),
     Quit,
 }
 
@@ -117,6 +120,10 @@ impl ObjectImpl for WaylandDisplaySrc {
                     .blurb("libinput seat to use (e.g. seat-0")
                     .construct()
                     .build(),
+                glib::ParamSpecString::builder("socket-name")
+                    .nick("Wayland socket")
+                    .blurb("Wayland socket to listen on (e.g. wayland-1), picked on start if unset")
+                    .build(),
             ]
         });
 
@@ -140,6 +147,12 @@ impl ObjectImpl for WaylandDisplaySrc {
                     .expect("type checked upstream");
                 settings.input_seat = seat;
             }
+            "socket-name" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.socket_name = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+            }
             _ => unreachable!(),
         }
     }
@@ -160,6 +173,10 @@ impl ObjectImpl for WaylandDisplaySrc {
                 let settings = self.settings.lock().unwrap();
                 settings.input_seat.to_value()
             }
+            "socket-name" => {
+                let settings = self.settings.lock().unwrap();
+                settings.socket_name.to_value()
+            }
             _ => unreachable!(),
         }
     }
@@ -255,6 +272,22 @@ impl BaseSrcImpl for WaylandDisplaySrc {
         BaseSrcImplExt::parent_query(self, query)
     }
 
+    fn event(&self, event: &gst::Event) -> bool {
+        // input for the compositor, sent upstream to the src pad
+        if let gst::EventView::CustomUpstream(custom) = event.view() {
+            if let Some(input) = custom
+                .structure()
+                .and_then(super::comp::Input::from_structure)
+            {
+                return match self.state.lock().unwrap().as_ref() {
+                    Some(state) => state.command_tx.send(Command::Input(input)).is_ok(),
+                    None => false,
+                };
+            }
+        }
+        BaseSrcImplExt::parent_event(self, event)
+    }
+
     fn caps(&self, filter: Option<&gst::Caps>) -> Option<gst::Caps> {
         let max_refresh = gst::Fraction::new(i32::MAX, 1);
 
@@ -379,7 +412,7 @@ impl BaseSrcImpl for WaylandDisplaySrc {
             return Ok(());
         }
 
-        let settings = self.settings.lock().unwrap();
+        let mut settings = self.settings.lock().unwrap();
         let render_node = settings.render_node.clone().unwrap_or_else(|| {
             DrmNode::from_path("/dev/dri/renderD128")
                 .expect("Failed to open default DRM render node")
@@ -389,9 +422,24 @@ impl BaseSrcImpl for WaylandDisplaySrc {
             .clone()
             .unwrap_or_else(|| String::from("seat-0"));
 
+        // bound before returning, so clients can connect once the element started
+        let log = ::slog::Logger::root(SlogGstDrain.fuse(), slog::o!());
+        let socket = match settings.socket_name.as_deref() {
+            Some(name) => ListeningSocketSource::with_name(name, log),
+            None => ListeningSocketSource::new_auto(log),
+        }
+        .map_err(|err| {
+            gst::error_msg!(
+                gst::ResourceError::OpenReadWrite,
+                ["Failed to bind wayland socket: {}", err]
+            )
+        })?;
+        settings.socket_name = Some(socket.socket_name().to_string_lossy().into_owned());
+
         let (command_tx, command_src) = smithay::reexports::calloop::channel::channel();
-        let thread_handle =
-            std::thread::spawn(move || super::comp::init(command_src, render_node, &input_seat));
+        let thread_handle = std::thread::spawn(move || {
+            super::comp::init(command_src, socket, render_node, &input_seat)
+        });
 
         *state = Some(State {
             thread_handle,
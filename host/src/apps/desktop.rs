@@ -0,0 +1,337 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::Found;
+
+/// Icon sizes looked for, box art wants the largest there is.
+const ICON_SIZES: &[&str] = &["512x512", "256x256", "192x192", "128x128", "96x96", "64x64"];
+
+/// A `[Desktop Entry]` worth launching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopEntry {
+    pub name: String,
+    /// With field codes expanded, ready for `sh -c`.
+    pub command: String,
+    pub icon: Option<String>,
+    pub categories: Vec<String>,
+}
+
+/// Parses a desktop entry, `None` for anything but visible applications.
+pub fn parse(text: &str, path: &Path) -> Option<DesktopEntry> {
+    let mut in_entry = false;
+    let mut name = None;
+    let mut exec = None;
+    let mut icon = None;
+    let mut categories = Vec::new();
+    let mut application = false;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+            continue;
+        }
+        // localized keys like Name[de] are left out
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if in_entry => (key.trim(), unescape(value.trim())),
+            _ => continue,
+        };
+        match key {
+            "Type" => application = value == "Application",
+            "Name" => name = Some(value),
+            "Exec" => exec = Some(value),
+            "Icon" if !value.is_empty() => icon = Some(value),
+            "Categories" => {
+                categories = value
+                    .split(';')
+                    .filter(|category| !category.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            "NoDisplay" | "Hidden" | "Terminal" if value == "true" => return None,
+            _ => {}
+        }
+    }
+    let name = name?;
+    let command = expand(&exec?, &name, icon.as_deref(), path);
+    if !application || command.is_empty() {
+        return None;
+    }
+    Some(DesktopEntry {
+        name,
+        command,
+        icon,
+        categories,
+    })
+}
+
+/// Resolves the escapes of string values.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            // escapes of the Exec quoting are for the shell
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Expands the field codes of `Exec`, there are no files or URLs to open.
+fn expand(exec: &str, name: &str, icon: Option<&str>, path: &Path) -> String {
+    let mut command = String::with_capacity(exec.len());
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            command.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => command.push('%'),
+            Some('i') => {
+                if let Some(icon) = icon {
+                    command.push_str("--icon ");
+                    command.push_str(&quote(icon));
+                }
+            }
+            Some('c') => command.push_str(&quote(name)),
+            Some('k') => command.push_str(&quote(&path.to_string_lossy())),
+            // files, URLs and the deprecated codes, with the space before them if they
+            // were an argument of their own
+            Some(_) => {
+                if command.ends_with(' ') && matches!(chars.clone().next(), None | Some(' ' | '\t'))
+                {
+                    command.pop();
+                }
+            }
+            None => command.push('%'),
+        }
+    }
+    command.trim().to_owned()
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Applications in the `applications` directories of `data_dirs`, by their desktop file
+/// id. Earlier directories take precedence, like `XDG_DATA_HOME` over `XDG_DATA_DIRS`.
+pub fn scan(data_dirs: &[PathBuf], categories: &[String]) -> Vec<Found> {
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    for data_dir in data_dirs {
+        let applications = data_dir.join("applications");
+        let mut files = Vec::new();
+        desktop_files(&applications, &mut files);
+        files.sort();
+        for path in files {
+            // the id of a file in a subdirectory joins them with dashes
+            let id = match path.strip_prefix(&applications) {
+                Ok(relative) => relative.to_string_lossy().replace('/', "-"),
+                Err(_) => continue,
+            };
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let entry = match fs::read_to_string(&path) {
+                Ok(text) => parse(&text, &path),
+                Err(err) => {
+                    log::warn!("Skipping {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !categories.is_empty()
+                && !entry
+                    .categories
+                    .iter()
+                    .any(|category| categories.contains(category))
+            {
+                continue;
+            }
+            found.push(Found {
+                // entries Steam creates launch what the Steam import finds
+                id: match entry.command.strip_prefix("steam steam://rungameid/") {
+                    Some(game) => format!("steam:{}", game),
+                    None => format!("desktop:{}", id),
+                },
+                title: entry.name,
+                asset: entry
+                    .icon
+                    .as_deref()
+                    .and_then(|icon| find_icon(icon, data_dirs)),
+                command: entry.command,
+            });
+        }
+    }
+    found
+}
+
+fn desktop_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            desktop_files(&path, files);
+        } else if path.extension() == Some(OsStr::new("desktop")) {
+            files.push(path);
+        }
+    }
+}
+
+/// A PNG of `icon`, a path or a name in the hicolor theme every app installs into.
+fn find_icon(icon: &str, data_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(icon);
+    if path.is_absolute() {
+        return path.is_file().then(|| path.to_owned());
+    }
+    let file = format!("{}.png", icon);
+    data_dirs
+        .iter()
+        .flat_map(|data_dir| {
+            ICON_SIZES
+                .iter()
+                .map(move |size| data_dir.join("icons/hicolor").join(size).join("apps"))
+                .chain([data_dir.join("pixmaps")])
+        })
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let path = Path::new("/usr/share/applications/supertux2.desktop");
+        let entry = parse(
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name=SuperTux\n\
+             Name[de]=SuperTux 2\n\
+             Exec=supertux2 --title %c %U\n\
+             Icon=supertux2\n\
+             Categories=Game;ArcadeGame;\n\
+             \n\
+             [Desktop Action Editor]\n\
+             Name=Editor\n\
+             Exec=supertux2 --editor\n",
+            path,
+        )
+        .unwrap();
+        assert_eq!(
+            entry,
+            DesktopEntry {
+                name: "SuperTux".into(),
+                command: "supertux2 --title 'SuperTux'".into(),
+                icon: Some("supertux2".into()),
+                categories: vec!["Game".into(), "ArcadeGame".into()],
+            }
+        );
+
+        let hidden =
+            "[Desktop Entry]\nType=Application\nName=Helper\nExec=helper\nNoDisplay=true\n";
+        assert_eq!(parse(hidden, path), None);
+        let link = "[Desktop Entry]\nType=Link\nName=Site\nURL=https://example.org\n";
+        assert_eq!(parse(link, path), None);
+    }
+
+    #[test]
+    fn test_expand() {
+        let path = Path::new("/usr/share/applications/app.desktop");
+        assert_eq!(
+            expand(
+                "app %f --icon-arg %i --rate 100%%",
+                "It's",
+                Some("app"),
+                path
+            ),
+            "app --icon-arg --icon 'app' --rate 100%"
+        );
+        assert_eq!(expand("app %c", "It's", None, path), "app 'It'\\''s'");
+        assert_eq!(unescape(r"a\sb\\c\$d"), r"a b\c\$d");
+    }
+
+    #[test]
+    fn test_scan() {
+        let home = std::env::temp_dir().join(format!("sunrise-xdg-{}", uuid::Uuid::new_v4()));
+        let system = home.join("system");
+        let user = home.join("user");
+        let write = |path: PathBuf, text: &str| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+        let game = |name: &str, exec: &str| {
+            format!(
+                "[Desktop Entry]\nType=Application\nName={}\nExec={}\nIcon=game\nCategories=Game;\n",
+                name, exec
+            )
+        };
+        write(
+            system.join("applications/games/tux.desktop"),
+            &game("System Tux", "tux"),
+        );
+        write(
+            user.join("applications/games/tux.desktop"),
+            &game("User Tux", "tux --user"),
+        );
+        write(
+            user.join("applications/Dota 2.desktop"),
+            &game("Dota 2", "steam steam://rungameid/570"),
+        );
+        write(
+            system.join("applications/editor.desktop"),
+            "[Desktop Entry]\nType=Application\nName=Editor\nExec=editor\nCategories=Utility;\n",
+        );
+        let icon = system.join("icons/hicolor/256x256/apps/game.png");
+        write(icon.clone(), "");
+        write(system.join("icons/hicolor/64x64/apps/game.png"), "");
+
+        let found = scan(&[user, system], &["Game".to_owned()]);
+        fs::remove_dir_all(&home).unwrap();
+        assert_eq!(
+            found,
+            [
+                Found {
+                    id: "steam:570".into(),
+                    title: "Dota 2".into(),
+                    command: "steam steam://rungameid/570".into(),
+                    asset: Some(icon.clone()),
+                },
+                Found {
+                    id: "desktop:games-tux.desktop".into(),
+                    title: "User Tux".into(),
+                    command: "tux --user".into(),
+                    asset: Some(icon),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use xdg::BaseDirectories;
+
+use crate::{App, AppId};
+
+mod desktop;
+mod steam;
+
+/// Adds the apps installed on the host to the configured ones on startup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportConfig {
+    /// Desktop entries in the XDG data directories.
+    #[serde(default = "enabled")]
+    pub desktop_entries: bool,
+    /// Desktop entries are only imported if they are in one of these categories, or any if
+    /// empty.
+    #[serde(default = "default_categories")]
+    pub categories: Vec<String>,
+    /// Installed games of the local Steam libraries.
+    #[serde(default = "enabled")]
+    pub steam: bool,
+    /// Only apps whose title or id matches one of these patterns are imported, all if empty.
+    /// `*` matches any text and `?` one character, without case.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Apps whose title or id matches one of these patterns are left out.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_categories() -> Vec<String> {
+    vec!["Game".to_owned()]
+}
+
+/// An app found on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    /// Tells the app apart across rescans, like `steam:570` or `desktop:supertux2.desktop`.
+    pub id: String,
+    pub title: String,
+    pub command: String,
+    pub asset: Option<PathBuf>,
+}
+
+/// Gives the apps without an id the next free ones, returns whether there were any.
+///
+/// Configs predating ids get their apps numbered by position, as clients knew them.
+pub fn number(apps: &mut [App]) -> bool {
+    let mut next = next_id(apps);
+    let mut changed = false;
+    for app in apps.iter_mut().filter(|app| app.id == AppId::default()) {
+        app.id = next;
+        next.0 += 1;
+        changed = true;
+    }
+    changed
+}
+
+fn next_id(apps: &[App]) -> AppId {
+    AppId(apps.iter().map(|app| app.id.0).max().unwrap_or(0) + 1)
+}
+
+/// Scans the host for apps and merges them into `apps`, returns whether anything changed.
+pub fn import(config: &ImportConfig, apps: &mut Vec<App>) -> bool {
+    let dirs = match BaseDirectories::new() {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            log::warn!("Not importing apps: {}", err);
+            return false;
+        }
+    };
+
+    let mut found = Vec::new();
+    // first, so that the desktop entries Steam creates don't take the place of its games
+    if let (true, Some(home)) = (config.steam, std::env::var_os("HOME")) {
+        for root in steam::roots(Path::new(&home)) {
+            match steam::scan(&root) {
+                Ok(games) => found.extend(games),
+                Err(err) => log::warn!("Skipping Steam at {}: {:#}", root.display(), err),
+            }
+        }
+    }
+    if config.desktop_entries {
+        let mut data_dirs = vec![dirs.get_data_home()];
+        data_dirs.extend(dirs.get_data_dirs());
+        found.extend(desktop::scan(&data_dirs, &config.categories));
+    }
+
+    let found = found
+        .into_iter()
+        .filter(|app| {
+            let listed = |patterns: &[String]| {
+                patterns
+                    .iter()
+                    .any(|pattern| matches(pattern, &app.title) || matches(pattern, &app.id))
+            };
+            (config.include.is_empty() || listed(&config.include)) && !listed(&config.exclude)
+        })
+        .collect();
+    merge(apps, found)
+}
+
+/// Updates the apps imported before in place, keeping what was set for them in the config.
+/// New apps are added at the end with a new id and those no longer found removed. Apps added
+/// by hand are left alone, and apps launching the same as one of them aren't imported.
+fn merge(apps: &mut Vec<App>, found: Vec<Found>) -> bool {
+    let mut changed = false;
+    let mut unique = Vec::with_capacity(found.len());
+    for app in found {
+        let manual = apps
+            .iter()
+            .any(|known| known.import_id.is_none() && known.command == app.command);
+        if !manual && !unique.iter().any(|seen: &Found| seen.id == app.id) {
+            unique.push(app);
+        }
+    }
+
+    // taken before removing any, so that apps found now don't get the ids of those gone
+    let mut next = next_id(apps);
+    let before = apps.len();
+    apps.retain(|app| match &app.import_id {
+        Some(id) => unique.iter().any(|found| &found.id == id),
+        None => true,
+    });
+    changed |= apps.len() != before;
+
+    for found in unique {
+        match apps
+            .iter_mut()
+            .find(|app| app.import_id.as_ref() == Some(&found.id))
+        {
+            Some(app) => {
+                if app.title != found.title
+                    || app.command != found.command
+                    || app.asset != found.asset
+                {
+                    app.title = found.title;
+                    app.command = found.command;
+                    app.asset = found.asset;
+                    changed = true;
+                }
+            }
+            None => {
+                log::info!("Imported {} as {:?}", found.id, found.title);
+                apps.push(App {
+                    id: next,
+                    title: found.title,
+                    command: found.command,
+                    asset: found.asset,
+                    hdr: None,
+                    import_id: Some(found.id),
+                });
+                next.0 += 1;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Matches `text` against a pattern of `*` and `?` wildcards, ignoring case.
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    // where the last `*` was and the text it matched up to, to backtrack into
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(id: &str, title: &str, command: &str) -> Found {
+        Found {
+            id: id.into(),
+            title: title.into(),
+            command: command.into(),
+            asset: None,
+        }
+    }
+
+    fn app(title: &str, command: &str, import_id: Option<&str>) -> App {
+        App {
+            id: AppId::default(),
+            title: title.into(),
+            command: command.into(),
+            asset: None,
+            hdr: None,
+            import_id: import_id.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("Proton *", "Proton Experimental"));
+        assert!(matches("steam:*", "steam:570"));
+        assert!(matches("*tux?", "SuperTux2"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("Proton *", "Protonic"));
+        assert!(!matches("dota", "Dota 2"));
+    }
+
+    #[test]
+    fn test_number() {
+        let mut apps = vec![app("Desktop", "", None), app("Steam", "steam", None)];
+        assert!(number(&mut apps));
+        assert_eq!((apps[0].id, apps[1].id), (AppId(1), AppId(2)));
+
+        apps.remove(0);
+        apps.push(app("Xonotic", "xonotic-sdl", None));
+        assert!(number(&mut apps));
+        assert_eq!((apps[0].id, apps[1].id), (AppId(2), AppId(3)));
+        assert!(!number(&mut apps));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut apps = vec![
+            app("Desktop", "", None),
+            app("Dota", "steam steam://rungameid/570", Some("steam:570")),
+            app(
+                "Uninstalled",
+                "steam steam://rungameid/620",
+                Some("steam:620"),
+            ),
+            app("My Tux", "supertux2 --fullscreen", None),
+        ];
+        apps[1].hdr = Some(Default::default());
+        number(&mut apps);
+
+        let scan = || {
+            vec![
+                found("steam:570", "Dota 2", "steam steam://rungameid/570"),
+                found("steam:570", "Dota 2", "steam steam://rungameid/570"),
+                found(
+                    "desktop:supertux2.desktop",
+                    "SuperTux",
+                    "supertux2 --fullscreen",
+                ),
+                found("desktop:xonotic.desktop", "Xonotic", "xonotic-sdl"),
+            ]
+        };
+        assert!(merge(&mut apps, scan()));
+        let titles: Vec<_> = apps.iter().map(|app| app.title.as_str()).collect();
+        assert_eq!(titles, ["Desktop", "Dota 2", "My Tux", "Xonotic"]);
+        // clients keep launching the apps they knew
+        let ids: Vec<_> = apps.iter().map(|app| app.id.0).collect();
+        assert_eq!(ids, [1, 2, 4, 5]);
+        assert!(apps[1].hdr.is_some());
+        assert_eq!(
+            apps[3].import_id.as_deref(),
+            Some("desktop:xonotic.desktop")
+        );
+
+        // rescanning finds nothing new
+        assert!(!merge(&mut apps, scan()));
+        assert_eq!(apps.len(), 4);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::Found;
+
+/// `StateFlags` bit of apps that are fully installed.
+const STATE_FULLY_INSTALLED: u32 = 4;
+
+/// Steam installs tools as apps, they aren't worth streaming.
+const TOOLS: &[&str] = &[
+    "Proton *",
+    "Steam Linux Runtime*",
+    "Steamworks Common Redistributables",
+];
+
+/// Where Steam may be installed under `home`, natively or as a Flatpak.
+pub fn roots(home: &Path) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for root in [
+        ".local/share/Steam",
+        ".steam/steam",
+        ".var/app/com.valvesoftware.Steam/.local/share/Steam",
+    ] {
+        // .steam/steam usually links to the first
+        if let Ok(root) = home.join(root).canonicalize() {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+    }
+    roots
+}
+
+/// The installed games of the Steam at `root`, across all its libraries.
+pub fn scan(root: &Path) -> Result<Vec<Found>> {
+    let path = root.join("steamapps/libraryfolders.vdf");
+    let folders =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut libraries = library_paths(&parse(&folders)?);
+    if !libraries.iter().any(|library| library.as_path() == root) {
+        libraries.insert(0, root.to_owned());
+    }
+
+    let mut found = Vec::new();
+    for library in libraries {
+        let steamapps = library.join("steamapps");
+        let entries = match fs::read_dir(&steamapps) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("Skipping Steam library {}: {}", library.display(), err);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_manifest = matches!(
+                path.file_name().and_then(|name| name.to_str()),
+                Some(name) if name.starts_with("appmanifest_") && name.ends_with(".acf")
+            );
+            if !is_manifest {
+                continue;
+            }
+            match manifest(&path) {
+                Ok(Some((id, title))) => found.push(Found {
+                    id: format!("steam:{}", id),
+                    title,
+                    command: format!("steam steam://rungameid/{}", id),
+                    asset: box_art(root, &id),
+                }),
+                Ok(None) => {}
+                Err(err) => log::warn!("Skipping {}: {:#}", path.display(), err),
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The id and name of the app of a manifest, if it is an installed game.
+fn manifest(path: &Path) -> Result<Option<(String, String)>> {
+    let manifest = parse(&fs::read_to_string(path)?)?;
+    let state = manifest.get("AppState").context("Not an app manifest")?;
+    let id = state.str("appid").context("No appid")?;
+    let name = state.str("name").context("No name")?;
+    let flags = state
+        .str("StateFlags")
+        .and_then(|flags| flags.parse::<u32>().ok())
+        .unwrap_or(0);
+    if flags & STATE_FULLY_INSTALLED == 0 || TOOLS.iter().any(|tool| super::matches(tool, name)) {
+        return Ok(None);
+    }
+    Ok(Some((id.to_owned(), name.to_owned())))
+}
+
+/// Libraries in `libraryfolders.vdf`, both the current and the old format where folders
+/// were plain paths.
+fn library_paths(folders: &Value) -> Vec<PathBuf> {
+    let folders = match folders
+        .get("libraryfolders")
+        .or_else(|| folders.get("LibraryFolders"))
+    {
+        Some(Value::Table(folders)) => folders,
+        _ => return Vec::new(),
+    };
+    folders
+        .iter()
+        .filter(|(key, _)| key.parse::<u32>().is_ok())
+        .filter_map(|(_, folder)| match folder {
+            Value::String(path) => Some(PathBuf::from(path)),
+            folder => folder.str("path").map(PathBuf::from),
+        })
+        .collect()
+}
+
+/// The cover Steam shows in its library, cached once the library was opened.
+fn box_art(root: &Path, id: &str) -> Option<PathBuf> {
+    let cache = root.join("appcache/librarycache");
+    [
+        cache.join(format!("{}_library_600x900.jpg", id)),
+        cache.join(id).join("library_600x900.jpg"),
+        cache.join(format!("{}_header.jpg", id)),
+        cache.join(id).join("header.jpg"),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+/// A KeyValues value, as Steam writes its VDF and ACF files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Keys are matched without case, Steam isn't consistent about it.
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Table(entries) => entries
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value),
+            Value::String(_) => None,
+        }
+    }
+
+    fn str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::String(value) => Some(value),
+            Value::Table(_) => None,
+        }
+    }
+}
+
+/// Parses a KeyValues document into a table of its top level entries.
+pub fn parse(text: &str) -> Result<Value> {
+    let mut tokens = Tokens { rest: text };
+    let entries = table(&mut tokens, false)?;
+    Ok(Value::Table(entries))
+}
+
+fn table(tokens: &mut Tokens, nested: bool) -> Result<Vec<(String, Value)>> {
+    let mut entries = Vec::new();
+    loop {
+        let key = match tokens.next()? {
+            Some(Token::String(key)) => key,
+            Some(Token::Close) if nested => return Ok(entries),
+            None if !nested => return Ok(entries),
+            Some(Token::Open) | Some(Token::Close) => bail!("Unexpected brace"),
+            None => bail!("Unclosed table"),
+        };
+        let value = match tokens.next()? {
+            Some(Token::String(value)) => Value::String(value),
+            Some(Token::Open) => Value::Table(table(tokens, true)?),
+            _ => bail!("No value for {:?}", key),
+        };
+        entries.push((key, value));
+    }
+}
+
+enum Token {
+    String(String),
+    Open,
+    Close,
+}
+
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl Tokens<'_> {
+    fn next(&mut self) -> Result<Option<Token>> {
+        loop {
+            self.rest = self.rest.trim_start();
+            if self.rest.starts_with("//") {
+                self.rest = self.rest.split_once('\n').map_or("", |(_, rest)| rest);
+            } else {
+                break;
+            }
+        }
+        let mut chars = self.rest.chars();
+        let token = match chars.next() {
+            None => return Ok(None),
+            Some('{') => Token::Open,
+            Some('}') => Token::Close,
+            Some('"') => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(c) => value.push(c),
+                            None => bail!("Unterminated string"),
+                        },
+                        Some(c) => value.push(c),
+                        None => bail!("Unterminated string"),
+                    }
+                }
+                Token::String(value)
+            }
+            // unquoted, ends at whitespace or a brace
+            Some(_) => {
+                let end = self
+                    .rest
+                    .find(|c: char| c.is_whitespace() || c == '{' || c == '}' || c == '"')
+                    .unwrap_or(self.rest.len());
+                let value = self.rest[..end].to_owned();
+                self.rest = &self.rest[end..];
+                return Ok(Some(Token::String(value)));
+            }
+        };
+        self.rest = chars.as_str();
+        Ok(Some(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY_FOLDERS: &str = r#"
+"libraryfolders"
+{
+	"0"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"label"		""
+		"apps"
+		{
+			"228980"		"420096051"
+		}
+	}
+	"1"
+	{
+		"path"		"/mnt/games/Steam \"Library\""
+	}
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let folders = parse(LIBRARY_FOLDERS).unwrap();
+        assert_eq!(
+            folders
+                .get("libraryfolders")
+                .and_then(|folders| folders.get("1"))
+                .and_then(|folder| folder.str("path")),
+            Some("/mnt/games/Steam \"Library\"")
+        );
+        assert_eq!(
+            library_paths(&folders),
+            [
+                PathBuf::from("/home/user/.local/share/Steam"),
+                PathBuf::from("/mnt/games/Steam \"Library\""),
+            ]
+        );
+
+        // before 2021 folders were plain paths, next to other settings
+        let old = parse(
+            "\"LibraryFolders\" { // comment\n \"TimeNextStatsReport\" \"1625000000\" \
+             \"1\" \"/mnt/games\" }",
+        )
+        .unwrap();
+        assert_eq!(library_paths(&old), [PathBuf::from("/mnt/games")]);
+
+        assert!(parse("\"AppState\" { \"appid\" \"570\"").is_err());
+    }
+
+    #[test]
+    fn test_scan() {
+        let root = std::env::temp_dir().join(format!("sunrise-steam-{}", uuid::Uuid::new_v4()));
+        let steamapps = root.join("steamapps");
+        fs::create_dir_all(&steamapps).unwrap();
+        fs::create_dir_all(root.join("appcache/librarycache")).unwrap();
+        fs::write(
+            steamapps.join("libraryfolders.vdf"),
+            format!(
+                "\"libraryfolders\" {{ \"0\" {{ \"path\" \"{}\" }} }}",
+                root.display()
+            ),
+        )
+        .unwrap();
+        let manifest = |id: &str, name: &str, flags: u32| {
+            fs::write(
+                steamapps.join(format!("appmanifest_{}.acf", id)),
+                format!(
+                    "\"AppState\"\n{{\n\t\"appid\"\t\t\"{}\"\n\t\"name\"\t\t\"{}\"\n\
+                     \t\"StateFlags\"\t\t\"{}\"\n}}\n",
+                    id, name, flags
+                ),
+            )
+            .unwrap();
+        };
+        manifest("570", "Dota 2", 4);
+        manifest("1493710", "Proton Experimental", 4);
+        manifest("620", "Portal 2", 1026);
+        let art = root.join("appcache/librarycache/570_library_600x900.jpg");
+        fs::write(&art, b"").unwrap();
+
+        let found = scan(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            found,
+            [Found {
+                id: "steam:570".into(),
+                title: "Dota 2".into(),
+                command: "steam steam://rungameid/570".into(),
+                asset: Some(art),
+            }]
+        );
+    }
+}
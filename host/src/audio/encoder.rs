@@ -0,0 +1,109 @@
+use std::{ffi::CStr, os::raw::c_int};
+
+use audiopus_sys::{
+    opus_multistream_encode, opus_multistream_encoder_create, opus_multistream_encoder_ctl,
+    opus_multistream_encoder_destroy, opus_strerror, OpusMSEncoder,
+    OPUS_APPLICATION_RESTRICTED_LOWDELAY, OPUS_OK, OPUS_SET_BITRATE_REQUEST, OPUS_SET_VBR_REQUEST,
+};
+
+use super::AudioConfig;
+
+pub const SAMPLE_RATE: u32 = 48000;
+/// Upper bound for a single encoded packet, as recommended by libopus.
+const MAX_PACKET_LEN: usize = 1400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusError(c_int);
+
+impl std::fmt::Display for OpusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = unsafe { CStr::from_ptr(opus_strerror(self.0)) };
+        write!(f, "Opus error: {}", msg.to_string_lossy())
+    }
+}
+
+impl std::error::Error for OpusError {}
+
+/// Multistream Opus encoder, used for stereo as well so all layouts share a code path.
+#[derive(Debug)]
+pub struct OpusEncoder {
+    encoder: *mut OpusMSEncoder,
+    config: AudioConfig,
+    frame_size: usize,
+}
+
+// the encoder state is only ever accessed through `&mut self`
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(config: AudioConfig, packet_duration_ms: u32) -> Result<OpusEncoder, OpusError> {
+        let mut err = OPUS_OK;
+        let encoder = unsafe {
+            opus_multistream_encoder_create(
+                SAMPLE_RATE as i32,
+                config.channels as c_int,
+                config.streams as c_int,
+                config.coupled_streams as c_int,
+                config.mapping.as_ptr(),
+                OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+                &mut err,
+            )
+        };
+        if err != OPUS_OK || encoder.is_null() {
+            return Err(OpusError(err));
+        }
+        let encoder = OpusEncoder {
+            encoder,
+            config,
+            frame_size: (SAMPLE_RATE * packet_duration_ms / 1000) as usize,
+        };
+
+        // the audio FEC requires every packet of a block to have the same size
+        encoder.ctl(OPUS_SET_VBR_REQUEST, 0)?;
+        encoder.ctl(OPUS_SET_BITRATE_REQUEST, config.bitrate)?;
+        Ok(encoder)
+    }
+
+    fn ctl(&self, request: c_int, value: i32) -> Result<(), OpusError> {
+        match unsafe { opus_multistream_encoder_ctl(self.encoder, request, value) } {
+            OPUS_OK => Ok(()),
+            err => Err(OpusError(err)),
+        }
+    }
+
+    pub fn config(&self) -> &AudioConfig {
+        &self.config
+    }
+
+    /// Samples per channel expected by [`OpusEncoder::encode`].
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Encodes a single frame of interleaved PCM samples.
+    pub fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, OpusError> {
+        assert_eq!(pcm.len(), self.frame_size * self.config.channels as usize);
+
+        let mut packet = vec![0; MAX_PACKET_LEN];
+        let len = unsafe {
+            opus_multistream_encode(
+                self.encoder,
+                pcm.as_ptr(),
+                self.frame_size as c_int,
+                packet.as_mut_ptr(),
+                packet.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(OpusError(len));
+        }
+        packet.truncate(len as usize);
+        Ok(packet)
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { opus_multistream_encoder_destroy(self.encoder) }
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        oneshot,
+    },
+    task::{self, JoinHandle},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    capture::{Capture as ProtocolCapture, MediaStream},
+    control::{AudioRequest, ControlHandle, ControlMessage, TERMINATION_FAILURE},
+    ping::PingValidator,
+    recorder::AudioTrack,
+};
+
+mod encoder;
+mod packetizer;
+pub mod source;
+pub use self::encoder::{OpusEncoder, OpusError, SAMPLE_RATE};
+pub use self::packetizer::{AudioPacketizer, PacketizeError};
+pub use self::source::{AudioBackend, AudioSource};
+
+/// Captured packets waiting to be encoded, the capture blocks beyond this.
+const PCM_QUEUE: usize = 4;
+
+/// Opus multistream layout of a channel configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub channels: u8,
+    pub streams: u8,
+    pub coupled_streams: u8,
+    pub mapping: &'static [u8],
+    pub bitrate: i32,
+}
+
+pub const STEREO: AudioConfig = AudioConfig {
+    channels: 2,
+    streams: 1,
+    coupled_streams: 1,
+    mapping: &[0, 1],
+    bitrate: 96_000,
+};
+
+pub const SURROUND_51: AudioConfig = AudioConfig {
+    channels: 6,
+    streams: 4,
+    coupled_streams: 2,
+    mapping: &[0, 4, 1, 5, 2, 3],
+    bitrate: 256_000,
+};
+
+pub const SURROUND_71: AudioConfig = AudioConfig {
+    channels: 8,
+    streams: 5,
+    coupled_streams: 3,
+    mapping: &[0, 6, 1, 7, 2, 3, 4, 5],
+    bitrate: 450_000,
+};
+
+pub const SUPPORTED_CONFIGS: &[AudioConfig] = &[STEREO, SURROUND_51, SURROUND_71];
+
+impl AudioConfig {
+    /// Picks the layout for the launch's `surroundAudioInfo`, which holds the channel mask in
+    /// the upper and the channel count in the lower 16 bits.
+    pub fn from_surround_info(info: u32) -> Option<AudioConfig> {
+        let channels = info & 0xffff;
+        SUPPORTED_CONFIGS
+            .iter()
+            .find(|config| config.channels as u32 == channels)
+            .copied()
+    }
+
+    /// The `surround-params` value announced to the client, so its decoder uses our layout.
+    pub fn surround_params(&self) -> String {
+        let mapping = self
+            .mapping
+            .iter()
+            .map(|channel| channel.to_string())
+            .collect::<String>();
+        format!(
+            "{}{}{}{}",
+            self.channels, self.streams, self.coupled_streams, mapping
+        )
+    }
+}
+
+pub fn init(address: IpAddr) -> std::io::Result<UdpSocket> {
+    crate::net::bind_udp(SocketAddr::new(address, crate::rtsp::AUDIO_PORT))
+}
+
+/// Encodes PCM and sends the resulting packets to a client over UDP.
+#[derive(Debug)]
+pub struct AudioSender {
+    socket: UdpSocket,
+    client: Option<SocketAddr>,
+    encoder: OpusEncoder,
+    packetizer: AudioPacketizer,
+    /// Receives a copy of every Opus packet sent.
+    recording: Option<AudioTrack>,
+    /// Receives the headers of every packet sent.
+    protocol: Option<ProtocolCapture>,
+}
+
+impl AudioSender {
+    pub fn new(
+        socket: UdpSocket,
+        encoder: OpusEncoder,
+        packetizer: AudioPacketizer,
+    ) -> AudioSender {
+        AudioSender {
+            socket,
+            client: None,
+            encoder,
+            packetizer,
+            recording: None,
+            protocol: None,
+        }
+    }
+
+    /// Records the packets sent from now on.
+    pub fn record(&mut self, track: AudioTrack) {
+        self.recording = Some(track);
+    }
+
+    /// Captures the headers of the packets sent from now on.
+    pub fn capture_protocol(&mut self, capture: ProtocolCapture) {
+        self.protocol = Some(capture);
+    }
+
+    /// Waits for the client to ping the audio port, which tells us where to send the stream.
+    pub async fn wait_for_client(&mut self, validator: &PingValidator) -> Result<SocketAddr> {
+        let addr = validator.wait_for_client(&self.socket, "Audio").await?;
+        self.client = Some(addr);
+        Ok(addr)
+    }
+
+    /// Samples per channel expected by [`AudioSender::send`].
+    pub fn frame_size(&self) -> usize {
+        self.encoder.frame_size()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.encoder.config().channels as usize
+    }
+
+    /// Sends one packet worth of interleaved PCM samples.
+    pub async fn send(&mut self, pcm: &[i16]) -> Result<()> {
+        let client = self.client.context("No audio client connected yet")?;
+        let opus = self.encoder.encode(pcm)?;
+        for packet in self.packetizer.packetize(&opus)? {
+            if let Some(capture) = &self.protocol {
+                capture.media(MediaStream::Audio, &packet, packetizer::RTP_HEADER_LEN);
+            }
+            self.socket.send_to(&packet, client).await?;
+        }
+        if let Some(track) = &mut self.recording {
+            track.push(&opus);
+        }
+        Ok(())
+    }
+}
+
+/// Starts capturing and streaming the audio of a session.
+///
+/// Nothing is captured before the client pinged the audio port, the returned receiver
+/// resolves to its address then. Failures, including a client that never pings, end the
+/// session by cancelling `shutdown` after telling the client through `control`. The stream
+/// stops along with the control stream or once `shutdown` is cancelled. The returned task
+/// finishes after the port and the source were released.
+#[allow(clippy::too_many_arguments)]
+pub async fn start(
+    address: IpAddr,
+    config: AudioConfig,
+    packetizer: AudioPacketizer,
+    source: Box<dyn AudioSource>,
+    validator: PingValidator,
+    control: ControlHandle,
+    requests: UnboundedReceiver<AudioRequest>,
+    recording: Option<AudioTrack>,
+    protocol: Option<ProtocolCapture>,
+    shutdown: CancellationToken,
+) -> Result<(JoinHandle<()>, oneshot::Receiver<SocketAddr>)> {
+    let setup = init(address)
+        .context("Failed to bind audio port")
+        .and_then(|socket| {
+            Ok((
+                socket,
+                OpusEncoder::new(config, packetizer.packet_duration())?,
+            ))
+        });
+    let mut sender = match setup {
+        Ok((socket, encoder)) => AudioSender::new(socket, encoder, packetizer),
+        Err(err) => {
+            // removing a PulseAudio sink blocks on the sound server
+            let _ = task::spawn_blocking(move || drop(source)).await;
+            return Err(err);
+        }
+    };
+    if let Some(track) = recording {
+        sender.record(track);
+    }
+    if let Some(capture) = protocol {
+        sender.capture_protocol(capture);
+    }
+    let (client, client_addr) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let result = async {
+            let waited = tokio::select! {
+                addr = sender.wait_for_client(&validator) => addr.map(Some),
+                _ = shutdown.cancelled() => Ok(None),
+            };
+            let addr = match waited {
+                Ok(Some(addr)) => addr,
+                other => {
+                    // removing a PulseAudio sink blocks on the sound server
+                    let _ = task::spawn_blocking(move || drop(source)).await;
+                    return other.map(|_| ());
+                }
+            };
+            let _ = client.send(addr);
+            run(sender, source, requests, &shutdown).await
+        };
+        if let Err(err) = result.await {
+            log::error!("Audio stream failed: {:#}", err);
+            let _ = control.send(ControlMessage::Termination {
+                error_code: TERMINATION_FAILURE,
+            });
+            shutdown.cancel();
+        }
+    });
+    Ok((task, client_addr))
+}
+
+/// Reads the source on a thread of its own, sources block until they captured enough.
+///
+/// The thread drops the source once `packets` closed.
+fn spawn_capture(
+    mut source: Box<dyn AudioSource>,
+    samples: usize,
+    packets: mpsc::Sender<Result<Vec<i16>>>,
+) -> Result<thread::JoinHandle<()>> {
+    let thread = thread::Builder::new()
+        .name("audio-capture".into())
+        .spawn(move || loop {
+            let mut pcm = vec![0; samples];
+            let packet = match source.read(&mut pcm) {
+                Ok(true) => Ok(pcm),
+                Ok(false) => return,
+                Err(err) => Err(err),
+            };
+            let failed = packet.is_err();
+            // the stream ended
+            if packets.blocking_send(packet).is_err() || failed {
+                return;
+            }
+        })?;
+    Ok(thread)
+}
+
+async fn run(
+    mut sender: AudioSender,
+    source: Box<dyn AudioSource>,
+    mut requests: UnboundedReceiver<AudioRequest>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    log::info!("Capturing audio with {}", source.name());
+    let (packets, mut pcm) = mpsc::channel(PCM_QUEUE);
+    let capture = spawn_capture(source, sender.frame_size() * sender.channels(), packets)?;
+
+    let result = forward(&mut sender, &mut pcm, &mut requests, shutdown).await;
+    // a source like a PulseAudio sink is only removed as the thread drops it
+    drop(pcm);
+    let _ = task::spawn_blocking(move || capture.join()).await;
+    result
+}
+
+/// Sends the captured audio until the source or the session ends.
+async fn forward(
+    sender: &mut AudioSender,
+    pcm: &mut mpsc::Receiver<Result<Vec<i16>>>,
+    requests: &mut UnboundedReceiver<AudioRequest>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            packet = pcm.recv() => match packet {
+                Some(packet) => sender.send(&packet?).await?,
+                None => {
+                    log::info!("Audio source ended");
+                    return Ok(());
+                }
+            },
+            request = requests.recv() => match request {
+                Some(AudioRequest::Start) => {}
+                // the control stream closed
+                None => return Ok(()),
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_surround_info() {
+        assert_eq!(AudioConfig::from_surround_info(0x3 << 16 | 2), Some(STEREO));
+        assert_eq!(
+            AudioConfig::from_surround_info(0x3f << 16 | 6),
+            Some(SURROUND_51)
+        );
+        assert_eq!(
+            AudioConfig::from_surround_info(0x63f << 16 | 8),
+            Some(SURROUND_71)
+        );
+        assert_eq!(AudioConfig::from_surround_info(4), None);
+    }
+
+    #[test]
+    fn test_surround_params() {
+        assert_eq!(STEREO.surround_params(), "21101");
+        assert_eq!(SURROUND_51.surround_params(), "642041523");
+        assert_eq!(SURROUND_71.surround_params(), "85306172345");
+    }
+}
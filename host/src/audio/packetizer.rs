@@ -0,0 +1,215 @@
+use openssl::error::ErrorStack;
+use reed_solomon_erasure::{galois_8::ReedSolomon, Error as RsError};
+
+use crate::crypto::aes_encrypt_cbc;
+
+pub const RTP_HEADER_LEN: usize = 12;
+/// `fecShardIndex` + `payloadType` + `baseSequenceNumber` + `baseTimestamp` + `ssrc`.
+pub const FEC_HEADER_LEN: usize = 12;
+pub const DATA_SHARDS: usize = 4;
+pub const FEC_SHARDS: usize = 2;
+
+const RTP_VERSION: u8 = 0x80;
+pub const PAYLOAD_TYPE: u8 = 97;
+pub const FEC_PAYLOAD_TYPE: u8 = 127;
+
+#[derive(Debug)]
+pub enum PacketizeError {
+    Crypto(ErrorStack),
+    Fec(RsError),
+}
+
+impl std::fmt::Display for PacketizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketizeError::Crypto(err) => write!(f, "Failed to encrypt audio packet: {}", err),
+            PacketizeError::Fec(err) => write!(f, "Failed to generate FEC shards: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for PacketizeError {}
+
+/// The IV of every audio packet, derived from the `rikeyid` and its sequence number.
+pub fn iv(key_id: u32, sequence_number: u16) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[0..4].copy_from_slice(&key_id.wrapping_add(sequence_number as u32).to_be_bytes());
+    iv
+}
+
+fn rtp_header(packet: &mut Vec<u8>, payload_type: u8, sequence_number: u16, timestamp: u32) {
+    packet.push(RTP_VERSION);
+    packet.push(payload_type);
+    packet.extend_from_slice(&sequence_number.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+}
+
+/// Encrypts Opus packets into RTP and adds two FEC packets after every four data packets.
+#[derive(Debug)]
+pub struct AudioPacketizer {
+    key: [u8; 16],
+    key_id: u32,
+    /// Duration of a packet in milliseconds, which is also the timestamp increment.
+    packet_duration: u32,
+    sequence_number: u16,
+    timestamp: u32,
+    /// Encrypted payloads of the current FEC block.
+    shards: Vec<Vec<u8>>,
+}
+
+impl AudioPacketizer {
+    pub fn new(key: [u8; 16], key_id: u32, packet_duration: u32) -> AudioPacketizer {
+        AudioPacketizer {
+            key,
+            key_id,
+            packet_duration,
+            sequence_number: 0,
+            timestamp: 0,
+            shards: Vec::with_capacity(DATA_SHARDS + FEC_SHARDS),
+        }
+    }
+
+    /// Duration of a packet in milliseconds.
+    pub fn packet_duration(&self) -> u32 {
+        self.packet_duration
+    }
+
+    /// Returns the datagrams to send for an encoded Opus packet.
+    pub fn packetize(&mut self, opus: &[u8]) -> Result<Vec<Vec<u8>>, PacketizeError> {
+        let sequence_number = self.sequence_number;
+        let timestamp = self.timestamp;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(self.packet_duration);
+
+        let payload = aes_encrypt_cbc(opus, &self.key, &iv(self.key_id, sequence_number))
+            .map_err(PacketizeError::Crypto)?;
+        let mut packet = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+        rtp_header(&mut packet, PAYLOAD_TYPE, sequence_number, timestamp);
+        packet.extend_from_slice(&payload);
+
+        let mut packets = vec![packet];
+        self.shards.push(payload);
+        if self.shards.len() == DATA_SHARDS {
+            packets.extend(self.fec_packets(sequence_number, timestamp)?);
+        }
+        Ok(packets)
+    }
+
+    fn fec_packets(
+        &mut self,
+        sequence_number: u16,
+        timestamp: u32,
+    ) -> Result<Vec<Vec<u8>>, PacketizeError> {
+        let mut shards = std::mem::take(&mut self.shards);
+        // constant bitrate keeps these equal, but don't rely on it for correctness
+        let len = shards.iter().map(Vec::len).max().unwrap_or(0);
+        shards.iter_mut().for_each(|shard| shard.resize(len, 0));
+        shards.resize(DATA_SHARDS + FEC_SHARDS, vec![0; len]);
+        ReedSolomon::new(DATA_SHARDS, FEC_SHARDS)
+            .and_then(|rs| rs.encode(&mut shards))
+            .map_err(PacketizeError::Fec)?;
+
+        let base_sequence_number = sequence_number.wrapping_sub(DATA_SHARDS as u16 - 1);
+        let base_timestamp =
+            timestamp.wrapping_sub((DATA_SHARDS as u32 - 1) * self.packet_duration);
+
+        Ok(shards[DATA_SHARDS..]
+            .iter()
+            .enumerate()
+            .map(|(index, shard)| {
+                let mut packet = Vec::with_capacity(RTP_HEADER_LEN + FEC_HEADER_LEN + len);
+                rtp_header(
+                    &mut packet,
+                    FEC_PAYLOAD_TYPE,
+                    sequence_number.wrapping_add(index as u16 + 1),
+                    0,
+                );
+                packet.push(index as u8);
+                packet.push(PAYLOAD_TYPE);
+                packet.extend_from_slice(&base_sequence_number.to_be_bytes());
+                packet.extend_from_slice(&base_timestamp.to_be_bytes());
+                packet.extend_from_slice(&0u32.to_be_bytes());
+                packet.extend_from_slice(shard);
+                packet
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::aes_decrypt_cbc;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    const KEY_ID: u32 = 0x1234;
+
+    fn opus(index: u8) -> Vec<u8> {
+        vec![index; 40]
+    }
+
+    #[test]
+    fn test_data_packet() {
+        let mut packetizer = AudioPacketizer::new(KEY, KEY_ID, 5);
+        packetizer.packetize(&opus(0)).unwrap();
+        let packets = packetizer.packetize(&opus(1)).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let packet = &packets[0];
+        assert_eq!(&packet[0..2], &[0x80, PAYLOAD_TYPE]);
+        assert_eq!(&packet[2..4], &1u16.to_be_bytes());
+        assert_eq!(&packet[4..8], &5u32.to_be_bytes());
+
+        let iv = iv(KEY_ID, 1);
+        assert_eq!(&iv[0..4], &0x1235u32.to_be_bytes());
+        let plaintext = aes_decrypt_cbc(&packet[RTP_HEADER_LEN..], &KEY, &iv).unwrap();
+        assert_eq!(plaintext, opus(1));
+    }
+
+    #[test]
+    fn test_fec_recovers_lost_packet() {
+        let mut packetizer = AudioPacketizer::new(KEY, KEY_ID, 5);
+        let packets = (0..DATA_SHARDS as u8)
+            .flat_map(|index| packetizer.packetize(&opus(index)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(packets.len(), DATA_SHARDS + FEC_SHARDS);
+
+        let fec = &packets[DATA_SHARDS];
+        assert_eq!(fec[1], FEC_PAYLOAD_TYPE);
+        assert_eq!(&fec[2..4], &4u16.to_be_bytes());
+        assert_eq!(fec[RTP_HEADER_LEN], 0);
+        assert_eq!(fec[RTP_HEADER_LEN + 1], PAYLOAD_TYPE);
+        assert_eq!(
+            &fec[RTP_HEADER_LEN + 2..RTP_HEADER_LEN + 4],
+            &0u16.to_be_bytes()
+        );
+        assert_eq!(
+            &fec[RTP_HEADER_LEN + 4..RTP_HEADER_LEN + 8],
+            &0u32.to_be_bytes()
+        );
+        assert_eq!(packets[DATA_SHARDS + 1][RTP_HEADER_LEN], 1);
+
+        // lose the second data packet and rebuild it from parity
+        let mut shards = packets
+            .iter()
+            .enumerate()
+            .map(|(index, packet)| {
+                let offset = if index < DATA_SHARDS {
+                    RTP_HEADER_LEN
+                } else {
+                    RTP_HEADER_LEN + FEC_HEADER_LEN
+                };
+                Some(packet[offset..].to_vec())
+            })
+            .collect::<Vec<_>>();
+        shards[1] = None;
+        ReedSolomon::new(DATA_SHARDS, FEC_SHARDS)
+            .unwrap()
+            .reconstruct_data(&mut shards)
+            .unwrap();
+
+        let plaintext = aes_decrypt_cbc(shards[1].as_ref().unwrap(), &KEY, &iv(KEY_ID, 1));
+        assert_eq!(plaintext.unwrap(), opus(1));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use super::AudioSource;
+use crate::audio::AudioConfig;
+
+/// Plays back raw interleaved 16 bit little endian PCM, as fast as it is read.
+///
+/// The file has to match the negotiated layout, nothing is converted.
+pub struct FileSource {
+    reader: Box<dyn Read + Send>,
+    ended: bool,
+}
+
+impl FileSource {
+    pub fn open(path: &Path, config: AudioConfig) -> Result<FileSource> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open audio file {}", path.display()))?;
+        log::info!(
+            "Playing {} as {} channel audio",
+            path.display(),
+            config.channels
+        );
+        Ok(FileSource::new(BufReader::new(file)))
+    }
+
+    pub fn new(reader: impl Read + Send + 'static) -> FileSource {
+        FileSource {
+            reader: Box::new(reader),
+            ended: false,
+        }
+    }
+}
+
+impl AudioSource for FileSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn read(&mut self, pcm: &mut [i16]) -> Result<bool> {
+        if self.ended {
+            return Ok(false);
+        }
+
+        let mut bytes = vec![0; pcm.len() * 2];
+        let mut len = 0;
+        while len < bytes.len() {
+            match self.reader.read(&mut bytes[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err).context("Failed to read audio file"),
+            }
+        }
+        // the last samples are padded with silence
+        self.ended = len < bytes.len();
+        for (sample, bytes) in pcm.iter_mut().zip(bytes.chunks_exact(2)) {
+            *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(len > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file() {
+        let samples = (0..10i16).map(|i| i * 100 - 500).collect::<Vec<_>>();
+        let data = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut source = FileSource::new(std::io::Cursor::new(data));
+
+        let mut pcm = [0; 6];
+        assert!(source.read(&mut pcm).unwrap());
+        assert_eq!(pcm, samples[..6]);
+        assert!(source.read(&mut pcm).unwrap());
+        assert_eq!(pcm, [100, 200, 300, 400, 0, 0]);
+        assert!(!source.read(&mut pcm).unwrap());
+    }
+}
@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::AudioConfig;
+
+mod file;
+#[cfg(feature = "pulseaudio")]
+mod pulse;
+mod tone;
+pub use self::file::FileSource;
+#[cfg(feature = "pulseaudio")]
+pub use self::pulse::PulseSource;
+pub use self::tone::ToneSource;
+
+/// Frequency of the test tone, an A4.
+pub const TONE_FREQUENCY: f32 = 440.0;
+
+/// Where the audio of a session comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioBackend {
+    /// A virtual sink per session, the app's audio goes nowhere else.
+    #[default]
+    Pulse,
+    /// A sine tone on every channel.
+    Tone,
+    /// Raw interleaved 16 bit little endian PCM at 48 kHz and the negotiated layout.
+    File(PathBuf),
+}
+
+/// Delivers interleaved 16 bit PCM at [`super::SAMPLE_RATE`] in the layout of the session.
+pub trait AudioSource: Send {
+    fn name(&self) -> &'static str;
+
+    /// Fills `pcm` with the next samples, blocking until enough were captured.
+    ///
+    /// Returns false once the source ended.
+    fn read(&mut self, pcm: &mut [i16]) -> Result<bool>;
+
+    /// Environment variables routing the audio of a launched app into this source.
+    fn app_env(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Creates the configured source for a session, `name` tells sessions apart.
+pub fn create(
+    backend: &AudioBackend,
+    config: AudioConfig,
+    name: &str,
+) -> Result<Box<dyn AudioSource>> {
+    Ok(match backend {
+        #[cfg(feature = "pulseaudio")]
+        AudioBackend::Pulse => Box::new(PulseSource::new(config, name)?),
+        #[cfg(not(feature = "pulseaudio"))]
+        AudioBackend::Pulse => anyhow::bail!("Built without PulseAudio support ({})", name),
+        AudioBackend::Tone => Box::new(ToneSource::realtime(config, TONE_FREQUENCY)),
+        AudioBackend::File(path) => Box::new(FileSource::open(path, config)?),
+    })
+}
@@ -0,0 +1,216 @@
+use std::{cell::Cell, rc::Rc};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use pulse::{
+    channelmap::{Map, Position},
+    context::{Context, FlagSet, State},
+    def::BufferAttr,
+    mainloop::standard::{IterateResult, Mainloop},
+    operation::{self, Operation},
+    sample::{Format, Spec},
+    stream::Direction,
+};
+use pulse_simple::Simple;
+
+use super::AudioSource;
+use crate::audio::{AudioConfig, SAMPLE_RATE};
+
+const APPLICATION_NAME: &str = "Sunrise";
+/// Channel order of the Opus layouts for up to 7.1 channels.
+const POSITIONS: [(Position, &str); 8] = [
+    (Position::FrontLeft, "front-left"),
+    (Position::FrontRight, "front-right"),
+    (Position::FrontCenter, "front-center"),
+    (Position::Lfe, "lfe"),
+    (Position::RearLeft, "rear-left"),
+    (Position::RearRight, "rear-right"),
+    (Position::SideLeft, "side-left"),
+    (Position::SideRight, "side-right"),
+];
+/// Audio buffered by the sound server before it is handed to us, keeps the latency low.
+const FRAGMENT_MS: u32 = 10;
+
+/// A short lived connection to the sound server for managing modules.
+///
+/// The mainloop can't leave the thread it was created on, so none is kept around.
+struct Connection {
+    mainloop: Mainloop,
+    context: Context,
+}
+
+impl Connection {
+    fn new() -> Result<Connection> {
+        let mut mainloop = Mainloop::new().context("Failed to create PulseAudio mainloop")?;
+        let mut context = Context::new(&mainloop, APPLICATION_NAME)
+            .context("Failed to create PulseAudio context")?;
+        context
+            .connect(None, FlagSet::NOFLAGS, None)
+            .context("Failed to connect to the sound server")?;
+        loop {
+            iterate(&mut mainloop)?;
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => bail!("Failed to connect to the sound server"),
+                _ => {}
+            }
+        }
+        Ok(Connection { mainloop, context })
+    }
+
+    fn wait<T: ?Sized>(&mut self, pending: Operation<T>) -> Result<()> {
+        while pending.get_state() == operation::State::Running {
+            iterate(&mut self.mainloop)?;
+        }
+        Ok(())
+    }
+
+    fn load_module(&mut self, name: &str, argument: &str) -> Result<u32> {
+        let index = Rc::new(Cell::new(None));
+        let operation = {
+            let index = index.clone();
+            self.context
+                .introspect()
+                .load_module(name, argument, move |loaded| index.set(Some(loaded)))
+        };
+        self.wait(operation)?;
+        match index.get() {
+            Some(index) if index != pulse::def::INVALID_INDEX => Ok(index),
+            _ => bail!("Failed to load {} {}", name, argument),
+        }
+    }
+
+    fn unload_module(&mut self, index: u32) -> Result<()> {
+        let unloaded = Rc::new(Cell::new(false));
+        let operation = {
+            let unloaded = unloaded.clone();
+            self.context
+                .introspect()
+                .unload_module(index, move |success| unloaded.set(success))
+        };
+        self.wait(operation)?;
+        if !unloaded.get() {
+            bail!("Failed to unload module {}", index);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.context.disconnect();
+    }
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<()> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) => bail!("PulseAudio mainloop quit"),
+        IterateResult::Err(err) => Err(anyhow!("PulseAudio mainloop failed: {}", err)),
+    }
+}
+
+/// Records a virtual sink created for a session, through the PulseAudio protocol that
+/// PipeWire serves as well.
+///
+/// Apps launched with [`AudioSource::app_env`] play into the sink, which is removed again
+/// once this is dropped.
+pub struct PulseSource {
+    sink: String,
+    module: u32,
+    recorder: Option<Simple>,
+    buffer: Vec<u8>,
+}
+
+impl PulseSource {
+    pub fn new(config: AudioConfig, name: &str) -> Result<PulseSource> {
+        let channels = config.channels as usize;
+        if channels > POSITIONS.len() {
+            bail!("Unsupported channel count {}", channels);
+        }
+        let sink = format!("sunrise-{}", name);
+        let channel_map = POSITIONS[..channels]
+            .iter()
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(",");
+        let argument = format!(
+            "sink_name={} sink_properties=device.description={} rate={} channels={} channel_map={}",
+            sink, sink, SAMPLE_RATE, channels, channel_map
+        );
+        let module = Connection::new()?.load_module("module-null-sink", &argument)?;
+        log::info!("Created audio sink {}", sink);
+
+        let mut source = PulseSource {
+            sink,
+            module,
+            recorder: None,
+            buffer: Vec::new(),
+        };
+        source.recorder = Some(source.record(config)?);
+        Ok(source)
+    }
+
+    fn record(&self, config: AudioConfig) -> Result<Simple> {
+        let spec = Spec {
+            format: Format::S16le,
+            channels: config.channels,
+            rate: SAMPLE_RATE,
+        };
+        let mut map = Map::default();
+        map.set_len(config.channels);
+        for (position, (expected, _)) in map.get_mut().iter_mut().zip(POSITIONS) {
+            *position = expected;
+        }
+        let fragment = spec.usec_to_bytes(pulse::time::MicroSeconds(FRAGMENT_MS as u64 * 1000));
+        let attr = BufferAttr {
+            maxlength: u32::MAX,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: fragment as u32,
+        };
+        Simple::new(
+            None,
+            APPLICATION_NAME,
+            Direction::Record,
+            Some(&format!("{}.monitor", self.sink)),
+            "Session audio",
+            &spec,
+            Some(&map),
+            Some(&attr),
+        )
+        .map_err(|err| anyhow!("Failed to record {}: {}", self.sink, err))
+    }
+}
+
+impl AudioSource for PulseSource {
+    fn name(&self) -> &'static str {
+        "PulseAudio"
+    }
+
+    fn read(&mut self, pcm: &mut [i16]) -> Result<bool> {
+        let recorder = self.recorder.as_ref().context("Audio sink is gone")?;
+        self.buffer.resize(pcm.len() * 2, 0);
+        recorder
+            .read(&mut self.buffer)
+            .map_err(|err| anyhow!("Failed to record {}: {}", self.sink, err))?;
+        for (sample, bytes) in pcm.iter_mut().zip(self.buffer.chunks_exact(2)) {
+            *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(true)
+    }
+
+    fn app_env(&self) -> Vec<(String, String)> {
+        vec![("PULSE_SINK".into(), self.sink.clone())]
+    }
+}
+
+impl Drop for PulseSource {
+    fn drop(&mut self) {
+        // stop recording before the sink goes away
+        self.recorder = None;
+        if let Err(err) = Connection::new().and_then(|mut c| c.unload_module(self.module)) {
+            log::warn!("Failed to remove audio sink {}: {:#}", self.sink, err);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    f32::consts::TAU,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::AudioSource;
+use crate::audio::{AudioConfig, SAMPLE_RATE};
+
+/// Amplitude of the tone, loud enough to hear without clipping.
+const AMPLITUDE: f32 = 0.25 * i16::MAX as f32;
+
+/// A sine tone on every channel, for tests and setups without a sound server.
+#[derive(Debug)]
+pub struct ToneSource {
+    channels: usize,
+    frequency: f32,
+    /// Samples per channel produced so far.
+    position: u64,
+    /// Set to deliver samples no faster than they would be played.
+    started: Option<Instant>,
+}
+
+impl ToneSource {
+    /// A source producing samples as fast as they are read.
+    pub fn new(config: AudioConfig, frequency: f32) -> ToneSource {
+        ToneSource {
+            channels: config.channels as usize,
+            frequency,
+            position: 0,
+            started: None,
+        }
+    }
+
+    /// A source producing samples at the rate they would be played.
+    pub fn realtime(config: AudioConfig, frequency: f32) -> ToneSource {
+        ToneSource {
+            started: Some(Instant::now()),
+            ..ToneSource::new(config, frequency)
+        }
+    }
+}
+
+impl AudioSource for ToneSource {
+    fn name(&self) -> &'static str {
+        "test tone"
+    }
+
+    fn read(&mut self, pcm: &mut [i16]) -> Result<bool> {
+        for frame in pcm.chunks_mut(self.channels) {
+            let time = self.position as f32 / SAMPLE_RATE as f32;
+            let sample = (AMPLITUDE * (TAU * self.frequency * time).sin()) as i16;
+            frame.fill(sample);
+            self.position += 1;
+        }
+
+        if let Some(started) = self.started {
+            let due = started + Duration::from_secs_f64(self.position as f64 / SAMPLE_RATE as f64);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SURROUND_51;
+
+    #[test]
+    fn test_tone() {
+        let mut source = ToneSource::new(SURROUND_51, 1000.0);
+        // 10ms at 48kHz
+        let mut pcm = vec![0; 480 * 6];
+        assert!(source.read(&mut pcm).unwrap());
+
+        assert_eq!(&pcm[..6], [0; 6]);
+        // every channel carries the same tone
+        assert!(pcm
+            .chunks(6)
+            .all(|frame| frame.iter().all(|s| *s == frame[0])));
+        // a 1kHz tone peaks after 12 of its 48 samples per period
+        assert_eq!(pcm[12 * 6], AMPLITUDE as i16);
+        assert_eq!(pcm[36 * 6], -AMPLITUDE as i16);
+
+        // the next read continues the wave
+        let mut next = vec![0; 48 * 6];
+        source.read(&mut next).unwrap();
+        assert_eq!(&next[..6], [0; 6]);
+        assert_eq!(next[12 * 6], AMPLITUDE as i16);
+    }
+
+    #[test]
+    fn test_realtime() {
+        let mut source = ToneSource::realtime(crate::audio::STEREO, 440.0);
+        let started = Instant::now();
+        let mut pcm = vec![0; 240 * 2];
+        for _ in 0..4 {
+            source.read(&mut pcm).unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, TryRecvError},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::control::ControlMessage;
+
+mod replay;
+pub use self::replay::{replay, replay_control, replay_rtsp, Divergence, Replay};
+
+/// Which end of a connection sent a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Client,
+    Host,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaStream {
+    Video,
+    Audio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// What the client asked for at launch.
+    Launch {
+        rtsp_encryption: bool,
+        control_encryption: bool,
+        audio_channels: u8,
+    },
+    /// An RTSP message as sent on the wire, after decryption.
+    Rtsp { from: Side, message: String },
+    /// An encoded control message, after decryption.
+    Control { from: Side, message: Vec<u8> },
+    /// The headers of a media packet sent to the client, the payload is left out.
+    Media {
+        stream: MediaStream,
+        header: Vec<u8>,
+        len: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Microseconds since the capture started.
+    pub time_us: u64,
+    pub event: Event,
+}
+
+/// Records the protocol traffic of a session, one RON encoded [`Record`] per line.
+///
+/// Records are written on a thread of its own, so capturing never blocks the streams. The
+/// file is complete once every clone of this was dropped.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    epoch: Instant,
+    records: mpsc::Sender<Record>,
+}
+
+impl Capture {
+    /// Starts capturing a session into a new file in `directory`.
+    pub fn create(directory: &Path, session_id: Uuid) -> Result<Capture> {
+        fs::create_dir_all(directory).with_context(|| {
+            format!("Failed to create capture directory {}", directory.display())
+        })?;
+        let path = capture_path(directory, session_id);
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create capture {}", path.display()))?;
+        log::info!("Capturing session {} to {}", session_id, path.display());
+        Capture::new(BufWriter::new(file))
+    }
+
+    pub fn new(mut writer: impl Write + Send + 'static) -> Result<Capture> {
+        let (records, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("capture".into())
+            .spawn(move || {
+                if let Err(err) = write_records(&received, &mut writer) {
+                    log::error!("Failed to write capture: {:#}", err);
+                }
+            })?;
+        Ok(Capture {
+            epoch: Instant::now(),
+            records,
+        })
+    }
+
+    fn record(&self, event: Event) {
+        // the writer only goes away after failing, which it reported already
+        let _ = self.records.send(Record {
+            time_us: self.epoch.elapsed().as_micros() as u64,
+            event,
+        });
+    }
+
+    pub fn launch(&self, rtsp_encryption: bool, control_encryption: bool, audio_channels: u8) {
+        self.record(Event::Launch {
+            rtsp_encryption,
+            control_encryption,
+            audio_channels,
+        });
+    }
+
+    pub fn rtsp(&self, from: Side, message: &[u8]) {
+        self.record(Event::Rtsp {
+            from,
+            message: String::from_utf8_lossy(message).into_owned(),
+        });
+    }
+
+    pub fn control(&self, from: Side, message: &ControlMessage) {
+        self.record(Event::Control {
+            from,
+            message: message.encode(),
+        });
+    }
+
+    /// Records the first `header_len` bytes of a packet.
+    pub fn media(&self, stream: MediaStream, packet: &[u8], header_len: usize) {
+        self.record(Event::Media {
+            stream,
+            header: packet[..header_len.min(packet.len())].to_vec(),
+            len: packet.len(),
+        });
+    }
+}
+
+/// Writes records until every [`Capture`] was dropped.
+fn write_records(received: &mpsc::Receiver<Record>, writer: &mut impl Write) -> Result<()> {
+    loop {
+        let record = match received.try_recv() {
+            Ok(record) => record,
+            // write out what we have while waiting for more
+            Err(TryRecvError::Empty) => {
+                writer.flush()?;
+                match received.recv() {
+                    Ok(record) => record,
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(TryRecvError::Disconnected) => return Ok(writer.flush()?),
+        };
+        writeln!(writer, "{}", ron::ser::to_string(&record)?)?;
+    }
+}
+
+fn capture_path(directory: &Path, session_id: Uuid) -> PathBuf {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    directory.join(format!("{}-{}.ron", started, session_id.simple()))
+}
+
+/// Parses the records of a capture.
+pub fn read(reader: impl BufRead) -> Result<Vec<Record>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(number, line)| {
+            let line = line.context("Failed to read capture")?;
+            ron::de::from_str(&line)
+                .with_context(|| format!("Invalid capture record on line {}", number + 1))
+        })
+        .collect()
+}
+
+pub fn load(path: &Path) -> Result<Vec<Record>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open capture {}", path.display()))?;
+    read(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Collects what was written, so it can be inspected after the writer thread ended.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let output = Shared::default();
+        let capture = Capture::new(output.clone()).unwrap();
+        capture.launch(true, true, 2);
+        capture.rtsp(Side::Client, b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n");
+        capture.control(Side::Client, &ControlMessage::RequestIdr);
+        capture.media(MediaStream::Audio, &[0x80, 97, 0, 1, 2, 3], 4);
+        drop(capture);
+
+        // the writer ends once it drained the channel
+        let data = loop {
+            let data = output.0.lock().unwrap().clone();
+            if data.iter().filter(|b| **b == b'\n').count() == 4 {
+                break data;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let records = read(Cursor::new(data)).unwrap();
+        let events = records.into_iter().map(|r| r.event).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                Event::Launch {
+                    rtsp_encryption: true,
+                    control_encryption: true,
+                    audio_channels: 2
+                },
+                Event::Rtsp {
+                    from: Side::Client,
+                    message: "OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n".into()
+                },
+                Event::Control {
+                    from: Side::Client,
+                    message: ControlMessage::RequestIdr.encode()
+                },
+                Event::Media {
+                    stream: MediaStream::Audio,
+                    header: vec![0x80, 97, 0, 1],
+                    len: 6
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        let err = read(Cursor::new("\n(time_us: 1, event: Bogus)\n")).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid capture record on line 2");
+    }
+}
@@ -0,0 +1,344 @@
+use std::{net::IpAddr, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use rtsp_types::{headers::CSeq, Message, Method, ParseError, Response};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{Event, Record, Side};
+use crate::{
+    audio::AudioConfig,
+    control::{Components, ControlMessage},
+    ping::PingPayload,
+    state::Host,
+    AppId, Client, Config, Session, SharedState,
+};
+
+/// How the host handled a replayed capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub divergences: Vec<Divergence>,
+    /// The client's control messages, all of which the host understood.
+    pub control_messages: usize,
+}
+
+/// An answer of the host that differs from the captured one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// First line of the request.
+    pub request: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, got {}",
+            self.request, self.expected, self.actual
+        )
+    }
+}
+
+fn messages(records: &[Record], side: Side) -> impl Iterator<Item = &str> {
+    records
+        .iter()
+        .filter_map(move |record| match &record.event {
+            Event::Rtsp { from, message } if *from == side => Some(message.as_str()),
+            _ => None,
+        })
+}
+
+fn parse(message: &str) -> Result<Message<Vec<u8>>> {
+    match Message::<Vec<u8>>::parse(message.as_bytes()) {
+        Ok((message, _)) => Ok(message),
+        Err(_) => bail!("Invalid RTSP message in capture: {:?}", message),
+    }
+}
+
+/// The parts of a response that don't change between sessions.
+fn summary(response: &Response<Vec<u8>>) -> String {
+    let cseq = response
+        .typed_header::<CSeq>()
+        .ok()
+        .flatten()
+        .map(u32::from);
+    format!(
+        "{} (CSeq {:?}, {} byte body)",
+        u16::from(response.status()),
+        cseq,
+        response.body().len()
+    )
+}
+
+/// Reads the next response of the host.
+async fn receive(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Result<Response<Vec<u8>>> {
+    loop {
+        match Message::<Vec<u8>>::parse(buffer) {
+            Ok((Message::Response(response), len)) => {
+                buffer.drain(..len);
+                return Ok(response);
+            }
+            Ok(_) => bail!("Host sent something other than a response"),
+            Err(ParseError::Incomplete) => {}
+            Err(ParseError::Error) => bail!("Host sent a malformed response"),
+        }
+        if stream.read_buf(buffer).await? == 0 {
+            bail!("Host closed the RTSP connection");
+        }
+    }
+}
+
+/// Feeds the client's RTSP requests of a capture to a new connection of `session_id`,
+/// comparing the host's answers with the captured ones.
+///
+/// Captures hold plaintext, so the session is switched to plaintext RTSP. PLAY is skipped,
+/// it would start the streams of the session which a replay can't drive.
+pub async fn replay_rtsp(
+    records: &[Record],
+    state: SharedState,
+    session_id: Uuid,
+) -> Result<Vec<Divergence>> {
+    state
+        .0
+        .sessions
+        .lock()
+        .await
+        .get_mut(&session_id)
+        .context("Replayed session not found")?
+        .rtsp_encryption = false;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let mut stream = TcpStream::connect(listener.local_addr()?).await?;
+    let (host, _) = listener.accept().await?;
+    crate::rtsp::new_client(listener, host, state, session_id).await;
+
+    let mut responses = messages(records, Side::Host);
+    let mut buffer = Vec::new();
+    let mut divergences = Vec::new();
+    for text in messages(records, Side::Client) {
+        let request = match parse(text)? {
+            Message::Request(request) => request,
+            // nothing is answered to those
+            _ => continue,
+        };
+        let expected = match responses.next() {
+            Some(response) => match parse(response)? {
+                Message::Response(response) => summary(&response),
+                _ => bail!("Captured host message is not a response"),
+            },
+            None => break,
+        };
+        if *request.method() == Method::Play {
+            continue;
+        }
+
+        stream.write_all(text.as_bytes()).await?;
+        let actual = summary(&receive(&mut stream, &mut buffer).await?);
+        if actual != expected {
+            divergences.push(Divergence {
+                request: text.lines().next().unwrap_or_default().to_string(),
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(divergences)
+}
+
+/// Replays a capture against a host with `config`, in a session launched like the captured
+/// one. Nothing is streamed and the config is never written back.
+pub async fn replay(records: &[Record], config: Config) -> Result<Replay> {
+    let channels = records
+        .iter()
+        .find_map(|record| match record.event {
+            Event::Launch { audio_channels, .. } => Some(audio_channels),
+            _ => None,
+        })
+        .context("Capture has no launch")?;
+    let audio_config = crate::audio::SUPPORTED_CONFIGS
+        .iter()
+        .find(|config| config.channels == channels)
+        .copied()
+        .with_context(|| format!("Captured session has {} audio channels", channels))?;
+
+    let session = session(config.server_cert.clone(), audio_config)?;
+    let (host, _) = Host::new(config);
+    let session_id = Uuid::new_v4();
+    host.sessions.lock().await.insert(session_id, session);
+    let state = SharedState(Arc::new(host));
+
+    let divergences = replay_rtsp(records, state.clone(), session_id).await?;
+    let control_messages = replay_control(records, &Components::default())?;
+    state.0.end_sessions().await;
+    Ok(Replay {
+        divergences,
+        control_messages,
+    })
+}
+
+/// A session as launched by a paired client, whose certificate doesn't matter to replays.
+fn session(client_cert: openssl::x509::X509, audio_config: AudioConfig) -> Result<Session> {
+    Ok(Session {
+        app: AppId(1),
+        client: Client {
+            paired: true,
+            client_cert,
+            key: Vec::new(),
+            server_secret: None,
+            server_challenge: None,
+            client_hash: None,
+        },
+        rikey: "00".repeat(16),
+        rikeyid: "0".into(),
+        rtsp_encryption: false,
+        control_encryption: false,
+        audio_config,
+        stream_config: None,
+        control: None,
+        control_thread: None,
+        tasks: Vec::new(),
+        shutdown: CancellationToken::new(),
+        controllers: 0,
+        process: None,
+        compositor: None,
+        address: IpAddr::from([127, 0, 0, 1]),
+        ping_payload: PingPayload::generate()?,
+        endpoints: Default::default(),
+        capture: None,
+    })
+}
+
+/// Dispatches the client's control messages of a capture to `components`, as the control
+/// stream would have, returning how many there were.
+pub fn replay_control(records: &[Record], components: &Components) -> Result<usize> {
+    let mut replayed = 0;
+    for record in records {
+        if let Event::Control {
+            from: Side::Client,
+            message,
+        } = &record.event
+        {
+            let message = ControlMessage::decode(message)
+                .with_context(|| format!("Invalid control message at {}us", record.time_us))?;
+            components.dispatch(message);
+            replayed += 1;
+        }
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::control::{AudioRequest, VideoRequest};
+
+    fn record(from: Side, message: ControlMessage) -> Record {
+        Record {
+            time_us: 0,
+            event: Event::Control {
+                from,
+                message: message.encode(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_replay_control() {
+        let records = [
+            record(Side::Client, ControlMessage::StartB),
+            record(
+                Side::Host,
+                ControlMessage::Termination {
+                    error_code: crate::control::TERMINATION_GRACEFUL,
+                },
+            ),
+            record(
+                Side::Client,
+                ControlMessage::LossStats {
+                    lost_frames: 3,
+                    interval_ms: 50,
+                    last_good_frame: 120,
+                },
+            ),
+            Record {
+                time_us: 10,
+                event: Event::Rtsp {
+                    from: Side::Client,
+                    message: "OPTIONS * RTSP/1.0\r\n\r\n".into(),
+                },
+            },
+            record(Side::Client, ControlMessage::RequestIdr),
+        ];
+        let (video, mut video_requests) = unbounded_channel();
+        let (audio, mut audio_requests) = unbounded_channel();
+        let components = Components {
+            video: Some(video),
+            audio: Some(audio),
+            input: None,
+        };
+
+        assert_eq!(replay_control(&records, &components).unwrap(), 3);
+        assert_eq!(video_requests.try_recv().unwrap(), VideoRequest::Start);
+        assert_eq!(
+            video_requests.try_recv().unwrap(),
+            VideoRequest::LossStats {
+                lost_frames: 3,
+                interval_ms: 50
+            }
+        );
+        assert_eq!(video_requests.try_recv().unwrap(), VideoRequest::Idr);
+        assert!(video_requests.try_recv().is_err());
+        assert_eq!(audio_requests.try_recv().unwrap(), AudioRequest::Start);
+    }
+
+    #[test]
+    fn test_truncated_control_message() {
+        let records = [Record {
+            time_us: 42,
+            event: Event::Control {
+                from: Side::Client,
+                message: vec![0x02, 0x03, 8, 0],
+            },
+        }];
+        let err = replay_control(&records, &Components::default()).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid control message at 42us");
+    }
+
+    #[tokio::test]
+    async fn test_replay_capture() {
+        let capture = include_str!("fixtures/moonlight.ron");
+        let records = crate::capture::read(capture.as_bytes()).unwrap();
+        let replayed = replay(&records, crate::config::test_config())
+            .await
+            .unwrap();
+        assert_eq!(replayed.divergences, []);
+        assert_eq!(replayed.control_messages, 9);
+
+        // a host answering differently than captured is caught
+        let records = crate::capture::read(
+            capture
+                .replace(
+                    "RTSP/1.0 200 OK\\r\\nCSeq: 4",
+                    "RTSP/1.0 404 Not Found\\r\\nCSeq: 4",
+                )
+                .as_bytes(),
+        )
+        .unwrap();
+        let replayed = replay(&records, crate::config::test_config())
+            .await
+            .unwrap();
+        assert_eq!(replayed.divergences.len(), 1);
+        assert_eq!(
+            replayed.divergences[0].request,
+            "SETUP streamid=video/0/0 RTSP/1.0"
+        );
+    }
+}
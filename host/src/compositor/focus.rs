@@ -0,0 +1,153 @@
+use smithay::{
+    backend::input::KeyState,
+    desktop::{PopupKind, Window},
+    input::{
+        keyboard::{KeyboardTarget, KeysymHandle, ModifiersState},
+        pointer::{AxisFrame, ButtonEvent, MotionEvent, PointerTarget, RelativeMotionEvent},
+        Seat,
+    },
+    reexports::wayland_server::{backend::ObjectId, protocol::wl_surface::WlSurface},
+    utils::{IsAlive, Serial},
+    wayland::seat::WaylandFocus,
+};
+
+use super::State;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FocusTarget {
+    Window(Window),
+    Popup(PopupKind),
+}
+
+impl IsAlive for FocusTarget {
+    fn alive(&self) -> bool {
+        match self {
+            FocusTarget::Window(w) => w.alive(),
+            FocusTarget::Popup(p) => p.alive(),
+        }
+    }
+}
+
+impl From<Window> for FocusTarget {
+    fn from(w: Window) -> Self {
+        FocusTarget::Window(w)
+    }
+}
+
+impl From<PopupKind> for FocusTarget {
+    fn from(p: PopupKind) -> Self {
+        FocusTarget::Popup(p)
+    }
+}
+
+impl KeyboardTarget<State> for FocusTarget {
+    fn enter(
+        &self,
+        seat: &Seat<State>,
+        data: &mut State,
+        keys: Vec<KeysymHandle<'_>>,
+        serial: Serial,
+    ) {
+        match self {
+            FocusTarget::Window(w) => KeyboardTarget::enter(w, seat, data, keys, serial),
+            FocusTarget::Popup(p) => {
+                KeyboardTarget::enter(p.wl_surface(), seat, data, keys, serial)
+            }
+        }
+    }
+
+    fn leave(&self, seat: &Seat<State>, data: &mut State, serial: Serial) {
+        match self {
+            FocusTarget::Window(w) => KeyboardTarget::leave(w, seat, data, serial),
+            FocusTarget::Popup(p) => KeyboardTarget::leave(p.wl_surface(), seat, data, serial),
+        }
+    }
+
+    fn key(
+        &self,
+        seat: &Seat<State>,
+        data: &mut State,
+        key: KeysymHandle<'_>,
+        state: KeyState,
+        serial: Serial,
+        time: u32,
+    ) {
+        match self {
+            FocusTarget::Window(w) => w.key(seat, data, key, state, serial, time),
+            FocusTarget::Popup(p) => p.wl_surface().key(seat, data, key, state, serial, time),
+        }
+    }
+
+    fn modifiers(
+        &self,
+        seat: &Seat<State>,
+        data: &mut State,
+        modifiers: ModifiersState,
+        serial: Serial,
+    ) {
+        match self {
+            FocusTarget::Window(w) => w.modifiers(seat, data, modifiers, serial),
+            FocusTarget::Popup(p) => p.wl_surface().modifiers(seat, data, modifiers, serial),
+        }
+    }
+}
+
+impl PointerTarget<State> for FocusTarget {
+    fn enter(&self, seat: &Seat<State>, data: &mut State, event: &MotionEvent) {
+        match self {
+            FocusTarget::Window(w) => PointerTarget::enter(w, seat, data, event),
+            FocusTarget::Popup(p) => PointerTarget::enter(p.wl_surface(), seat, data, event),
+        }
+    }
+
+    fn motion(&self, seat: &Seat<State>, data: &mut State, event: &MotionEvent) {
+        match self {
+            FocusTarget::Window(w) => w.motion(seat, data, event),
+            FocusTarget::Popup(p) => p.wl_surface().motion(seat, data, event),
+        }
+    }
+
+    fn relative_motion(&self, seat: &Seat<State>, data: &mut State, event: &RelativeMotionEvent) {
+        match self {
+            FocusTarget::Window(w) => w.relative_motion(seat, data, event),
+            FocusTarget::Popup(p) => p.wl_surface().relative_motion(seat, data, event),
+        }
+    }
+
+    fn button(&self, seat: &Seat<State>, data: &mut State, event: &ButtonEvent) {
+        match self {
+            FocusTarget::Window(w) => w.button(seat, data, event),
+            FocusTarget::Popup(p) => p.wl_surface().button(seat, data, event),
+        }
+    }
+
+    fn axis(&self, seat: &Seat<State>, data: &mut State, frame: AxisFrame) {
+        match self {
+            FocusTarget::Window(w) => w.axis(seat, data, frame),
+            FocusTarget::Popup(p) => p.wl_surface().axis(seat, data, frame),
+        }
+    }
+
+    fn leave(&self, seat: &Seat<State>, data: &mut State, serial: Serial, time: u32) {
+        match self {
+            FocusTarget::Window(w) => PointerTarget::leave(w, seat, data, serial, time),
+            FocusTarget::Popup(p) => PointerTarget::leave(p.wl_surface(), seat, data, serial, time),
+        }
+    }
+}
+
+impl WaylandFocus for FocusTarget {
+    fn wl_surface(&self) -> Option<WlSurface> {
+        match self {
+            FocusTarget::Window(w) => w.wl_surface(),
+            FocusTarget::Popup(p) => Some(p.wl_surface().clone()),
+        }
+    }
+
+    fn same_client_as(&self, object_id: &ObjectId) -> bool {
+        match self {
+            FocusTarget::Window(w) => w.same_client_as(object_id),
+            FocusTarget::Popup(p) => p.wl_surface().same_client_as(object_id),
+        }
+    }
+}
@@ -1,159 +1,389 @@
+use super::{focus::FocusTarget, State};
+use crate::input::{keymap, InputEvent as RemoteEvent, MouseButton};
 use smithay::{
-    backend::{
-        input::{
-            Axis,
-            Event,
-            InputEvent,
-            KeyboardKeyEvent,
-            PointerMotionEvent,
-            PointerButtonEvent,
-            PointerAxisEvent,
-        },
-        libinput::LibinputInputBackend,
+    backend::input::{Axis, AxisSource, ButtonState, KeyState},
+    input::{
+        keyboard::{FilterResult, XkbConfig},
+        pointer::{AxisFrame, ButtonEvent, MotionEvent},
     },
-    desktop::WindowSurfaceType,
-    reexports::{
-        input::LibinputInterface,
-        nix::{fcntl, fcntl::OFlag, sys::stat, unistd::close},
-        wayland_server::{
-            DisplayHandle,
-            protocol::wl_pointer,
-        },
+    utils::{Logical, Point, Serial, SERIAL_COUNTER},
+};
+use xkbcommon::xkb;
+
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+const BTN_SIDE: u32 = 0x113;
+const BTN_EXTRA: u32 = 0x114;
+
+/// Scroll distance clients send per wheel notch.
+const WHEEL_DELTA: f64 = 120.0;
+/// Scroll distance libinput reports per wheel notch.
+const DEGREES_PER_NOTCH: f64 = 15.0;
+
+/// Keyboard and mouse input of a streaming client, fed into the session's seat.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteInput {
+    PointerMotion {
+        dx: f64,
+        dy: f64,
     },
-    wayland::{
-        SERIAL_COUNTER,
-        Serial,
-        seat::{
-            FilterResult,
-            MotionEvent,
-            ButtonEvent,
-            AxisFrame,
-        },
+    /// Position normalized to `0.0..=1.0`, mapped to the output's current mode.
+    PointerMotionAbsolute {
+        x: f64,
+        y: f64,
     },
-    utils::{Point, Logical},
-};
-use std::{
-    path::Path,
-    os::unix::io::RawFd,
-};
-use super::State;
+    /// `button` is an evdev code.
+    PointerButton {
+        button: u32,
+        pressed: bool,
+    },
+    /// Amounts in wheel notches, positive values scroll down and right.
+    PointerAxis {
+        horizontal: f64,
+        vertical: f64,
+    },
+    /// `keycode` is an evdev code, `keysym` what the client's key types, in case the seat's
+    /// layout has no such key.
+    Key {
+        keycode: u32,
+        keysym: u32,
+        pressed: bool,
+    },
+    /// Typed with whichever keys of the seat's layout produce it, or keys it gets for them.
+    Text(String),
+}
 
-pub struct NixInterface;
+impl RemoteInput {
+    pub fn from_event(event: &RemoteEvent) -> Option<RemoteInput> {
+        match *event {
+            RemoteEvent::MouseMoveRelative { dx, dy } => Some(RemoteInput::PointerMotion {
+                dx: dx as f64,
+                dy: dy as f64,
+            }),
+            RemoteEvent::MouseMoveAbsolute {
+                x,
+                y,
+                width,
+                height,
+            } if width > 0 && height > 0 => Some(RemoteInput::PointerMotionAbsolute {
+                x: x as f64 / width as f64,
+                y: y as f64 / height as f64,
+            }),
+            RemoteEvent::MouseButton { button, pressed } => Some(RemoteInput::PointerButton {
+                button: match button {
+                    MouseButton::Left => BTN_LEFT,
+                    MouseButton::Middle => BTN_MIDDLE,
+                    MouseButton::Right => BTN_RIGHT,
+                    MouseButton::X1 => BTN_SIDE,
+                    MouseButton::X2 => BTN_EXTRA,
+                },
+                pressed,
+            }),
+            // clients send positive values to scroll up
+            RemoteEvent::Scroll { amount } => Some(RemoteInput::PointerAxis {
+                horizontal: 0.0,
+                vertical: -(amount as f64) / WHEEL_DELTA,
+            }),
+            RemoteEvent::HorizontalScroll { amount } => Some(RemoteInput::PointerAxis {
+                horizontal: amount as f64 / WHEEL_DELTA,
+                vertical: 0.0,
+            }),
+            RemoteEvent::Key {
+                pressed,
+                key_code,
+                modifiers,
+                ..
+            } => {
+                let key = match keymap::lookup(key_code) {
+                    Some(key) => key,
+                    None => {
+                        log::debug!("Dropping unknown virtual key {:#04x}", key_code);
+                        return None;
+                    }
+                };
+                Some(RemoteInput::Key {
+                    keycode: key.evdev,
+                    keysym: if modifiers.shift {
+                        key.shifted_keysym
+                    } else {
+                        key.keysym
+                    },
+                    pressed,
+                })
+            }
+            RemoteEvent::Text(ref text) => Some(RemoteInput::Text(text.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// The seat's layout, to type keys clients send that it has no keycode for.
+///
+/// Keysyms missing from the layout are put on keycodes it leaves unused, which the seat's
+/// keymap is then updated with.
+pub struct RemoteKeymap {
+    context: xkb::Context,
+    /// The layout as configured, in text form.
+    base: String,
+    keymap: xkb::Keymap,
+    /// Keycodes without symbols in the configured layout, with their names.
+    spare: Vec<(u32, String)>,
+    /// Keysyms put on the spare keycodes, in order.
+    synthesized: Vec<u32>,
+    /// Spare keycode to use next, the oldest once all are taken.
+    next_spare: usize,
+}
+
+impl RemoteKeymap {
+    pub fn new(config: &XkbConfig<'_>) -> Option<RemoteKeymap> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            config.rules,
+            config.model,
+            config.layout,
+            config.variant,
+            config.options.clone(),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+        let spare = (keymap.min_keycode().max(8)..=keymap.max_keycode())
+            .filter(|keycode| keymap.key_get_syms_by_level(*keycode, 0, 0).is_empty())
+            .filter_map(|keycode| Some((keycode, keymap.key_get_name(keycode)?.to_string())))
+            .collect();
+        Some(RemoteKeymap {
+            context,
+            base: keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1),
+            keymap,
+            spare,
+            synthesized: Vec::new(),
+            next_spare: 0,
+        })
+    }
 
-impl LibinputInterface for NixInterface {
-    fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<RawFd, i32> {
-        fcntl::open(path, OFlag::from_bits_truncate(flags), stat::Mode::empty()).map_err(|err| err as i32)
+    /// Whether the evdev keycode types anything in this layout.
+    fn has_key(&self, keycode: u32) -> bool {
+        !self
+            .keymap
+            .key_get_syms_by_level(keycode + 8, 0, 0)
+            .is_empty()
     }
-    fn close_restricted(&mut self, fd: RawFd) {
-        if let Err(err) = close(fd) {
-            slog_scope::warn!("Failed to close fd: {}", err);
+
+    /// Finds the evdev keycode typing `keysym`, and whether it needs shift held.
+    fn find_keysym(&self, keysym: u32) -> Option<(u32, bool)> {
+        let keycodes = self.keymap.min_keycode().max(8)..=self.keymap.max_keycode();
+        for level in 0..2 {
+            for keycode in keycodes.clone() {
+                if self.keymap.num_levels_for_key(keycode, 0) > level
+                    && self
+                        .keymap
+                        .key_get_syms_by_level(keycode, 0, level)
+                        .contains(&keysym)
+                {
+                    return Some((keycode - 8, level == 1));
+                }
+            }
         }
+        None
     }
-}
 
-impl State {
-    pub fn process_input_event(&mut self, dh: &DisplayHandle, event: InputEvent<LibinputInputBackend>) {
-        match event {
-            InputEvent::Keyboard { event, .. } => {
-                let keycode = event.key_code();
-                let state = event.state();
-                let serial = SERIAL_COUNTER.next_serial();
-                let time = event.time();
-                let keyboard = self.seat.get_keyboard().unwrap();
+    /// Puts `keysym` on a spare keycode, returning its evdev keycode and the updated keymap
+    /// to hand to the seat.
+    fn synthesize(&mut self, keysym: u32) -> Option<(u32, String)> {
+        if self.spare.is_empty() {
+            return None;
+        }
+        let index = self.next_spare;
+        self.next_spare = (self.next_spare + 1) % self.spare.len();
+        match self.synthesized.get_mut(index) {
+            Some(synthesized) => *synthesized = keysym,
+            None => self.synthesized.push(keysym),
+        }
 
-                keyboard.input::<(), _>(dh, keycode, state, serial, time, |_modifiers, _handle| {
-                    FilterResult::Forward 
-                });
-            },
-            InputEvent::PointerMotion { event, .. } => {
-                let serial = SERIAL_COUNTER.next_serial();
-                self.pointer_location += event.delta();
-                self.pointer_location = self.clamp_coords(self.pointer_location);
+        // the symbols section is the last one, closed right before the keymap
+        let keymap_end = self.base.rfind("};")?;
+        let symbols_end = self.base[..keymap_end].rfind("};")?;
+        let mut text = self.base[..symbols_end].to_string();
+        for ((_, name), keysym) in self.spare.iter().zip(&self.synthesized) {
+            text += &format!(
+                "\tkey <{}> {{ [ {} ] }};\n",
+                name,
+                xkb::keysym_get_name(*keysym)
+            );
+        }
+        text += &self.base[symbols_end..];
 
-                let pointer = self.seat.get_pointer().unwrap();
-                let under = self.space.surface_under(self.pointer_location, WindowSurfaceType::ALL);
-                pointer.motion(
-                    self,
-                    dh,
-                    &MotionEvent {
-                        location: self.pointer_location,
-                        focus: under.map(|(w, _, pos)| (
-                            w.toplevel().wl_surface().clone(),
-                            pos,
-                        )),
-                        serial,
-                        time: event.time(),
-                    }
-                );
-            },
-            InputEvent::PointerButton { event, .. } => {
-                let serial = SERIAL_COUNTER.next_serial();
-                let button = event.button_code();
+        self.keymap = xkb::Keymap::new_from_string(
+            &self.context,
+            text.clone(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+        Some((self.spare[index].0 - 8, text))
+    }
+}
 
-                let state = wl_pointer::ButtonState::from(event.state());
-                if wl_pointer::ButtonState::Pressed == state {
-                    self.update_keyboard_focus(dh, serial);
+impl State {
+    /// Feeds input sent through an [`super::InputSender`] into the seat, the headless
+    /// compositor has no other input devices.
+    pub fn process_remote_input(&mut self, input: RemoteInput) {
+        let time = self.start_time.elapsed().as_millis() as u32;
+        match input {
+            RemoteInput::PointerMotion { dx, dy } => {
+                let location = self.pointer_location + Point::from((dx, dy));
+                self.pointer_motion(location, time);
+            }
+            RemoteInput::PointerMotionAbsolute { x, y } => {
+                if let Some(mode) = self.output.current_mode() {
+                    let location = (x * mode.size.w as f64, y * mode.size.h as f64).into();
+                    self.pointer_motion(location, time);
+                }
+            }
+            RemoteInput::PointerButton { button, pressed } => {
+                let serial = SERIAL_COUNTER.next_serial();
+                let state = if pressed {
+                    self.update_keyboard_focus(serial);
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
                 };
                 self.seat.get_pointer().unwrap().button(
                     self,
-                    dh,
                     &ButtonEvent {
                         button,
                         state,
                         serial,
-                        time: event.time(),
+                        time,
                     },
                 );
-            },
-            InputEvent::PointerAxis { event, .. } => {
-                let source = wl_pointer::AxisSource::from(event.source());
-
-                let horizontal_amount = event
-                    .amount(Axis::Horizontal)
-                    .unwrap_or_else(|| event.amount_discrete(Axis::Horizontal).unwrap() * 2.0);
-                let vertical_amount = event
-                    .amount(Axis::Vertical)
-                    .unwrap_or_else(|| event.amount_discrete(Axis::Vertical).unwrap() * 2.0);
-                let horizontal_amount_discrete = event.amount_discrete(Axis::Horizontal);
-                let vertical_amount_discrete = event.amount_discrete(Axis::Vertical);
-
+            }
+            RemoteInput::PointerAxis {
+                horizontal,
+                vertical,
+            } => {
+                let mut frame = AxisFrame::new(time).source(AxisSource::Wheel);
+                for (axis, notches) in [(Axis::Horizontal, horizontal), (Axis::Vertical, vertical)]
                 {
-                    let mut frame = AxisFrame::new(event.time()).source(source);
-                    if horizontal_amount != 0.0 {
-                        frame = frame.value(wl_pointer::Axis::HorizontalScroll, horizontal_amount);
-                        if let Some(discrete) = horizontal_amount_discrete {
-                            frame = frame.discrete(wl_pointer::Axis::HorizontalScroll, discrete as i32);
+                    if notches != 0.0 {
+                        frame = frame.value(axis, notches * DEGREES_PER_NOTCH);
+                        // high resolution scrolling may send fractions of a notch
+                        if notches.fract() == 0.0 {
+                            frame = frame.discrete(axis, notches as i32);
                         }
-                    } else if source == wl_pointer::AxisSource::Finger {
-                        frame = frame.stop(wl_pointer::Axis::HorizontalScroll);
                     }
-                    if vertical_amount != 0.0 {
-                        frame = frame.value(wl_pointer::Axis::VerticalScroll, vertical_amount);
-                        if let Some(discrete) = vertical_amount_discrete {
-                            frame = frame.discrete(wl_pointer::Axis::VerticalScroll, discrete as i32);
-                        }
-                    } else if source == wl_pointer::AxisSource::Finger {
-                        frame = frame.stop(wl_pointer::Axis::VerticalScroll);
-                    }
-                    self.seat.get_pointer().unwrap().axis(self, dh, frame);
                 }
-            },
-            _ => {},
+                self.seat.get_pointer().unwrap().axis(self, frame);
+            }
+            RemoteInput::Key {
+                keycode,
+                keysym,
+                pressed,
+            } => {
+                if self.remote_keymap.has_key(keycode) {
+                    self.remote_key(keycode, pressed, time);
+                    return;
+                }
+                // the layout lacks this key, type its keysym wherever the layout has it instead
+                self.remote_keysym(keysym, pressed, time);
+            }
+            RemoteInput::Text(text) => {
+                for c in text.chars() {
+                    let keysym = keymap::char_to_keysym(c);
+                    self.remote_keysym(keysym, true, time);
+                    self.remote_keysym(keysym, false, time);
+                }
+            }
+        }
+    }
+
+    /// Presses or releases the key typing `keysym` in the active layout, with shift if it
+    /// is on the second level. Keysyms the layout lacks are added to it on press.
+    fn remote_keysym(&mut self, keysym: u32, pressed: bool, time: u32) {
+        let key = match self.remote_keymap.find_keysym(keysym) {
+            Some(key) => Some(key),
+            None if pressed => self.synthesize_keysym(keysym),
+            None => None,
+        };
+        match key {
+            Some((keycode, false)) => self.remote_key(keycode, pressed, time),
+            Some((keycode, true)) if pressed => {
+                self.remote_key(keymap::KEY_LEFTSHIFT, true, time);
+                self.remote_key(keycode, true, time);
+            }
+            Some((keycode, true)) => {
+                self.remote_key(keycode, false, time);
+                self.remote_key(keymap::KEY_LEFTSHIFT, false, time);
+            }
+            None => slog::debug!(
+                self.log,
+                "Keysym {:#x} missing from the active layout",
+                keysym
+            ),
         }
     }
-    
+
+    /// Adds `keysym` to the seat's keymap, returning the key typing it.
+    fn synthesize_keysym(&mut self, keysym: u32) -> Option<(u32, bool)> {
+        let (keycode, keymap) = self.remote_keymap.synthesize(keysym)?;
+        let keyboard = self.seat.get_keyboard().unwrap();
+        if let Err(err) = keyboard.set_keymap_from_string(self, keymap) {
+            slog::warn!(self.log, "Failed to update the seat's keymap: {}", err);
+            return None;
+        }
+        Some((keycode, false))
+    }
+
+    fn remote_key(&mut self, keycode: u32, pressed: bool, time: u32) {
+        let serial = SERIAL_COUNTER.next_serial();
+        let state = if pressed {
+            KeyState::Pressed
+        } else {
+            KeyState::Released
+        };
+        let keyboard = self.seat.get_keyboard().unwrap();
+        keyboard.input::<(), _>(
+            self,
+            keycode,
+            state,
+            serial,
+            time,
+            |_data, _modifiers, _handle| FilterResult::Forward,
+        );
+    }
+
+    fn pointer_motion(&mut self, location: Point<f64, Logical>, time: u32) {
+        let serial = SERIAL_COUNTER.next_serial();
+        self.pointer_location = self.clamp_coords(location);
+
+        let pointer = self.seat.get_pointer().unwrap();
+        let under = self
+            .space
+            .element_under(self.pointer_location)
+            .map(|(w, pos)| (w.clone().into(), pos));
+        pointer.motion(
+            self,
+            under,
+            &MotionEvent {
+                location: self.pointer_location,
+                serial,
+                time,
+            },
+        );
+    }
+
     fn clamp_coords(&self, pos: Point<f64, Logical>) -> Point<f64, Logical> {
         if let Some(mode) = self.output.current_mode() {
             (
                 pos.x.max(0.0).min(mode.size.w as f64),
                 pos.y.max(0.0).min(mode.size.h as f64),
-            ).into()
+            )
+                .into()
         } else {
             pos
         }
     }
 
-    fn update_keyboard_focus(&mut self, dh: &DisplayHandle, serial: Serial) {
+    fn update_keyboard_focus(&mut self, serial: Serial) {
         let pointer = self.seat.get_pointer().unwrap();
         let keyboard = self.seat.get_keyboard().unwrap();
         // change the keyboard focus unless the pointer or keyboard is grabbed
@@ -166,14 +396,39 @@ impl State {
         // see here for a discussion about that issue:
         // https://gitlab.freedesktop.org/wayland/wayland/-/issues/294
         if !pointer.is_grabbed() && !keyboard.is_grabbed() {
-            if let Some((window, _, _)) = self
+            if let Some((window, _)) = self
                 .space
-                .surface_under(self.pointer_location, WindowSurfaceType::ALL)
+                .element_under(self.pointer_location)
+                .map(|(w, p)| (w.clone(), p))
             {
-                self.space.raise_window(&window, true);
-                keyboard.set_focus(dh, Some(window.toplevel().wl_surface()), serial);
-                return;
+                self.space.raise_element(&window, true);
+                keyboard.set_focus(self, Some(FocusTarget::from(window)), serial);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYSYM_A: u32 = 0x61;
+    const EURO: u32 = 0x20ac;
+    const SNOWMAN: u32 = 0x100_2603;
+
+    #[test]
+    fn test_synthesize() {
+        let mut keymap = RemoteKeymap::new(&XkbConfig::default()).unwrap();
+        assert_eq!(keymap.find_keysym(KEYSYM_A), Some((30, false)));
+        assert_eq!(keymap.find_keysym(SNOWMAN), None);
+
+        let (keycode, text) = keymap.synthesize(SNOWMAN).unwrap();
+        assert_eq!(keymap.find_keysym(SNOWMAN), Some((keycode, false)));
+        assert!(text.contains("U2603"));
+        // earlier keysyms keep their keys
+        let (other, _) = keymap.synthesize(EURO).unwrap();
+        assert_ne!(other, keycode);
+        assert_eq!(keymap.find_keysym(SNOWMAN), Some((keycode, false)));
+        assert_eq!(keymap.find_keysym(KEYSYM_A), Some((30, false)));
+    }
+}
@@ -1,70 +1,210 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::File,
+    os::unix::prelude::{AsRawFd, OwnedFd},
+    path::PathBuf,
+    sync::mpsc::{sync_channel, SyncSender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use slog::Drain;
 use smithay::{
     backend::{
-        allocator::{
-            dmabuf::{AsDmabuf, Dmabuf},
-            gbm::{GbmBuffer, GbmDevice},
-            Fourcc, Modifier, Swapchain,
-        },
-        drm::{DrmNode, NodeType},
-        egl::{EGLContext, EGLDevice, EGLDisplay},
-        libinput::LibinputInputBackend,
+        allocator::{dmabuf::Dmabuf, gbm::GbmDevice},
+        drm::{DrmDeviceFd, DrmNode, NodeType},
+        egl::{EGLContext, EGLDisplay},
         renderer::{
-            gles2::Gles2Renderer,
-            utils::{import_surface_tree, on_commit_buffer_handler, with_renderer_surface_state},
-            Bind, ExportDma, ImportDma, ImportMemWl, Unbind,
+            damage::{DamageTrackedRenderer, DamageTrackedRendererError as DTRError},
+            element::memory::{MemoryRenderBuffer, MemoryRenderBufferRenderElement},
+            gles2::{Gles2Renderbuffer, Gles2Renderer},
+            utils::{import_surface_tree, on_commit_buffer_handler},
+            Bind, ExportMem, ImportDma, ImportMemWl, Offscreen, TextureMapping, Unbind,
         },
+        udev::primary_gpu,
     },
     delegate_compositor, delegate_data_device, delegate_dmabuf, delegate_output, delegate_seat,
     delegate_shm, delegate_viewporter, delegate_xdg_shell,
     desktop::{
-        Kind as SurfaceKind, PopupKeyboardGrab, PopupKind, PopupManager, PopupPointerGrab,
-        PopupUngrabStrategy, Space, Window,
+        find_popup_root_surface, space::render_output, PopupKeyboardGrab, PopupKind, PopupManager,
+        PopupPointerGrab, PopupUngrabStrategy, Space, Window,
     },
+    input::{keyboard::XkbConfig, pointer::Focus, Seat, SeatHandler, SeatState},
+    output::{Mode as OutputMode, Output, PhysicalProperties, Subpixel},
     reexports::{
-        calloop::{generic::Generic, EventLoop, Interest, Mode, PostAction},
-        input::Libinput,
+        calloop::{
+            channel::{channel, Channel, Event, Sender},
+            generic::Generic,
+            timer::{TimeoutAction, Timer},
+            EventLoop, Interest, Mode, PostAction,
+        },
         wayland_protocols::xdg::shell::server::xdg_toplevel::State as XdgState,
         wayland_server::{
             backend::{ClientData, ClientId, DisconnectReason},
-            protocol::{
-                wl_buffer::WlBuffer,
-                wl_output::{Subpixel, WlOutput},
-                wl_seat::WlSeat,
-                wl_surface::WlSurface,
-            },
+            protocol::{wl_buffer::WlBuffer, wl_seat::WlSeat, wl_surface::WlSurface},
             Display, DisplayHandle, Resource,
         },
     },
-    utils::{Logical, Point, Size, Transform},
+    utils::{DeviceFd, Logical, Physical, Point, Rectangle, Serial, Size, Transform},
     wayland::{
         buffer::BufferHandler,
-        compositor::{get_children, with_states, CompositorHandler, CompositorState},
+        compositor::{with_states, CompositorHandler, CompositorState},
         data_device::{
             set_data_device_focus, ClientDndGrabHandler, DataDeviceHandler, DataDeviceState,
             ServerDndGrabHandler,
         },
-        dmabuf::{get_dmabuf, DmabufGlobal, DmabufHandler, DmabufState, ImportError},
-        output::{Mode as OutputMode, Output, OutputManagerState, PhysicalProperties, Scale},
-        seat::{Seat, SeatHandler, SeatState, XkbConfig},
+        dmabuf::{DmabufGlobal, DmabufHandler, DmabufState, ImportError},
+        output::OutputManagerState,
         shell::xdg::{
-            PopupSurface, PositionerState, ToplevelSurface, XdgPopupSurfaceRoleAttributes,
-            XdgShellHandler, XdgShellState, XdgToplevelSurfaceRoleAttributes,
+            PopupSurface, PositionerState, ToplevelSurface, XdgPopupSurfaceData, XdgShellHandler,
+            XdgShellState, XdgToplevelSurfaceData,
         },
         shm::{ShmHandler, ShmState},
         socket::ListeningSocketSource,
         viewporter::ViewporterState,
-        Serial,
     },
 };
-use std::sync::Mutex;
+use tokio::{
+    process::Child,
+    sync::mpsc::{error::TrySendError, Sender as FrameSender},
+};
+
+use crate::{
+    input::InputEvent,
+    rtsp::StreamConfig,
+    video::{pipeline::PipelineInput, Frame},
+};
 
-mod cursor;
-#[macro_use]
-mod drm;
+mod focus;
 mod input;
-use self::drm::WlDrmState;
-use self::input::*;
-use cursor::CursorElement;
+
+use self::focus::FocusTarget;
+pub use self::input::{RemoteInput, RemoteKeymap};
+
+const CURSOR_DATA_BYTES: &[u8] = include_bytes!("./cursor.rgba");
+
+/// A headless compositor running the apps of a single session on a thread of its own.
+///
+/// The compositor shuts down once this is dropped.
+pub struct Compositor {
+    socket_name: OsString,
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    Input(RemoteInput),
+    Quit,
+}
+
+impl Compositor {
+    /// Starts a compositor with a single output in the client's requested mode.
+    ///
+    /// Every rendered frame is handed to `frames`, frames are dropped while it is full.
+    pub fn spawn(config: &StreamConfig, frames: FrameSender<Frame>) -> Result<Compositor> {
+        let size = Size::from((config.width as i32, config.height as i32));
+        let fps = config.fps.max(1);
+        let (commands, command_source) = channel();
+        let (ready_sender, ready) = sync_channel(1);
+        let thread = thread::Builder::new()
+            .name("compositor".into())
+            .spawn(move || {
+                if let Err(err) = run(size, fps, command_source, frames, &ready_sender) {
+                    let _ = ready_sender.send(Err(err));
+                }
+            })
+            .context("Failed to spawn compositor thread")?;
+
+        let socket_name = ready
+            .recv()
+            .map_err(|_| anyhow!("Compositor thread died during startup"))??;
+        log::info!(
+            "Session compositor listening on {}",
+            socket_name.to_string_lossy()
+        );
+        Ok(Compositor {
+            socket_name,
+            commands,
+            thread: Some(thread),
+        })
+    }
+
+    /// Name of the Wayland socket, the `WAYLAND_DISPLAY` of the session's apps.
+    pub fn socket_name(&self) -> &OsStr {
+        &self.socket_name
+    }
+
+    pub fn input(&self) -> InputSender {
+        InputSender(InputTarget::Compositor(self.commands.clone()))
+    }
+}
+
+/// Runs a shell command as an app of the compositor listening on the Wayland socket
+/// `display` with `env` added to its environment, it is killed once the child is dropped.
+pub fn launch(display: &OsStr, command: &str, env: &[(String, String)]) -> Result<Child> {
+    tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .env("WAYLAND_DISPLAY", display)
+        // keep apps from picking up an X server of the host
+        .env_remove("DISPLAY")
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to launch {:?}", command))
+}
+
+impl fmt::Debug for Compositor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compositor")
+            .field("socket_name", &self.socket_name)
+            .finish()
+    }
+}
+
+impl Drop for Compositor {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("Compositor thread panicked");
+            }
+        }
+    }
+}
+
+/// Feeds a client's keyboard and mouse input into the seat of a [`Compositor`], or of the
+/// one inside a capture pipeline.
+#[derive(Clone)]
+pub struct InputSender(InputTarget);
+
+#[derive(Clone)]
+enum InputTarget {
+    Compositor(Sender<Command>),
+    Pipeline(PipelineInput),
+}
+
+impl InputSender {
+    pub fn send(&self, event: &InputEvent) {
+        if let Some(input) = RemoteInput::from_event(event) {
+            match &self.0 {
+                // the compositor is gone once the session ended
+                InputTarget::Compositor(commands) => {
+                    let _ = commands.send(Command::Input(input));
+                }
+                InputTarget::Pipeline(pipeline) => pipeline.send(&input),
+            }
+        }
+    }
+}
+
+impl From<PipelineInput> for InputSender {
+    fn from(pipeline: PipelineInput) -> InputSender {
+        InputSender(InputTarget::Pipeline(pipeline))
+    }
+}
 
 struct ClientState;
 impl ClientData for ClientState {
@@ -79,29 +219,34 @@ struct Data {
 
 #[allow(dead_code)]
 struct State {
-    start_time: std::time::Instant,
+    should_quit: bool,
+    start_time: Instant,
     log: slog::Logger,
 
     // render
     egl: EGLDisplay,
+    dtr: DamageTrackedRenderer,
     renderer: Gles2Renderer,
+    framebuffer: Gles2Renderbuffer,
+    /// Whether `framebuffer` still holds the previous frame.
+    rendered: bool,
     dmabuf_global: DmabufGlobal,
-    swapchain: Swapchain<GbmDevice<std::fs::File>, GbmBuffer<()>>,
-    direct_scanout: bool,
+    frames: FrameSender<Frame>,
 
     // management
     output: Output,
     seat: Seat<Self>,
+    remote_keymap: RemoteKeymap,
     space: Space<Window>,
     popups: PopupManager,
     pointer_location: Point<f64, Logical>,
-    cursor_element: CursorElement,
+    cursor_element: MemoryRenderBuffer,
     pending_windows: Vec<Window>,
 
     // wayland state
+    dh: DisplayHandle,
     compositor_state: CompositorState,
     data_device_state: DataDeviceState,
-    drm_state: WlDrmState,
     dmabuf_state: DmabufState,
     output_state: OutputManagerState,
     seat_state: SeatState<Self>,
@@ -119,13 +264,19 @@ impl CompositorHandler for State {
         &mut self.compositor_state
     }
 
-    fn commit(&mut self, dh: &DisplayHandle, surface: &WlSurface) {
-        on_commit_buffer_handler(dh, surface);
-        if let Err(err) = import_surface_tree(dh, &mut self.renderer, surface, &self.log) {
+    fn commit(&mut self, surface: &WlSurface) {
+        on_commit_buffer_handler(surface);
+        if let Err(err) = import_surface_tree(&mut self.renderer, surface, &self.log) {
             slog::warn!(self.log, "Failed to load client buffer: {}", err);
         }
 
-        self.space.commit(surface);
+        if let Some(window) = self
+            .space
+            .elements()
+            .find(|w| w.toplevel().wl_surface() == surface)
+        {
+            window.on_commit();
+        }
         self.popups.commit(surface);
 
         // send the initial configure if relevant
@@ -135,57 +286,44 @@ impl CompositorHandler for State {
             .position(|w| w.toplevel().wl_surface() == surface)
         {
             let window = self.pending_windows.swap_remove(idx);
+            let toplevel = window.toplevel();
+            let (initial_configure_sent, max_size) = with_states(surface, |states| {
+                let attributes = states.data_map.get::<XdgToplevelSurfaceData>().unwrap();
+                let attributes_guard = attributes.lock().unwrap();
+
+                (
+                    attributes_guard.initial_configure_sent,
+                    attributes_guard.max_size,
+                )
+            });
+            let output_size: Size<i32, _> = self
+                .output
+                .current_mode()
+                .unwrap()
+                .size
+                .to_f64()
+                .to_logical(self.output.current_scale().fractional_scale())
+                .to_i32_round();
 
-            #[cfg_attr(not(feature = "xwayland"), allow(irrefutable_let_patterns))]
-            if let SurfaceKind::Xdg(ref toplevel) = window.toplevel() {
-                let (initial_configure_sent, max_size) = with_states(surface, |states| {
-                    let attributes = states
-                        .data_map
-                        .get::<Mutex<XdgToplevelSurfaceRoleAttributes>>()
-                        .unwrap();
-                    let attributes_guard = attributes.lock().unwrap();
-
-                    (
-                        attributes_guard.initial_configure_sent,
-                        attributes_guard.max_size,
-                    )
-                });
-                if !initial_configure_sent {
-                    if max_size.w == 0 && max_size.h == 0 {
-                        toplevel.with_pending_state(|state| {
-                            state.size = Some(
-                                self.output
-                                    .current_mode()
-                                    .unwrap()
-                                    .size
-                                    .to_f64()
-                                    .to_logical(self.output.current_scale().fractional_scale())
-                                    .to_i32_round(),
-                            );
-                            state.states.set(XdgState::Fullscreen);
-                        });
-                    }
+            if !initial_configure_sent {
+                if max_size.w == 0 && max_size.h == 0 {
                     toplevel.with_pending_state(|state| {
-                        state.states.set(XdgState::Activated);
+                        state.size = Some(output_size);
+                        state.states.set(XdgState::Fullscreen);
                     });
-                    toplevel.send_configure();
-                    self.pending_windows.push(window);
-                } else {
-                    let window_size = toplevel.current_state().size.unwrap_or((0, 0).into());
-                    let output_size: Size<i32, _> = self
-                        .output
-                        .current_mode()
-                        .unwrap()
-                        .size
-                        .to_f64()
-                        .to_logical(self.output.current_scale().fractional_scale())
-                        .to_i32_round();
-                    let loc = (
-                        (output_size.w / 2) - (window_size.w / 2),
-                        (output_size.h / 2) - (window_size.h / 2),
-                    );
-                    self.space.map_window(&window, loc, false);
                 }
+                toplevel.with_pending_state(|state| {
+                    state.states.set(XdgState::Activated);
+                });
+                toplevel.send_configure();
+                self.pending_windows.push(window);
+            } else {
+                let window_size = toplevel.current_state().size.unwrap_or((0, 0).into());
+                let loc = (
+                    (output_size.w / 2) - (window_size.w / 2),
+                    (output_size.h / 2) - (window_size.h / 2),
+                );
+                self.space.map_element(window, loc, false);
             }
 
             return;
@@ -196,7 +334,7 @@ impl CompositorHandler for State {
             let initial_configure_sent = with_states(surface, |states| {
                 states
                     .data_map
-                    .get::<Mutex<XdgPopupSurfaceRoleAttributes>>()
+                    .get::<XdgPopupSurfaceData>()
                     .unwrap()
                     .lock()
                     .unwrap()
@@ -207,8 +345,6 @@ impl CompositorHandler for State {
                 // allowed.
                 popup.send_configure().expect("initial configure failed");
             }
-
-            return;
         };
     }
 }
@@ -228,7 +364,6 @@ impl DmabufHandler for State {
 
     fn dmabuf_imported(
         &mut self,
-        _dh: &DisplayHandle,
         _global: &DmabufGlobal,
         dmabuf: Dmabuf,
     ) -> Result<(), ImportError> {
@@ -240,9 +375,20 @@ impl DmabufHandler for State {
 }
 
 impl SeatHandler for State {
+    type KeyboardFocus = FocusTarget;
+    type PointerFocus = FocusTarget;
+
     fn seat_state(&mut self) -> &mut SeatState<Self> {
         &mut self.seat_state
     }
+
+    fn focus_changed(&mut self, seat: &Seat<Self>, focus: Option<&Self::KeyboardFocus>) {
+        let client = focus.and_then(|target| match target {
+            FocusTarget::Window(w) => w.toplevel().wl_surface().client(),
+            FocusTarget::Popup(p) => p.wl_surface().client(),
+        });
+        set_data_device_focus(&self.dh, seat, client);
+    }
 }
 
 impl ShmHandler for State {
@@ -256,17 +402,11 @@ impl XdgShellHandler for State {
         &mut self.shell_state
     }
 
-    fn new_toplevel(&mut self, _dh: &DisplayHandle, surface: ToplevelSurface) {
-        let window = Window::new(SurfaceKind::Xdg(surface));
-        self.pending_windows.push(window);
+    fn new_toplevel(&mut self, surface: ToplevelSurface) {
+        self.pending_windows.push(Window::new(surface));
     }
 
-    fn new_popup(
-        &mut self,
-        _dh: &DisplayHandle,
-        surface: PopupSurface,
-        positioner: PositionerState,
-    ) {
+    fn new_popup(&mut self, surface: PopupSurface, positioner: PositionerState) {
         // TODO: properly recompute the geometry with the whole of positioner state
         surface.with_pending_state(|state| {
             // NOTE: This is not really necessary as the default geometry
@@ -280,268 +420,199 @@ impl XdgShellHandler for State {
         }
     }
 
-    fn grab(&mut self, dh: &DisplayHandle, surface: PopupSurface, seat: WlSeat, serial: Serial) {
+    fn grab(&mut self, surface: PopupSurface, seat: WlSeat, serial: Serial) {
         let seat: Seat<State> = Seat::from_resource(&seat).unwrap();
-        let ret = self.popups.grab_popup(dh, surface.into(), &seat, serial);
-
-        if let Ok(mut grab) = ret {
-            if let Some(keyboard) = seat.get_keyboard() {
-                if keyboard.is_grabbed()
-                    && !(keyboard.has_grab(serial)
-                        || keyboard.has_grab(grab.previous_serial().unwrap_or(serial)))
-                {
-                    grab.ungrab(dh, PopupUngrabStrategy::All);
-                    return;
-                }
-                keyboard.set_focus(dh, grab.current_grab().as_ref(), serial);
-                keyboard.set_grab(PopupKeyboardGrab::new(&grab), serial);
-            }
-            if let Some(pointer) = seat.get_pointer() {
-                if pointer.is_grabbed()
-                    && !(pointer.has_grab(serial)
-                        || pointer
-                            .has_grab(grab.previous_serial().unwrap_or_else(|| grab.serial())))
-                {
-                    grab.ungrab(dh, PopupUngrabStrategy::All);
-                    return;
+        let kind = PopupKind::Xdg(surface.clone());
+        if let Some(root) = find_popup_root_surface(&kind).ok().and_then(|root| {
+            self.space
+                .elements()
+                .find(|w| w.toplevel().wl_surface() == &root)
+                .cloned()
+                .map(FocusTarget::from)
+        }) {
+            let ret = self.popups.grab_popup(root, surface.into(), &seat, serial);
+            if let Ok(mut grab) = ret {
+                if let Some(keyboard) = seat.get_keyboard() {
+                    if keyboard.is_grabbed()
+                        && !(keyboard.has_grab(serial)
+                            || keyboard.has_grab(grab.previous_serial().unwrap_or(serial)))
+                    {
+                        grab.ungrab(PopupUngrabStrategy::All);
+                        return;
+                    }
+                    keyboard.set_focus(self, grab.current_grab(), serial);
+                    keyboard.set_grab(PopupKeyboardGrab::new(&grab), serial);
                 }
-                pointer.set_grab(PopupPointerGrab::new(&grab), serial, 0);
-            }
-        }
-    }
-}
-
-/*
-impl ExportDmabufHandler for State {
-    fn capture_frame(
-        &mut self,
-        dh: &DisplayHandle,
-        _output: WlOutput,
-        overlay_cursor: bool,
-    ) -> Result<Capture, CaptureError> {
-        self.space
-            .send_frames(self.start_time.elapsed().as_millis() as u32);
-        // check for "direct scanout"
-        if let Some(window) = self.space.windows().next() {
-            let SurfaceKind::Xdg(xdg) = window.toplevel();
-            let surface = xdg.wl_surface();
-            if get_children(surface).is_empty() && self.popups.find_popup(surface).is_none() {
-                let output_size: Size<i32, _> = self.output.current_mode().unwrap().size
-                    .to_f64()
-                    .to_logical(self.output.current_scale().fractional_scale())
-                    .to_i32_round();
-                if let Some(dmabuf) = with_renderer_surface_state(xdg.wl_surface(), |state| {
-                    if state.surface_size().map(|size| size == output_size).unwrap_or(false) {
-                        state.wl_buffer().and_then(|buf| get_dmabuf(buf).ok())
-                    } else {
-                        None
+                if let Some(pointer) = seat.get_pointer() {
+                    if pointer.is_grabbed()
+                        && !(pointer.has_grab(serial)
+                            || pointer
+                                .has_grab(grab.previous_serial().unwrap_or_else(|| grab.serial())))
+                    {
+                        grab.ungrab(PopupUngrabStrategy::All);
+                        return;
                     }
-                }) {
-                    if !self.direct_scanout {
-                        self.swapchain.reset_buffers();
-                        self.direct_scanout = true;
-                    };
-                    return Ok(Capture {
-                        dmabuf,
-                        presentation_time: std::time::Instant::now(),
-                    });
+                    pointer.set_grab(self, PopupPointerGrab::new(&grab), serial, Focus::Clear);
                 }
             }
         }
-        self.direct_scanout = false;
-
-        let physical_output_size = self.output.current_mode().unwrap().size;
-        let buffer_size = physical_output_size
-            .to_logical(1)
-            .to_buffer(1, Transform::Normal);
-
-        let elements = if overlay_cursor {
-            self.cursor_element.set_location(
-                self.pointer_location
-                    .to_physical_precise_round(self.output.current_scale().fractional_scale()),
-            );
-            vec![self.cursor_element.clone()]
-        } else {
-            Vec::new()
-        };
-
-        let offscreen = self
-            .swapchain
-            .acquire()
-            .map_err(|err| CaptureError::Temporary(Box::new(err)))?
-            .unwrap();
-        let age = offscreen.age();
-
-        // EGLDevice code path
-        //self.renderer.bind(offscreen.buffer.clone()).map_err(|err| CaptureError::Temporary(Box::new(err)))?;
-        // GBM code path
-        let mut dmabuf = offscreen.userdata().get::<Dmabuf>().cloned();
-        if dmabuf.is_none() {
-            let new_dmabuf = offscreen.export().unwrap();
-            offscreen
-                .userdata()
-                .insert_if_missing(|| new_dmabuf.clone());
-            dmabuf = Some(new_dmabuf);
-        }
-        let dmabuf = dmabuf.unwrap();
-        self.renderer
-            .bind(dmabuf)
-            .map_err(|err| CaptureError::Temporary(Box::new(err)))?;
-
-        self.space
-            .render_output(
-                &dh,
-                &mut self.renderer,
-                &self.output,
-                age as usize,
-                [0.0, 0.0, 0.0, 1.0],
-                &*elements,
-            )
-            .map_err(|err| CaptureError::Temporary(Box::new(err)))?;
-        let res = self
-            .renderer
-            .export_framebuffer(buffer_size)
-            .map(|dmabuf| Capture {
-                dmabuf: dbg!(dmabuf),
-                presentation_time: std::time::Instant::now(),
-            })
-            .map_err(|err| CaptureError::Temporary(Box::new(err)))?;
-        self.renderer
-            .unbind()
-            .map_err(|err| CaptureError::Temporary(Box::new(err)))?;
-        Ok(res)
-    }
-
-    fn start_time(&mut self) -> std::time::Instant {
-        self.start_time
     }
 }
-*/
 
 delegate_compositor!(State);
 delegate_data_device!(State);
 delegate_dmabuf!(State);
-delegate_wl_drm!(State);
 delegate_output!(State);
 delegate_seat!(State);
 delegate_shm!(State);
 delegate_xdg_shell!(State);
 delegate_viewporter!(State);
 
-fn main() -> smithay::reexports::calloop::Result<()> {
-    use slog::Drain;
-
-    let args = Args::parse();
-    let (w, h) = args
-        .resolution
-        .split_once("x")
-        .expect("resolution should be in format <W>x<H>");
-    let size = (
-        w.parse::<u32>()
-            .expect(&format!("{} is no valid integer", w)) as i32,
-        h.parse::<u32>()
-            .expect(&format!("{} is no valid integer", h)) as i32,
-    );
+impl State {
+    /// Renders the output into the framebuffer and reads it back for the encoder.
+    fn render(&mut self) -> Result<Frame, DTRError<Gles2Renderer>> {
+        let size = self.output.current_mode().unwrap().size;
+        let elements = vec![MemoryRenderBufferRenderElement::from_buffer(
+            &mut self.renderer,
+            self.pointer_location.to_physical_precise_round(1),
+            &self.cursor_element,
+            None,
+            None,
+            None,
+            None,
+        )
+        .map_err(DTRError::Rendering)?];
 
-    let log = ::slog::Logger::root(
-        slog_term::FullFormat::new(slog_term::PlainSyncDecorator::new(std::io::stdout()))
-            .build()
-            .fuse(),
-        slog::o!(),
-    );
-    let _guard = slog_scope::set_global_logger(log.clone());
-    slog_stdlog::init().expect("Failed to init logger");
+        self.renderer
+            .bind(self.framebuffer.clone())
+            .map_err(DTRError::Rendering)?;
+        // the framebuffer is reused, so it always holds the last frame
+        let age = usize::from(self.rendered);
+        render_output(
+            &self.output,
+            &mut self.renderer,
+            age,
+            [&self.space],
+            &*elements,
+            &mut self.dtr,
+            [0.0, 0.0, 0.0, 1.0],
+            self.log.clone(),
+        )?;
+        self.rendered = true;
+
+        let region = Rectangle::from_loc_and_size(
+            (0, 0),
+            size.to_logical(1).to_buffer(1, Transform::Normal),
+        );
+        let mapping = self
+            .renderer
+            .copy_framebuffer(region)
+            .map_err(DTRError::Rendering)?;
+        let data = self
+            .renderer
+            .map_texture(&mapping)
+            .map_err(DTRError::Rendering)?;
+        let frame = Frame::from_rgba(
+            size.w as u32,
+            size.h as u32,
+            data,
+            mapping.flipped(),
+            self.start_time.elapsed(),
+        );
+        self.renderer.unbind().map_err(DTRError::Rendering)?;
+        Ok(frame)
+    }
+
+    fn render_frame(&mut self) {
+        match self.render() {
+            Ok(frame) => match self.frames.try_send(frame) {
+                Ok(()) => {}
+                // drop the frame instead of adding latency
+                Err(TrySendError::Full(_)) => {
+                    slog::debug!(self.log, "Encoder is busy, dropping frame")
+                }
+                // the video stream ended
+                Err(TrySendError::Closed(_)) => self.should_quit = true,
+            },
+            Err(err) => slog::error!(self.log, "Rendering failed: {}", err),
+        }
+
+        let time = self.start_time.elapsed();
+        for window in self.space.elements() {
+            window.send_frame(&self.output, time, None, |_, _| Some(self.output.clone()));
+        }
+    }
+}
 
-    let mut display = Display::<State>::new().unwrap();
+/// Render node of the GPU compositors on the default seat would pick.
+fn render_node() -> Result<PathBuf> {
+    let gpu = primary_gpu("seat0")
+        .context("Failed to enumerate GPUs")?
+        .context("No GPU found")?;
+    let node = DrmNode::from_path(&gpu).context("Invalid drm node")?;
+    Ok(node
+        .dev_path_with_type(NodeType::Render)
+        .or_else(|| node.dev_path())
+        .unwrap_or(gpu))
+}
+
+/// Runs the compositor until it is told to quit.
+///
+/// Errors are only returned until the socket name was sent to `ready`.
+fn run(
+    size: Size<i32, Physical>,
+    fps: u32,
+    commands: Channel<Command>,
+    frames: FrameSender<Frame>,
+    ready: &SyncSender<Result<OsString>>,
+) -> Result<()> {
+    let log = slog::Logger::root(slog_stdlog::StdLog.fuse(), slog::o!());
+
+    let mut display = Display::<State>::new().context("Failed to create wayland display")?;
     let dh = display.handle();
 
     // init state
     let compositor_state = CompositorState::new::<State, _>(&dh, log.clone());
     let data_device_state = DataDeviceState::new::<State, _>(&dh, log.clone());
     let mut dmabuf_state = DmabufState::new();
-    let mut drm_state = WlDrmState;
     let output_state = OutputManagerState::new_with_xdg_output::<State>(&dh);
-    let seat_state = SeatState::new();
+    let mut seat_state = SeatState::new();
     let shell_state = XdgShellState::new::<State, _>(&dh, log.clone());
     let viewporter_state = ViewporterState::new::<State, _>(&dh, log.clone());
 
     // init render backend
-    let user_node = DrmNode::from_path(&args.device_path).expect("Invalid render node path");
-
-    /* // EGL Device code path, no working allocator
-    let device = EGLDevice::enumerate()
-        .expect("Failed to enumerate EGLDevice")
-        .find(|dev| {
-            if let Ok(Some(node)) = dev.try_get_render_node() {
-                if let Some(Ok(user_node)) = user_node.node_with_type(node.ty()) {
-                    return user_node == node;
-                }
-            }
-            false
-        })
-        .expect(&format!("Could not find node matching: {:?}", user_node));
-    */
-
-    // GBM device code path
-    let drm_node = std::fs::File::open(
-        user_node
-            .dev_path_with_type(NodeType::Render)
-            .or_else(|| user_node.dev_path())
-            .unwrap_or_else(|| std::path::PathBuf::from(&args.device_path)),
-    )
-    .expect("Failed to open drm device");
-    let device = GbmDevice::new(drm_node).expect("Failed to open gbm device");
+    let render_node = render_node()?;
+    let drm_file = File::open(&render_node)
+        .with_context(|| format!("Failed to open {}", render_node.display()))?;
+    let drm_fd = DrmDeviceFd::new(DeviceFd::from(OwnedFd::from(drm_file)), None);
+    let gbm_device = GbmDevice::new(drm_fd).context("Failed to open gbm device")?;
 
-    let egl = EGLDisplay::new(&device, log.clone()).expect("Failed to create EGLDisplay");
-    let _guard = egl
-        .bind_wl_display(&dh)
-        .expect("Failed to bind egl display");
-    let context = EGLContext::new(&egl, log.clone()).expect("Failed to create EGLContext");
-
-    /* // EGL Devicecode  path, no working allocator
-    let alloc_context = EGLContext::new_shared(&egl, &context, log.clone()).expect("Failed to create shared EGLContext");
-    let allocator = GlAllocator::new(unsafe { Gles2Renderer::new(alloc_context, log.clone()).expect("Failed to create allocator") });
-    */
-    // GBM device code path
-    let allocator = device;
-
-    let modifiers = context
-        .dmabuf_texture_formats()
-        .into_iter()
-        .filter(|x| x.code == Fourcc::Nv12)
-        .map(|x| x.modifier)
-        .collect();
-    let swapchain = Swapchain::new(
-        allocator,
-        size.0 as u32,
-        size.1 as u32,
-        Fourcc::Nv12,
-        modifiers,
-    );
-    let mut renderer =
-        unsafe { Gles2Renderer::new(context, log.clone()) }.expect("Failed to initialize renderer");
+    let egl = EGLDisplay::new(gbm_device, log.clone()).context("Failed to create EGLDisplay")?;
+    let context = EGLContext::new(&egl, log.clone()).context("Failed to create EGLContext")?;
+
+    let mut renderer = unsafe { Gles2Renderer::new(context, log.clone()) }
+        .context("Failed to initialize renderer")?;
     let formats = Bind::<Dmabuf>::supported_formats(&renderer)
-        .expect("Failed to query formats")
+        .context("Failed to query formats")?
         .into_iter()
         .collect::<Vec<_>>();
-    //egl.bind_wl_display(&dh).expect("Failed to bind EGLDisplay");
+    let framebuffer = Offscreen::<Gles2Renderbuffer>::create_buffer(
+        &mut renderer,
+        size.to_logical(1).to_buffer(1, Transform::Normal),
+    )
+    .context("Failed to create framebuffer")?;
+
+    // shm buffer
     let shm_state = ShmState::new::<State, _>(&dh, Vec::from(renderer.shm_formats()), log.clone());
-    let dmabuf_global = dmabuf_state.create_global::<State, _>(&dh, formats.clone(), log.clone());
-    let _drm_global = drm_state.create_global::<State>(
-        &dh,
-        std::path::PathBuf::from(&args.device_path),
-        formats,
-        &dmabuf_global,
-    );
-    let cursor_element =
-        CursorElement::new(&mut renderer, (size.0 as f64 / 2.0, size.1 as f64 / 2.0));
+    // egl buffer
+    let _egl_guard = egl
+        .bind_wl_display(&dh)
+        .context("Failed to bind EGLDisplay")?;
+    // dma buffer
+    let dmabuf_global = dmabuf_state.create_global::<State, _>(&dh, formats, log.clone());
 
-    // init input backend
-    let mut libinput_context = Libinput::new_with_udev(NixInterface);
-    libinput_context
-        .udev_assign_seat(&args.input_seat)
-        .expect("Failed to assign libinput seat");
-    let libinput_backend = LibinputInputBackend::new(libinput_context, log.clone());
+    let cursor_element =
+        MemoryRenderBuffer::from_memory(CURSOR_DATA_BYTES, (64, 64), 1, Transform::Normal, None);
 
     // init wayland objects
     let output = Output::new(
@@ -555,51 +626,49 @@ fn main() -> smithay::reexports::calloop::Result<()> {
         log.clone(),
     );
     let mode = OutputMode {
-        size: size.into(),
-        refresh: (args.framerate * 1000) as i32,
+        size,
+        refresh: fps as i32 * 1000,
     };
     output.change_current_state(Some(mode), None, None, None);
     output.set_preferred(mode);
+    let _output_global = output.create_global::<State>(&dh);
+    let dtr = DamageTrackedRenderer::from_output(&output);
 
     let mut space = Space::new(log.clone());
     space.map_output(&output, (0, 0));
 
-    output_conf_state.add_heads([output.clone()].iter());
-    output_conf_state.update();
-
-    let mut seat = Seat::<State>::new(&dh, "seat-0", log.clone());
-    seat.add_keyboard(XkbConfig::default(), 200, 25, move |seat, focus| {
-        if let Some(surface) = focus {
-            let client = dh.get_client(surface.id());
-            set_data_device_focus(&dh, seat, client.ok());
-        } else {
-            set_data_device_focus(&dh, seat, None);
-        }
-    })
-    .expect("Failed to add keyboard to seat");
-    seat.add_pointer(|_| {});
+    let mut seat = seat_state.new_wl_seat(&dh, "seat-0", log.clone());
+    let xkb_config = XkbConfig::default();
+    let remote_keymap = RemoteKeymap::new(&xkb_config).context("Failed to compile keymap")?;
+    seat.add_keyboard(xkb_config, 200, 25)
+        .context("Failed to add keyboard to seat")?;
+    seat.add_pointer();
 
     let state = State {
-        start_time: std::time::Instant::now(),
+        should_quit: false,
+        start_time: Instant::now(),
         log: log.clone(),
 
         egl,
+        dtr,
         renderer,
+        framebuffer,
+        rendered: false,
         dmabuf_global,
-        swapchain,
-        direct_scanout: false,
+        frames,
 
-        space,
-        popups: PopupManager::new(log.clone()),
         output,
         seat,
-        pointer_location: (320.0, 240.0).into(),
+        remote_keymap,
+        space,
+        popups: PopupManager::new(log.clone()),
+        pointer_location: (size.w as f64 / 2.0, size.h as f64 / 2.0).into(),
         cursor_element,
         pending_windows: Vec::new(),
 
+        dh: display.handle(),
         compositor_state,
         data_device_state,
-        drm_state,
         dmabuf_state,
         output_state,
         seat_state,
@@ -609,21 +678,27 @@ fn main() -> smithay::reexports::calloop::Result<()> {
     };
 
     // init event loop
-    let mut event_loop = EventLoop::<Data>::try_new().expect("Unable to create event_loop");
+    let mut event_loop = EventLoop::<Data>::try_new().context("Unable to create event loop")?;
     event_loop
         .handle()
-        .insert_source(libinput_backend, move |event, _, data| {
-            let dh = data.display.handle();
-            data.state.process_input_event(&dh, event)
+        .insert_source(commands, |event, _, data| match event {
+            Event::Msg(Command::Input(input)) => data.state.process_remote_input(input),
+            Event::Msg(Command::Quit) | Event::Closed => data.state.should_quit = true,
         })
-        .unwrap();
+        .map_err(|err| anyhow!("Failed to init command source: {}", err.error))?;
 
-    let source = ListeningSocketSource::new_auto(log.clone()).unwrap();
-    slog::info!(
-        log,
-        "Listening on wayland socket: {}",
-        source.socket_name().to_string_lossy()
-    );
+    let frame_interval = Duration::from_secs(1) / fps;
+    event_loop
+        .handle()
+        .insert_source(Timer::immediate(), move |deadline, _, data| {
+            data.state.render_frame();
+            TimeoutAction::ToInstant(deadline + frame_interval)
+        })
+        .map_err(|err| anyhow!("Failed to init render timer: {}", err.error))?;
+
+    let source =
+        ListeningSocketSource::new_auto(log.clone()).context("Failed to create wayland socket")?;
+    let socket_name = source.socket_name().to_os_string();
     event_loop
         .handle()
         .insert_source(source, |client_stream, _, data| {
@@ -635,26 +710,39 @@ fn main() -> smithay::reexports::calloop::Result<()> {
                 slog::error!(data.state.log, "Error adding wayland client: {}", err);
             };
         })
-        .expect("Failed to init wayland socket source");
+        .map_err(|err| anyhow!("Failed to init wayland socket source: {}", err.error))?;
 
     event_loop
         .handle()
         .insert_source(
-            Generic::new(display.backend().poll_fd(), Interest::READ, Mode::Level),
+            Generic::new(
+                display.backend().poll_fd().as_raw_fd(),
+                Interest::READ,
+                Mode::Level,
+            ),
             |_, _, data| {
                 data.display.dispatch_clients(&mut data.state).unwrap();
                 Ok(PostAction::Continue)
             },
         )
-        .expect("Failed to init wayland server source");
+        .map_err(|err| anyhow!("Failed to init wayland server source: {}", err.error))?;
+
+    let _ = ready.send(Ok(socket_name));
 
     let mut data = Data { display, state };
-    loop {
-        event_loop.dispatch(std::time::Duration::from_millis(16), &mut data)?;
-        data.state.space.refresh(&data.display.handle());
+    let signal = event_loop.get_signal();
+    if let Err(err) = event_loop.run(None, &mut data, |data| {
+        if let Err(err) = data.display.flush_clients() {
+            slog::warn!(data.state.log, "Failed to flush clients: {}", err);
+        }
+        data.state.space.refresh();
         data.state.popups.cleanup();
-        data.display
-            .flush_clients()
-            .expect("Failed to flush clients");
+
+        if data.state.should_quit {
+            signal.stop();
+        }
+    }) {
+        slog::error!(data.state.log, "Event loop broke: {}", err);
     }
+    Ok(())
 }
@@ -1,60 +1,105 @@
-use crate::State;
+use crate::Config;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use ron::{de::from_reader, ser::to_writer_pretty};
 use uuid::Uuid;
 use xdg::BaseDirectories;
 
 use std::{collections::HashMap, fs::File};
 
-pub fn load_config() -> Result<State> {
+pub fn load_config() -> Result<Config> {
     let dirs = BaseDirectories::new().context("No HOME")?;
     match dirs.find_config_file("sunrise.ron") {
         Some(path) => {
             let file = File::open(&path)
                 .with_context(|| format!("Unable to open config file at: {}", path.display()))?;
-            from_reader(&file).with_context(|| format!("Unable to parse config file."))
+            let config =
+                from_reader(&file).with_context(|| format!("Unable to parse config file."))?;
+            validate(&config)?;
+            Ok(config)
         }
         None => {
             let path = dirs
                 .place_config_file("sunrise.ron")
                 .context("Unable to place config file")?;
             let file = File::create(path).context("Unable to write config file")?;
-            let state = generate_new_state()?;
-            to_writer_pretty(file, &state, Default::default())
+            let config = generate_new_config()?;
+            to_writer_pretty(file, &config, Default::default())
                 .context("Unable to serialize config")?;
-            Ok(state)
+            Ok(config)
         }
     }
 }
 
-pub fn save_config(state: &State) -> Result<()> {
+/// Refuses settings the host can't honour.
+fn validate(config: &Config) -> Result<()> {
+    // every session listens on the same RTSP, control and media ports
+    if config.max_sessions > 1 {
+        bail!(
+            "max_sessions is {}, but only one session can run at a time",
+            config.max_sessions
+        );
+    }
+    Ok(())
+}
+
+pub fn save_config(config: &Config) -> Result<()> {
     let dirs = BaseDirectories::new().context("No HOME")?;
     let path = dirs.get_config_file("sunrise.ron");
     let file = File::create(path).context("Unable to write config file")?;
-    to_writer_pretty(file, state, Default::default()).context("Unable to serialize config")?;
+    to_writer_pretty(file, config, Default::default()).context("Unable to serialize config")?;
     Ok(())
 }
 
-fn generate_new_state() -> Result<State> {
+fn generate_new_config() -> Result<Config> {
     let (cred, key) = crate::crypto::gen_creds().context("Generation certificate failed")?;
 
-    Ok(State {
+    Ok(Config {
         unique_id: Uuid::new_v4(),
         server_cert: cred,
         server_key: key,
         known_clients: HashMap::new(),
         apps: Vec::new(),
+        app_import: None,
 
         hostname: hostname::get()
             .ok()
             .and_then(|host| host.into_string().ok())
             .unwrap_or_else(|| String::from("Sunrise")),
-        interface: crate::serialization::get_default_interface(),
         http_port: 47989,
         https_port: 47984,
 
+        network: Default::default(),
+
         max_sessions: 1,
-        sessions: HashMap::new(),
+        video_capture: Default::default(),
+        video_encoder: None,
+        audio_capture: Default::default(),
+        rate_limits: Default::default(),
+        recording: None,
+        port_mapping: None,
+        capture_directory: None,
     })
 }
+
+/// A newly generated config, for tests.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    generate_new_config().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = test_config();
+        assert!(validate(&config).is_ok());
+        config.max_sessions = 2;
+        assert_eq!(
+            validate(&config).unwrap_err().to_string(),
+            "max_sessions is 2, but only one session can run at a time"
+        );
+    }
+}
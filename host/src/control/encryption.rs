@@ -0,0 +1,231 @@
+use openssl::error::ErrorStack;
+
+use super::messages::ControlMessage;
+use crate::crypto::{aes_decrypt_gcm, aes_encrypt_gcm, GCM_TAG_LEN};
+
+/// `sequenceNumber` + GCM tag, ahead of the encrypted message.
+pub const HEADER_LEN: usize = 4 + GCM_TAG_LEN;
+/// Size of the IVs of control messages.
+const IV_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The payload is shorter than its header.
+    Truncated,
+    /// The message was received before, or got overtaken by later ones.
+    Replayed { sequence_number: u32 },
+    /// Decryption or authentication of the message failed.
+    Crypto(ErrorStack),
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Truncated => write!(f, "Truncated encrypted control message"),
+            CryptoError::Replayed { sequence_number } => {
+                write!(f, "Replayed control message {}", sequence_number)
+            }
+            CryptoError::Crypto(err) => write!(f, "Failed to decrypt control message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// AES-GCM encryption of control messages as used by newer GameStream clients.
+///
+/// Messages are wrapped into an [`ControlMessage::Encrypted`] carrying their sequence
+/// number and authentication tag. The key is the `rikey` handed over at launch.
+///
+/// Clients only switch to the 12 byte IVs of `SS_ENC_CONTROL_V2` when DESCRIBE advertises it,
+/// which it doesn't, so both directions use GameStream's original IVs.
+#[derive(Debug)]
+pub struct ControlCipher {
+    key: [u8; 16],
+    sequence_number: u32,
+    /// Sequence number of the last message accepted from the client.
+    last_received: Option<u32>,
+}
+
+impl ControlCipher {
+    pub fn new(key: [u8; 16]) -> ControlCipher {
+        ControlCipher {
+            key,
+            sequence_number: 0,
+            last_received: None,
+        }
+    }
+
+    /// Encrypts an encoded message originating from the host.
+    pub fn encrypt(&mut self, message: &[u8]) -> Result<ControlMessage, ErrorStack> {
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        encrypt_message(&self.key, sequence_number, message)
+    }
+
+    /// Decrypts the payload of an encrypted message from the client into an encoded message.
+    ///
+    /// ENet delivers the control stream in order, so anything but increasing sequence
+    /// numbers is rejected.
+    pub fn decrypt(&mut self, payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (sequence_number, message) = decrypt_message(&self.key, payload)?;
+        if self
+            .last_received
+            .is_some_and(|last| sequence_number <= last)
+        {
+            return Err(CryptoError::Replayed { sequence_number });
+        }
+        self.last_received = Some(sequence_number);
+        Ok(message)
+    }
+}
+
+/// The IV of a message, only its first byte is set, to the truncated sequence number.
+fn message_iv(sequence_number: u32) -> [u8; IV_LEN] {
+    let mut iv = [0; IV_LEN];
+    iv[0] = sequence_number as u8;
+    iv
+}
+
+pub fn encrypt_message(
+    key: &[u8],
+    sequence_number: u32,
+    message: &[u8],
+) -> Result<ControlMessage, ErrorStack> {
+    let iv = message_iv(sequence_number);
+    let (ciphertext, tag) = aes_encrypt_gcm(message, key, &iv)?;
+
+    let mut payload = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    payload.extend_from_slice(&sequence_number.to_le_bytes());
+    payload.extend_from_slice(&tag);
+    payload.extend_from_slice(&ciphertext);
+    Ok(ControlMessage::Encrypted(payload))
+}
+
+/// Decrypts the payload of an encrypted message, returning its sequence number and message.
+pub fn decrypt_message(key: &[u8], payload: &[u8]) -> Result<(u32, Vec<u8>), CryptoError> {
+    if payload.len() < HEADER_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let sequence_number = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    let tag = &payload[4..HEADER_LEN];
+    let iv = message_iv(sequence_number);
+    let message =
+        aes_decrypt_gcm(&payload[HEADER_LEN..], key, &iv, tag).map_err(CryptoError::Crypto)?;
+    Ok((sequence_number, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::TERMINATION_GRACEFUL;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+
+    // The vectors were encrypted with Python's `cryptography` AESGCM, building the IV as
+    // moonlight-common-c's encryptControlMessage and decryptControlMessageToV1 do without
+    // SS_ENC_CONTROL_V2: 16 bytes, the first the sequence number truncated to a byte.
+
+    /// An IDR request as encrypted by a client, its second message.
+    const CLIENT_IDR_REQUEST: [u8; 28] = [
+        0x01, 0x00, 24, 0, // type and length
+        0x01, 0x00, 0x00, 0x00, // sequence number
+        0xe0, 0x48, 0xcc, 0xb0, 0x24, 0xd7, 0x0e, 0x3e, 0xd5, 0xcb, 0x81, 0x89, 0xa1, 0x93, 0xb2,
+        0x48, // tag
+        0xe5, 0x50, 0x3e, 0xd7,
+    ];
+
+    #[test]
+    fn test_client_vector() {
+        let mut cipher = ControlCipher::new(KEY);
+        let payload = match ControlMessage::decode(&CLIENT_IDR_REQUEST).unwrap() {
+            ControlMessage::Encrypted(payload) => payload,
+            x => panic!("Unexpected message: {:?}", x),
+        };
+        let message = cipher.decrypt(&payload).unwrap();
+        assert_eq!(
+            ControlMessage::decode(&message).unwrap(),
+            ControlMessage::RequestIdr
+        );
+    }
+
+    #[test]
+    fn test_host_vector() {
+        let mut cipher = ControlCipher::new(KEY);
+        let message = ControlMessage::Termination {
+            error_code: TERMINATION_GRACEFUL,
+        };
+        let packet = cipher.encrypt(&message.encode()).unwrap().encode();
+        assert_eq!(
+            packet,
+            [
+                0x01, 0x00, 28, 0, // type and length
+                0x00, 0x00, 0x00, 0x00, // sequence number
+                0x53, 0x79, 0xcd, 0xb9, 0x60, 0xe0, 0x2b, 0x4d, 0xba, 0xe4, 0x0d, 0x63, 0xc1, 0xe2,
+                0x0e, 0x7b, // tag
+                0x00, 0x17, 0x7f, 0xf6, 0x6b, 0xbd, 0xf4, 0x54,
+            ]
+        );
+        // the next message uses the next sequence number
+        let next = cipher.encrypt(&message.encode()).unwrap().encode();
+        assert_eq!(&next[4..8], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let mut cipher = ControlCipher::new(KEY);
+        let message = ControlMessage::RequestIdr.encode();
+        let encrypt =
+            |sequence_number| match encrypt_message(&KEY, sequence_number, &message).unwrap() {
+                ControlMessage::Encrypted(payload) => payload,
+                _ => unreachable!(),
+            };
+
+        assert!(cipher.decrypt(&encrypt(5)).is_ok());
+        assert!(matches!(
+            cipher.decrypt(&encrypt(5)),
+            Err(CryptoError::Replayed { sequence_number: 5 })
+        ));
+        assert!(matches!(
+            cipher.decrypt(&encrypt(3)),
+            Err(CryptoError::Replayed { sequence_number: 3 })
+        ));
+        // gaps are fine, ENet only guarantees order
+        assert!(cipher.decrypt(&encrypt(9)).is_ok());
+    }
+
+    #[test]
+    fn test_tampering_rejected() {
+        let mut cipher = ControlCipher::new(KEY);
+        let mut payload = CLIENT_IDR_REQUEST[4..].to_vec();
+        *payload.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&payload),
+            Err(CryptoError::Crypto(_))
+        ));
+        // failed messages don't advance the sequence
+        assert!(cipher.decrypt(&CLIENT_IDR_REQUEST[4..]).is_ok());
+        // both directions share IVs, host messages reflected back are only stopped by their
+        // sequence number
+        let mut host = ControlCipher::new(KEY);
+        let reflected = |host: &mut ControlCipher| match host.encrypt(&[2, 3, 0, 0]).unwrap() {
+            ControlMessage::Encrypted(payload) => payload,
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            cipher.decrypt(&reflected(&mut host)),
+            Err(CryptoError::Replayed { sequence_number: 0 })
+        ));
+        assert!(matches!(
+            cipher.decrypt(&reflected(&mut host)),
+            Err(CryptoError::Replayed { sequence_number: 1 })
+        ));
+        assert!(matches!(
+            cipher.decrypt(&CLIENT_IDR_REQUEST[4..4 + HEADER_LEN - 1]),
+            Err(CryptoError::Truncated)
+        ));
+    }
+}
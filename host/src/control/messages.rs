@@ -0,0 +1,389 @@
+use crate::video::HdrMetadata;
+
+/// `type` + `payloadLength`, both little endian.
+pub const HEADER_LEN: usize = 4;
+/// Thirteen little endian 16 bit values following the `enabled` flag of an HDR mode message.
+const HDR_METADATA_LEN: usize = 26;
+
+pub const TYPE_ENCRYPTED: u16 = 0x0001;
+pub const TYPE_TERMINATION: u16 = 0x0100;
+pub const TYPE_RUMBLE: u16 = 0x010b;
+pub const TYPE_HDR_MODE: u16 = 0x010e;
+pub const TYPE_PERIODIC_PING: u16 = 0x0200;
+pub const TYPE_LOSS_STATS: u16 = 0x0201;
+pub const TYPE_FRAME_STATS: u16 = 0x0204;
+pub const TYPE_INPUT: u16 = 0x0206;
+pub const TYPE_INVALIDATE_REFERENCE_FRAMES: u16 = 0x0301;
+pub const TYPE_REQUEST_IDR: u16 = 0x0302;
+pub const TYPE_START_A: u16 = 0x0305;
+pub const TYPE_START_B: u16 = 0x0307;
+
+/// Termination code clients treat as a regular end of the stream.
+pub const TERMINATION_GRACEFUL: u32 = 0x8003_0023;
+/// Generic `E_FAIL`, clients report the stream as having ended unexpectedly.
+pub const TERMINATION_FAILURE: u32 = 0x8000_4005;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet is shorter than its header or announced length.
+    Truncated,
+    /// The payload is too short for the message type.
+    InvalidPayload(u16),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "Truncated control message"),
+            DecodeError::InvalidPayload(ty) => {
+                write!(f, "Invalid payload for control message {:#06x}", ty)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Messages exchanged on the control stream, in either direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    StartA,
+    StartB,
+    PeriodicPing,
+    LossStats {
+        /// Frames lost since the last report.
+        lost_frames: i32,
+        /// Time covered by this report.
+        interval_ms: i32,
+        last_good_frame: i32,
+    },
+    FrameStats(Vec<u8>),
+    InvalidateReferenceFrames {
+        first_frame: i64,
+        last_frame: i64,
+    },
+    RequestIdr,
+    /// An input packet, decoded by the input handling.
+    Input(Vec<u8>),
+    Encrypted(Vec<u8>),
+    Rumble {
+        controller: u16,
+        low_frequency: u16,
+        high_frequency: u16,
+    },
+    Termination {
+        error_code: u32,
+    },
+    HdrMode {
+        enabled: bool,
+        /// Left out by hosts predating HDR10 metadata.
+        metadata: Option<HdrMetadata>,
+    },
+    Unknown {
+        ty: u16,
+        payload: Vec<u8>,
+    },
+}
+
+fn le_i32(payload: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_i64(payload: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(payload[offset..offset + 8].try_into().unwrap())
+}
+
+fn le_u16(payload: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(payload[offset..offset + 2].try_into().unwrap())
+}
+
+fn decode_hdr_metadata(payload: &[u8]) -> Option<HdrMetadata> {
+    if payload.len() < HDR_METADATA_LEN {
+        return None;
+    }
+    let value = |index: usize| le_u16(payload, index * 2);
+    Some(HdrMetadata {
+        display_primaries: [
+            (value(0), value(1)),
+            (value(2), value(3)),
+            (value(4), value(5)),
+        ],
+        white_point: (value(6), value(7)),
+        max_display_luminance: value(8),
+        min_display_luminance: value(9),
+        max_content_light_level: value(10),
+        max_frame_average_light_level: value(11),
+    })
+}
+
+fn encode_hdr_metadata(metadata: &HdrMetadata, payload: &mut Vec<u8>) {
+    let values = metadata
+        .display_primaries
+        .iter()
+        .flat_map(|(x, y)| [*x, *y])
+        .chain([
+            metadata.white_point.0,
+            metadata.white_point.1,
+            metadata.max_display_luminance,
+            metadata.min_display_luminance,
+            metadata.max_content_light_level,
+            metadata.max_frame_average_light_level,
+            // the full frame luminance of the mastering display is unknown
+            0,
+        ]);
+    for value in values {
+        payload.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl ControlMessage {
+    pub fn ty(&self) -> u16 {
+        match self {
+            ControlMessage::StartA => TYPE_START_A,
+            ControlMessage::StartB => TYPE_START_B,
+            ControlMessage::PeriodicPing => TYPE_PERIODIC_PING,
+            ControlMessage::LossStats { .. } => TYPE_LOSS_STATS,
+            ControlMessage::FrameStats(_) => TYPE_FRAME_STATS,
+            ControlMessage::InvalidateReferenceFrames { .. } => TYPE_INVALIDATE_REFERENCE_FRAMES,
+            ControlMessage::RequestIdr => TYPE_REQUEST_IDR,
+            ControlMessage::Input(_) => TYPE_INPUT,
+            ControlMessage::Encrypted(_) => TYPE_ENCRYPTED,
+            ControlMessage::Rumble { .. } => TYPE_RUMBLE,
+            ControlMessage::Termination { .. } => TYPE_TERMINATION,
+            ControlMessage::HdrMode { .. } => TYPE_HDR_MODE,
+            ControlMessage::Unknown { ty, .. } => *ty,
+        }
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<ControlMessage, DecodeError> {
+        if packet.len() < HEADER_LEN {
+            return Err(DecodeError::Truncated);
+        }
+        let ty = le_u16(packet, 0);
+        let len = le_u16(packet, 2) as usize;
+        let payload = packet
+            .get(HEADER_LEN..HEADER_LEN + len)
+            .ok_or(DecodeError::Truncated)?;
+
+        let min_len = match ty {
+            TYPE_LOSS_STATS => 16,
+            TYPE_INVALIDATE_REFERENCE_FRAMES => 16,
+            TYPE_RUMBLE => 10,
+            TYPE_TERMINATION => 4,
+            TYPE_HDR_MODE => 1,
+            _ => 0,
+        };
+        if payload.len() < min_len {
+            return Err(DecodeError::InvalidPayload(ty));
+        }
+
+        Ok(match ty {
+            TYPE_START_A => ControlMessage::StartA,
+            TYPE_START_B => ControlMessage::StartB,
+            TYPE_PERIODIC_PING => ControlMessage::PeriodicPing,
+            TYPE_LOSS_STATS => ControlMessage::LossStats {
+                lost_frames: le_i32(payload, 0),
+                interval_ms: le_i32(payload, 4),
+                last_good_frame: le_i32(payload, 12),
+            },
+            TYPE_FRAME_STATS => ControlMessage::FrameStats(payload.to_vec()),
+            TYPE_INVALIDATE_REFERENCE_FRAMES => ControlMessage::InvalidateReferenceFrames {
+                first_frame: le_i64(payload, 0),
+                last_frame: le_i64(payload, 8),
+            },
+            TYPE_REQUEST_IDR => ControlMessage::RequestIdr,
+            TYPE_INPUT => ControlMessage::Input(payload.to_vec()),
+            TYPE_ENCRYPTED => ControlMessage::Encrypted(payload.to_vec()),
+            TYPE_RUMBLE => ControlMessage::Rumble {
+                controller: le_u16(payload, 4),
+                low_frequency: le_u16(payload, 6),
+                high_frequency: le_u16(payload, 8),
+            },
+            TYPE_TERMINATION => ControlMessage::Termination {
+                error_code: u32::from_be_bytes(payload[0..4].try_into().unwrap()),
+            },
+            TYPE_HDR_MODE => ControlMessage::HdrMode {
+                enabled: payload[0] != 0,
+                metadata: decode_hdr_metadata(&payload[1..]),
+            },
+            ty => ControlMessage::Unknown {
+                ty,
+                payload: payload.to_vec(),
+            },
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            ControlMessage::StartA
+            | ControlMessage::StartB
+            | ControlMessage::PeriodicPing
+            | ControlMessage::RequestIdr => {}
+            ControlMessage::LossStats {
+                lost_frames,
+                interval_ms,
+                last_good_frame,
+            } => {
+                payload.extend_from_slice(&lost_frames.to_le_bytes());
+                payload.extend_from_slice(&interval_ms.to_le_bytes());
+                payload.extend_from_slice(&1000i32.to_le_bytes());
+                payload.extend_from_slice(&last_good_frame.to_le_bytes());
+            }
+            ControlMessage::InvalidateReferenceFrames {
+                first_frame,
+                last_frame,
+            } => {
+                payload.extend_from_slice(&first_frame.to_le_bytes());
+                payload.extend_from_slice(&last_frame.to_le_bytes());
+            }
+            ControlMessage::FrameStats(data)
+            | ControlMessage::Input(data)
+            | ControlMessage::Encrypted(data)
+            | ControlMessage::Unknown { payload: data, .. } => payload.extend_from_slice(data),
+            ControlMessage::Rumble {
+                controller,
+                low_frequency,
+                high_frequency,
+            } => {
+                payload.extend_from_slice(&0u32.to_le_bytes());
+                payload.extend_from_slice(&controller.to_le_bytes());
+                payload.extend_from_slice(&low_frequency.to_le_bytes());
+                payload.extend_from_slice(&high_frequency.to_le_bytes());
+            }
+            ControlMessage::Termination { error_code } => {
+                payload.extend_from_slice(&error_code.to_be_bytes())
+            }
+            ControlMessage::HdrMode { enabled, metadata } => {
+                payload.push(*enabled as u8);
+                if let Some(metadata) = metadata {
+                    encode_hdr_metadata(metadata, &mut payload);
+                }
+            }
+        }
+
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.extend_from_slice(&self.ty().to_le_bytes());
+        packet.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        packet.extend_from_slice(&payload);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let messages = [
+            ControlMessage::StartA,
+            ControlMessage::StartB,
+            ControlMessage::PeriodicPing,
+            ControlMessage::LossStats {
+                lost_frames: 3,
+                interval_ms: 50,
+                last_good_frame: 1234,
+            },
+            ControlMessage::FrameStats(vec![1, 2, 3]),
+            ControlMessage::InvalidateReferenceFrames {
+                first_frame: 10,
+                last_frame: 12,
+            },
+            ControlMessage::RequestIdr,
+            ControlMessage::Input(vec![0, 0, 0, 8, 7, 0, 0, 0]),
+            ControlMessage::Rumble {
+                controller: 1,
+                low_frequency: 0x8000,
+                high_frequency: 0xffff,
+            },
+            ControlMessage::Termination {
+                error_code: TERMINATION_GRACEFUL,
+            },
+            ControlMessage::HdrMode {
+                enabled: true,
+                metadata: Some(HdrMetadata::default()),
+            },
+            ControlMessage::HdrMode {
+                enabled: false,
+                metadata: None,
+            },
+            ControlMessage::Unknown {
+                ty: 0x1234,
+                payload: vec![42],
+            },
+        ];
+
+        for message in messages {
+            assert_eq!(ControlMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let packet = ControlMessage::Rumble {
+            controller: 2,
+            low_frequency: 0x0102,
+            high_frequency: 0x0304,
+        }
+        .encode();
+        assert_eq!(
+            packet,
+            [0x0b, 0x01, 10, 0, 0, 0, 0, 0, 2, 0, 0x02, 0x01, 0x04, 0x03]
+        );
+
+        let packet = ControlMessage::Termination {
+            error_code: TERMINATION_GRACEFUL,
+        }
+        .encode();
+        assert_eq!(packet, [0x00, 0x01, 4, 0, 0x80, 0x03, 0x00, 0x23]);
+
+        let packet = ControlMessage::HdrMode {
+            enabled: true,
+            metadata: Some(HdrMetadata::default()),
+        }
+        .encode();
+        assert_eq!(&packet[..5], [0x0e, 0x01, 27, 0, 1]);
+        // red primary, then the luminances following the white point
+        assert_eq!(&packet[5..9], [0x48, 0x8a, 0x08, 0x39]);
+        assert_eq!(
+            &packet[21..31],
+            [0xe8, 0x03, 50, 0, 0xe8, 0x03, 0x90, 0x01, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_loss_stats_from_client() {
+        // as sent by moonlight every 50ms
+        let mut packet = vec![0x01, 0x02, 32, 0];
+        for value in [2i32, 50, 1000, 345, 0, 0, 0x14, 0] {
+            packet.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(
+            ControlMessage::decode(&packet).unwrap(),
+            ControlMessage::LossStats {
+                lost_frames: 2,
+                interval_ms: 50,
+                last_good_frame: 345,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_packets() {
+        assert_eq!(
+            ControlMessage::decode(&[0x05, 0x03]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            ControlMessage::decode(&[0x06, 0x02, 8, 0, 1, 2]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            ControlMessage::decode(&[0x01, 0x03, 4, 0, 1, 2, 3, 4]),
+            Err(DecodeError::InvalidPayload(
+                TYPE_INVALIDATE_REFERENCE_FRAMES
+            ))
+        );
+    }
+}
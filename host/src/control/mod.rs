@@ -0,0 +1,296 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use enet::{
+    Address, BandwidthLimit, ChannelLimit, Enet, Event, Host, Packet, PacketMode, PeerState,
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+use crate::capture::{Capture, Side};
+
+mod encryption;
+mod messages;
+pub use self::encryption::{ControlCipher, CryptoError};
+pub use self::messages::{ControlMessage, DecodeError, TERMINATION_FAILURE, TERMINATION_GRACEFUL};
+
+/// How long a single service call may block before queued outgoing messages are sent.
+const SERVICE_TIMEOUT_MS: u32 = 10;
+/// How often the round trip time is passed on to the video stream.
+const RTT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Requests from the client the video stream has to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoRequest {
+    Start,
+    Idr,
+    InvalidateReferenceFrames {
+        first_frame: i64,
+        last_frame: i64,
+    },
+    LossStats {
+        lost_frames: i32,
+        interval_ms: i32,
+    },
+    /// Round trip time ENet measured on the control stream.
+    RoundTripTime(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioRequest {
+    Start,
+}
+
+/// Where messages received on the control stream get dispatched to.
+///
+/// Components that aren't running for a session are left out, their messages are dropped.
+#[derive(Debug, Default)]
+pub struct Components {
+    pub video: Option<UnboundedSender<VideoRequest>>,
+    pub audio: Option<UnboundedSender<AudioRequest>>,
+    /// Raw input packets, one per message.
+    pub input: Option<UnboundedSender<Vec<u8>>>,
+}
+
+impl Components {
+    pub fn dispatch(&self, message: ControlMessage) {
+        match message {
+            ControlMessage::StartA | ControlMessage::PeriodicPing => {}
+            ControlMessage::StartB => {
+                if let Some(video) = self.video.as_ref() {
+                    let _ = video.send(VideoRequest::Start);
+                }
+                if let Some(audio) = self.audio.as_ref() {
+                    let _ = audio.send(AudioRequest::Start);
+                }
+            }
+            ControlMessage::RequestIdr => self.send_video(VideoRequest::Idr),
+            ControlMessage::InvalidateReferenceFrames {
+                first_frame,
+                last_frame,
+            } => self.send_video(VideoRequest::InvalidateReferenceFrames {
+                first_frame,
+                last_frame,
+            }),
+            ControlMessage::LossStats {
+                lost_frames,
+                interval_ms,
+                ..
+            } => self.send_video(VideoRequest::LossStats {
+                lost_frames,
+                interval_ms,
+            }),
+            ControlMessage::Input(data) => {
+                if let Some(input) = self.input.as_ref() {
+                    let _ = input.send(data);
+                }
+            }
+            ControlMessage::FrameStats(_) => {}
+            ControlMessage::Encrypted(_) => {
+                log::warn!(
+                    "Dropping encrypted control message, the client didn't negotiate encryption"
+                );
+            }
+            x => log::debug!("Unhandled control message: {:?}", x),
+        }
+    }
+
+    fn send_video(&self, request: VideoRequest) {
+        if let Some(video) = self.video.as_ref() {
+            let _ = video.send(request);
+        }
+    }
+}
+
+/// Handle to a running control stream, used to send messages to the client.
+///
+/// The listener shuts down once its session is cancelled or every handle is dropped.
+#[derive(Debug, Clone)]
+pub struct ControlHandle {
+    sender: Sender<ControlMessage>,
+}
+
+impl ControlHandle {
+    /// Queues a message for the client, fails if the control stream has ended.
+    pub fn send(&self, message: ControlMessage) -> Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| anyhow::anyhow!("Control stream closed"))
+    }
+}
+
+/// ENet may only be initialized once per process.
+fn enet() -> Result<Enet> {
+    static ENET: Mutex<Option<Enet>> = Mutex::new(None);
+
+    let mut enet = ENET.lock().unwrap();
+    if enet.is_none() {
+        *enet = Some(Enet::new().context("Failed to initialize ENet")?);
+    }
+    Ok(enet.clone().unwrap())
+}
+
+fn create_host(address: Ipv4Addr, port: u16) -> Result<Host<()>> {
+    enet()?
+        .create_host::<()>(
+            Some(&Address::new(address, port)),
+            1,
+            ChannelLimit::Maximum,
+            BandwidthLimit::Unlimited,
+            BandwidthLimit::Unlimited,
+        )
+        .context("Failed to create control stream host")
+}
+
+/// Starts listening for the control stream of a session on its own thread, ENet hosts
+/// can't be moved between threads and thus not be driven by the async runtime.
+///
+/// With a `cipher` every message is encrypted, plaintext ones from the client are dropped.
+/// Messages in either direction are recorded into `capture` before encryption. The stream
+/// ends once `shutdown` is cancelled, after telling the client, and cancels `shutdown` when
+/// the client disconnects.
+pub fn spawn(
+    address: IpAddr,
+    port: u16,
+    components: Components,
+    cipher: Option<ControlCipher>,
+    capture: Option<Capture>,
+    shutdown: CancellationToken,
+) -> Result<(ControlHandle, thread::JoinHandle<()>)> {
+    let address = crate::net::control_address(address)?;
+    let (sender, receiver) = mpsc::channel();
+    let (ready_sender, ready) = mpsc::sync_channel(1);
+    let thread = thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            let host = match create_host(address, port) {
+                Ok(host) => {
+                    let _ = ready_sender.send(Ok(()));
+                    host
+                }
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                    return;
+                }
+            };
+            if let Err(err) = run(host, receiver, components, cipher, capture, &shutdown) {
+                log::error!("Control stream failed: {}", err);
+            }
+            log::info!("Control stream closed");
+            // the session is over without its control stream, whichever side closed it
+            shutdown.cancel();
+        })?;
+
+    ready.recv().context("Control stream thread died")??;
+    Ok((ControlHandle { sender }, thread))
+}
+
+fn run(
+    mut host: Host<()>,
+    receiver: Receiver<ControlMessage>,
+    components: Components,
+    mut cipher: Option<ControlCipher>,
+    capture: Option<Capture>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let mut last_rtt = Instant::now();
+    let mut terminated = false;
+    loop {
+        // checked first, so what was queued before the session ended is still sent
+        let cancelled = shutdown.is_cancelled();
+        loop {
+            match receiver.try_recv() {
+                Ok(message) => {
+                    if let Some(capture) = &capture {
+                        capture.control(Side::Host, &message);
+                    }
+                    terminated |= matches!(message, ControlMessage::Termination { .. });
+                    send(&mut host, cipher.as_mut(), &message)?
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if cancelled {
+            // unless a stream already told the client why it failed
+            if !terminated {
+                let message = ControlMessage::Termination {
+                    error_code: TERMINATION_GRACEFUL,
+                };
+                if let Some(capture) = &capture {
+                    capture.control(Side::Host, &message);
+                }
+                send(&mut host, cipher.as_mut(), &message)?;
+            }
+            return Ok(());
+        }
+
+        match host.service(SERVICE_TIMEOUT_MS)? {
+            Some(Event::Connect(ref peer)) => {
+                log::info!("Control stream connected: {:?}", peer.address());
+            }
+            Some(Event::Disconnect(..)) => return Ok(()),
+            Some(Event::Receive { ref packet, .. }) => {
+                match decode(cipher.as_mut(), packet.data()) {
+                    Ok(message) => {
+                        if let Some(capture) = &capture {
+                            capture.control(Side::Client, &message);
+                        }
+                        components.dispatch(message)
+                    }
+                    Err(err) => log::warn!("{:#}", err),
+                }
+            }
+            None => {}
+        }
+
+        if last_rtt.elapsed() >= RTT_INTERVAL {
+            last_rtt = Instant::now();
+            if let Some(peer) = host
+                .peers()
+                .find(|peer| peer.state() == PeerState::Connected)
+            {
+                components.send_video(VideoRequest::RoundTripTime(peer.mean_rtt()));
+            }
+        }
+    }
+}
+
+/// Decodes a message from the client, decrypting it if the session negotiated encryption.
+fn decode(cipher: Option<&mut ControlCipher>, packet: &[u8]) -> Result<ControlMessage> {
+    let message = ControlMessage::decode(packet)?;
+    match (cipher, message) {
+        (Some(cipher), ControlMessage::Encrypted(payload)) => {
+            Ok(ControlMessage::decode(&cipher.decrypt(&payload)?)?)
+        }
+        (Some(_), message) => bail!("Dropping unencrypted control message {:#06x}", message.ty()),
+        (None, message) => Ok(message),
+    }
+}
+
+fn send(
+    host: &mut Host<()>,
+    cipher: Option<&mut ControlCipher>,
+    message: &ControlMessage,
+) -> Result<()> {
+    let data = match cipher {
+        Some(cipher) => cipher.encrypt(&message.encode())?.encode(),
+        None => message.encode(),
+    };
+    for mut peer in host
+        .peers()
+        .filter(|peer| peer.state() == PeerState::Connected)
+    {
+        peer.send_packet(Packet::new(&data, PacketMode::ReliableSequenced)?, 0)?;
+    }
+    host.flush();
+    Ok(())
+}
@@ -9,7 +9,7 @@ use openssl::{
     rand::rand_bytes,
     rsa::Rsa,
     sha::Sha256,
-    symm::{Cipher, Crypter, Mode},
+    symm::{decrypt, decrypt_aead, encrypt, encrypt_aead, Cipher, Crypter, Mode},
     x509::{X509Builder, X509NameBuilder, X509Ref, X509},
 };
 
@@ -81,6 +81,83 @@ fn aes_ecb<A: AsRef<[u8]>>(
     Ok(plaintext)
 }
 
+/// Encrypts `payload` with AES-128-CBC and PKCS#7 padding.
+pub fn aes_encrypt_cbc<A: AsRef<[u8]>>(
+    payload: A,
+    key: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>, ErrorStack> {
+    encrypt(Cipher::aes_128_cbc(), key, Some(iv), payload.as_ref())
+}
+
+pub fn aes_decrypt_cbc<A: AsRef<[u8]>>(
+    payload: A,
+    key: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>, ErrorStack> {
+    decrypt(Cipher::aes_128_cbc(), key, Some(iv), payload.as_ref())
+}
+
+pub const GCM_TAG_LEN: usize = 16;
+pub const GCM_IV_LEN: usize = 12;
+
+/// Which side of a connection originated a message, part of the GCM IVs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Client,
+    Host,
+}
+
+/// The IV of a message in a stream encrypted with the launch key.
+///
+/// Messages are numbered per direction, the IVs of both directions and of the different
+/// streams sharing the key are told apart by the origin and `stream` tag in the last bytes.
+pub fn gcm_sequence_iv(sequence_number: u32, origin: Origin, stream: u8) -> [u8; GCM_IV_LEN] {
+    let mut iv = [0; GCM_IV_LEN];
+    iv[0..4].copy_from_slice(&sequence_number.to_le_bytes());
+    iv[10] = match origin {
+        Origin::Client => b'C',
+        Origin::Host => b'H',
+    };
+    iv[11] = stream;
+    iv
+}
+
+/// Encrypts `payload` with AES-128-GCM, returning the ciphertext and authentication tag.
+pub fn aes_encrypt_gcm<A: AsRef<[u8]>>(
+    payload: A,
+    key: &[u8],
+    iv: &[u8],
+) -> Result<(Vec<u8>, [u8; GCM_TAG_LEN]), ErrorStack> {
+    let mut tag = [0; GCM_TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_128_gcm(),
+        key,
+        Some(iv),
+        &[],
+        payload.as_ref(),
+        &mut tag,
+    )?;
+    Ok((ciphertext, tag))
+}
+
+/// Decrypts an AES-128-GCM `payload`, failing if the authentication `tag` doesn't match.
+pub fn aes_decrypt_gcm<A: AsRef<[u8]>>(
+    payload: A,
+    key: &[u8],
+    iv: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, ErrorStack> {
+    decrypt_aead(
+        Cipher::aes_128_gcm(),
+        key,
+        Some(iv),
+        &[],
+        payload.as_ref(),
+        tag,
+    )
+}
+
 pub fn sign<A: AsRef<[u8]>>(
     pkey: &PKeyRef<Private>,
     payload: A,
@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
 use default_net::interface::MacAddr;
-use format_xml::xml;
 use gotham::{
+    hyper::{
+        header::{HeaderMap, HOST},
+        http::uri::Authority,
+    },
     prelude::*,
     rustls::Certificate,
     state::{client_addr, State},
@@ -9,278 +12,333 @@ use gotham::{
 use openssl::{md::Md, rand::rand_bytes, sha::Sha256, x509::X509};
 use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use super::AddCert;
+use super::{
+    responses::{AppEntry, AppList, DisplayMode, Launch, NoGameSession, Pairing, ServerInfo},
+    xml::{RequestError, Response},
+    AddCert,
+};
 use crate::{
-    config::save_config, AppId, Client, ClientInfo, Session, SharedState, State as RawState,
+    capture::Capture,
+    net::{control_address, is_outside, local_ip},
+    ping::PingPayload,
+    state::{Host, Settings},
+    App, AppId, Client, ClientInfo, Session, SharedState,
 };
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
 
-const VERSION: &'static str = "7.1.431.0";
-const GFE_VERSION: &'static str = "3.23.0.74";
+/// Codec mode bits advertised on top of the SDR ones when an app streams in HDR.
+const SCM_HEVC_MAIN10: u32 = 0x200;
+const SCM_AV1_MAIN10: u32 = 0x20000;
+/// 8K, what clients expect of a host able to encode 10 bit HEVC.
+const MAX_LUMA_PIXELS_HEVC: u32 = 1869449984;
 
-pub async fn server_info(mut state: State) -> (State, String) {
+pub async fn server_info(mut state: State) -> (State, Response) {
     let info = ClientInfo::take_from(&mut state);
+    let host = SharedState::borrow_from(&state).0.clone();
+    let identity = &host.identity;
 
-    let resp = {
-        let raw_state = SharedState::borrow_from(&state).0.clone();
-        let config = raw_state.lock().await;
-
-        let (client, is_paired, session) = if let Some(client) = config.known_clients.get(&info) {
-            let is_paired = client.paired;
-            let session = config
+    let (paired, current_game) = match host.clients.get(&info) {
+        Some(client) => {
+            let current_game = host
                 .sessions
+                .lock()
+                .await
                 .values()
-                .find(|session| &session.client == client);
-            (Some(client), is_paired, session)
-        } else {
-            (None, false, None)
-        };
-
-        xml! {
-            <root status_code=200>
-                <hostname>{config.hostname}</hostname>
-                <appversion>{VERSION}</appversion>
-                <GfeVersion>{GFE_VERSION}</GfeVersion>
-                <uniqueid>{config.unique_id}</uniqueid>
-                <HttpsPort>{config.https_port}</HttpsPort>
-                <ExternalPort>{config.http_port}</ExternalPort>
-                <mac>{config.interface.mac_addr.as_ref().unwrap_or(&MacAddr::zero())}</mac>
-                <MaxLumaPixelsHEVC>0</MaxLumaPixelsHEVC>
-                <LocalIP>{config.interface.ipv4[0].addr}</LocalIP>
-                <ServerCodecModeSupport>3</ServerCodecModeSupport>
-                <SupportedDisplayMode>
-                    <DisplayMode>
-                        <Width>1920</Width>
-                        <Height>1080</Height>
-                        <RefreshRate>60</RefreshRate>
-                    </DisplayMode>
-                </SupportedDisplayMode>
-                <PairStatus>{if is_paired { 1 } else { 0 }}</PairStatus>
-                <currentgame>{session.map(|x| x.app.0).unwrap_or(0)}</currentgame>
-                <state>{session.map(|_| "SUNSHINE_SERVER_BUSY").unwrap_or("SUNSHINE_SERVER_FREE")}</state>
-            </root>
+                .find(|session| session.client == client)
+                .map(|session| session.app.0);
+            (client.paired, current_game)
         }
-        .to_string()
+        None => (false, None),
+    };
+    let hdr = host
+        .apps
+        .iter()
+        .any(|app| hdr_supported(&host.settings, app));
+    let (codec_modes, max_luma_pixels_hevc) = if hdr {
+        (3 | SCM_HEVC_MAIN10 | SCM_AV1_MAIN10, MAX_LUMA_PIXELS_HEVC)
+    } else {
+        (3, 0)
     };
 
-    (state, resp)
+    let external = host.external.get();
+    let info = ServerInfo {
+        hostname: identity.hostname.clone(),
+        unique_id: identity.unique_id,
+        https_port: identity.https_port,
+        external_port: external.map_or(identity.http_port, |external| external.http_port),
+        mac: identity
+            .interface
+            .as_ref()
+            .and_then(|interface| interface.mac_addr.as_ref())
+            .unwrap_or(&MacAddr::zero())
+            .to_string(),
+        local_ip: identity
+            .interface
+            .as_ref()
+            .and_then(local_ip)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default(),
+        external_ip: external.map(|external| external.address.to_string()),
+        max_luma_pixels_hevc,
+        codec_modes,
+        display_modes: vec![DisplayMode {
+            width: 1920,
+            height: 1080,
+            refresh_rate: 60,
+        }],
+        paired,
+        current_game,
+    };
+    (state, Response::ok(&info))
 }
 
-pub async fn http_pair(mut state: State) -> (State, String) {
+pub async fn http_pair(mut state: State) -> (State, Response) {
     let pairing_query = PairingQueryExtractor::take_from(&mut state);
     let config = SharedState::borrow_from(&state);
     let sender = AddCert::borrow_from(&state);
 
     let result = {
-        let mut raw_state = config.0.lock().await;
+        let host = &config.0;
         let client_info = ClientInfo {
             uniqueid: pairing_query.uniqueid.clone(),
         };
 
-        let result = match pairing_query.try_into() {
+        match pairing_query.try_into() {
             Ok(PairingVariant::GetServerCert { salt, clientcert }) => {
-                get_server_cert(&mut raw_state, client_info, salt, clientcert).await
+                get_server_cert(host, client_info, salt, clientcert).await
             }
             Ok(PairingVariant::ClientChallenge { clientchallenge }) => {
-                client_challenge(&mut raw_state, client_info, clientchallenge)
+                client_challenge(host, client_info, clientchallenge)
             }
             Ok(PairingVariant::ServerChallengeResp {
                 serverchallengeresp,
-            }) => server_challenge_response(&mut raw_state, client_info, serverchallengeresp),
+            }) => server_challenge_response(host, client_info, serverchallengeresp),
             Ok(PairingVariant::ClientPairingSecret {
                 clientpairingsecret,
             }) => {
-                client_pairing_secret(
-                    &mut raw_state,
-                    client_info,
-                    clientpairingsecret,
-                    &sender.add_cert,
-                )
-                .await
+                client_pairing_secret(host, client_info, clientpairingsecret, &sender.add_cert)
+                    .await
             }
-            Err(()) => Err(anyhow::anyhow!("Unknown pairing request")),
-        };
-
-        let _ = save_config(&raw_state);
-
-        result
+            Err(()) => Err(RequestError::Invalid("unknown pairing phase".into()).into()),
+        }
     };
 
-    match result {
-        Ok(resp) => (state, resp),
-        Err(err) => {
-            log::error!("Error: {}", err);
-            (
-                state,
-                xml! {
-                    <root status_code=200>
-                        <paired>0</paired>
-                    </root>
-                }
-                .to_string(),
-            )
-        }
-    }
+    (state, Response::from(result.context("Pairing failed")))
 }
 
-pub async fn https_pair(mut state: State) -> (State, String) {
+pub async fn https_pair(mut state: State) -> (State, Response) {
     let client_info = ClientInfo::take_from(&mut state);
     let config = SharedState::borrow_from(&state);
-    let resp = {
-        let mut raw_state = config.0.lock().await;
-        log::info!("PAIRED: {:?}!", client_info);
 
-        match raw_state
-            .known_clients
+    let result: Result<Pairing> = config.0.clients.try_update(|clients| {
+        let client = clients
             .get_mut(&client_info)
-            .with_context(|| format!("Failed to find client for id: {:?}", client_info))
-        {
-            Ok(mut client) => {
-                client.paired = true;
-                let _ = save_config(&raw_state);
-
-                xml! {
-                    <root status_code=200>
-                        <paired>1</paired>
-                    </root>
-                }
-                .to_string()
-            }
-            Err(_) => xml! {
-                <root status_code=400>
-                    <paired>0</paired>
-                </root>
-            }
-            .to_string(),
-        }
-    };
+            .ok_or(RequestError::UnknownClient)?;
+        client.paired = true;
+        Ok(Pairing::Paired)
+    });
+    if result.is_ok() {
+        log::info!("PAIRED: {:?}!", client_info);
+    }
 
-    (state, resp)
+    (state, Response::from(result))
 }
 
-pub async fn applist(mut state: State) -> (State, String) {
+fn hdr_supported(settings: &Settings, app: &App) -> bool {
+    app.hdr.is_some() && settings.video_capture.supports_hdr()
+}
+
+pub async fn applist(mut state: State) -> (State, Response) {
     let info = ClientInfo::take_from(&mut state);
+    let host = &SharedState::borrow_from(&state).0;
+
+    let result = if host.clients.contains(&info) {
+        let apps = host
+            .apps
+            .iter()
+            .map(|app| AppEntry {
+                id: app.id.0,
+                title: app.title.clone(),
+                hdr_supported: hdr_supported(&host.settings, app),
+            })
+            .collect();
+        Ok(AppList(apps))
+    } else {
+        Err(RequestError::UnknownClient.into())
+    };
+
+    (state, Response::from(result))
+}
+
+pub async fn launch(mut state: State) -> (State, Response) {
+    let args = LaunchQueryExtractor::take_from(&mut state);
     let config = SharedState::borrow_from(&state);
-    let resp = {
-        let raw_state = config.0.lock().await;
+    let addr = client_addr(&state).expect("no client address");
+    let requested = requested_ip(&state);
 
-        if !raw_state.known_clients.contains_key(&info) {
-            xml! { <root status_code=501 /> }.to_string()
-        } else {
-            xml! {
-                <root status_code=200>
-                for (i, app) in (raw_state.apps.iter().enumerate()) {
-                    <App>
-                        <IsHdrSupported>0</IsHdrSupported>
-                        <AppTitle>{app.title}</AppTitle>
-                        <ID>{i+1}</ID>
-                    </App>
-                }
-                </root>
-            }
-            .to_string()
-        }
+    let response = match start_session(config, args, addr.ip(), requested).await {
+        Ok(launch) => Response::ok(&launch),
+        Err(err) => Response::error(&err).with(&NoGameSession),
     };
+    (state, response)
+}
 
-    (state, resp)
+/// The address the client reached the host on, if its `Host` header names one.
+fn requested_ip(state: &State) -> Option<IpAddr> {
+    let host = HeaderMap::borrow_from(state).get(HOST)?.to_str().ok()?;
+    let authority = host.parse::<Authority>().ok()?;
+    // IPv6 addresses are bracketed
+    authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
 }
 
-pub async fn launch(mut state: State) -> (State, String) {
-    let args = LaunchQueryExtractor::take_from(&mut state);
+async fn start_session(
+    config: &SharedState,
+    args: LaunchQueryExtractor,
+    address: IpAddr,
+    requested: Option<IpAddr>,
+) -> Result<Launch> {
+    let host = &config.0;
     let info = ClientInfo {
         uniqueid: args.uniqueid.clone(),
     };
-    let config = SharedState::borrow_from(&state);
-    let addr = client_addr(&state).expect("no client address");
-
-    let resp = {
-        let mut raw_state = config.0.lock().await;
-        if raw_state.apps.get(args.appid - 1).is_some() {
-            let rtsp_listener = crate::rtsp::init().await.unwrap();
-            let rtsp_port = rtsp_listener.local_addr().unwrap().port();
-
-            let id = Uuid::new_v4();
-            let session = Session {
-                app: AppId((args.appid - 1) as u64),
-                client: raw_state.known_clients.get(&info).unwrap().clone(),
-                rikey: args.rikey,
-                rikeyid: args.rikeyid,
-            };
-            raw_state.sessions.insert(id.clone(), session);
-
-            let move_state = config.clone();
-            tokio::spawn(async move {
-                if let Ok(Ok((stream, addr))) =
-                    tokio::time::timeout(Duration::from_secs(30), rtsp_listener.accept()).await
-                {
-                    log::info!("RTSP Connection from: {}", addr);
-                    crate::rtsp::new_client(rtsp_listener, stream, move_state, id).await;
-                } else {
-                    // TODO: we didn't even make it to the start, discard session
-                }
-            });
+    let client = host.clients.get(&info).ok_or(RequestError::UnknownClient)?;
+    let app = host
+        .app(AppId(args.appid))
+        .ok_or(RequestError::UnknownApp)
+        .with_context(|| format!("Failed to launch app {}", args.appid))?
+        .id;
+    // checked before binding, every session listens on the same ports
+    if host.sessions.lock().await.len() >= host.settings.max_sessions {
+        return Err(RequestError::Busy.into());
+    }
 
-            // TODO, find free ports (just use 0? and query tokio?)
-            // Launch tasks for all of them
-            // Add keys, joinhandles,  to session struct
-            // launch compositor
-            // launch sockets
-            // answer client
-
-            let ip = "127.0.0.1"; //addr.ip();
-            let url = format!("rtsp://{ip}:{rtsp_port}");
-
-            xml! {
-                <root status_code=200>
-                    <sessionUrl0>{url}</sessionUrl0>
-                    <gamesession>1</gamesession>
-                </root>
+    // clients announce support for encrypted RTSP and control streams via `corever`
+    let rtsp_encryption = args.corever.unwrap_or(0) >= 1;
+    let control_encryption = rtsp_encryption;
+    let audio_config = match args.surroundAudioInfo {
+        Some(info) => crate::audio::AudioConfig::from_surround_info(info).unwrap_or_else(|| {
+            log::warn!("Unsupported surround audio info {:#x}, using stereo", info);
+            crate::audio::STEREO
+        }),
+        None => crate::audio::STEREO,
+    };
+    let rtsp_listener = crate::rtsp::init(host.settings.network.rtsp_address)
+        .context("Failed to listen for RTSP")?;
+    let rtsp_address = rtsp_listener.local_addr()?;
+
+    let id = Uuid::new_v4();
+    let capture = host
+        .settings
+        .capture_directory
+        .as_ref()
+        .and_then(|directory| match Capture::create(directory, id) {
+            Ok(capture) => {
+                capture.launch(rtsp_encryption, control_encryption, audio_config.channels);
+                Some(capture)
             }
-            .to_string()
-        } else {
-            // app does not exist
-            xml! {
-                <root status_code=400>
-                    <gamesession>0</gamesession>
-                </root>
+            Err(err) => {
+                log::error!("Failed to capture session: {:#}", err);
+                None
             }
-            .to_string()
-        }
+        });
+    let session = Session {
+        app,
+        client,
+        rikey: args.rikey,
+        rikeyid: args.rikeyid,
+        rtsp_encryption,
+        control_encryption,
+        audio_config,
+        stream_config: None,
+        control: None,
+        control_thread: None,
+        tasks: Vec::new(),
+        shutdown: CancellationToken::new(),
+        controllers: args.remoteControllersBitmap.unwrap_or(0),
+        process: None,
+        compositor: None,
+        address,
+        ping_payload: PingPayload::generate().context("Failed to generate ping payload")?,
+        endpoints: Default::default(),
+        capture,
     };
+    let shutdown = session.shutdown.clone();
+    {
+        // another launch may have started a session meanwhile
+        let mut sessions = host.sessions.lock().await;
+        if sessions.len() >= host.settings.max_sessions {
+            return Err(RequestError::Busy.into());
+        }
+        sessions.insert(id, session);
+    }
 
-    (state, resp)
+    // the control stream and the media streams cancel the session once they are done with it
+    let move_state = config.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        move_state.0.end_session(id).await;
+    });
+
+    let move_state = config.clone();
+    tokio::spawn(async move {
+        if let Ok(Ok((stream, addr))) =
+            tokio::time::timeout(Duration::from_secs(30), rtsp_listener.accept()).await
+        {
+            log::info!("RTSP Connection from: {}", addr);
+            crate::rtsp::new_client(rtsp_listener, stream, move_state, id).await;
+        } else {
+            log::warn!("Client didn't connect to RTSP, discarding session {}", id);
+            move_state.0.end_session(id).await;
+        }
+    });
+
+    // clients outside the network only reach the host through the router's mapping
+    let external = host
+        .external
+        .get()
+        .map(|external| external.address)
+        .filter(|external| requested == Some(*external) || is_outside(address));
+    // else where RTSP listens if bound to one address, else where the client reached HTTP.
+    // Clients reach the control stream at the same address, which ENet only has on IPv4.
+    let ip = external
+        .into_iter()
+        .chain(Some(rtsp_address.ip()))
+        .chain(requested)
+        .chain(host.identity.interface.as_ref().and_then(local_ip))
+        .filter_map(|ip| control_address(ip).ok())
+        .find(|ip| !ip.is_unspecified())
+        .context("No IPv4 address to tell the client")?;
+    let scheme = if rtsp_encryption { "rtspenc" } else { "rtsp" };
+    Ok(Launch {
+        session_url: format!("{scheme}://{ip}:{}", rtsp_address.port()),
+    })
 }
 
-pub async fn unpair(mut state: State) -> (State, String) {
+pub async fn unpair(mut state: State) -> (State, Response) {
     let info = ClientInfo::take_from(&mut state);
     let config = SharedState::borrow_from(&state);
 
-    {
-        let mut raw_state = config.0.lock().await;
-        raw_state.known_clients.remove(&info);
-        let _ = save_config(&raw_state);
-    }
+    config.0.clients.remove(&info);
 
-    (
-        state,
-        xml! {
-            <root status_code=200>
-                <paired>0</paired>
-            </root>
-        }
-        .to_string(),
-    )
+    (state, Response::ok(&Pairing::NotPaired))
 }
 
 async fn get_server_cert(
-    state: &mut RawState,
+    host: &Host,
     client_id: ClientInfo,
     salt: String,
     client_cert: String,
-) -> Result<String> {
-    let salt = hex::decode(salt.into_bytes()).context("Unable to decode salt")?;
+) -> Result<Pairing> {
+    let salt = hex::decode(salt.into_bytes())
+        .map_err(|_| RequestError::Invalid("salt is not hex".into()))?;
 
     // read pin from command line
     let pin = tokio::task::spawn_blocking(|| {
@@ -291,148 +349,126 @@ async fn get_server_cert(
     let key = crate::crypto::gen_aes_key(&salt, &pin);
 
     let client_cert = client_cert.into_bytes();
-    let decoded = hex::decode(client_cert).context("Unable to decode client certificate")?;
+    let decoded = hex::decode(client_cert)
+        .map_err(|_| RequestError::Invalid("client certificate is not hex".into()))?;
     log::debug!("client_cert: {:?}", std::str::from_utf8(&decoded));
-    let client_cert = X509::from_pem(&decoded)?;
-
-    state
-        .known_clients
-        .entry(client_id)
-        .and_modify(|client| {
-            client.client_cert = client_cert.clone();
-            client.key = key.clone();
-        })
-        .or_insert_with(|| Client {
-            paired: false,
-            client_cert,
-            key,
-            server_challenge: None,
-            server_secret: None,
-            client_hash: None,
-        });
+    let client_cert = X509::from_pem(&decoded)
+        .map_err(|_| RequestError::Invalid("client certificate is not PEM".into()))?;
+
+    host.clients.update(|clients| {
+        clients
+            .entry(client_id)
+            .and_modify(|client| {
+                client.client_cert = client_cert.clone();
+                client.key = key.clone();
+            })
+            .or_insert_with(|| Client {
+                paired: false,
+                client_cert,
+                key,
+                server_challenge: None,
+                server_secret: None,
+                client_hash: None,
+            });
+    });
 
-    let server_cert = state.server_cert.to_pem()?;
+    let server_cert = host.identity.server_cert.to_pem()?;
     log::debug!("server_cert: {:?}", std::str::from_utf8(&server_cert));
     let server_cert = hex::encode(server_cert);
 
-    Ok(xml! {
-        <root status_code=200>
-            <paired>1</paired>
-            <plaincert>{server_cert}</plaincert>
-        </root>
-    }
-    .to_string())
+    Ok(Pairing::ServerCert(server_cert))
 }
 
-fn client_challenge(
-    state: &mut RawState,
-    client_id: ClientInfo,
-    challenge: String,
-) -> Result<String> {
-    let challenge =
-        hex::decode(challenge.into_bytes()).context("Unable to decode client challenge")?;
-
-    let mut client = state
-        .known_clients
-        .get_mut(&client_id)
-        .with_context(|| format!("Failed to find client for id: {:?}", client_id))?;
-
-    let decrypted = crate::crypto::aes_decrypt_ecb(&challenge, &client.key, false)
-        .context("Unable to decrypt client challenge")?;
-    let signature = state.server_cert.signature().as_slice();
-    let mut secret = [0; 16];
-    rand_bytes(&mut secret)?;
-
-    let mut hasher = Sha256::new();
-    hasher.update(&decrypted);
-    hasher.update(&signature);
-    hasher.update(&secret);
-    let hash = Vec::from(hasher.finish());
-
-    let mut server_challenge = [0; 16];
-    rand_bytes(&mut server_challenge)?;
-
-    let mut plaintext = Vec::new();
-    plaintext.extend(&hash);
-    plaintext.extend(&server_challenge);
-
-    let encrypted = crate::crypto::aes_encrypt_ecb(&plaintext, &client.key, false)
-        .context("Unable to encode response")?;
-    let response = hex::encode(encrypted);
-    client.server_secret = Some(secret);
-    client.server_challenge = Some(server_challenge);
-
-    Ok(xml! {
-        <root status_code=200>
-            <paired>1</paired>
-            <challengeresponse>{response}</challengeresponse>
-        </root>
-    }
-    .to_string())
+fn client_challenge(host: &Host, client_id: ClientInfo, challenge: String) -> Result<Pairing> {
+    let challenge = hex::decode(challenge.into_bytes())
+        .map_err(|_| RequestError::Invalid("challenge is not hex".into()))?;
+
+    host.clients.try_update(|clients| {
+        let mut client = clients
+            .get_mut(&client_id)
+            .ok_or(RequestError::UnknownClient)?;
+
+        let decrypted = crate::crypto::aes_decrypt_ecb(&challenge, &client.key, false)
+            .context("Unable to decrypt client challenge")?;
+        let signature = host.identity.server_cert.signature().as_slice();
+        let mut secret = [0; 16];
+        rand_bytes(&mut secret)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&decrypted);
+        hasher.update(&signature);
+        hasher.update(&secret);
+        let hash = Vec::from(hasher.finish());
+
+        let mut server_challenge = [0; 16];
+        rand_bytes(&mut server_challenge)?;
+
+        let mut plaintext = Vec::new();
+        plaintext.extend(&hash);
+        plaintext.extend(&server_challenge);
+
+        let encrypted = crate::crypto::aes_encrypt_ecb(&plaintext, &client.key, false)
+            .context("Unable to encode response")?;
+        let response = hex::encode(encrypted);
+        client.server_secret = Some(secret);
+        client.server_challenge = Some(server_challenge);
+
+        Ok(Pairing::ChallengeResponse(response))
+    })
 }
 
 fn server_challenge_response(
-    state: &mut RawState,
+    host: &Host,
     client_id: ClientInfo,
     challenge: String,
-) -> Result<String> {
-    let challenge =
-        hex::decode(challenge.into_bytes()).context("Unable to decode client challenge")?;
-
-    let mut client = state
-        .known_clients
-        .get_mut(&client_id)
-        .with_context(|| format!("Failed to find client for id: {:?}", client_id))?;
-
-    let decrypted = crate::crypto::aes_decrypt_ecb(&challenge, &client.key, false)
-        .context("Unable to decrypt client challenge")?;
-    client.client_hash = Some(decrypted);
-
-    if let Some(secret) = client.server_secret.as_ref() {
-        let signed = crate::crypto::sign(&state.server_key, secret, Md::sha256())?;
-        assert!(crate::crypto::verify(
-            &state.server_cert,
-            secret,
-            &signed,
-            Md::sha256(),
-        )?);
-        let mut pairingsecret = Vec::from(secret.as_slice());
-        pairingsecret.extend(signed);
-        let pairingsecret = hex::encode(pairingsecret);
-
-        Ok(xml! {
-            <root status_code=200>
-                <paired>1</paired>
-                <pairingsecret>{pairingsecret}</pairingsecret>
-            </root>
-        }
-        .to_string())
-    } else {
-        Ok(xml! {
-            <root status_code=200>
-                <paired>0</paired>
-            </root>
+) -> Result<Pairing> {
+    let challenge = hex::decode(challenge.into_bytes())
+        .map_err(|_| RequestError::Invalid("challenge is not hex".into()))?;
+
+    host.clients.try_update(|clients| {
+        let mut client = clients
+            .get_mut(&client_id)
+            .ok_or(RequestError::UnknownClient)?;
+
+        let decrypted = crate::crypto::aes_decrypt_ecb(&challenge, &client.key, false)
+            .context("Unable to decrypt client challenge")?;
+        client.client_hash = Some(decrypted);
+
+        if let Some(secret) = client.server_secret.as_ref() {
+            let signed = crate::crypto::sign(&host.identity.server_key, secret, Md::sha256())?;
+            if !crate::crypto::verify(&host.identity.server_cert, secret, &signed, Md::sha256())? {
+                anyhow::bail!("Server key doesn't match its certificate");
+            }
+            let mut pairingsecret = Vec::from(secret.as_slice());
+            pairingsecret.extend(signed);
+            let pairingsecret = hex::encode(pairingsecret);
+
+            Ok(Pairing::PairingSecret(pairingsecret))
+        } else {
+            Ok(Pairing::NotPaired)
         }
-        .to_string())
-    }
+    })
 }
 
 async fn client_pairing_secret(
-    state: &mut RawState,
+    host: &Host,
     client_id: ClientInfo,
     client_pairing_secret: String,
     verifier: &Sender<Certificate>,
-) -> Result<String> {
+) -> Result<Pairing> {
     let client_secret = hex::decode(client_pairing_secret.into_bytes())
-        .context("Unable to decode client pairing secret")?;
+        .map_err(|_| RequestError::Invalid("pairing secret is not hex".into()))?;
+    if client_secret.len() < 16 {
+        return Err(RequestError::Invalid("pairing secret is too short".into()).into());
+    }
 
     let secret = &client_secret[0..16];
     let sign = &client_secret[16..];
 
-    let client = state
-        .known_clients
-        .get_mut(&client_id)
-        .with_context(|| format!("Failed to find client for id: {:?}", client_id))?;
+    let client = host
+        .clients
+        .get(&client_id)
+        .ok_or(RequestError::UnknownClient)?;
 
     if let (Some(challenge), Some(client_hash)) = (
         client.server_challenge.as_ref(),
@@ -452,21 +488,11 @@ async fn client_pairing_secret(
                 .send(Certificate(client.client_cert.to_der()?))
                 .await?;
 
-            return Ok(xml! {
-                <root status_code=200>
-                    <paired>1</paired>
-                </root>
-            }
-            .to_string());
+            return Ok(Pairing::Paired);
         }
     }
 
-    Ok(xml! {
-        <root status_code=200>
-            <paired>0</paired>
-        </root>
-    }
-    .to_string())
+    Ok(Pairing::NotPaired)
 }
 
 #[derive(Deserialize, StateData, StaticResponseExtender)]
@@ -523,14 +549,15 @@ impl std::convert::TryFrom<PairingQueryExtractor> for PairingVariant {
 pub struct LaunchQueryExtractor {
     uniqueid: String,
     //uuid
-    appid: usize,
+    appid: u64,
     mode: String,
     //additionalStates=1
     //sops=0
     rikey: String,
     rikeyid: String,
+    corever: Option<u32>,
     //localAudioPlayMode: String,
-    //surroundAudioInfo: u64,
-    //remoteControllersBitmap: String,
+    surroundAudioInfo: Option<u32>,
+    remoteControllersBitmap: Option<u16>,
     //gcmap: String,
 }
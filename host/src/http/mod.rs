@@ -1,19 +1,20 @@
-use crate::{ClientInfo, SharedState};
+use crate::{net, systemd::Listeners, ClientInfo, SharedState};
 
 use std::{
-    future::Future,
+    future::{self, Future},
+    net::SocketAddr,
     panic::RefUnwindSafe,
     pin::Pin,
     sync::{Arc, Mutex},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
 use gotham::{
-    handler::IntoResponse,
-    middleware::{logger::RequestLogger, state::StateMiddleware},
+    bind_server,
+    handler::{HandlerFuture, IntoResponse},
+    middleware::{logger::RequestLogger, state::StateMiddleware, Middleware, NewMiddleware},
     pipeline::{new_pipeline, single_pipeline},
-    plain::init_server,
     prelude::{DefineSingleRoute, DrawRoutes},
     router::{build_router, Router},
     rustls::{
@@ -21,9 +22,7 @@ use gotham::{
         server::{ClientCertVerified, ClientCertVerifier},
         Certificate, Error as TlsError, PrivateKey, ServerConfig,
     },
-    state::StateData,
-    tls::init_server as tls_init_server,
-    StartError,
+    state::{State, StateData},
 };
 use openssl::{
     error::ErrorStack,
@@ -35,15 +34,85 @@ use openssl::{
     },
 };
 use rustls::{client::HandshakeSignatureValid, internal::msgs::handshake::DigitallySignedStruct};
-use tokio::sync::mpsc;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, Notify},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
 
 use self::handlers::LaunchQueryExtractor;
 
 mod handlers;
+mod responses;
+mod xml;
 
 pub struct HttpState {
-    pub http_server: Pin<Box<dyn Future<Output = Result<(), StartError>>>>,
-    pub https_server: Pin<Box<dyn Future<Output = Result<(), StartError>>>>,
+    pub http_server: Pin<Box<dyn Future<Output = ()>>>,
+    pub https_server: Pin<Box<dyn Future<Output = ()>>>,
+    pub requests: Arc<Requests>,
+}
+
+/// The requests being handled, so that shutdown can let them finish.
+#[derive(Debug, Default)]
+pub struct Requests {
+    active: Mutex<usize>,
+    idle: Notify,
+}
+
+impl Requests {
+    fn start(self: &Arc<Self>) -> ActiveRequest {
+        *self.active.lock().unwrap() += 1;
+        ActiveRequest(self.clone())
+    }
+
+    /// Waits up to `limit` for the requests being handled, returns whether all finished.
+    ///
+    /// Servers have to be stopped first, or requests keep coming.
+    pub async fn drain(&self, limit: Duration) -> bool {
+        timeout(limit, async {
+            loop {
+                // created first, so a request finishing in between isn't missed
+                let idle = self.idle.notified();
+                if *self.active.lock().unwrap() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+struct ActiveRequest(Arc<Requests>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[derive(Clone, NewMiddleware)]
+struct TrackRequests(Arc<Requests>);
+impl RefUnwindSafe for TrackRequests {}
+
+impl Middleware for TrackRequests {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let request = self.0.start();
+        Box::pin(async move {
+            let result = chain(state).await;
+            drop(request);
+            result
+        })
+    }
 }
 
 #[derive(Clone, StateData)]
@@ -179,9 +248,14 @@ impl ClientCertVerifier for MoonlightVerifier {
     }
 }
 
-fn http_router(state: SharedState, send: mpsc::Sender<Certificate>) -> Router {
+fn http_router(
+    state: SharedState,
+    send: mpsc::Sender<Certificate>,
+    requests: Arc<Requests>,
+) -> Router {
     let (chain, pipelines) = single_pipeline(
         new_pipeline()
+            .add(TrackRequests(requests))
             .add(StateMiddleware::new(state))
             .add(StateMiddleware::new(AddCert { add_cert: send }))
             .add(RequestLogger::new(log::Level::Info))
@@ -193,32 +267,33 @@ fn http_router(state: SharedState, send: mpsc::Sender<Certificate>) -> Router {
             .get("/serverinfo")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::server_info(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::server_info(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         route
             .get("/pair")
             .with_query_string_extractor::<handlers::PairingQueryExtractor>()
             .to_async(|state| async {
-                let (state, string) = handlers::http_pair(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::http_pair(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         route
             .get("/unpair")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::unpair(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::unpair(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
     })
 }
 
-fn https_router(state: SharedState) -> Router {
+fn https_router(state: SharedState, requests: Arc<Requests>) -> Router {
     let (chain, pipelines) = single_pipeline(
         new_pipeline()
+            .add(TrackRequests(requests))
             .add(StateMiddleware::new(state))
             .add(RequestLogger::new(log::Level::Info))
             .build(),
@@ -229,24 +304,24 @@ fn https_router(state: SharedState) -> Router {
             .get("/serverinfo")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::server_info(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::server_info(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         route
             .get("/pair")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::https_pair(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::https_pair(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         route
             .get("/applist")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::applist(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::applist(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         /*
@@ -254,8 +329,8 @@ fn https_router(state: SharedState) -> Router {
             .get("/appasset")
             .with_query_string_extractor::<ClientInfo>()
             .to_async(|state| async {
-                let (state, string) = handlers::appasset(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::appasset(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
         */
@@ -263,21 +338,34 @@ fn https_router(state: SharedState) -> Router {
             .get("/launch")
             .with_query_string_extractor::<LaunchQueryExtractor>()
             .to_async(|state| async {
-                let (state, string) = handlers::launch(state).await;
-                let resp = string.into_response(&state);
+                let (state, response) = handlers::launch(state).await;
+                let resp = response.into_response(&state);
                 Ok((state, resp))
             });
     })
 }
 
-pub async fn init(state: SharedState) -> Result<HttpState> {
-    let config = state.0.lock().await;
+/// Takes over the listener systemd passed, or listens on `address`.
+fn listener(activated: Option<std::net::TcpListener>, address: SocketAddr) -> Result<TcpListener> {
+    let listener = match activated {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => net::bind_tcp(address)?,
+    };
+    Ok(listener)
+}
+
+pub async fn init(state: SharedState, listeners: Listeners) -> Result<HttpState> {
+    let identity = &state.0.identity;
+    let network = &state.0.settings.network;
 
-    let der_cert = config
+    let der_cert = identity
         .server_cert
         .to_der()
         .context("Failed to convert server cert")?;
-    let der_key = config
+    let der_key = identity
         .server_key
         .private_key_to_der()
         .context("Failed to convert server key")?;
@@ -289,18 +377,39 @@ pub async fn init(state: SharedState) -> Result<HttpState> {
         .with_client_cert_verifier(Arc::new(verifier))
         .with_single_cert(vec![Certificate(der_cert)], PrivateKey(der_key))?;
 
-    let http_server = Box::pin(init_server(
-        ("0.0.0.0", config.http_port),
-        http_router(state.clone(), send),
-    ));
-    let https_server = Box::pin(tls_init_server(
-        ("0.0.0.0", config.https_port),
-        https_router(state.clone()),
-        ssl_config,
-    ));
+    let http_listener = listener(
+        listeners.http,
+        SocketAddr::new(network.http_address, identity.http_port),
+    )
+    .context("Failed to listen for HTTP")?;
+    let https_listener = listener(
+        listeners.https,
+        SocketAddr::new(network.https_address, identity.https_port),
+    )
+    .context("Failed to listen for HTTPS")?;
+
+    let requests = Arc::new(Requests::default());
+    let router = http_router(state.clone(), send, requests.clone());
+    let http_server = Box::pin(async move {
+        bind_server(http_listener, router, |socket| future::ready(Ok(socket))).await;
+    });
+    let router = https_router(state.clone(), requests.clone());
+    let acceptor = TlsAcceptor::from(Arc::new(ssl_config));
+    let https_server = Box::pin(async move {
+        bind_server(https_listener, router, move |socket| {
+            let accept = acceptor.accept(socket);
+            Box::pin(async move {
+                accept
+                    .await
+                    .map_err(|err| log::debug!("TLS handshake failed: {}", err))
+            })
+        })
+        .await;
+    });
 
     Ok(HttpState {
         http_server,
         https_server,
+        requests,
     })
 }
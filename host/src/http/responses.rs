@@ -0,0 +1,247 @@
+use uuid::Uuid;
+
+use super::xml::{Xml, XmlBody};
+
+const VERSION: &str = "7.1.431.0";
+const GFE_VERSION: &str = "3.23.0.74";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub hostname: String,
+    pub unique_id: Uuid,
+    pub https_port: u16,
+    /// The HTTP port clients outside the network connect to, the local one unless the
+    /// router mapped it elsewhere.
+    pub external_port: u16,
+    pub mac: String,
+    pub local_ip: String,
+    /// Known once ports are mapped on the router.
+    pub external_ip: Option<String>,
+    /// 0 unless 10 bit HEVC can be streamed.
+    pub max_luma_pixels_hevc: u32,
+    /// `SCM_*` bits of the codecs clients may ask for.
+    pub codec_modes: u32,
+    pub display_modes: Vec<DisplayMode>,
+    pub paired: bool,
+    /// The app the asking client is streaming, if it is.
+    pub current_game: Option<u64>,
+}
+
+impl XmlBody for ServerInfo {
+    fn write(&self, xml: &mut Xml) {
+        xml.field("hostname", &self.hostname)
+            .field("appversion", VERSION)
+            .field("GfeVersion", GFE_VERSION)
+            .field("uniqueid", self.unique_id)
+            .field("HttpsPort", self.https_port)
+            .field("ExternalPort", self.external_port)
+            .field("mac", &self.mac)
+            .field("MaxLumaPixelsHEVC", self.max_luma_pixels_hevc)
+            .field("LocalIP", &self.local_ip);
+        if let Some(external_ip) = &self.external_ip {
+            xml.field("ExternalIP", external_ip);
+        }
+        xml.field("ServerCodecModeSupport", self.codec_modes)
+            .element("SupportedDisplayMode", |xml| {
+                for mode in &self.display_modes {
+                    xml.element("DisplayMode", |xml| {
+                        xml.field("Width", mode.width)
+                            .field("Height", mode.height)
+                            .field("RefreshRate", mode.refresh_rate);
+                    });
+                }
+            })
+            .field("PairStatus", self.paired as u8)
+            .field("currentgame", self.current_game.unwrap_or(0))
+            .field(
+                "state",
+                match self.current_game {
+                    Some(_) => "SUNSHINE_SERVER_BUSY",
+                    None => "SUNSHINE_SERVER_FREE",
+                },
+            );
+    }
+}
+
+/// The answer to a step of pairing, or to unpairing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pairing {
+    /// The step failed and clients start over, or the client was unpaired.
+    NotPaired,
+    Paired,
+    /// The hex encoded PEM of the host's certificate.
+    ServerCert(String),
+    ChallengeResponse(String),
+    PairingSecret(String),
+}
+
+impl XmlBody for Pairing {
+    fn write(&self, xml: &mut Xml) {
+        xml.field("paired", (*self != Pairing::NotPaired) as u8);
+        match self {
+            Pairing::NotPaired | Pairing::Paired => {}
+            Pairing::ServerCert(cert) => {
+                xml.field("plaincert", cert);
+            }
+            Pairing::ChallengeResponse(response) => {
+                xml.field("challengeresponse", response);
+            }
+            Pairing::PairingSecret(secret) => {
+                xml.field("pairingsecret", secret);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppEntry {
+    /// Clients treat 0 as no app.
+    pub id: u64,
+    pub title: String,
+    pub hdr_supported: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppList(pub Vec<AppEntry>);
+
+impl XmlBody for AppList {
+    fn write(&self, xml: &mut Xml) {
+        for app in &self.0 {
+            xml.element("App", |xml| {
+                xml.field("IsHdrSupported", app.hdr_supported as u8)
+                    .field("AppTitle", &app.title)
+                    .field("ID", app.id);
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Launch {
+    /// Where the client finds the RTSP server of its session.
+    pub session_url: String,
+}
+
+impl XmlBody for Launch {
+    fn write(&self, xml: &mut Xml) {
+        xml.field("sessionUrl0", &self.session_url)
+            .field("gamesession", 1);
+    }
+}
+
+/// Sent along with a failed launch, like GameStream hosts do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoGameSession;
+
+impl XmlBody for NoGameSession {
+    fn write(&self, xml: &mut Xml) {
+        xml.field("gamesession", 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use gotham::hyper::StatusCode;
+
+    use super::*;
+    use crate::http::xml::{RequestError, Response};
+
+    /// Fixtures are written by hand after the answers of Sunshine's nvhttp.cpp, no answers of
+    /// a real server are recorded yet. Line breaks, which the files have after the
+    /// declaration, are ignored.
+    fn normalize(xml: &str) -> String {
+        xml.lines().map(str::trim).collect()
+    }
+
+    fn assert_matches(response: &Response, fixture: &str) {
+        assert_eq!(normalize(&response.to_xml()), normalize(fixture));
+    }
+
+    #[test]
+    fn test_server_info() {
+        let mut info = ServerInfo {
+            hostname: "host".into(),
+            unique_id: Uuid::nil(),
+            https_port: 47984,
+            external_port: 47989,
+            mac: "00:00:00:00:00:00".into(),
+            local_ip: "192.0.2.1".into(),
+            external_ip: None,
+            max_luma_pixels_hevc: 1869449984,
+            codec_modes: 0x20203,
+            display_modes: vec![DisplayMode {
+                width: 1920,
+                height: 1080,
+                refresh_rate: 60,
+            }],
+            paired: true,
+            current_game: Some(2),
+        };
+        assert_matches(
+            &Response::ok(&info),
+            include_str!("fixtures/serverinfo.xml"),
+        );
+
+        // Sunshine doesn't tell clients its external address
+        info.external_ip = Some("203.0.113.7".into());
+        assert!(Response::ok(&info)
+            .to_xml()
+            .contains("<LocalIP>192.0.2.1</LocalIP><ExternalIP>203.0.113.7</ExternalIP>"));
+    }
+
+    #[test]
+    fn test_pairing() {
+        let response = "00".repeat(48);
+        assert_matches(
+            &Response::ok(&Pairing::ChallengeResponse(response)),
+            include_str!("fixtures/pair_challenge.xml"),
+        );
+        assert_matches(
+            &Response::ok(&Pairing::NotPaired),
+            include_str!("fixtures/pair_refused.xml"),
+        );
+    }
+
+    #[test]
+    fn test_app_list() {
+        let apps = AppList(vec![
+            AppEntry {
+                id: 1,
+                title: "Desktop".into(),
+                hdr_supported: false,
+            },
+            AppEntry {
+                id: 2,
+                title: "Steam Big Picture".into(),
+                hdr_supported: true,
+            },
+        ]);
+        assert_matches(&Response::ok(&apps), include_str!("fixtures/applist.xml"));
+    }
+
+    #[test]
+    fn test_launch() {
+        let launch = Launch {
+            session_url: "rtspenc://192.0.2.1:48010".into(),
+        };
+        assert_matches(&Response::ok(&launch), include_str!("fixtures/launch.xml"));
+    }
+
+    #[test]
+    fn test_unknown_app() {
+        let err = Err::<Launch, _>(RequestError::UnknownApp)
+            .context("Failed to launch app 7")
+            .unwrap_err();
+        let response = Response::error(&err).with(&NoGameSession);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_matches(&response, include_str!("fixtures/launch_unknown_app.xml"));
+    }
+}
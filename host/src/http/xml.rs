@@ -0,0 +1,233 @@
+use std::{borrow::Cow, fmt::Display};
+
+use gotham::{
+    handler::IntoResponse,
+    helpers::http::response::create_response,
+    hyper::{Body, Response as HttpResponse, StatusCode},
+    mime,
+    state::State,
+};
+
+/// Writes the children of an element, escaping all text.
+pub struct Xml<'a> {
+    out: &'a mut String,
+}
+
+impl Xml<'_> {
+    /// Writes `<name>value</name>`.
+    pub fn field(&mut self, name: &str, value: impl Display) -> &mut Self {
+        let value = value.to_string();
+        self.out.push('<');
+        self.out.push_str(name);
+        self.out.push('>');
+        self.out.push_str(&escape(&value));
+        self.out.push_str("</");
+        self.out.push_str(name);
+        self.out.push('>');
+        self
+    }
+
+    pub fn element(&mut self, name: &str, children: impl FnOnce(&mut Xml)) -> &mut Self {
+        self.out.push('<');
+        self.out.push_str(name);
+        self.out.push('>');
+        children(&mut Xml {
+            out: &mut *self.out,
+        });
+        self.out.push_str("</");
+        self.out.push_str(name);
+        self.out.push('>');
+        self
+    }
+}
+
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// The payload of a successful response, written as the children of `<root>`.
+pub trait XmlBody {
+    fn write(&self, xml: &mut Xml);
+}
+
+/// Why a request was refused, each maps to the status clients are told.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The client never paired with this host.
+    UnknownClient,
+    UnknownApp,
+    /// As many sessions as the host allows are running.
+    Busy,
+    /// Parameters are missing or malformed.
+    Invalid(String),
+}
+
+impl RequestError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RequestError::UnknownClient => StatusCode::UNAUTHORIZED,
+            RequestError::UnknownApp => StatusCode::NOT_FOUND,
+            RequestError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            RequestError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::UnknownClient => write!(f, "The client is not paired"),
+            RequestError::UnknownApp => write!(f, "No such app"),
+            RequestError::Busy => write!(f, "The host runs as many sessions as it allows"),
+            RequestError::Invalid(reason) => write!(f, "Invalid request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// A GameStream response, a `<root>` element carrying the status as attributes.
+///
+/// Clients read the status off the XML, the HTTP status matches it so that proxies and
+/// logs see failures too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    status: StatusCode,
+    message: String,
+    /// The children of `<root>`, already written.
+    body: String,
+}
+
+impl Response {
+    pub fn ok(body: &impl XmlBody) -> Response {
+        let mut out = String::new();
+        body.write(&mut Xml { out: &mut out });
+        Response {
+            status: StatusCode::OK,
+            message: "OK".into(),
+            body: out,
+        }
+    }
+
+    /// Refuses a request, with the status of the [`RequestError`] in `err` if there is
+    /// one and an internal error otherwise.
+    pub fn error(err: &anyhow::Error) -> Response {
+        let status = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<RequestError>())
+            .map(RequestError::status)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        log::warn!("Request failed with {}: {:#}", status.as_u16(), err);
+        Response {
+            status,
+            message: format!("{:#}", err),
+            body: String::new(),
+        }
+    }
+
+    /// Adds `body` to the children of `<root>`, for failures clients still read fields of.
+    pub fn with(mut self, body: &impl XmlBody) -> Response {
+        body.write(&mut Xml {
+            out: &mut self.body,
+        });
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn to_xml(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <root status_code=\"{}\" status_message=\"{}\">{}</root>",
+            self.status.as_u16(),
+            escape(&self.message),
+            self.body
+        )
+    }
+}
+
+impl<T: XmlBody> From<anyhow::Result<T>> for Response {
+    fn from(result: anyhow::Result<T>) -> Response {
+        match result {
+            Ok(body) => Response::ok(&body),
+            Err(err) => Response::error(&err),
+        }
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self, state: &State) -> HttpResponse<Body> {
+        create_response(state, self.status, mime::TEXT_XML, self.to_xml())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    struct Nested;
+
+    impl XmlBody for Nested {
+        fn write(&self, xml: &mut Xml) {
+            xml.field("title", "Tom & Jerry <1>")
+                .element("Mode", |xml| {
+                    xml.field("Width", 1920);
+                });
+        }
+    }
+
+    #[test]
+    fn test_ok() {
+        assert_eq!(
+            Response::ok(&Nested).to_xml(),
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <root status_code=\"200\" status_message=\"OK\">\
+             <title>Tom &amp; Jerry &lt;1&gt;</title><Mode><Width>1920</Width></Mode></root>"
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let err = Err::<(), _>(RequestError::UnknownApp)
+            .context("Failed to launch \"Steam\"")
+            .unwrap_err();
+        let response = Response::error(&err);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.to_xml(),
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <root status_code=\"404\" status_message=\"Failed to launch &quot;Steam&quot;: \
+             No such app\"></root>"
+        );
+
+        let err = anyhow::anyhow!("Unable to decode salt");
+        assert_eq!(
+            Response::error(&err).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let err = anyhow::Error::new(RequestError::Busy);
+        assert_eq!(
+            Response::error(&err).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,572 @@
+use super::{
+    ControllerArrival, ControllerBattery, ControllerMotion, ControllerTouch, GamepadState,
+    InputEvent, Modifiers, MotionType, MouseButton, PenEvent, PenTool, TouchEvent, TouchEventType,
+};
+
+/// Big endian size of the remaining packet + little endian magic.
+const HEADER_LEN: usize = 8;
+
+const MAGIC_KEY_DOWN: u32 = 0x0000_0003;
+const MAGIC_KEY_UP: u32 = 0x0000_0004;
+const MAGIC_MOUSE_MOVE_ABS: u32 = 0x0000_0005;
+const MAGIC_MOUSE_MOVE_REL: u32 = 0x0000_0007;
+const MAGIC_MOUSE_BUTTON_DOWN: u32 = 0x0000_0008;
+const MAGIC_MOUSE_BUTTON_UP: u32 = 0x0000_0009;
+const MAGIC_SCROLL: u32 = 0x0000_000a;
+const MAGIC_MULTI_CONTROLLER: u32 = 0x0000_000c;
+const MAGIC_UTF8_TEXT: u32 = 0x0000_0017;
+const MAGIC_HSCROLL: u32 = 0x5500_0001;
+const MAGIC_TOUCH: u32 = 0x5500_0002;
+const MAGIC_PEN: u32 = 0x5500_0003;
+const MAGIC_CONTROLLER_ARRIVAL: u32 = 0x5500_0004;
+const MAGIC_CONTROLLER_TOUCH: u32 = 0x5500_0005;
+const MAGIC_CONTROLLER_MOTION: u32 = 0x5500_0006;
+const MAGIC_CONTROLLER_BATTERY: u32 = 0x5500_0007;
+
+const KEY_FLAG_NON_NORMALIZED: u8 = 0x01;
+const UNKNOWN_ROTATION: u16 = 0xffff;
+const UNKNOWN_TILT: u8 = 0xff;
+const UNKNOWN_BATTERY_PERCENTAGE: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputError {
+    /// The packet ended before all fields of its type were read.
+    Truncated,
+    /// The size in the header doesn't match the packet.
+    InvalidSize(u32),
+    UnknownMagic(u32),
+    /// A field holds a value the protocol doesn't define.
+    InvalidValue(&'static str, u32),
+    InvalidUtf8,
+}
+
+impl std::fmt::Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputError::Truncated => write!(f, "Truncated input packet"),
+            InputError::InvalidSize(size) => write!(f, "Invalid input packet size: {}", size),
+            InputError::UnknownMagic(magic) => write!(f, "Unknown input packet: {:#010x}", magic),
+            InputError::InvalidValue(field, value) => {
+                write!(f, "Invalid value for {}: {:#x}", field, value)
+            }
+            InputError::InvalidUtf8 => write!(f, "Input text is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Bounds checked reads, so malformed packets never panic.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], InputError> {
+        if self.data.len() < N {
+            return Err(InputError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(N);
+        self.data = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), InputError> {
+        self.data = self.data.get(len..).ok_or(InputError::Truncated)?;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, InputError> {
+        self.take::<1>().map(|b| b[0])
+    }
+
+    fn i16_be(&mut self) -> Result<i16, InputError> {
+        self.take().map(i16::from_be_bytes)
+    }
+
+    fn i16_le(&mut self) -> Result<i16, InputError> {
+        self.take().map(i16::from_le_bytes)
+    }
+
+    fn u16_le(&mut self) -> Result<u16, InputError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, InputError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn f32_le(&mut self) -> Result<f32, InputError> {
+        self.take().map(f32::from_le_bytes)
+    }
+}
+
+fn touch_event_type(value: u8) -> Result<TouchEventType, InputError> {
+    Ok(match value {
+        0x00 => TouchEventType::Hover,
+        0x01 => TouchEventType::Down,
+        0x02 => TouchEventType::Up,
+        0x03 => TouchEventType::Move,
+        0x04 => TouchEventType::Cancel,
+        0x05 => TouchEventType::ButtonOnly,
+        0x06 => TouchEventType::HoverLeave,
+        0x07 => TouchEventType::CancelAll,
+        x => return Err(InputError::InvalidValue("touch event type", x as u32)),
+    })
+}
+
+fn rotation(value: u16) -> Option<u16> {
+    (value != UNKNOWN_ROTATION).then_some(value)
+}
+
+impl InputEvent {
+    /// Decodes a single input packet as carried by the control stream.
+    pub fn decode(packet: &[u8]) -> Result<InputEvent, InputError> {
+        if packet.len() < HEADER_LEN {
+            return Err(InputError::Truncated);
+        }
+        let size = u32::from_be_bytes(packet[0..4].try_into().unwrap());
+        let body = packet
+            .get(4..)
+            .filter(|body| body.len() as u64 >= size as u64 && size >= 4)
+            .map(|body| &body[..size as usize])
+            .ok_or(InputError::InvalidSize(size))?;
+
+        let mut reader = Reader { data: body };
+        let magic = reader.u32_le()?;
+        let r = &mut reader;
+        Ok(match magic {
+            MAGIC_KEY_DOWN | MAGIC_KEY_UP => {
+                let flags = r.u8()?;
+                let key_code = r.u16_le()?;
+                let modifiers = r.u8()?;
+                InputEvent::Key {
+                    pressed: magic == MAGIC_KEY_DOWN,
+                    // the upper byte is always 0x80
+                    key_code: (key_code & 0xff) as u8,
+                    modifiers: Modifiers::from_bits(modifiers),
+                    non_normalized: flags & KEY_FLAG_NON_NORMALIZED != 0,
+                }
+            }
+            MAGIC_MOUSE_MOVE_REL => InputEvent::MouseMoveRelative {
+                dx: r.i16_be()?,
+                dy: r.i16_be()?,
+            },
+            MAGIC_MOUSE_MOVE_ABS => {
+                let x = r.i16_be()?;
+                let y = r.i16_be()?;
+                r.skip(2)?;
+                InputEvent::MouseMoveAbsolute {
+                    x,
+                    y,
+                    width: r.i16_be()?,
+                    height: r.i16_be()?,
+                }
+            }
+            MAGIC_MOUSE_BUTTON_DOWN | MAGIC_MOUSE_BUTTON_UP => {
+                let button = match r.u8()? {
+                    1 => MouseButton::Left,
+                    2 => MouseButton::Middle,
+                    3 => MouseButton::Right,
+                    4 => MouseButton::X1,
+                    5 => MouseButton::X2,
+                    x => return Err(InputError::InvalidValue("mouse button", x as u32)),
+                };
+                InputEvent::MouseButton {
+                    button,
+                    pressed: magic == MAGIC_MOUSE_BUTTON_DOWN,
+                }
+            }
+            MAGIC_SCROLL => InputEvent::Scroll {
+                amount: r.i16_be()?,
+            },
+            MAGIC_HSCROLL => InputEvent::HorizontalScroll {
+                amount: r.i16_be()?,
+            },
+            MAGIC_UTF8_TEXT => {
+                let text = std::str::from_utf8(r.data).map_err(|_| InputError::InvalidUtf8)?;
+                InputEvent::Text(text.trim_end_matches('\0').to_string())
+            }
+            MAGIC_MULTI_CONTROLLER => {
+                r.skip(2)?;
+                let controller = r.u16_le()?;
+                let active_mask = r.u16_le()?;
+                r.skip(2)?;
+                let buttons = r.u16_le()?;
+                let left_trigger = r.u8()?;
+                let right_trigger = r.u8()?;
+                let left_stick = (r.i16_le()?, r.i16_le()?);
+                let right_stick = (r.i16_le()?, r.i16_le()?);
+                r.skip(2)?;
+                let buttons2 = r.u16_le()?;
+                InputEvent::Gamepad(GamepadState {
+                    controller,
+                    active_mask,
+                    buttons: buttons as u32 | (buttons2 as u32) << 16,
+                    left_trigger,
+                    right_trigger,
+                    left_stick,
+                    right_stick,
+                })
+            }
+            MAGIC_CONTROLLER_ARRIVAL => InputEvent::ControllerArrival(ControllerArrival {
+                controller: r.u8()?,
+                ty: r.u8()?,
+                capabilities: r.u16_le()?,
+                supported_buttons: r.u32_le()?,
+            }),
+            MAGIC_CONTROLLER_TOUCH => {
+                let controller = r.u8()?;
+                let ty = touch_event_type(r.u8()?)?;
+                r.skip(2)?;
+                InputEvent::ControllerTouch(ControllerTouch {
+                    controller,
+                    ty,
+                    pointer_id: r.u32_le()?,
+                    x: r.f32_le()?,
+                    y: r.f32_le()?,
+                    pressure: r.f32_le()?,
+                })
+            }
+            MAGIC_CONTROLLER_MOTION => {
+                let controller = r.u8()?;
+                let ty = match r.u8()? {
+                    0x01 => MotionType::Accelerometer,
+                    0x02 => MotionType::Gyroscope,
+                    x => return Err(InputError::InvalidValue("motion type", x as u32)),
+                };
+                r.skip(2)?;
+                InputEvent::ControllerMotion(ControllerMotion {
+                    controller,
+                    ty,
+                    x: r.f32_le()?,
+                    y: r.f32_le()?,
+                    z: r.f32_le()?,
+                })
+            }
+            MAGIC_CONTROLLER_BATTERY => {
+                let controller = r.u8()?;
+                let state = r.u8()?;
+                let percentage = r.u8()?;
+                InputEvent::ControllerBattery(ControllerBattery {
+                    controller,
+                    state,
+                    percentage: (percentage != UNKNOWN_BATTERY_PERCENTAGE).then_some(percentage),
+                })
+            }
+            MAGIC_TOUCH => {
+                let ty = touch_event_type(r.u8()?)?;
+                r.skip(1)?;
+                let rotation = rotation(r.u16_le()?);
+                InputEvent::Touch(TouchEvent {
+                    ty,
+                    pointer_id: r.u32_le()?,
+                    x: r.f32_le()?,
+                    y: r.f32_le()?,
+                    pressure_or_distance: r.f32_le()?,
+                    contact_area: (r.f32_le()?, r.f32_le()?),
+                    rotation,
+                })
+            }
+            MAGIC_PEN => {
+                let ty = touch_event_type(r.u8()?)?;
+                let tool = match r.u8()? {
+                    0x00 => PenTool::Unknown,
+                    0x01 => PenTool::Pen,
+                    0x02 => PenTool::Eraser,
+                    x => return Err(InputError::InvalidValue("pen tool", x as u32)),
+                };
+                let buttons = r.u8()?;
+                r.skip(1)?;
+                let x = r.f32_le()?;
+                let y = r.f32_le()?;
+                let pressure_or_distance = r.f32_le()?;
+                let rotation = rotation(r.u16_le()?);
+                let tilt = r.u8()?;
+                r.skip(1)?;
+                InputEvent::Pen(PenEvent {
+                    ty,
+                    tool,
+                    buttons,
+                    x,
+                    y,
+                    pressure_or_distance,
+                    contact_area: (r.f32_le()?, r.f32_le()?),
+                    rotation,
+                    tilt: (tilt != UNKNOWN_TILT).then_some(tilt),
+                })
+            }
+            magic => return Err(InputError::UnknownMagic(magic)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::buttons;
+
+    fn packet(magic: u32, body: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&(4 + body.len() as u32).to_be_bytes());
+        packet.extend_from_slice(&magic.to_le_bytes());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let mut touch = vec![0x01, 0x00];
+        touch.extend_from_slice(&90u16.to_le_bytes());
+        touch.extend_from_slice(&7u32.to_le_bytes());
+        for value in [0.25f32, 0.75, 0.5, 0.01, 0.02] {
+            touch.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut pen = vec![0x03, 0x02, 0x01, 0x00];
+        for value in [0.5f32, 0.5, 1.0] {
+            pen.extend_from_slice(&value.to_le_bytes());
+        }
+        pen.extend_from_slice(&UNKNOWN_ROTATION.to_le_bytes());
+        pen.extend_from_slice(&[45, 0]);
+        for value in [0.0f32, 0.0] {
+            pen.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut controller_touch = vec![0x01, 0x01, 0x00, 0x00];
+        controller_touch.extend_from_slice(&3u32.to_le_bytes());
+        for value in [0.25f32, 0.5, 1.0] {
+            controller_touch.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut motion = vec![0x00, 0x02, 0x00, 0x00];
+        for value in [1.5f32, -9.75, 0.0] {
+            motion.extend_from_slice(&value.to_le_bytes());
+        }
+
+        vec![
+            packet(MAGIC_KEY_DOWN, &[0x00, 0x41, 0x80, 0x03, 0x00, 0x00]),
+            packet(MAGIC_MOUSE_MOVE_REL, &[0xff, 0xfe, 0x00, 0x10]),
+            packet(
+                MAGIC_MOUSE_MOVE_ABS,
+                &[0x01, 0x00, 0x00, 0x80, 0, 0, 0x07, 0x80, 0x04, 0x38],
+            ),
+            packet(MAGIC_MOUSE_BUTTON_UP, &[0x03]),
+            packet(MAGIC_SCROLL, &[0x00, 0x78, 0x00, 0x78, 0x00, 0x00]),
+            packet(MAGIC_HSCROLL, &[0xff, 0x88]),
+            packet(MAGIC_UTF8_TEXT, "ü".as_bytes()),
+            packet(
+                MAGIC_MULTI_CONTROLLER,
+                &[
+                    0x1a, 0x00, 0x01, 0x00, 0x03, 0x00, 0x14, 0x00, 0x01, 0x10, 0xff, 0x00, 0x00,
+                    0x80, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x9c, 0x00, 0x01, 0x00, 0x55, 0x00,
+                ],
+            ),
+            packet(
+                MAGIC_CONTROLLER_ARRIVAL,
+                &[0x01, 0x01, 0x03, 0x00, 0xff, 0xff, 0x3f, 0x00],
+            ),
+            packet(MAGIC_TOUCH, &touch),
+            packet(MAGIC_PEN, &pen),
+            packet(MAGIC_CONTROLLER_TOUCH, &controller_touch),
+            packet(MAGIC_CONTROLLER_MOTION, &motion),
+            packet(MAGIC_CONTROLLER_BATTERY, &[0x01, 0x02, 0x4b, 0x00]),
+            packet(MAGIC_CONTROLLER_BATTERY, &[0x00, 0x00, 0xff, 0x00]),
+        ]
+    }
+
+    #[test]
+    fn test_decode() {
+        let events = samples()
+            .iter()
+            .map(|packet| InputEvent::decode(packet).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events[0],
+            InputEvent::Key {
+                pressed: true,
+                key_code: 0x41,
+                modifiers: Modifiers {
+                    shift: true,
+                    ctrl: true,
+                    ..Default::default()
+                },
+                non_normalized: false,
+            }
+        );
+        assert_eq!(events[1], InputEvent::MouseMoveRelative { dx: -2, dy: 16 });
+        assert_eq!(
+            events[2],
+            InputEvent::MouseMoveAbsolute {
+                x: 256,
+                y: 128,
+                width: 1920,
+                height: 1080,
+            }
+        );
+        assert_eq!(
+            events[3],
+            InputEvent::MouseButton {
+                button: MouseButton::Right,
+                pressed: false,
+            }
+        );
+        assert_eq!(events[4], InputEvent::Scroll { amount: 120 });
+        assert_eq!(events[5], InputEvent::HorizontalScroll { amount: -120 });
+        assert_eq!(events[6], InputEvent::Text("ü".to_string()));
+        assert_eq!(
+            events[7],
+            InputEvent::Gamepad(GamepadState {
+                controller: 1,
+                active_mask: 3,
+                buttons: buttons::DPAD_UP | buttons::A | buttons::PADDLE1,
+                left_trigger: 255,
+                right_trigger: 0,
+                left_stick: (-32768, 32767),
+                right_stick: (0, 0),
+            })
+        );
+        assert_eq!(
+            events[8],
+            InputEvent::ControllerArrival(ControllerArrival {
+                controller: 1,
+                ty: 1,
+                capabilities: 3,
+                supported_buttons: 0x3f_ffff,
+            })
+        );
+        assert_eq!(
+            events[9],
+            InputEvent::Touch(TouchEvent {
+                ty: TouchEventType::Down,
+                pointer_id: 7,
+                x: 0.25,
+                y: 0.75,
+                pressure_or_distance: 0.5,
+                contact_area: (0.01, 0.02),
+                rotation: Some(90),
+            })
+        );
+        assert_eq!(
+            events[10],
+            InputEvent::Pen(PenEvent {
+                ty: TouchEventType::Move,
+                tool: PenTool::Eraser,
+                buttons: 1,
+                x: 0.5,
+                y: 0.5,
+                pressure_or_distance: 1.0,
+                contact_area: (0.0, 0.0),
+                rotation: None,
+                tilt: Some(45),
+            })
+        );
+        assert_eq!(
+            events[11],
+            InputEvent::ControllerTouch(ControllerTouch {
+                controller: 1,
+                ty: TouchEventType::Down,
+                pointer_id: 3,
+                x: 0.25,
+                y: 0.5,
+                pressure: 1.0,
+            })
+        );
+        assert_eq!(
+            events[12],
+            InputEvent::ControllerMotion(ControllerMotion {
+                controller: 0,
+                ty: MotionType::Gyroscope,
+                x: 1.5,
+                y: -9.75,
+                z: 0.0,
+            })
+        );
+        assert_eq!(
+            events[13],
+            InputEvent::ControllerBattery(ControllerBattery {
+                controller: 1,
+                state: 2,
+                percentage: Some(75),
+            })
+        );
+        assert_eq!(
+            events[14],
+            InputEvent::ControllerBattery(ControllerBattery {
+                controller: 0,
+                state: 0,
+                percentage: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        for packet in samples() {
+            for len in 0..packet.len() {
+                let mut truncated = packet[..len].to_vec();
+                assert!(InputEvent::decode(&truncated).is_err());
+
+                // trailing padding may be missing, but nothing must panic
+                if len >= HEADER_LEN {
+                    truncated[0..4].copy_from_slice(&(len as u32 - 4).to_be_bytes());
+                    let _ = InputEvent::decode(&truncated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            InputEvent::decode(&packet(0x1234, &[])),
+            Err(InputError::UnknownMagic(0x1234))
+        );
+        assert_eq!(
+            InputEvent::decode(&packet(MAGIC_MOUSE_BUTTON_DOWN, &[6])),
+            Err(InputError::InvalidValue("mouse button", 6))
+        );
+        assert_eq!(
+            InputEvent::decode(&packet(MAGIC_CONTROLLER_MOTION, &[0, 3, 0, 0])),
+            Err(InputError::InvalidValue("motion type", 3))
+        );
+        assert_eq!(
+            InputEvent::decode(&packet(MAGIC_UTF8_TEXT, &[0xff])),
+            Err(InputError::InvalidUtf8)
+        );
+        assert_eq!(
+            InputEvent::decode(&[0, 0, 0, 2, 3, 0, 0, 0]),
+            Err(InputError::InvalidSize(2))
+        );
+    }
+
+    #[test]
+    fn test_garbage_never_panics() {
+        // cheap xorshift, good enough to shake out missing bounds checks
+        let mut seed = 0x2545_f491_u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let magics = [
+            MAGIC_KEY_DOWN,
+            MAGIC_MOUSE_MOVE_ABS,
+            MAGIC_MULTI_CONTROLLER,
+            MAGIC_TOUCH,
+            MAGIC_PEN,
+            next(),
+        ];
+
+        for _ in 0..10_000 {
+            let len = next() as usize % 48;
+            let body = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+            let mut packet = packet(magics[next() as usize % magics.len()], &body);
+            if next() % 4 == 0 {
+                packet[0..4].copy_from_slice(&next().to_be_bytes());
+            }
+            let _ = InputEvent::decode(&packet);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Virtual gamepads for the controllers of a client, created through uinput.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result};
+use evdev::{
+    uinput::{VirtualDeviceBuilder, VirtualEventStream},
+    AbsInfo, AbsoluteAxisType, AttributeSet, BusType, EventType, FFEffectData, FFEffectKind,
+    FFEffectType, InputEvent, InputEventKind, InputId, Key, UInputEventType, UinputAbsSetup,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    time::Instant,
+};
+
+use super::{buttons, ControllerArrival, GamepadState};
+use crate::control::{ControlHandle, ControlMessage};
+
+/// Clients number their controllers `0..16`.
+pub const MAX_CONTROLLERS: usize = 16;
+
+/// Force feedback effects a game can upload to a pad at once.
+const FF_EFFECTS_MAX: u32 = 16;
+
+/// Identify as a wired Xbox 360 pad, which games have mappings for.
+const XBOX_360_ID: (u16, u16, u16) = (0x045e, 0x028e, 0x0110);
+
+const BUTTONS: &[(u32, Key)] = &[
+    (buttons::A, Key::BTN_SOUTH),
+    (buttons::B, Key::BTN_EAST),
+    (buttons::X, Key::BTN_NORTH),
+    (buttons::Y, Key::BTN_WEST),
+    (buttons::LEFT_BUMPER, Key::BTN_TL),
+    (buttons::RIGHT_BUMPER, Key::BTN_TR),
+    (buttons::BACK, Key::BTN_SELECT),
+    (buttons::START, Key::BTN_START),
+    (buttons::HOME, Key::BTN_MODE),
+    (buttons::LEFT_STICK, Key::BTN_THUMBL),
+    (buttons::RIGHT_STICK, Key::BTN_THUMBR),
+];
+
+fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+}
+
+/// Clients send positive values for up, evdev expects them for down.
+fn invert(value: i16) -> i32 {
+    (-(value as i32)).min(i16::MAX as i32)
+}
+
+fn hat(state: &GamepadState, negative: u32, positive: u32) -> i32 {
+    (state.buttons & positive != 0) as i32 - (state.buttons & negative != 0) as i32
+}
+
+/// Reads the value of an absolute axis from a client's state.
+type AxisValue = fn(&GamepadState) -> i32;
+
+/// Events moving a device from `prev` to `next`, without the final `SYN_REPORT`.
+fn state_events(prev: &GamepadState, next: &GamepadState) -> Vec<InputEvent> {
+    let mut events = Vec::new();
+
+    for &(bit, key) in BUTTONS {
+        if (prev.buttons ^ next.buttons) & bit != 0 {
+            events.push(InputEvent::new(
+                EventType::KEY,
+                key.code(),
+                (next.buttons & bit != 0) as i32,
+            ));
+        }
+    }
+
+    let axes: [(AbsoluteAxisType, AxisValue); 8] = [
+        (AbsoluteAxisType::ABS_X, |s| s.left_stick.0 as i32),
+        (AbsoluteAxisType::ABS_Y, |s| invert(s.left_stick.1)),
+        (AbsoluteAxisType::ABS_RX, |s| s.right_stick.0 as i32),
+        (AbsoluteAxisType::ABS_RY, |s| invert(s.right_stick.1)),
+        (AbsoluteAxisType::ABS_Z, |s| s.left_trigger as i32),
+        (AbsoluteAxisType::ABS_RZ, |s| s.right_trigger as i32),
+        (AbsoluteAxisType::ABS_HAT0X, |s| {
+            hat(s, buttons::DPAD_LEFT, buttons::DPAD_RIGHT)
+        }),
+        (AbsoluteAxisType::ABS_HAT0Y, |s| {
+            hat(s, buttons::DPAD_UP, buttons::DPAD_DOWN)
+        }),
+    ];
+    for (axis, value) in axes {
+        if value(prev) != value(next) {
+            events.push(abs(axis, value(next)));
+        }
+    }
+
+    events
+}
+
+fn create_device(controller: usize) -> Result<VirtualEventStream> {
+    let mut keys = AttributeSet::<Key>::new();
+    for &(_, key) in BUTTONS {
+        keys.insert(key);
+    }
+    let mut ff = AttributeSet::<FFEffectType>::new();
+    ff.insert(FFEffectType::FF_RUMBLE);
+
+    let stick = AbsInfo::new(0, i16::MIN as i32, i16::MAX as i32, 16, 128, 0);
+    let trigger = AbsInfo::new(0, 0, u8::MAX as i32, 0, 0, 0);
+    let dpad = AbsInfo::new(0, -1, 1, 0, 0, 0);
+    let (vendor, product, version) = XBOX_360_ID;
+    let name = format!("Sunrise Gamepad {}", controller);
+
+    let mut builder = VirtualDeviceBuilder::new()?
+        .name(&name)
+        .input_id(InputId::new(BusType::BUS_USB, vendor, product, version))
+        .with_keys(&keys)?
+        .with_ff(&ff)?
+        .with_ff_effects_max(FF_EFFECTS_MAX);
+    for (axis, info) in [
+        (AbsoluteAxisType::ABS_X, stick),
+        (AbsoluteAxisType::ABS_Y, stick),
+        (AbsoluteAxisType::ABS_RX, stick),
+        (AbsoluteAxisType::ABS_RY, stick),
+        (AbsoluteAxisType::ABS_Z, trigger),
+        (AbsoluteAxisType::ABS_RZ, trigger),
+        (AbsoluteAxisType::ABS_HAT0X, dpad),
+        (AbsoluteAxisType::ABS_HAT0Y, dpad),
+    ] {
+        builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+    }
+
+    Ok(builder.build()?.into_event_stream()?)
+}
+
+/// A rumble effect a game uploaded, as forwarded to the client.
+#[derive(Debug, Clone, Copy)]
+struct Rumble {
+    low_frequency: u16,
+    high_frequency: u16,
+    length: Duration,
+}
+
+impl Rumble {
+    fn from_effect(effect: &FFEffectData) -> Option<Rumble> {
+        match effect.kind {
+            FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude,
+            } => Some(Rumble {
+                low_frequency: strong_magnitude,
+                high_frequency: weak_magnitude,
+                length: Duration::from_millis(effect.replay.length as u64),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Drives one virtual device until its sender is dropped, which removes the device.
+async fn run_device(
+    controller: usize,
+    mut device: VirtualEventStream,
+    mut states: tokio::sync::mpsc::UnboundedReceiver<GamepadState>,
+    control: ControlHandle,
+) {
+    let mut state = GamepadState::default();
+    let mut effects = HashMap::new();
+    let mut rumble_until: Option<Instant> = None;
+    let send_rumble = |low_frequency, high_frequency| {
+        let _ = control.send(ControlMessage::Rumble {
+            controller: controller as u16,
+            low_frequency,
+            high_frequency,
+        });
+    };
+
+    loop {
+        tokio::select! {
+            next = states.recv() => {
+                let next = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let events = state_events(&state, &next);
+                state = next;
+                if !events.is_empty() {
+                    if let Err(err) = device.device_mut().emit(&events) {
+                        log::warn!("Failed to update gamepad {}: {}", controller, err);
+                    }
+                }
+            }
+            event = device.next_event() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        log::error!("Failed to read from gamepad {}: {}", controller, err);
+                        break;
+                    }
+                };
+                match event.kind() {
+                    InputEventKind::UInput(ty) if ty == UInputEventType::UI_FF_UPLOAD.0 => {
+                        match device.device_mut().process_ff_upload(event) {
+                            Ok(upload) => {
+                                effects.insert(upload.effect_id(), upload.effect());
+                            }
+                            Err(err) => log::warn!("Failed to upload effect: {}", err),
+                        }
+                    }
+                    InputEventKind::UInput(ty) if ty == UInputEventType::UI_FF_ERASE.0 => {
+                        match device.device_mut().process_ff_erase(event) {
+                            Ok(erase) => {
+                                effects.remove(&(erase.effect_id() as i16));
+                            }
+                            Err(err) => log::warn!("Failed to erase effect: {}", err),
+                        }
+                    }
+                    InputEventKind::ForceFeedback(id) => {
+                        let rumble = effects.get(&(id as i16)).and_then(Rumble::from_effect);
+                        match rumble {
+                            Some(rumble) if event.value() > 0 => {
+                                send_rumble(rumble.low_frequency, rumble.high_frequency);
+                                // a length of zero plays until stopped
+                                rumble_until = (!rumble.length.is_zero())
+                                    .then(|| Instant::now() + rumble.length);
+                            }
+                            _ => {
+                                send_rumble(0, 0);
+                                rumble_until = None;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = tokio::time::sleep_until(rumble_until.unwrap_or_else(Instant::now)),
+                if rumble_until.is_some() =>
+            {
+                send_rumble(0, 0);
+                rumble_until = None;
+            }
+        }
+    }
+
+    if rumble_until.is_some() {
+        send_rumble(0, 0);
+    }
+    log::info!("Removed gamepad {}", controller);
+}
+
+/// The virtual devices of a session, removed once this is dropped.
+pub struct Gamepads {
+    devices: [Option<UnboundedSender<GamepadState>>; MAX_CONTROLLERS],
+    control: ControlHandle,
+}
+
+impl Gamepads {
+    /// Creates devices for the controllers in `mask` right away, as announced at launch.
+    pub fn new(mask: u16, control: ControlHandle) -> Gamepads {
+        let mut gamepads = Gamepads {
+            devices: Default::default(),
+            control,
+        };
+        gamepads.update_mask(mask);
+        gamepads
+    }
+
+    fn add(&mut self, controller: usize) -> Result<()> {
+        if self.devices[controller].is_some() {
+            return Ok(());
+        }
+        let device = create_device(controller)
+            .with_context(|| format!("Failed to create gamepad {}", controller))?;
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(run_device(
+            controller,
+            device,
+            receiver,
+            self.control.clone(),
+        ));
+        self.devices[controller] = Some(sender);
+        log::info!("Added gamepad {}", controller);
+        Ok(())
+    }
+
+    /// Adds and removes devices to match the controllers connected to the client.
+    fn update_mask(&mut self, mask: u16) {
+        for controller in 0..MAX_CONTROLLERS {
+            if mask & (1 << controller) == 0 {
+                self.devices[controller] = None;
+            } else if let Err(err) = self.add(controller) {
+                log::error!("{:#}", err);
+            }
+        }
+    }
+
+    pub fn arrival(&mut self, arrival: &ControllerArrival) {
+        let controller = arrival.controller as usize;
+        if controller >= MAX_CONTROLLERS {
+            log::warn!("Invalid controller number {}", controller);
+        } else if let Err(err) = self.add(controller) {
+            log::error!("{:#}", err);
+        }
+    }
+
+    pub fn update(&mut self, state: GamepadState) {
+        let controller = state.controller as usize;
+        if controller >= MAX_CONTROLLERS {
+            log::warn!("Invalid controller number {}", controller);
+            return;
+        }
+        self.update_mask(state.active_mask | (1 << controller));
+        if let Some(device) = self.devices[controller].as_ref() {
+            let _ = device.send(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(events: &[InputEvent]) -> Vec<(EventType, u16, i32)> {
+        events
+            .iter()
+            .map(|event| (event.event_type(), event.code(), event.value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_unchanged_state() {
+        let state = GamepadState {
+            buttons: buttons::A | buttons::DPAD_UP,
+            left_stick: (100, -100),
+            ..Default::default()
+        };
+        assert!(state_events(&state, &state).is_empty());
+    }
+
+    #[test]
+    fn test_buttons() {
+        let prev = GamepadState {
+            buttons: buttons::A,
+            ..Default::default()
+        };
+        let next = GamepadState {
+            buttons: buttons::Y | buttons::HOME,
+            ..Default::default()
+        };
+        assert_eq!(
+            values(&state_events(&prev, &next)),
+            [
+                (EventType::KEY, Key::BTN_SOUTH.code(), 0),
+                (EventType::KEY, Key::BTN_WEST.code(), 1),
+                (EventType::KEY, Key::BTN_MODE.code(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_axes() {
+        let next = GamepadState {
+            buttons: buttons::DPAD_LEFT | buttons::DPAD_UP,
+            left_trigger: 255,
+            left_stick: (i16::MIN, i16::MIN),
+            right_stick: (0, i16::MAX),
+            ..Default::default()
+        };
+        assert_eq!(
+            values(&state_events(&GamepadState::default(), &next)),
+            [
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, -32768),
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, 32767),
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, -32767),
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, 255),
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, -1),
+                (EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0Y.0, -1),
+            ]
+        );
+    }
+}
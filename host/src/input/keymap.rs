@@ -0,0 +1,277 @@
+//! Translation of the Windows virtual key codes sent by clients.
+//!
+//! Keys are mapped by position to evdev codes, which is what games expect. Each entry also
+//! carries the keysyms the key produces on a US layout, to type the key through whatever
+//! keycode produces them if the active layout lacks its position.
+
+/// A key clients may send, as found on a US keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMapping {
+    pub vk: u8,
+    pub evdev: u32,
+    pub keysym: u32,
+    /// Keysym with shift held, same as `keysym` for keys without a second level.
+    pub shifted_keysym: u32,
+}
+
+const fn key(vk: u8, evdev: u32, keysym: u32) -> KeyMapping {
+    KeyMapping {
+        vk,
+        evdev,
+        keysym,
+        shifted_keysym: keysym,
+    }
+}
+
+const fn char_key(vk: u8, evdev: u32, keysym: char, shifted_keysym: char) -> KeyMapping {
+    KeyMapping {
+        vk,
+        evdev,
+        keysym: keysym as u32,
+        shifted_keysym: shifted_keysym as u32,
+    }
+}
+
+pub const KEY_LEFTSHIFT: u32 = 42;
+
+#[rustfmt::skip]
+const KEYS: &[KeyMapping] = &[
+    key(0x08, 14, 0xff08), // VK_BACK
+    key(0x09, 15, 0xff09), // VK_TAB
+    key(0x0c, 355, 0xff0b), // VK_CLEAR
+    key(0x0d, 28, 0xff0d), // VK_RETURN
+    key(0x10, KEY_LEFTSHIFT, 0xffe1), // VK_SHIFT
+    key(0x11, 29, 0xffe3), // VK_CONTROL
+    key(0x12, 56, 0xffe9), // VK_MENU
+    key(0x13, 119, 0xff13), // VK_PAUSE
+    key(0x14, 58, 0xffe5), // VK_CAPITAL
+    key(0x15, 93, 0xff27), // VK_KANA
+    key(0x19, 123, 0xff34), // VK_HANJA
+    key(0x1b, 1, 0xff1b), // VK_ESCAPE
+    key(0x1c, 92, 0xff23), // VK_CONVERT
+    key(0x1d, 94, 0xff22), // VK_NONCONVERT
+    char_key(0x20, 57, ' ', ' '), // VK_SPACE
+    key(0x21, 104, 0xff55), // VK_PRIOR
+    key(0x22, 109, 0xff56), // VK_NEXT
+    key(0x23, 107, 0xff57), // VK_END
+    key(0x24, 102, 0xff50), // VK_HOME
+    key(0x25, 105, 0xff51), // VK_LEFT
+    key(0x26, 103, 0xff52), // VK_UP
+    key(0x27, 106, 0xff53), // VK_RIGHT
+    key(0x28, 108, 0xff54), // VK_DOWN
+    key(0x29, 353, 0xff60), // VK_SELECT
+    key(0x2a, 210, 0xff61), // VK_PRINT
+    key(0x2c, 99, 0xff61), // VK_SNAPSHOT
+    key(0x2d, 110, 0xff63), // VK_INSERT
+    key(0x2e, 111, 0xffff), // VK_DELETE
+    key(0x2f, 138, 0xff6a), // VK_HELP
+    char_key(0x30, 11, '0', ')'),
+    char_key(0x31, 2, '1', '!'),
+    char_key(0x32, 3, '2', '@'),
+    char_key(0x33, 4, '3', '#'),
+    char_key(0x34, 5, '4', '$'),
+    char_key(0x35, 6, '5', '%'),
+    char_key(0x36, 7, '6', '^'),
+    char_key(0x37, 8, '7', '&'),
+    char_key(0x38, 9, '8', '*'),
+    char_key(0x39, 10, '9', '('),
+    char_key(0x41, 30, 'a', 'A'),
+    char_key(0x42, 48, 'b', 'B'),
+    char_key(0x43, 46, 'c', 'C'),
+    char_key(0x44, 32, 'd', 'D'),
+    char_key(0x45, 18, 'e', 'E'),
+    char_key(0x46, 33, 'f', 'F'),
+    char_key(0x47, 34, 'g', 'G'),
+    char_key(0x48, 35, 'h', 'H'),
+    char_key(0x49, 23, 'i', 'I'),
+    char_key(0x4a, 36, 'j', 'J'),
+    char_key(0x4b, 37, 'k', 'K'),
+    char_key(0x4c, 38, 'l', 'L'),
+    char_key(0x4d, 50, 'm', 'M'),
+    char_key(0x4e, 49, 'n', 'N'),
+    char_key(0x4f, 24, 'o', 'O'),
+    char_key(0x50, 25, 'p', 'P'),
+    char_key(0x51, 16, 'q', 'Q'),
+    char_key(0x52, 19, 'r', 'R'),
+    char_key(0x53, 31, 's', 'S'),
+    char_key(0x54, 20, 't', 'T'),
+    char_key(0x55, 22, 'u', 'U'),
+    char_key(0x56, 47, 'v', 'V'),
+    char_key(0x57, 17, 'w', 'W'),
+    char_key(0x58, 45, 'x', 'X'),
+    char_key(0x59, 21, 'y', 'Y'),
+    char_key(0x5a, 44, 'z', 'Z'),
+    key(0x5b, 125, 0xffeb), // VK_LWIN
+    key(0x5c, 126, 0xffec), // VK_RWIN
+    key(0x5d, 127, 0xff67), // VK_APPS
+    key(0x5f, 142, 0x1008_ff2f), // VK_SLEEP
+    key(0x60, 82, 0xffb0), // VK_NUMPAD0
+    key(0x61, 79, 0xffb1),
+    key(0x62, 80, 0xffb2),
+    key(0x63, 81, 0xffb3),
+    key(0x64, 75, 0xffb4),
+    key(0x65, 76, 0xffb5),
+    key(0x66, 77, 0xffb6),
+    key(0x67, 71, 0xffb7),
+    key(0x68, 72, 0xffb8),
+    key(0x69, 73, 0xffb9), // VK_NUMPAD9
+    key(0x6a, 55, 0xffaa), // VK_MULTIPLY
+    key(0x6b, 78, 0xffab), // VK_ADD
+    key(0x6c, 121, 0xffac), // VK_SEPARATOR
+    key(0x6d, 74, 0xffad), // VK_SUBTRACT
+    key(0x6e, 83, 0xffae), // VK_DECIMAL
+    key(0x6f, 98, 0xffaf), // VK_DIVIDE
+    key(0x70, 59, 0xffbe), // VK_F1
+    key(0x71, 60, 0xffbf),
+    key(0x72, 61, 0xffc0),
+    key(0x73, 62, 0xffc1),
+    key(0x74, 63, 0xffc2),
+    key(0x75, 64, 0xffc3),
+    key(0x76, 65, 0xffc4),
+    key(0x77, 66, 0xffc5),
+    key(0x78, 67, 0xffc6),
+    key(0x79, 68, 0xffc7), // VK_F10
+    key(0x7a, 87, 0xffc8), // VK_F11
+    key(0x7b, 88, 0xffc9), // VK_F12
+    key(0x7c, 183, 0xffca), // VK_F13
+    key(0x7d, 184, 0xffcb),
+    key(0x7e, 185, 0xffcc),
+    key(0x7f, 186, 0xffcd),
+    key(0x80, 187, 0xffce),
+    key(0x81, 188, 0xffcf),
+    key(0x82, 189, 0xffd0),
+    key(0x83, 190, 0xffd1),
+    key(0x84, 191, 0xffd2),
+    key(0x85, 192, 0xffd3),
+    key(0x86, 193, 0xffd4),
+    key(0x87, 194, 0xffd5), // VK_F24
+    key(0x90, 69, 0xff7f), // VK_NUMLOCK
+    key(0x91, 70, 0xff14), // VK_SCROLL
+    key(0xa0, KEY_LEFTSHIFT, 0xffe1), // VK_LSHIFT
+    key(0xa1, 54, 0xffe2), // VK_RSHIFT
+    key(0xa2, 29, 0xffe3), // VK_LCONTROL
+    key(0xa3, 97, 0xffe4), // VK_RCONTROL
+    key(0xa4, 56, 0xffe9), // VK_LMENU
+    key(0xa5, 100, 0xffea), // VK_RMENU
+    key(0xa6, 158, 0x1008_ff26), // VK_BROWSER_BACK
+    key(0xa7, 159, 0x1008_ff27), // VK_BROWSER_FORWARD
+    key(0xa8, 173, 0x1008_ff29), // VK_BROWSER_REFRESH
+    key(0xa9, 128, 0x1008_ff28), // VK_BROWSER_STOP
+    key(0xaa, 217, 0x1008_ff1b), // VK_BROWSER_SEARCH
+    key(0xab, 156, 0x1008_ff30), // VK_BROWSER_FAVORITES
+    key(0xac, 172, 0x1008_ff18), // VK_BROWSER_HOME
+    key(0xad, 113, 0x1008_ff12), // VK_VOLUME_MUTE
+    key(0xae, 114, 0x1008_ff11), // VK_VOLUME_DOWN
+    key(0xaf, 115, 0x1008_ff13), // VK_VOLUME_UP
+    key(0xb0, 163, 0x1008_ff17), // VK_MEDIA_NEXT_TRACK
+    key(0xb1, 165, 0x1008_ff16), // VK_MEDIA_PREV_TRACK
+    key(0xb2, 166, 0x1008_ff15), // VK_MEDIA_STOP
+    key(0xb3, 164, 0x1008_ff14), // VK_MEDIA_PLAY_PAUSE
+    key(0xb4, 155, 0x1008_ff19), // VK_LAUNCH_MAIL
+    key(0xb5, 226, 0x1008_ff32), // VK_LAUNCH_MEDIA_SELECT
+    key(0xb6, 148, 0x1008_ff5d), // VK_LAUNCH_APP1
+    key(0xb7, 140, 0x1008_ff1d), // VK_LAUNCH_APP2
+    char_key(0xba, 39, ';', ':'), // VK_OEM_1
+    char_key(0xbb, 13, '=', '+'), // VK_OEM_PLUS
+    char_key(0xbc, 51, ',', '<'), // VK_OEM_COMMA
+    char_key(0xbd, 12, '-', '_'), // VK_OEM_MINUS
+    char_key(0xbe, 52, '.', '>'), // VK_OEM_PERIOD
+    char_key(0xbf, 53, '/', '?'), // VK_OEM_2
+    char_key(0xc0, 41, '`', '~'), // VK_OEM_3
+    char_key(0xdb, 26, '[', '{'), // VK_OEM_4
+    char_key(0xdc, 43, '\\', '|'), // VK_OEM_5
+    char_key(0xdd, 27, ']', '}'), // VK_OEM_6
+    char_key(0xde, 40, '\'', '"'), // VK_OEM_7
+    char_key(0xe2, 86, '<', '>'), // VK_OEM_102
+    key(0xfa, 207, 0x1008_ff14), // VK_PLAY
+    key(0xfb, 372, 0x1008_ff8b), // VK_ZOOM
+];
+
+/// Looks up the key of a virtual key code, `None` for codes without a physical key.
+pub fn lookup(vk: u8) -> Option<&'static KeyMapping> {
+    KEYS.iter().find(|key| key.vk == vk)
+}
+
+/// Keysym typing the given character, as defined by `xkb_utf32_to_keysym`.
+pub fn char_to_keysym(c: char) -> u32 {
+    let c = c as u32;
+    match c {
+        // latin-1 maps directly
+        0x20..=0x7e | 0xa0..=0xff => c,
+        0x08 | 0x09 | 0x0a | 0x0d | 0x1b => c | 0xff00,
+        0x7f => 0xffff,
+        _ => c | 0x0100_0000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_unique_vks() {
+        let mut seen = HashSet::new();
+        for key in KEYS {
+            assert!(seen.insert(key.vk), "duplicate vk {:#04x}", key.vk);
+        }
+    }
+
+    #[test]
+    fn test_only_generic_modifiers_share_keys() {
+        let mut seen = HashSet::new();
+        let generic = [0x10, 0x11, 0x12];
+        for key in KEYS.iter().filter(|key| !generic.contains(&key.vk)) {
+            // print shares the sysrq key with snapshot on most layouts
+            if key.vk == 0x2a {
+                continue;
+            }
+            assert!(seen.insert(key.evdev), "duplicate evdev {}", key.evdev);
+        }
+    }
+
+    #[test]
+    fn test_alphanumeric() {
+        assert_eq!(lookup(b'A').unwrap().evdev, 30);
+        assert_eq!(lookup(b'Z').unwrap().evdev, 44);
+        assert_eq!(lookup(b'Q').unwrap().keysym, 'q' as u32);
+        assert_eq!(lookup(b'Q').unwrap().shifted_keysym, 'Q' as u32);
+        assert_eq!(lookup(b'0').unwrap().evdev, 11);
+        assert_eq!(lookup(b'1').unwrap().evdev, 2);
+        assert_eq!(lookup(b'2').unwrap().shifted_keysym, '@' as u32);
+    }
+
+    #[test]
+    fn test_modifiers() {
+        // left and right variants stay distinct
+        assert_eq!(lookup(0xa0).unwrap().evdev, 42);
+        assert_eq!(lookup(0xa1).unwrap().evdev, 54);
+        assert_eq!(lookup(0xa2).unwrap().evdev, 29);
+        assert_eq!(lookup(0xa3).unwrap().evdev, 97);
+        assert_eq!(lookup(0xa4).unwrap().evdev, 56);
+        assert_eq!(lookup(0xa5).unwrap().evdev, 100);
+        assert_eq!(lookup(0x5b).unwrap().evdev, 125);
+        assert_eq!(lookup(0x5c).unwrap().evdev, 126);
+        // generic ones default to the left key
+        assert_eq!(lookup(0x10).unwrap().evdev, 42);
+    }
+
+    #[test]
+    fn test_numpad_and_media() {
+        assert_eq!(lookup(0x60).unwrap().evdev, 82);
+        assert_eq!(lookup(0x69).unwrap().evdev, 73);
+        assert_eq!(lookup(0x6e).unwrap().keysym, 0xffae);
+        assert_eq!(lookup(0xad).unwrap().evdev, 113);
+        assert_eq!(lookup(0xb3).unwrap().keysym, 0x1008_ff14);
+        assert_eq!(lookup(0x87).unwrap().evdev, 194);
+        assert_eq!(lookup(0x07), None);
+    }
+
+    #[test]
+    fn test_char_to_keysym() {
+        assert_eq!(char_to_keysym('a'), 0x61);
+        assert_eq!(char_to_keysym('ü'), 0xfc);
+        assert_eq!(char_to_keysym('\n'), 0xff0a);
+        assert_eq!(char_to_keysym('€'), 0x0100_20ac);
+    }
+}
@@ -0,0 +1,255 @@
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::compositor::InputSender;
+
+mod decode;
+pub mod gamepad;
+pub mod keymap;
+pub use self::decode::InputError;
+pub use self::gamepad::Gamepads;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    X1,
+    X2,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub meta: bool,
+}
+
+impl Modifiers {
+    pub const SHIFT: u8 = 0x01;
+    pub const CTRL: u8 = 0x02;
+    pub const ALT: u8 = 0x04;
+    pub const META: u8 = 0x08;
+
+    pub fn from_bits(bits: u8) -> Modifiers {
+        Modifiers {
+            shift: bits & Modifiers::SHIFT != 0,
+            ctrl: bits & Modifiers::CTRL != 0,
+            alt: bits & Modifiers::ALT != 0,
+            meta: bits & Modifiers::META != 0,
+        }
+    }
+}
+
+/// Button bits of [`GamepadState::buttons`], the upper half is only sent by newer clients.
+pub mod buttons {
+    pub const DPAD_UP: u32 = 0x0001;
+    pub const DPAD_DOWN: u32 = 0x0002;
+    pub const DPAD_LEFT: u32 = 0x0004;
+    pub const DPAD_RIGHT: u32 = 0x0008;
+    pub const START: u32 = 0x0010;
+    pub const BACK: u32 = 0x0020;
+    pub const LEFT_STICK: u32 = 0x0040;
+    pub const RIGHT_STICK: u32 = 0x0080;
+    pub const LEFT_BUMPER: u32 = 0x0100;
+    pub const RIGHT_BUMPER: u32 = 0x0200;
+    pub const HOME: u32 = 0x0400;
+    pub const A: u32 = 0x1000;
+    pub const B: u32 = 0x2000;
+    pub const X: u32 = 0x4000;
+    pub const Y: u32 = 0x8000;
+    pub const PADDLE1: u32 = 0x01_0000;
+    pub const PADDLE2: u32 = 0x02_0000;
+    pub const PADDLE3: u32 = 0x04_0000;
+    pub const PADDLE4: u32 = 0x08_0000;
+    pub const TOUCHPAD: u32 = 0x10_0000;
+    pub const MISC: u32 = 0x20_0000;
+}
+
+/// Full state of one gamepad, clients always send all of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GamepadState {
+    pub controller: u16,
+    /// Bitmask of all gamepads currently connected to the client.
+    pub active_mask: u16,
+    pub buttons: u32,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub left_stick: (i16, i16),
+    pub right_stick: (i16, i16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerArrival {
+    pub controller: u8,
+    pub ty: u8,
+    pub capabilities: u16,
+    pub supported_buttons: u32,
+}
+
+/// A finger on the touchpad of a gamepad, coordinates are normalized to `0.0..=1.0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerTouch {
+    pub controller: u8,
+    pub ty: TouchEventType,
+    pub pointer_id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub pressure: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionType {
+    /// In m/s².
+    Accelerometer,
+    /// In degrees per second.
+    Gyroscope,
+}
+
+/// A reading of the motion sensors of a gamepad.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerMotion {
+    pub controller: u8,
+    pub ty: MotionType,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerBattery {
+    pub controller: u8,
+    /// `LI_BATTERY_STATE_*` of the client.
+    pub state: u8,
+    /// `None` if unknown.
+    pub percentage: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchEventType {
+    Hover,
+    Down,
+    Up,
+    Move,
+    Cancel,
+    ButtonOnly,
+    HoverLeave,
+    CancelAll,
+}
+
+/// Coordinates are normalized to `0.0..=1.0` of the stream's dimensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TouchEvent {
+    pub ty: TouchEventType,
+    pub pointer_id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub pressure_or_distance: f32,
+    pub contact_area: (f32, f32),
+    /// Degrees, `None` if unknown.
+    pub rotation: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PenTool {
+    Unknown,
+    Pen,
+    Eraser,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PenEvent {
+    pub ty: TouchEventType,
+    pub tool: PenTool,
+    /// Primary, secondary and tertiary button in the lowest bits.
+    pub buttons: u8,
+    pub x: f32,
+    pub y: f32,
+    pub pressure_or_distance: f32,
+    pub contact_area: (f32, f32),
+    /// Degrees, `None` if unknown.
+    pub rotation: Option<u16>,
+    /// Degrees from the surface normal, `None` if unknown.
+    pub tilt: Option<u8>,
+}
+
+/// A decoded input packet of the control stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    Key {
+        pressed: bool,
+        /// Windows virtual key code.
+        key_code: u8,
+        modifiers: Modifiers,
+        /// Set if the client didn't translate the key to a US layout.
+        non_normalized: bool,
+    },
+    MouseMoveRelative {
+        dx: i16,
+        dy: i16,
+    },
+    MouseMoveAbsolute {
+        x: i16,
+        y: i16,
+        /// Reference size the coordinates are relative to.
+        width: i16,
+        height: i16,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+    },
+    /// Vertical scrolling, 120 per notch.
+    Scroll {
+        amount: i16,
+    },
+    /// Horizontal scrolling, 120 per notch.
+    HorizontalScroll {
+        amount: i16,
+    },
+    Text(String),
+    Gamepad(GamepadState),
+    ControllerArrival(ControllerArrival),
+    ControllerTouch(ControllerTouch),
+    ControllerMotion(ControllerMotion),
+    ControllerBattery(ControllerBattery),
+    Touch(TouchEvent),
+    Pen(PenEvent),
+}
+
+/// Handles the input packets of a session until the control stream closes or `shutdown`
+/// is cancelled, then removes its gamepads.
+///
+/// Keyboard and mouse input goes to the session's compositor, if it runs one.
+pub async fn run(
+    mut packets: UnboundedReceiver<Vec<u8>>,
+    mut gamepads: Gamepads,
+    compositor: Option<InputSender>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let packet = tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        match InputEvent::decode(&packet) {
+            Ok(InputEvent::Gamepad(state)) => gamepads.update(state),
+            Ok(InputEvent::ControllerArrival(arrival)) => gamepads.arrival(&arrival),
+            // the virtual gamepads have no touchpad, motion sensors or battery
+            Ok(
+                InputEvent::ControllerTouch(_)
+                | InputEvent::ControllerMotion(_)
+                | InputEvent::ControllerBattery(_),
+            ) => {}
+            Ok(event) => match &compositor {
+                Some(compositor) => compositor.send(&event),
+                None => log::trace!("Unhandled input: {:?}", event),
+            },
+            Err(err) => log::warn!("{}", err),
+        }
+    }
+}
@@ -1,28 +1,56 @@
 #![recursion_limit = "256"]
 
-use anyhow::Result;
-use default_net::Interface;
+use anyhow::{Context, Result};
 use gotham::{router::response::StaticResponseExtender, state::StateData};
 use openssl::{
     pkey::{PKey, Private},
     x509::X509,
 };
+use sd_notify::NotifyState;
 use serde::{Deserialize, Serialize};
 use simplelog::*;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-//pub mod compositor;
+pub mod apps;
+pub mod audio;
+pub mod capture;
+pub mod compositor;
 pub mod config;
+pub mod control;
 pub mod crypto;
 pub mod http;
+pub mod input;
+pub mod net;
+pub mod ping;
+pub mod portmap;
+pub mod recorder;
 pub mod rtsp;
 pub mod serialization;
+pub mod state;
+pub mod systemd;
+pub mod video;
+
+/// How long apps get to exit on their own when their session is ended.
+const APP_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long requests being handled get to finish on shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(StateData, Debug, Clone)]
-pub struct SharedState(Arc<Mutex<State>>);
+pub struct SharedState(Arc<state::Host>);
 impl std::panic::RefUnwindSafe for SharedState {}
 
 #[derive(
@@ -32,8 +60,9 @@ pub struct ClientInfo {
     uniqueid: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct State {
+/// The config file, split into the parts of [`state::Host`] once loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
     unique_id: Uuid,
     #[serde(with = "serialization::cert")]
     server_cert: X509,
@@ -41,16 +70,37 @@ pub struct State {
     server_key: PKey<Private>,
     known_clients: HashMap<ClientInfo, Client>,
     apps: Vec<App>,
+    /// Adds the apps installed on the host on startup, nothing is imported if unset.
+    #[serde(default)]
+    app_import: Option<apps::ImportConfig>,
 
     hostname: String,
-    #[serde(skip, default = "serialization::get_default_interface")]
-    interface: Interface,
     http_port: u16,
     https_port: u16,
 
+    #[serde(default)]
+    network: net::NetworkConfig,
+
     max_sessions: usize,
-    #[serde(skip)]
-    sessions: HashMap<Uuid, Session>,
+    #[serde(default)]
+    video_capture: video::CaptureBackend,
+    /// Encoder elements of the video pipeline, VA-API for the negotiated codec if unset.
+    #[serde(default)]
+    video_encoder: Option<String>,
+    #[serde(default)]
+    audio_capture: audio::AudioBackend,
+    /// Bounds of the bitrate and FEC adapted to the client's packet loss.
+    #[serde(default)]
+    rate_limits: video::RateLimits,
+    /// Where sessions are recorded to, nothing is recorded if unset.
+    #[serde(default)]
+    recording: Option<recorder::RecordingConfig>,
+    /// Forwarding of the ports on the router for clients outside the network, off if unset.
+    #[serde(default)]
+    port_mapping: Option<portmap::PortMappingConfig>,
+    /// Where the protocol traffic of sessions is captured to, nothing is captured if unset.
+    #[serde(default)]
+    capture_directory: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -59,6 +109,30 @@ pub struct Session {
     client: Client,
     rikey: String,
     rikeyid: String,
+    rtsp_encryption: bool,
+    /// Whether the client encrypts its control stream with the launch key.
+    control_encryption: bool,
+    audio_config: audio::AudioConfig,
+    stream_config: Option<rtsp::StreamConfig>,
+    control: Option<control::ControlHandle>,
+    /// The control stream, on a thread of its own.
+    control_thread: Option<std::thread::JoinHandle<()>>,
+    /// The streams and the input of the session, running until `shutdown` is cancelled.
+    tasks: Vec<JoinHandle<()>>,
+    /// Stops everything started for the session, cancelled once it ends or is dropped.
+    shutdown: CancellationToken,
+    /// Bitmask of the gamepads connected to the client at launch.
+    controllers: u16,
+    /// The app, running until the session ends.
+    process: Option<tokio::process::Child>,
+    compositor: Option<compositor::Compositor>,
+    /// Address the session was launched from.
+    address: IpAddr,
+    /// Handed to the client at SETUP, tells its media pings apart.
+    ping_payload: ping::PingPayload,
+    /// Where the client receives the media, learned from its pings.
+    endpoints: ping::Endpoints,
+    capture: Option<capture::Capture>,
     /*
     rtsp_port: u16,
     ctrl_port: u16,
@@ -67,7 +141,114 @@ pub struct Session {
     */
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+impl Session {
+    /// The AES key the client handed over at launch.
+    pub fn aes_key(&self) -> Result<[u8; 16]> {
+        let key = hex::decode(&self.rikey).context("rikey is not valid hex")?;
+        key.try_into()
+            .map_err(|_| anyhow::anyhow!("rikey has an invalid length"))
+    }
+
+    /// The key id handed over at launch, used to derive the audio IVs.
+    pub fn key_id(&self) -> Result<u32> {
+        // clients send it as a signed integer
+        let id = self
+            .rikeyid
+            .parse::<i32>()
+            .context("rikeyid is not a number")?;
+        Ok(id as u32)
+    }
+
+    /// Accepts the pings of this session's client on the media ports.
+    pub fn ping_validator(&self) -> ping::PingValidator {
+        ping::PingValidator::new(self.ping_payload, self.address)
+    }
+
+    /// Ends the session: gives its app a chance to exit before it is killed, then shuts
+    /// down the compositor, stops the streams and the input and waits for their ports to
+    /// be released.
+    pub async fn end(mut self) {
+        // first, so the app doesn't play into the host's speakers once its sink is gone
+        if let Some(mut process) = self.process.take() {
+            // the id is gone once the app exited and was reaped, nothing else can have it
+            if let Some(pid) = process.id() {
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            }
+            if tokio::time::timeout(APP_EXIT_TIMEOUT, process.wait())
+                .await
+                .is_err()
+            {
+                log::warn!("App didn't exit in time, killing it");
+                if let Err(err) = process.kill().await {
+                    log::error!("Failed to kill app: {}", err);
+                }
+            }
+        }
+
+        self.shutdown.cancel();
+        if let Some(compositor) = self.compositor.take() {
+            // joins the compositor's thread, which closes the frames the encoder waits for
+            let _ = tokio::task::spawn_blocking(move || drop(compositor)).await;
+        }
+        for task in self.tasks.drain(..) {
+            if let Err(err) = task.await {
+                log::error!("Session task failed: {}", err);
+            }
+        }
+        if let Some(thread) = self.control_thread.take() {
+            // exits within a service call of noticing the cancellation
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+        self.control = None;
+    }
+}
+
+#[cfg(test)]
+impl Session {
+    /// A session of the first app for a paired client on loopback, as launched.
+    pub fn test() -> Session {
+        let (client_cert, _) = crypto::gen_creds().unwrap();
+        Session {
+            app: AppId(1),
+            client: Client {
+                paired: true,
+                client_cert,
+                key: Vec::new(),
+                server_secret: None,
+                server_challenge: None,
+                client_hash: None,
+            },
+            rikey: "000102030405060708090a0b0c0d0e0f".into(),
+            rikeyid: "0".into(),
+            rtsp_encryption: false,
+            control_encryption: false,
+            audio_config: audio::STEREO,
+            stream_config: None,
+            control: None,
+            control_thread: None,
+            tasks: Vec::new(),
+            shutdown: CancellationToken::new(),
+            controllers: 0,
+            process: None,
+            compositor: None,
+            address: IpAddr::from([127, 0, 0, 1]),
+            ping_payload: ping::PingPayload::generate().unwrap(),
+            endpoints: Default::default(),
+            capture: None,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // streams of a session dropped without being ended stop on their own
+        self.shutdown.cancel();
+    }
+}
+
+/// Clients know apps by this, so it stays the same while apps come and go. 0 is no app.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
 pub struct AppId(u64);
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Client {
@@ -80,11 +261,20 @@ pub struct Client {
     client_hash: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct App {
+    /// Given to apps without one on startup.
+    #[serde(default)]
+    id: AppId,
     title: String,
     command: String,
     asset: Option<PathBuf>,
+    /// Streams the app in HDR10 to clients asking for it.
+    #[serde(default)]
+    hdr: Option<video::HdrMetadata>,
+    /// Where the app was imported from, unset for apps added by hand.
+    #[serde(default)]
+    import_id: Option<String>,
 }
 
 #[tokio::main]
@@ -96,20 +286,86 @@ async fn main() -> Result<()> {
         } else {
             LevelFilter::Warn
         },
-        Config::default(),
+        simplelog::Config::default(),
         TerminalMode::Stderr,
         ColorChoice::Auto,
     );
 
-    let config = config::load_config()?;
-    let state = SharedState(Arc::new(Mutex::new(config)));
-    let http_state = http::init(state.clone()).await?;
+    let mut config = config::load_config()?;
+    // `replay <capture>` checks the answers to a captured session instead of hosting
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    if let [command, path] = args.as_slice() {
+        if command == "replay" {
+            return replay(Path::new(path), config).await;
+        }
+    }
+    let mut apps_changed = apps::number(&mut config.apps);
+    if let Some(import) = &config.app_import {
+        apps_changed |= apps::import(import, &mut config.apps);
+    }
+    if apps_changed {
+        if let Err(err) = config::save_config(&config) {
+            log::error!("Failed to save apps: {:#}", err);
+        }
+    }
+    let (host, persister) = state::Host::new(config);
+    let (stop_persister, persister_stopped) = oneshot::channel();
+    let persister = tokio::spawn(persister.run(persister_stopped));
+    let port_mapper = host.settings.port_mapping.clone().map(|config| {
+        portmap::PortMapper::spawn(
+            config,
+            portmap::host_mappings(host.identity.http_port, host.identity.https_port),
+            host.external.clone(),
+        )
+    });
+    let state = SharedState(Arc::new(host));
+    let listeners =
+        systemd::take_listeners().context("Failed to take the sockets passed by systemd")?;
+    let http_state = http::init(state.clone(), listeners).await?;
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+    systemd::notify(&[NotifyState::Ready]);
+    tokio::spawn(systemd::watchdog());
+
     tokio::select! {
         biased;
 
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
         _ = http_state.http_server => {},
         _ = http_state.https_server => {},
     };
 
+    // the servers are gone, requests already accepted are let finish
+    log::info!("Shutting down");
+    systemd::notify(&[NotifyState::Stopping]);
+    if !http_state.requests.drain(DRAIN_TIMEOUT).await {
+        log::warn!("Requests still being handled, shutting down regardless");
+    }
+    state.0.end_sessions().await;
+    if let Some(port_mapper) = port_mapper {
+        port_mapper.stop().await;
+    }
+    let _ = stop_persister.send(());
+    if let Err(err) = persister.await {
+        log::error!("Failed to save config: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Replays the capture at `path`, failing if the host answers differently than it did.
+async fn replay(path: &Path, config: Config) -> Result<()> {
+    let records = capture::load(path)?;
+    let replay = capture::replay(&records, config).await?;
+    println!("Replayed {} control messages", replay.control_messages);
+    for divergence in &replay.divergences {
+        println!("{}", divergence);
+    }
+    if !replay.divergences.is_empty() {
+        anyhow::bail!(
+            "{} answers differ from the capture",
+            replay.divergences.len()
+        );
+    }
     Ok(())
 }
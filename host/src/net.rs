@@ -0,0 +1,199 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::{bail, Result};
+use default_net::Interface;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Where the services of the host listen, and which address it tells clients about.
+///
+/// The default `::` listens on IPv4 and IPv6 alike, or only on IPv4 where the host has no
+/// IPv6.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub http_address: IpAddr,
+    pub https_address: IpAddr,
+    pub rtsp_address: IpAddr,
+    /// Video and audio.
+    pub media_address: IpAddr,
+    /// ENet only speaks IPv4, so `::` means `0.0.0.0` here and other IPv6 addresses fail.
+    pub control_address: IpAddr,
+    /// Interface whose address is advertised as `LocalIP`, the default route's if unset.
+    pub interface: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        let any = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        NetworkConfig {
+            http_address: any,
+            https_address: any,
+            rtsp_address: any,
+            media_address: any,
+            control_address: any,
+            interface: None,
+        }
+    }
+}
+
+/// The address to actually listen on for `ip`, `::` turns into `0.0.0.0` without IPv6.
+pub fn listen_address(ip: IpAddr) -> IpAddr {
+    if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && !ipv6_available() {
+        log::debug!("No IPv6 on this host, listening on IPv4 only");
+        return IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    }
+    ip
+}
+
+fn ipv6_available() -> bool {
+    std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok()
+}
+
+fn socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<(Socket, SocketAddr)> {
+    let addr = SocketAddr::new(listen_address(addr.ip()), addr.port());
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    // don't depend on the system default for accepting IPv4 on `::`
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok((socket, addr))
+}
+
+/// Listens on `addr`, on both IPv4 and IPv6 if it is `::`.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let (socket, addr) = socket(addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds `addr`, on both IPv4 and IPv6 if it is `::`.
+///
+/// IPv4 peers of a dual stack socket show up as IPv4-mapped IPv6 addresses, which is also
+/// what has to be sent to.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let (socket, addr) = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The IPv4 address ENet listens on for `ip`.
+pub fn control_address(ip: IpAddr) -> Result<Ipv4Addr> {
+    match ip {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ok(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => Ok(ip),
+            None => bail!("The control stream can't listen on IPv6 address {}", ip),
+        },
+    }
+}
+
+/// The interface called `name`, or the one of the default route.
+pub fn advertised_interface(name: Option<&str>) -> Option<Interface> {
+    if let Some(name) = name {
+        match default_net::get_interfaces()
+            .into_iter()
+            .find(|interface| interface.name == name)
+        {
+            Some(interface) => return Some(interface),
+            None => log::warn!("No interface {}, advertising the default one", name),
+        }
+    }
+    default_net::get_default_interface()
+        .ok()
+        .or_else(|| default_net::get_interfaces().into_iter().next())
+}
+
+/// The address of `interface` clients are told to reach the host on.
+///
+/// IPv4 is preferred, older clients know nothing else. Link-local IPv6 addresses are
+/// useless without a scope and skipped.
+pub fn local_ip(interface: &Interface) -> Option<IpAddr> {
+    let ipv4 = interface.ipv4.iter().map(|net| IpAddr::V4(net.addr));
+    let ipv6 = interface
+        .ipv6
+        .iter()
+        .map(|net| net.addr)
+        .filter(|addr| addr.segments()[0] & 0xffc0 != 0xfe80)
+        .map(IpAddr::V6);
+    ipv4.chain(ipv6).next()
+}
+
+/// Whether a client at `ip` reaches the host from outside its network, through the NAT of
+/// the router. IPv6 has no NAT to tell by.
+pub fn is_outside(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return false,
+        },
+    };
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_address() {
+        assert_eq!(
+            control_address("::".parse().unwrap()).unwrap(),
+            Ipv4Addr::UNSPECIFIED
+        );
+        assert_eq!(
+            control_address("192.168.1.20".parse().unwrap()).unwrap(),
+            Ipv4Addr::new(192, 168, 1, 20)
+        );
+        assert_eq!(
+            control_address("::ffff:192.168.1.20".parse().unwrap()).unwrap(),
+            Ipv4Addr::new(192, 168, 1, 20)
+        );
+        assert!(control_address("fd00::20".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_is_outside() {
+        assert!(is_outside("203.0.113.7".parse().unwrap()));
+        assert!(is_outside("::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!is_outside("192.168.1.20".parse().unwrap()));
+        assert!(!is_outside("::ffff:10.0.0.3".parse().unwrap()));
+        assert!(!is_outside("127.0.0.1".parse().unwrap()));
+        assert!(!is_outside("2001:db8::20".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let socket = bind_udp("[::]:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        // an IPv4 client reaches the socket, whether it ended up dual stack or IPv4 only
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"PING", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0; 4];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"PING");
+        let from_ip = match from.ip() {
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        assert_eq!(from_ip, client.local_addr().unwrap().ip());
+
+        // and is answered at the address it was seen from
+        socket.send_to(b"PONG", from).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"PONG");
+    }
+}
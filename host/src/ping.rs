@@ -0,0 +1,261 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use openssl::{error::ErrorStack, rand::rand_bytes};
+use tokio::net::UdpSocket;
+
+/// Length of the payload newer clients identify their session's pings with.
+pub const PAYLOAD_LEN: usize = 16;
+/// What clients without a session payload send.
+const LEGACY_PING: &[u8] = b"PING";
+/// Clients ping the media ports right after PLAY, those that didn't by then are gone.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies the pings of a session, handed to the client with the SETUP responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingPayload([u8; PAYLOAD_LEN]);
+
+impl PingPayload {
+    pub fn generate() -> Result<PingPayload, ErrorStack> {
+        let mut random = [0; PAYLOAD_LEN / 2];
+        rand_bytes(&mut random)?;
+        let mut payload = [0; PAYLOAD_LEN];
+        payload.copy_from_slice(hex::encode(random).as_bytes());
+        Ok(PingPayload(payload))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever made up of hex digits
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+/// A ping received on one of the media ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ping {
+    Legacy,
+    Session {
+        payload: PingPayload,
+        sequence_number: u32,
+    },
+}
+
+impl Ping {
+    pub fn parse(data: &[u8]) -> Result<Ping, PingError> {
+        if data == LEGACY_PING {
+            return Ok(Ping::Legacy);
+        }
+        if data.len() != PAYLOAD_LEN + 4 {
+            return Err(PingError::Malformed(data.len()));
+        }
+        let (payload, sequence_number) = data.split_at(PAYLOAD_LEN);
+        Ok(Ping::Session {
+            payload: PingPayload(payload.try_into().unwrap()),
+            sequence_number: u32::from_be_bytes(sequence_number.try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PingError {
+    /// Neither a legacy nor a session ping, with the given length.
+    Malformed(usize),
+    /// The payload belongs to another session.
+    WrongSession,
+    /// A legacy ping from an address other than the session's client.
+    UnknownAddress(IpAddr),
+}
+
+impl std::fmt::Display for PingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PingError::Malformed(len) => write!(f, "Malformed ping of {} bytes", len),
+            PingError::WrongSession => write!(f, "Ping for another session"),
+            PingError::UnknownAddress(ip) => write!(f, "Legacy ping from unknown address {}", ip),
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+/// Tells the pings of a session's client apart from anything else hitting the media ports.
+///
+/// Legacy pings carry nothing to check, those are accepted from the address the session
+/// was launched from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingValidator {
+    payload: PingPayload,
+    client_ip: IpAddr,
+    timeout: Duration,
+}
+
+impl PingValidator {
+    pub fn new(payload: PingPayload, client_ip: IpAddr) -> PingValidator {
+        PingValidator {
+            payload,
+            client_ip: client_ip.to_canonical(),
+            timeout: PING_TIMEOUT,
+        }
+    }
+
+    pub fn validate(&self, data: &[u8], from: SocketAddr) -> Result<Ping, PingError> {
+        let ping = Ping::parse(data)?;
+        match ping {
+            Ping::Legacy if from.ip().to_canonical() != self.client_ip => {
+                Err(PingError::UnknownAddress(from.ip()))
+            }
+            Ping::Session { payload, .. } if payload != self.payload => {
+                Err(PingError::WrongSession)
+            }
+            _ => Ok(ping),
+        }
+    }
+
+    /// Waits for a valid ping on `socket`, returning the client's address as seen from here.
+    ///
+    /// Anything else is dropped, `stream` names the socket in the logs. Fails with
+    /// [`io::ErrorKind::TimedOut`] if the client didn't ping in time.
+    pub async fn wait_for_client(
+        &self,
+        socket: &UdpSocket,
+        stream: &str,
+    ) -> io::Result<SocketAddr> {
+        let mut buf = [0; 64];
+        let wait = async {
+            loop {
+                let (len, addr) = socket.recv_from(&mut buf).await?;
+                match self.validate(&buf[..len], addr) {
+                    Ok(ping) => {
+                        log::info!("{} client at {} ({:?})", stream, addr, ping);
+                        return Ok(addr);
+                    }
+                    Err(err) => log::warn!("Ignoring {} ping from {}: {}", stream, addr, err),
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, wait)
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Client didn't ping the {} port", stream.to_lowercase()),
+                )
+            })?
+    }
+}
+
+/// Where the client receives the media of a session, known once it pinged the ports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Endpoints {
+    pub video: Option<SocketAddr>,
+    pub audio: Option<SocketAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> PingValidator {
+        PingValidator::new(
+            PingPayload(*b"0123456789abcdef"),
+            "10.0.0.2".parse().unwrap(),
+        )
+    }
+
+    fn session_ping(payload: &[u8], sequence_number: u32) -> Vec<u8> {
+        let mut ping = payload.to_vec();
+        ping.extend(sequence_number.to_be_bytes());
+        ping
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ping::parse(b"PING"), Ok(Ping::Legacy));
+        assert_eq!(
+            Ping::parse(&session_ping(b"0123456789abcdef", 3)),
+            Ok(Ping::Session {
+                payload: PingPayload(*b"0123456789abcdef"),
+                sequence_number: 3,
+            })
+        );
+        assert_eq!(Ping::parse(b"PONG!"), Err(PingError::Malformed(5)));
+    }
+
+    #[test]
+    fn test_validate() {
+        let validator = validator();
+        let client = "10.0.0.2:51000".parse().unwrap();
+        let other = "10.0.0.3:51000".parse().unwrap();
+
+        assert_eq!(validator.validate(b"PING", client), Ok(Ping::Legacy));
+        assert_eq!(
+            validator.validate(b"PING", other),
+            Err(PingError::UnknownAddress("10.0.0.3".parse().unwrap()))
+        );
+        // the payload is what counts, the client may be behind another NAT by now
+        let ping = session_ping(b"0123456789abcdef", 1);
+        assert!(validator.validate(&ping, other).is_ok());
+        let ping = session_ping(b"fedcba9876543210", 1);
+        assert_eq!(
+            validator.validate(&ping, client),
+            Err(PingError::WrongSession)
+        );
+        // IPv4 clients show up mapped on dual stack sockets
+        let mapped = "[::ffff:10.0.0.2]:51000".parse().unwrap();
+        assert!(validator.validate(b"PING", mapped).is_ok());
+    }
+
+    #[test]
+    fn test_generate() {
+        let payload = PingPayload::generate().unwrap();
+        assert_eq!(payload.as_str().len(), PAYLOAD_LEN);
+        assert_ne!(payload, PingPayload::generate().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_client() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(socket.local_addr().unwrap()).await.unwrap();
+        let validator = PingValidator::new(
+            PingPayload(*b"0123456789abcdef"),
+            "127.0.0.1".parse().unwrap(),
+        );
+
+        client
+            .send(&session_ping(b"fedcba9876543210", 1))
+            .await
+            .unwrap();
+        client.send(b"hello").await.unwrap();
+        client
+            .send(&session_ping(b"0123456789abcdef", 1))
+            .await
+            .unwrap();
+        let addr = validator.wait_for_client(&socket, "Video").await.unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_client_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let validator = PingValidator {
+            timeout: Duration::from_millis(50),
+            ..validator()
+        };
+
+        // a ping from anyone else doesn't count
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        other
+            .send_to(b"PING", socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        let err = validator
+            .wait_for_client(&socket, "Audio")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, task::JoinHandle, time::timeout};
+use url::Url;
+
+use crate::rtsp::{AUDIO_PORT, CONTROL_PORT, RTSP_PORT, VIDEO_PORT};
+
+mod natpmp;
+mod upnp;
+
+/// How long to wait before trying again after the router couldn't be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long the router is given to remove the mappings on shutdown.
+const UNMAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maps the ports of the host on the router, so that clients outside the network can
+/// connect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMappingConfig {
+    #[serde(default)]
+    pub protocol: GatewayProtocol,
+    /// The URL of the UPnP description of the router, searched for if unset.
+    #[serde(default)]
+    pub upnp_location: Option<String>,
+    /// The address of the router for NAT-PMP, the default gateway if unset.
+    #[serde(default)]
+    pub natpmp_gateway: Option<Ipv4Addr>,
+    /// Seconds a mapping lasts unless renewed, so that it goes away with the host.
+    #[serde(default = "default_lease")]
+    pub lease: u32,
+}
+
+fn default_lease() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GatewayProtocol {
+    /// UPnP if a gateway answers the search, NAT-PMP otherwise.
+    #[default]
+    Auto,
+    Upnp,
+    NatPmp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Http,
+    Https,
+    Rtsp,
+    Video,
+    Control,
+    Audio,
+}
+
+impl Service {
+    fn transport(self) -> Transport {
+        match self {
+            Service::Http | Service::Https | Service::Rtsp => Transport::Tcp,
+            Service::Video | Service::Control | Service::Audio => Transport::Udp,
+        }
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Service::Http => write!(f, "HTTP"),
+            Service::Https => write!(f, "HTTPS"),
+            Service::Rtsp => write!(f, "RTSP"),
+            Service::Video => write!(f, "video"),
+            Service::Control => write!(f, "control"),
+            Service::Audio => write!(f, "audio"),
+        }
+    }
+}
+
+/// A port of the host, mapped to the same port on the external address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub service: Service,
+    pub port: u16,
+}
+
+/// All ports clients connect to.
+pub fn host_mappings(http_port: u16, https_port: u16) -> Vec<Mapping> {
+    [
+        (Service::Http, http_port),
+        (Service::Https, https_port),
+        (Service::Rtsp, RTSP_PORT),
+        (Service::Video, VIDEO_PORT),
+        (Service::Control, CONTROL_PORT),
+        (Service::Audio, AUDIO_PORT),
+    ]
+    .into_iter()
+    .map(|(service, port)| Mapping { service, port })
+    .collect()
+}
+
+/// The host as seen from outside the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct External {
+    pub address: IpAddr,
+    /// The router may not map the HTTP port to itself, clients are told which it is.
+    pub http_port: u16,
+}
+
+/// What the router last told about the host, `None` while nothing is mapped.
+#[derive(Debug, Clone, Default)]
+pub struct ExternalAddress(Arc<Mutex<Option<External>>>);
+
+impl ExternalAddress {
+    pub fn get(&self) -> Option<External> {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn set(&self, external: Option<External>) {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = external;
+    }
+}
+
+enum Gateway {
+    Upnp(upnp::Gateway),
+    NatPmp(natpmp::Gateway),
+}
+
+impl Gateway {
+    async fn discover(config: &PortMappingConfig) -> Result<Gateway> {
+        match config.protocol {
+            GatewayProtocol::Upnp => upnp_gateway(config).await.map(Gateway::Upnp),
+            GatewayProtocol::NatPmp => natpmp_gateway(config).map(Gateway::NatPmp),
+            GatewayProtocol::Auto => match upnp_gateway(config).await {
+                Ok(gateway) => Ok(Gateway::Upnp(gateway)),
+                Err(err) => {
+                    log::debug!("Trying NAT-PMP, no UPnP gateway: {:#}", err);
+                    natpmp_gateway(config).map(Gateway::NatPmp)
+                }
+            },
+        }
+    }
+
+    async fn external_address(&self) -> Result<IpAddr> {
+        match self {
+            Gateway::Upnp(gateway) => gateway.external_address().await,
+            Gateway::NatPmp(gateway) => gateway.external_address().await.map(IpAddr::V4),
+        }
+    }
+
+    /// Returns the external port and how long the mapping lasts.
+    async fn map(&self, mapping: Mapping, lease: Duration) -> Result<(u16, Duration)> {
+        let transport = mapping.service.transport();
+        match self {
+            Gateway::Upnp(gateway) => {
+                let description = format!("Sunrise {}", mapping.service);
+                let granted = gateway
+                    .map(transport, mapping.port, &description, lease)
+                    .await?;
+                // permanent mappings are renewed all the same, in case the router restarts
+                Ok((mapping.port, granted.unwrap_or(lease)))
+            }
+            Gateway::NatPmp(gateway) => {
+                let granted = gateway.map(transport, mapping.port, lease).await?;
+                Ok((granted.external_port, granted.lifetime))
+            }
+        }
+    }
+
+    async fn unmap(&self, mapping: Mapping) -> Result<()> {
+        let transport = mapping.service.transport();
+        match self {
+            Gateway::Upnp(gateway) => gateway.unmap(transport, mapping.port).await,
+            Gateway::NatPmp(gateway) => gateway.unmap(transport, mapping.port).await,
+        }
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gateway::Upnp(gateway) => write!(f, "UPnP gateway {}", gateway.control_url()),
+            Gateway::NatPmp(gateway) => write!(f, "NAT-PMP gateway {}", gateway.address()),
+        }
+    }
+}
+
+async fn upnp_gateway(config: &PortMappingConfig) -> Result<upnp::Gateway> {
+    let location = match &config.upnp_location {
+        Some(location) => Url::parse(location).context("Invalid UPnP location")?,
+        None => upnp::discover().await?,
+    };
+    upnp::Gateway::from_location(&location)
+        .await
+        .with_context(|| format!("Failed to read UPnP description {}", location))
+}
+
+fn natpmp_gateway(config: &PortMappingConfig) -> Result<natpmp::Gateway> {
+    let address = match config.natpmp_gateway {
+        Some(address) => address,
+        None => match default_net::get_default_gateway() {
+            Ok(gateway) => match gateway.ip_addr {
+                IpAddr::V4(address) => address,
+                IpAddr::V6(address) => bail!("NAT-PMP needs an IPv4 gateway, not {}", address),
+            },
+            Err(err) => bail!("No default gateway: {}", err),
+        },
+    };
+    Ok(natpmp::Gateway::new(SocketAddrV4::new(
+        address,
+        natpmp::PORT,
+    )))
+}
+
+/// Maps all ports, returning how the host is reached from outside and when to renew.
+async fn map_all(
+    gateway: &Gateway,
+    mappings: &[Mapping],
+    lease: Duration,
+) -> Result<(External, Duration)> {
+    let address = gateway
+        .external_address()
+        .await
+        .context("Failed to get the external address")?;
+    let mut http_port = None;
+    let mut renew = lease;
+    for &mapping in mappings {
+        let (external_port, lifetime) = gateway.map(mapping, lease).await.with_context(|| {
+            format!(
+                "Failed to map the {} port {}",
+                mapping.service, mapping.port
+            )
+        })?;
+        if mapping.service == Service::Http {
+            http_port = Some(external_port);
+        } else if external_port != mapping.port {
+            log::warn!(
+                "The {} port {} was mapped to {}, clients outside the network won't find it",
+                mapping.service,
+                mapping.port,
+                external_port
+            );
+        }
+        if !lifetime.is_zero() {
+            renew = renew.min(lifetime);
+        }
+    }
+    let external = External {
+        address,
+        http_port: http_port.context("The HTTP port isn't mapped")?,
+    };
+    // halfway through, so a lost renewal can be retried before the mappings expire
+    Ok((external, renew / 2))
+}
+
+/// Keeps the ports of the host mapped on the router until stopped.
+#[derive(Debug)]
+pub struct PortMapper {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl PortMapper {
+    pub fn spawn(
+        config: PortMappingConfig,
+        mappings: Vec<Mapping>,
+        external: ExternalAddress,
+    ) -> PortMapper {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(run(config, mappings, external, stopped));
+        PortMapper { stop, task }
+    }
+
+    /// Removes the mappings from the router.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        if let Err(err) = self.task.await {
+            log::error!("Port mapping failed: {}", err);
+        }
+    }
+}
+
+async fn run(
+    config: PortMappingConfig,
+    mappings: Vec<Mapping>,
+    external: ExternalAddress,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let lease = Duration::from_secs(config.lease.into());
+    let mut gateway = None;
+    loop {
+        if gateway.is_none() {
+            gateway = match Gateway::discover(&config).await {
+                Ok(found) => Some(found),
+                Err(err) => {
+                    log::warn!("No router to map ports on: {:#}", err);
+                    None
+                }
+            };
+        }
+        let renew = match &gateway {
+            Some(current) => match map_all(current, &mappings, lease).await {
+                Ok((mapped, renew)) => {
+                    if external.get() != Some(mapped) {
+                        log::info!("Reachable at {} through {}", mapped.address, current);
+                    }
+                    external.set(Some(mapped));
+                    Some(renew)
+                }
+                Err(err) => {
+                    log::warn!("Failed to map ports on {}: {:#}", current, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let delay = renew.unwrap_or_else(|| {
+            external.set(None);
+            // the router may have restarted elsewhere, whatever it mapped expires
+            gateway = None;
+            RETRY_DELAY
+        });
+        tokio::select! {
+            _ = &mut stopped => break,
+            _ = tokio::time::sleep(delay) => {},
+        }
+    }
+
+    external.set(None);
+    if let Some(gateway) = gateway {
+        let unmap_all = async {
+            for &mapping in &mappings {
+                if let Err(err) = gateway.unmap(mapping).await {
+                    log::warn!("Failed to unmap the {} port: {:#}", mapping.service, err);
+                }
+            }
+        };
+        if timeout(UNMAP_TIMEOUT, unmap_all).await.is_err() {
+            log::warn!("{} didn't remove all mappings in time", gateway);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_map_all() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            _ => unreachable!(),
+        };
+        // a NAT-PMP gateway that maps HTTP elsewhere and grants UDP ports 10 minutes
+        tokio::spawn(async move {
+            let mut buf = [0; 12];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut response = vec![0, buf[1] | 0x80, 0, 0, 0, 0, 0, 1];
+                if len == 2 {
+                    response.extend([203, 0, 113, 7]);
+                } else {
+                    let port = u16::from_be_bytes([buf[4], buf[5]]);
+                    let external = if port == 47989 { 50000 } else { port };
+                    let lifetime: u32 = if buf[1] == 1 { 600 } else { 3600 };
+                    response.extend(port.to_be_bytes());
+                    response.extend(external.to_be_bytes());
+                    response.extend(lifetime.to_be_bytes());
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        let gateway = Gateway::NatPmp(natpmp::Gateway::new(address));
+        let (external, renew) = map_all(
+            &gateway,
+            &host_mappings(47989, 47984),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            external,
+            External {
+                address: "203.0.113.7".parse().unwrap(),
+                http_port: 50000,
+            }
+        );
+        assert_eq!(renew, Duration::from_secs(300));
+    }
+}
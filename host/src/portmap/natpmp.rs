@@ -0,0 +1,283 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::Transport;
+
+/// Where gateways listen for NAT-PMP requests.
+pub const PORT: u16 = 5351;
+
+/// The first retransmission interval, doubled after every attempt (RFC 6886 3.1).
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+/// The RFC allows nine, which waits for a minute, routers without NAT-PMP answer never.
+const ATTEMPTS: u32 = 4;
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+
+/// A result code other than success.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatPmpError {
+    UnsupportedVersion,
+    /// Mapping is disabled on the gateway.
+    NotAuthorized,
+    /// The gateway has no external address, yet.
+    NetworkFailure,
+    OutOfResources,
+    UnsupportedOpcode,
+    Unknown(u16),
+}
+
+impl NatPmpError {
+    fn from_code(code: u16) -> NatPmpError {
+        match code {
+            1 => NatPmpError::UnsupportedVersion,
+            2 => NatPmpError::NotAuthorized,
+            3 => NatPmpError::NetworkFailure,
+            4 => NatPmpError::OutOfResources,
+            5 => NatPmpError::UnsupportedOpcode,
+            code => NatPmpError::Unknown(code),
+        }
+    }
+}
+
+impl std::fmt::Display for NatPmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatPmpError::UnsupportedVersion => write!(f, "Unsupported NAT-PMP version"),
+            NatPmpError::NotAuthorized => write!(f, "Port mapping is disabled on the gateway"),
+            NatPmpError::NetworkFailure => write!(f, "The gateway has no external address"),
+            NatPmpError::OutOfResources => write!(f, "The gateway is out of mappings"),
+            NatPmpError::UnsupportedOpcode => write!(f, "Unsupported NAT-PMP request"),
+            NatPmpError::Unknown(code) => write!(f, "NAT-PMP result code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for NatPmpError {}
+
+/// A mapping as granted by the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Granted {
+    pub external_port: u16,
+    pub lifetime: Duration,
+}
+
+/// A gateway speaking NAT-PMP (RFC 6886).
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    address: SocketAddrV4,
+}
+
+impl Gateway {
+    pub fn new(address: SocketAddrV4) -> Gateway {
+        Gateway { address }
+    }
+
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    pub async fn external_address(&self) -> Result<Ipv4Addr> {
+        let response = self.request(&[0, OP_EXTERNAL_ADDRESS], 12).await?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    /// Maps `port` to the same external port if the gateway lets it.
+    pub async fn map(
+        &self,
+        transport: Transport,
+        port: u16,
+        lifetime: Duration,
+    ) -> Result<Granted> {
+        let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+        let response = self
+            .request(&map_request(transport, port, port, lifetime), 16)
+            .await?;
+        let internal_port = u16::from_be_bytes([response[8], response[9]]);
+        if internal_port != port {
+            bail!(
+                "The gateway answered for port {} instead of {}",
+                internal_port,
+                port
+            );
+        }
+        Ok(Granted {
+            external_port: u16::from_be_bytes([response[10], response[11]]),
+            lifetime: Duration::from_secs(
+                u32::from_be_bytes([response[12], response[13], response[14], response[15]]).into(),
+            ),
+        })
+    }
+
+    pub async fn unmap(&self, transport: Transport, port: u16) -> Result<()> {
+        self.request(&map_request(transport, port, 0, 0), 16)
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends `request` until the gateway answers, and checks the answer is a successful one
+    /// of at least `len` bytes.
+    async fn request(&self, request: &[u8], len: usize) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket
+            .connect(self.address)
+            .await
+            .with_context(|| format!("Failed to reach gateway {}", self.address))?;
+
+        let mut buf = [0; 16];
+        let mut wait = INITIAL_TIMEOUT;
+        for _ in 0..ATTEMPTS {
+            socket.send(request).await?;
+            // other answers, late ones to an earlier attempt say, are skipped
+            let received = timeout(wait, async {
+                loop {
+                    let received = socket.recv(&mut buf).await?;
+                    if received >= 4 && buf[0] == 0 && buf[1] == request[1] | 0x80 {
+                        return std::io::Result::Ok(received);
+                    }
+                }
+            })
+            .await;
+            match received {
+                Ok(received) => {
+                    let received = received?;
+                    let code = u16::from_be_bytes([buf[2], buf[3]]);
+                    if code != 0 {
+                        return Err(NatPmpError::from_code(code).into());
+                    }
+                    if received < len {
+                        bail!("Truncated NAT-PMP response of {} bytes", received);
+                    }
+                    return Ok(buf[..received].to_vec());
+                }
+                Err(_) => wait *= 2,
+            }
+        }
+        bail!("No NAT-PMP response from {}", self.address)
+    }
+}
+
+fn map_request(transport: Transport, internal: u16, external: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[1] = match transport {
+        Transport::Udp => OP_MAP_UDP,
+        Transport::Tcp => OP_MAP_TCP,
+    };
+    request[4..6].copy_from_slice(&internal.to_be_bytes());
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers like a gateway with external address 203.0.113.7, mapping TCP ports to
+    /// themselves and UDP ports 10000 higher, and returns the requests it got.
+    async fn gateway(socket: UdpSocket, requests: usize) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        let mut buf = [0; 12];
+        while received.len() < requests {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let request = buf[..len].to_vec();
+            let mut response = vec![0, request[1] | 0x80, 0, 0, 0, 0, 0, 42];
+            match request[1] {
+                OP_EXTERNAL_ADDRESS => response.extend([203, 0, 113, 7]),
+                OP_MAP_UDP | OP_MAP_TCP => {
+                    let internal = u16::from_be_bytes([request[4], request[5]]);
+                    let external = match (request[1], &request[8..12]) {
+                        (_, [0, 0, 0, 0]) => 0,
+                        (OP_MAP_UDP, _) => internal + 10000,
+                        _ => internal,
+                    };
+                    response.extend(internal.to_be_bytes());
+                    response.extend(external.to_be_bytes());
+                    response.extend(&request[8..12]);
+                }
+                _ => response[3] = 5,
+            }
+            socket.send_to(&response, from).await.unwrap();
+            received.push(request);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            _ => unreachable!(),
+        };
+        let stand_in = tokio::spawn(gateway(socket, 4));
+        let gateway = Gateway::new(address);
+
+        assert_eq!(
+            gateway.external_address().await.unwrap(),
+            Ipv4Addr::new(203, 0, 113, 7)
+        );
+        assert_eq!(
+            gateway
+                .map(Transport::Tcp, 47989, Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            Granted {
+                external_port: 47989,
+                lifetime: Duration::from_secs(3600),
+            }
+        );
+        assert_eq!(
+            gateway
+                .map(Transport::Udp, 47998, Duration::from_secs(3600))
+                .await
+                .unwrap()
+                .external_port,
+            57998
+        );
+        gateway.unmap(Transport::Udp, 47998).await.unwrap();
+
+        let requests = stand_in.await.unwrap();
+        assert_eq!(requests[0], [0, 0]);
+        assert_eq!(
+            requests[1],
+            [0, 2, 0, 0, 0xbb, 0x75, 0xbb, 0x75, 0, 0, 0x0e, 0x10]
+        );
+        assert_eq!(requests[3], [0, 1, 0, 0, 0xbb, 0x7e, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            _ => unreachable!(),
+        };
+        tokio::spawn(async move {
+            let mut buf = [0; 12];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            let response = [0, 0x82, 0, 2, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0, 0];
+            socket.send_to(&response, from).await.unwrap();
+        });
+
+        let err = Gateway::new(address)
+            .map(Transport::Tcp, 47989, Duration::from_secs(3600))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<NatPmpError>(),
+            Some(&NatPmpError::NotAuthorized)
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+use url::Url;
+
+use super::Transport;
+
+/// Where gateways listen for SSDP searches.
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+/// How long gateways are waited for, they answer within the `MX` seconds of the search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The services mappings can be added to, the first one a gateway has is used.
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// `OnlyPermanentLeasesSupported`, mappings have to be added without a lease.
+const ERROR_PERMANENT_LEASES_ONLY: u16 = 725;
+
+/// A SOAP fault of the gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpnpError {
+    pub code: u16,
+    pub description: String,
+}
+
+impl std::fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UPnP error {}: {}", self.code, self.description)
+    }
+}
+
+impl std::error::Error for UpnpError {}
+
+/// Searches the network for an Internet Gateway Device, returning the location of its
+/// description.
+pub async fn discover() -> Result<Url> {
+    search(SocketAddr::from((SSDP_GROUP, SSDP_PORT))).await
+}
+
+async fn search(target: SocketAddr) -> Result<Url> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         \r\n",
+        target
+    );
+    socket.send_to(search.as_bytes(), target).await?;
+
+    let mut buf = [0; 2048];
+    let location = timeout(SEARCH_TIMEOUT, async {
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            let response = String::from_utf8_lossy(&buf[..len]);
+            match header(&response, "location") {
+                Some(location) => return Url::parse(location).map_err(anyhow::Error::from),
+                None => log::debug!("Ignoring SSDP response without location from {}", from),
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("No UPnP gateway answered"))??;
+    Ok(location)
+}
+
+/// A gateway speaking UPnP IGD.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    control_url: Url,
+    service: &'static str,
+    /// The address of the host as the gateway sees it, mappings are made to it.
+    local_address: IpAddr,
+}
+
+impl Gateway {
+    /// Reads the description of the gateway at `location` for its WAN connection service.
+    pub async fn from_location(location: &Url) -> Result<Gateway> {
+        let response = request(location, "GET", None, "").await?;
+        if response.status != 200 {
+            bail!("Gateway description returned status {}", response.status);
+        }
+        let (service, control_url) = wan_service(&response.body)
+            .ok_or_else(|| anyhow!("The gateway has no WAN connection service"))?;
+        let base = match element(&response.body, "URLBase") {
+            Some(base) if !base.is_empty() => Url::parse(base)?,
+            _ => location.clone(),
+        };
+        Ok(Gateway {
+            control_url: base.join(control_url)?,
+            service,
+            local_address: response.local_address.ip(),
+        })
+    }
+
+    pub fn control_url(&self) -> &Url {
+        &self.control_url
+    }
+
+    pub async fn external_address(&self) -> Result<IpAddr> {
+        let response = self.call("GetExternalIPAddress", &[]).await?;
+        element(&response, "NewExternalIPAddress")
+            .ok_or_else(|| anyhow!("The gateway returned no external address"))?
+            .parse()
+            .context("The gateway returned an invalid external address")
+    }
+
+    /// Maps the same port on the external address to `port` of the host, without a lease
+    /// if the gateway can't expire mappings.
+    ///
+    /// Returns the lease given, `None` for a permanent mapping.
+    pub async fn map(
+        &self,
+        transport: Transport,
+        port: u16,
+        description: &str,
+        lease: Duration,
+    ) -> Result<Option<Duration>> {
+        match self.add_mapping(transport, port, description, lease).await {
+            Err(err)
+                if matches!(
+                    err.downcast_ref::<UpnpError>(),
+                    Some(UpnpError {
+                        code: ERROR_PERMANENT_LEASES_ONLY,
+                        ..
+                    })
+                ) =>
+            {
+                self.add_mapping(transport, port, description, Duration::ZERO)
+                    .await?;
+                Ok(None)
+            }
+            result => result.map(|()| Some(lease)),
+        }
+    }
+
+    async fn add_mapping(
+        &self,
+        transport: Transport,
+        port: u16,
+        description: &str,
+        lease: Duration,
+    ) -> Result<()> {
+        let port = port.to_string();
+        let local_address = self.local_address.to_string();
+        let lease = lease.as_secs().to_string();
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &port),
+                ("NewProtocol", protocol(transport)),
+                ("NewInternalPort", &port),
+                ("NewInternalClient", &local_address),
+                ("NewEnabled", "1"),
+                ("NewPortMappingDescription", description),
+                ("NewLeaseDuration", &lease),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn unmap(&self, transport: Transport, port: u16) -> Result<()> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &port.to_string()),
+                ("NewProtocol", protocol(transport)),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Invokes `action` on the WAN connection service, returning the response envelope.
+    async fn call(&self, action: &str, arguments: &[(&str, &str)]) -> Result<String> {
+        let mut body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{} xmlns:u=\"{}\">",
+            action, self.service
+        );
+        for (name, value) in arguments {
+            body.push_str(&format!("<{0}>{1}</{0}>", name, value));
+        }
+        body.push_str(&format!("</u:{}></s:Body></s:Envelope>", action));
+
+        let soap_action = format!("\"{}#{}\"", self.service, action);
+        let response = request(&self.control_url, "POST", Some(&soap_action), &body)
+            .await
+            .with_context(|| format!("Failed to call {}", action))?;
+        match response.status {
+            200 => Ok(response.body),
+            status => match element(&response.body, "errorCode").and_then(|code| code.parse().ok())
+            {
+                Some(code) => Err(UpnpError {
+                    code,
+                    description: element(&response.body, "errorDescription")
+                        .unwrap_or_default()
+                        .to_owned(),
+                }
+                .into()),
+                None => bail!("{} returned status {}", action, status),
+            },
+        }
+    }
+}
+
+fn protocol(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Tcp => "TCP",
+        Transport::Udp => "UDP",
+    }
+}
+
+/// The type and control URL of the first service in `description` mappings can be added to.
+fn wan_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            Some((
+                element(service, "serviceType")?,
+                element(service, "controlURL")?,
+            ))
+        })
+        .collect();
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service, _)| service == wanted)
+            .map(|(_, control_url)| (*wanted, *control_url))
+    })
+}
+
+/// The text of the first `<name>` element, namespace prefixes aren't understood.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/// The value of the header `name` in an HTTP message.
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: u16,
+    body: String,
+    local_address: SocketAddr,
+}
+
+/// Sends a request to the gateway, just enough HTTP/1.1 to talk to one.
+async fn request(
+    url: &Url,
+    method: &str,
+    soap_action: Option<&str>,
+    body: &str,
+) -> Result<HttpResponse> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host in {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+
+    timeout(REQUEST_TIMEOUT, async {
+        // IPv6 hosts are bracketed in URLs but not when connecting
+        let address = host.trim_start_matches('[').trim_end_matches(']');
+        let mut stream = TcpStream::connect((address, port)).await?;
+        let local_address = stream.local_addr()?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            host,
+            port,
+            body.len()
+        );
+        if let Some(soap_action) = soap_action {
+            request.push_str("Content-Type: text/xml; charset=\"utf-8\"\r\n");
+            request.push_str(&format!("SOAPAction: {}\r\n", soap_action));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("Truncated HTTP response"))?;
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid HTTP status line"))?;
+        let body = match header(head, "transfer-encoding") {
+            Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => dechunk(body)?,
+            _ => body.to_owned(),
+        };
+        Ok(HttpResponse {
+            status,
+            body,
+            local_address,
+        })
+    })
+    .await
+    .map_err(|_| anyhow!("{} timed out", url))?
+}
+
+fn dechunk(mut body: &str) -> Result<String> {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body
+            .split_once("\r\n")
+            .ok_or_else(|| anyhow!("Truncated chunk"))?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+            .context("Invalid chunk size")?;
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = rest.get(..size).ok_or_else(|| anyhow!("Truncated chunk"))?;
+        out.push_str(chunk);
+        body = rest[size..].trim_start_matches("\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+    </serviceList>
+    <deviceList>
+      <device>
+        <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+            <controlURL>/ctl/IPConn</controlURL>
+          </service>
+        </serviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>";
+
+    /// Answers the requests a stand-in gateway gets with `responses`, returning the
+    /// requests.
+    async fn gateway(listener: TcpListener, responses: Vec<(u16, String)>) -> Vec<String> {
+        let mut requests = Vec::new();
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the request is complete once its body is, its length is in the header
+            loop {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = header(head, "content-length").unwrap().parse().unwrap();
+                    if body.len() >= length {
+                        requests.push(text);
+                        break;
+                    }
+                }
+            }
+            // chunked, as some gateways answer
+            let response = format!(
+                "HTTP/1.1 {} X\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    fn envelope(action: &str, body: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+             <s:Body><u:{0}Response xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+             {1}</u:{0}Response></s:Body></s:Envelope>",
+            action, body
+        )
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let stand_in = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = stand_in.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (len, from) = stand_in.recv_from(&mut buf).await.unwrap();
+            let search = String::from_utf8_lossy(&buf[..len]).into_owned();
+            assert!(search.starts_with("M-SEARCH * HTTP/1.1\r\n"));
+            assert_eq!(
+                header(&search, "st"),
+                Some("urn:schemas-upnp-org:device:InternetGatewayDevice:1")
+            );
+            let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                            LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+            stand_in.send_to(response.as_bytes(), from).await.unwrap();
+        });
+
+        assert_eq!(
+            search(target).await.unwrap().as_str(),
+            "http://192.168.1.1:5000/rootDesc.xml"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = Url::parse(&format!(
+            "http://{}/rootDesc.xml",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let permanent_only = "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                              <errorCode>725</errorCode>\
+                              <errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                              </UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        let stand_in = tokio::spawn(gateway(
+            listener,
+            vec![
+                (200, DESCRIPTION.into()),
+                (
+                    200,
+                    envelope(
+                        "GetExternalIPAddress",
+                        "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+                    ),
+                ),
+                (200, envelope("AddPortMapping", "")),
+                (500, permanent_only.into()),
+                (200, envelope("AddPortMapping", "")),
+                (200, envelope("DeletePortMapping", "")),
+            ],
+        ));
+
+        let gateway = Gateway::from_location(&location).await.unwrap();
+        assert_eq!(gateway.control_url().path(), "/ctl/IPConn");
+        assert_eq!(
+            gateway.external_address().await.unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        let lease = Duration::from_secs(3600);
+        assert_eq!(
+            gateway
+                .map(Transport::Tcp, 47989, "Sunrise HTTP", lease)
+                .await
+                .unwrap(),
+            Some(lease)
+        );
+        assert_eq!(
+            gateway
+                .map(Transport::Udp, 47998, "Sunrise video", lease)
+                .await
+                .unwrap(),
+            None
+        );
+        gateway.unmap(Transport::Udp, 47998).await.unwrap();
+
+        let requests = stand_in.await.unwrap();
+        assert!(requests[0].starts_with("GET /rootDesc.xml HTTP/1.1\r\n"));
+        assert_eq!(
+            header(&requests[2], "soapaction"),
+            Some("\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\"")
+        );
+        assert_eq!(
+            element(&requests[2], "NewInternalClient"),
+            Some("127.0.0.1")
+        );
+        assert_eq!(element(&requests[2], "NewLeaseDuration"), Some("3600"));
+        assert_eq!(element(&requests[3], "NewProtocol"), Some("UDP"));
+        assert_eq!(element(&requests[4], "NewLeaseDuration"), Some("0"));
+        assert_eq!(element(&requests[5], "NewExternalPort"), Some("47998"));
+    }
+
+    #[test]
+    fn test_wan_service() {
+        assert_eq!(
+            wan_service(DESCRIPTION),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                "/ctl/IPConn"
+            ))
+        );
+        assert_eq!(wan_service("<root><service></service></root>"), None);
+    }
+}
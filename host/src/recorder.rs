@@ -0,0 +1,378 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    audio::{AudioConfig, SAMPLE_RATE},
+    rtsp::StreamConfig,
+    video::{pipeline::encoded_format, AccessUnit, FrameType},
+};
+
+/// How long a finished recording may take to be written out.
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Records every session into a file of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub container: Container,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Container {
+    #[default]
+    Matroska,
+    /// Fragmented, so the file stays readable if the host goes down mid session.
+    Mp4,
+}
+
+impl Container {
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Matroska => "matroskamux",
+            Container::Mp4 => "mp4mux fragment-duration=1000",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::Mp4 => "mp4",
+        }
+    }
+}
+
+/// Builds the `gst-launch` style description of a recording pipeline.
+pub fn description(config: &StreamConfig, container: Container) -> String {
+    let (parser, _) = encoded_format(config.codec);
+    format!(
+        "appsrc name=video format=time max-bytes=0 block=false ! {} ! queue ! mux. \
+         appsrc name=audio format=time max-bytes=0 block=false ! queue ! mux. \
+         {} name=mux ! filesink name=file",
+        parser,
+        container.muxer(),
+    )
+}
+
+/// Caps of the Opus packets of a layout, stereo needs no mapping table.
+pub fn opus_caps(config: AudioConfig) -> String {
+    if config.channels == 2 {
+        return format!(
+            "audio/x-opus,channel-mapping-family=0,channels=2,rate={}",
+            SAMPLE_RATE
+        );
+    }
+    let mapping = config
+        .mapping
+        .iter()
+        .map(|channel| channel.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "audio/x-opus,channel-mapping-family=1,channels={},rate={},stream-count={},\
+         coupled-count={},channel-mapping=(int)<{}>",
+        config.channels, SAMPLE_RATE, config.streams, config.coupled_streams, mapping
+    )
+}
+
+/// Maps the timestamps of a track onto the recording.
+///
+/// A track starts at the time its first packet arrived, so tracks joining late stay in
+/// sync with the others.
+#[derive(Debug)]
+struct Timeline {
+    epoch: Instant,
+    /// Timestamp of the first packet and where it was placed.
+    first: Option<(Duration, Duration)>,
+}
+
+impl Timeline {
+    fn new(epoch: Instant) -> Timeline {
+        Timeline { epoch, first: None }
+    }
+
+    fn position(&mut self, timestamp: Duration, now: Instant) -> Duration {
+        let epoch = self.epoch;
+        let (first, start) = *self
+            .first
+            .get_or_insert_with(|| (timestamp, now.saturating_duration_since(epoch)));
+        start + timestamp.saturating_sub(first)
+    }
+}
+
+/// The recording pipeline, written out once all of its tracks ended.
+#[derive(Debug)]
+struct Recording {
+    pipeline: gst::Pipeline,
+    path: PathBuf,
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let pipeline = self.pipeline.clone();
+        let path = std::mem::take(&mut self.path);
+        // waiting for the muxer mustn't hold up the session's tasks
+        let finalize = move || {
+            let finished = pipeline.bus().and_then(|bus| {
+                bus.timed_pop_filtered(
+                    gst::ClockTime::from_nseconds(FINALIZE_TIMEOUT.as_nanos() as u64),
+                    &[gst::MessageType::Eos, gst::MessageType::Error],
+                )
+            });
+            match finished.as_ref().map(|message| message.view()) {
+                Some(gst::MessageView::Eos(_)) => {
+                    log::info!("Saved recording {}", path.display())
+                }
+                Some(gst::MessageView::Error(err)) => log::error!(
+                    "Failed to write recording {}: {}",
+                    path.display(),
+                    err.error()
+                ),
+                _ => log::warn!("Recording {} may be incomplete", path.display()),
+            }
+            if let Err(err) = pipeline.set_state(gst::State::Null) {
+                log::warn!("Failed to stop recording pipeline: {}", err);
+            }
+        };
+        if let Err(err) = thread::Builder::new()
+            .name("recorder".into())
+            .spawn(finalize)
+        {
+            log::error!("Failed to finish recording: {}", err);
+        }
+    }
+}
+
+/// A stream of the recording, which ends once this is dropped.
+#[derive(Debug)]
+struct Track {
+    src: gst_app::AppSrc,
+    timeline: Timeline,
+    recording: Arc<Recording>,
+}
+
+impl Track {
+    fn new(recording: &Arc<Recording>, name: &str, caps: &str, epoch: Instant) -> Result<Track> {
+        let src = recording
+            .pipeline
+            .by_name(name)
+            .and_then(|src| src.downcast::<gst_app::AppSrc>().ok())
+            .with_context(|| format!("Recording pipeline has no {} appsrc", name))?;
+        let caps = gst::Caps::from_str(caps).with_context(|| format!("Invalid caps {}", caps))?;
+        src.set_caps(Some(&caps));
+        Ok(Track {
+            src,
+            timeline: Timeline::new(epoch),
+            recording: recording.clone(),
+        })
+    }
+
+    /// Queues a packet for the muxer, never blocks.
+    fn push(
+        &mut self,
+        data: Vec<u8>,
+        timestamp: Duration,
+        duration: Option<Duration>,
+        delta: bool,
+    ) {
+        let position = self.timeline.position(timestamp, Instant::now());
+        let mut buffer = gst::Buffer::from_slice(data);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::ClockTime::from_nseconds(position.as_nanos() as u64));
+            buffer
+                .set_duration(duration.map(|d| gst::ClockTime::from_nseconds(d.as_nanos() as u64)));
+            if delta {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+        if let Err(err) = self.src.push_buffer(buffer) {
+            log::warn!(
+                "Recording {} dropped a packet: {:?}",
+                self.recording.path.display(),
+                err
+            );
+        }
+    }
+}
+
+impl Drop for Track {
+    fn drop(&mut self) {
+        let _ = self.src.end_of_stream();
+    }
+}
+
+/// Copies the encoded frames sent to a client into a recording.
+#[derive(Debug)]
+pub struct VideoTrack(Track);
+
+impl VideoTrack {
+    pub fn push(&mut self, unit: &AccessUnit) {
+        self.0.push(
+            unit.data.clone(),
+            unit.timestamp,
+            None,
+            unit.frame_type == FrameType::P,
+        );
+    }
+}
+
+/// Copies the Opus packets sent to a client into a recording.
+#[derive(Debug)]
+pub struct AudioTrack {
+    track: Track,
+    packet_duration: Duration,
+    packets: u32,
+}
+
+impl AudioTrack {
+    pub fn push(&mut self, opus: &[u8]) {
+        let timestamp = self.packet_duration * self.packets;
+        self.packets += 1;
+        self.track
+            .push(opus.to_vec(), timestamp, Some(self.packet_duration), false);
+    }
+}
+
+/// Starts recording a session, returning the tracks the video and audio streams feed.
+///
+/// The file is complete once both tracks were dropped. Packets are queued without a
+/// bound, a slow disk never holds up the live stream.
+pub fn start(
+    config: &RecordingConfig,
+    session_id: Uuid,
+    stream_config: &StreamConfig,
+    audio_config: AudioConfig,
+) -> Result<(VideoTrack, AudioTrack)> {
+    gst::init().context("Failed to initialize GStreamer")?;
+    fs::create_dir_all(&config.directory).with_context(|| {
+        format!(
+            "Failed to create recording directory {}",
+            config.directory.display()
+        )
+    })?;
+    let path = recording_path(&config.directory, session_id, config.container);
+    let location = path.to_str().context("Recording path is not valid UTF-8")?;
+
+    let description = description(stream_config, config.container);
+    log::debug!("Recording pipeline: {}", description);
+    let pipeline = gst::parse_launch(&description)
+        .context("Failed to create recording pipeline")?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Recording pipeline is not a pipeline"))?;
+    pipeline
+        .by_name("file")
+        .context("Recording pipeline has no filesink")?
+        .set_property("location", location);
+
+    let recording = Arc::new(Recording {
+        pipeline,
+        path: path.clone(),
+    });
+    let epoch = Instant::now();
+    let (_, video_caps) = encoded_format(stream_config.codec);
+    let video = Track::new(&recording, "video", video_caps, epoch)?;
+    let audio = Track::new(&recording, "audio", &opus_caps(audio_config), epoch)?;
+    recording
+        .pipeline
+        .set_state(gst::State::Playing)
+        .context("Failed to start recording pipeline")?;
+    log::info!("Recording session {} to {}", session_id, path.display());
+
+    Ok((
+        VideoTrack(video),
+        AudioTrack {
+            track: audio,
+            packet_duration: Duration::from_millis(stream_config.audio_packet_duration as u64),
+            packets: 0,
+        },
+    ))
+}
+
+fn recording_path(directory: &Path, session_id: Uuid, container: Container) -> PathBuf {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    directory.join(format!(
+        "{}-{}.{}",
+        started,
+        session_id.simple(),
+        container.extension()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{STEREO, SURROUND_51},
+        rtsp::VideoCodec,
+    };
+
+    #[test]
+    fn test_description() {
+        let config = StreamConfig {
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            packet_size: 1024,
+            max_bitrate_kbps: 20_000,
+            min_fec_packets: 0,
+            codec: VideoCodec::Hevc,
+            audio_channels: 2,
+            audio_channel_mask: 0x3,
+            audio_packet_duration: 5,
+            hdr: false,
+        };
+        let mkv = description(&config, Container::Matroska);
+        assert!(
+            mkv.starts_with("appsrc name=video format=time max-bytes=0 block=false ! h265parse")
+        );
+        assert!(mkv.contains("matroskamux name=mux ! filesink name=file"));
+        let mp4 = description(&config, Container::Mp4);
+        assert!(mp4.contains("mp4mux fragment-duration=1000 name=mux"));
+    }
+
+    #[test]
+    fn test_opus_caps() {
+        assert_eq!(
+            opus_caps(STEREO),
+            "audio/x-opus,channel-mapping-family=0,channels=2,rate=48000"
+        );
+        assert_eq!(
+            opus_caps(SURROUND_51),
+            "audio/x-opus,channel-mapping-family=1,channels=6,rate=48000,stream-count=4,\
+             coupled-count=2,channel-mapping=(int)<0,4,1,5,2,3>"
+        );
+    }
+
+    #[test]
+    fn test_timeline() {
+        let epoch = Instant::now();
+        let ms = Duration::from_millis;
+
+        // the encoder's clock started long before the recording
+        let mut video = Timeline::new(epoch);
+        assert_eq!(video.position(ms(5_000), epoch), ms(0));
+        assert_eq!(video.position(ms(5_016), epoch + ms(40)), ms(16));
+
+        // audio joined later and starts where it arrived
+        let mut audio = Timeline::new(epoch);
+        assert_eq!(audio.position(ms(0), epoch + ms(100)), ms(100));
+        assert_eq!(audio.position(ms(5), epoch + ms(100)), ms(105));
+        // a late packet keeps its place
+        assert_eq!(audio.position(ms(10), epoch + ms(300)), ms(110));
+    }
+}
@@ -0,0 +1,207 @@
+use openssl::error::ErrorStack;
+
+use crate::crypto::{aes_decrypt_gcm, aes_encrypt_gcm, gcm_sequence_iv, Origin, GCM_TAG_LEN};
+
+/// Set in the first header word of every encrypted RTSP message.
+pub const ENCRYPTED_MESSAGE_BIT: u32 = 0x8000_0000;
+/// `typeAndLength` + `sequenceNumber` + GCM tag.
+pub const HEADER_LEN: usize = 4 + 4 + GCM_TAG_LEN;
+/// Upper bound for a single message, RTSP payloads in practice are a few KiB at most.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FramingError {
+    /// More data is needed to decode the next message.
+    Incomplete,
+    /// The data is not an encrypted RTSP frame.
+    NotEncrypted,
+    /// The frame announces an unreasonably large message.
+    TooLarge(usize),
+    /// Decryption or authentication of the frame failed.
+    Crypto(ErrorStack),
+}
+
+impl std::fmt::Display for FramingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FramingError::Incomplete => write!(f, "Incomplete encrypted message"),
+            FramingError::NotEncrypted => write!(f, "Message is not encrypted"),
+            FramingError::TooLarge(len) => write!(f, "Encrypted message too large: {}", len),
+            FramingError::Crypto(err) => write!(f, "Failed to decrypt message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+/// Tags the IVs of RTSP messages.
+const STREAM_TAG: u8 = b'R';
+
+fn iv(sequence_number: u32, origin: Origin) -> [u8; 12] {
+    gcm_sequence_iv(sequence_number, origin, STREAM_TAG)
+}
+
+/// Returns true if the buffered data starts with an encrypted frame rather than plain RTSP.
+pub fn is_encrypted(buffer: &[u8]) -> bool {
+    buffer.first().map(|byte| byte & 0x80 != 0).unwrap_or(false)
+}
+
+/// AES-GCM framing of RTSP messages as used by newer GameStream clients.
+///
+/// Every message is prefixed by a header holding its length, a sequence number and the
+/// authentication tag. The key is the `rikey` handed over at launch.
+#[derive(Debug)]
+pub struct RtspCipher {
+    key: [u8; 16],
+    sequence_number: u32,
+}
+
+impl RtspCipher {
+    pub fn new(key: [u8; 16]) -> RtspCipher {
+        RtspCipher {
+            key,
+            sequence_number: 0,
+        }
+    }
+
+    /// Encrypts a serialized RTSP message originating from the host.
+    pub fn encrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        encrypt_message(&self.key, sequence_number, Origin::Host, message)
+    }
+
+    /// Decrypts the first client message in `buffer`, returning it and the number of bytes consumed.
+    pub fn decrypt(&self, buffer: &[u8]) -> Result<(Vec<u8>, usize), FramingError> {
+        decrypt_message(&self.key, Origin::Client, buffer)
+    }
+}
+
+pub fn encrypt_message(
+    key: &[u8],
+    sequence_number: u32,
+    origin: Origin,
+    message: &[u8],
+) -> Result<Vec<u8>, ErrorStack> {
+    let (ciphertext, tag) = aes_encrypt_gcm(message, key, &iv(sequence_number, origin))?;
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    frame.extend_from_slice(&(ENCRYPTED_MESSAGE_BIT | ciphertext.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&sequence_number.to_be_bytes());
+    frame.extend_from_slice(&tag);
+    frame.extend_from_slice(&ciphertext);
+    Ok(frame)
+}
+
+pub fn decrypt_message(
+    key: &[u8],
+    origin: Origin,
+    buffer: &[u8],
+) -> Result<(Vec<u8>, usize), FramingError> {
+    if buffer.len() < HEADER_LEN {
+        return if buffer.is_empty() || is_encrypted(buffer) {
+            Err(FramingError::Incomplete)
+        } else {
+            Err(FramingError::NotEncrypted)
+        };
+    }
+
+    let type_and_length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
+    if type_and_length & ENCRYPTED_MESSAGE_BIT == 0 {
+        return Err(FramingError::NotEncrypted);
+    }
+    let len = (type_and_length & !ENCRYPTED_MESSAGE_BIT) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(FramingError::TooLarge(len));
+    }
+    if buffer.len() < HEADER_LEN + len {
+        return Err(FramingError::Incomplete);
+    }
+
+    let sequence_number = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
+    let tag = &buffer[8..HEADER_LEN];
+    let ciphertext = &buffer[HEADER_LEN..HEADER_LEN + len];
+    let message = aes_decrypt_gcm(ciphertext, key, &iv(sequence_number, origin), tag)
+        .map_err(FramingError::Crypto)?;
+
+    Ok((message, HEADER_LEN + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    const OPTIONS: &[u8] = b"OPTIONS rtsp://10.0.0.1:48010 RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+
+    #[test]
+    fn test_round_trip() {
+        let frame = encrypt_message(&KEY, 7, Origin::Client, OPTIONS).unwrap();
+        assert!(is_encrypted(&frame));
+        assert_eq!(frame.len(), HEADER_LEN + OPTIONS.len());
+        assert_eq!(&frame[4..8], &7u32.to_be_bytes());
+
+        let (message, consumed) = decrypt_message(&KEY, Origin::Client, &frame).unwrap();
+        assert_eq!(message, OPTIONS);
+        assert_eq!(consumed, frame.len());
+    }
+
+    #[test]
+    fn test_pipelined_frames() {
+        let mut buffer = encrypt_message(&KEY, 0, Origin::Client, OPTIONS).unwrap();
+        buffer.extend(encrypt_message(&KEY, 1, Origin::Client, b"second").unwrap());
+
+        let (first, consumed) = decrypt_message(&KEY, Origin::Client, &buffer).unwrap();
+        assert_eq!(first, OPTIONS);
+        let (second, _) = decrypt_message(&KEY, Origin::Client, &buffer[consumed..]).unwrap();
+        assert_eq!(second, b"second");
+    }
+
+    #[test]
+    fn test_cipher_sequence() {
+        let mut cipher = RtspCipher::new(KEY);
+        let first = cipher.encrypt(OPTIONS).unwrap();
+        let second = cipher.encrypt(OPTIONS).unwrap();
+        assert_ne!(first, second);
+
+        let (message, _) = decrypt_message(&KEY, Origin::Host, &second).unwrap();
+        assert_eq!(message, OPTIONS);
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let frame = encrypt_message(&KEY, 0, Origin::Client, OPTIONS).unwrap();
+        for len in [0, 1, HEADER_LEN, frame.len() - 1] {
+            assert!(matches!(
+                decrypt_message(&KEY, Origin::Client, &frame[..len]),
+                Err(FramingError::Incomplete)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_plaintext_rejected() {
+        assert!(!is_encrypted(OPTIONS));
+        assert!(matches!(
+            decrypt_message(&KEY, Origin::Client, OPTIONS),
+            Err(FramingError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn test_tampering_detected() {
+        let mut frame = encrypt_message(&KEY, 0, Origin::Client, OPTIONS).unwrap();
+        *frame.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt_message(&KEY, Origin::Client, &frame),
+            Err(FramingError::Crypto(_))
+        ));
+
+        // a message from the host can't be replayed as a client message
+        let frame = encrypt_message(&KEY, 0, Origin::Host, OPTIONS).unwrap();
+        assert!(matches!(
+            decrypt_message(&KEY, Origin::Client, &frame),
+            Err(FramingError::Crypto(_))
+        ));
+    }
+}
@@ -1,131 +1,859 @@
-use rtsp_types::{self, Message, Method, ParseError, Request, Response, WriteError};
-use std::time::Duration;
+use anyhow::Context;
+use rtsp_types::{
+    self, headers, Message, Method, ParseError, Request, Response, StatusCode, Version, WriteError,
+};
+use std::{
+    ffi::OsString,
+    net::{IpAddr, SocketAddr},
+    thread,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Error as IoError},
     net::{TcpListener, TcpStream},
+    process::Child,
+    sync::{mpsc::UnboundedReceiver, oneshot},
     task,
 };
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::SharedState;
+use crate::{
+    audio::{self, AudioBackend, AudioConfig, AudioPacketizer},
+    capture::{Capture, Side},
+    compositor::{Compositor, InputSender},
+    control::{AudioRequest, ControlCipher, ControlHandle},
+    net,
+    ping::{Endpoints, PingValidator},
+    recorder::{self, AudioTrack},
+    video::{self, CaptureBackend, CapturePipeline, Source},
+    AppId, Session, SharedState,
+};
+
+mod encryption;
+mod sdp;
+use self::encryption::{FramingError, RtspCipher};
+pub use self::sdp::{StreamConfig, VideoCodec};
+
+pub const RTSP_PORT: u16 = 48010;
+pub const VIDEO_PORT: u16 = 47998;
+pub const CONTROL_PORT: u16 = 47999;
+pub const AUDIO_PORT: u16 = 48000;
+
+const SESSION_TIMEOUT: u64 = 90;
+/// Payload newer clients put into their pings to the media ports.
+const PING_PAYLOAD_HEADER: &str = "X-SS-Ping-Payload";
+/// Rendered frames waiting for the encoder, the compositor drops frames beyond this.
+const FRAME_QUEUE: usize = 2;
+const SUPPORTED_METHODS: &[Method] = &[
+    Method::Options,
+    Method::Describe,
+    Method::Setup,
+    Method::Announce,
+    Method::Play,
+    Method::GetParameter,
+    Method::Teardown,
+];
 
-pub async fn init() -> std::io::Result<TcpListener> {
-    TcpListener::bind(("0.0.0.0", 48010)).await
+pub fn init(address: IpAddr) -> std::io::Result<TcpListener> {
+    net::bind_tcp(SocketAddr::new(address, RTSP_PORT))
 }
 
-pub async fn new_client(
-    listener: TcpListener,
-    mut stream: TcpStream,
-    state: SharedState,
-    id: Uuid,
-) {
+pub async fn new_client(listener: TcpListener, stream: TcpStream, state: SharedState, id: Uuid) {
     task::spawn(async move {
         let _ = stream.set_nodelay(true);
-        let _listener = listener;
-        let mut buffer = Vec::new();
-        while let Ok(_len) = stream.read_buf(&mut buffer).await {
-            let len = match Message::parse(&buffer) {
+        let (cipher, capture) = match state.0.sessions.lock().await.get(&id) {
+            Some(session) if session.rtsp_encryption => match session.aes_key() {
+                Ok(key) => (Some(RtspCipher::new(key)), session.capture.clone()),
+                Err(err) => {
+                    log::error!("Unable to setup RTSP encryption: {}", err);
+                    return;
+                }
+            },
+            Some(session) => (None, session.capture.clone()),
+            None => (None, None),
+        };
+        let connection = Connection {
+            _listener: listener,
+            stream,
+            state,
+            session_id: id,
+            phase: Phase::Init,
+            cipher,
+            capture,
+            buffer: Vec::new(),
+            plaintext: Vec::new(),
+        };
+        connection.run().await;
+        log::info!("RTSP connection closed");
+    });
+}
+
+/// Progress of a client through the RTSP handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Init,
+    Setup,
+    Announced,
+    Playing,
+    Closed,
+}
+
+struct Connection {
+    _listener: TcpListener,
+    stream: TcpStream,
+    state: SharedState,
+    session_id: Uuid,
+    phase: Phase,
+    /// Set if the client negotiated encrypted RTSP at launch.
+    cipher: Option<RtspCipher>,
+    /// Receives every message in plaintext, if the session is captured.
+    capture: Option<Capture>,
+    /// Data as read from the socket.
+    buffer: Vec<u8>,
+    /// Decrypted data waiting to be parsed.
+    plaintext: Vec<u8>,
+}
+
+impl Connection {
+    async fn run(mut self) {
+        while self.phase != Phase::Closed {
+            match self.stream.read_buf(&mut self.buffer).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Error reading from RTSP connection: {}", err);
+                    break;
+                }
+            }
+
+            if let Err(err) = self.process_buffer().await {
+                log::error!("Error writing RTSP response: {}", err);
+                break;
+            }
+        }
+    }
+
+    /// Moves every complete message from the socket buffer into the plaintext buffer.
+    fn decrypt_buffer(&mut self) -> Result<(), FramingError> {
+        match self.cipher.as_ref() {
+            Some(cipher) => loop {
+                match cipher.decrypt(&self.buffer) {
+                    Ok((message, len)) => {
+                        self.buffer.drain(..len);
+                        self.plaintext.extend(message);
+                    }
+                    Err(FramingError::Incomplete) => return Ok(()),
+                    Err(err) => return Err(err),
+                }
+            },
+            None => {
+                self.plaintext.append(&mut self.buffer);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles every complete message currently buffered, as clients may pipeline requests.
+    async fn process_buffer(&mut self) -> Result<(), IoError> {
+        if let Err(err) = self.decrypt_buffer() {
+            // we can't answer in a way the client would understand
+            log::warn!(
+                "Invalid encrypted RTSP message, closing connection: {}",
+                err
+            );
+            self.phase = Phase::Closed;
+            return Ok(());
+        }
+
+        while self.phase != Phase::Closed {
+            match Message::<Vec<u8>>::parse(&self.plaintext) {
                 Ok((message, len)) => {
-                    if let Err(err) = handle_message(message, &mut stream, &state, &id).await {
-                        log::error!("Error handling RTSP message: {}", err);
+                    if let Some(capture) = &self.capture {
+                        capture.rtsp(Side::Client, &self.plaintext[..len]);
+                    }
+                    self.plaintext.drain(..len);
+                    if let Some(response) = self.handle_message(message).await {
+                        self.send(response).await?;
                     }
-                    len
                 }
-                Err(ParseError::Incomplete) => 0,
+                Err(ParseError::Incomplete) => break,
                 Err(ParseError::Error) => {
-                    break;
+                    // we have no way to find the start of the next message
+                    log::warn!("Malformed RTSP message, closing connection");
+                    self.plaintext.clear();
+                    self.phase = Phase::Closed;
+                    self.send(error_response(None, StatusCode::BadRequest))
+                        .await?;
                 }
-            };
-            buffer = buffer.split_off(len);
+            }
         }
-        log::info!("RTSP connection closed");
-    });
-}
 
-fn handle_options(request: &Request<&[u8]>) -> Response<Vec<u8>> {
-    Response::builder(rtsp_types::Version::V1_0, rtsp_types::StatusCode::Ok)
-        .typed_header::<rtsp_types::headers::CSeq>(
-            &request
-                .typed_header::<rtsp_types::headers::CSeq>()
-                .unwrap()
-                .unwrap(),
+        Ok(())
+    }
+
+    async fn send(&mut self, response: Response<Vec<u8>>) -> Result<(), IoError> {
+        let mut out_buf = Vec::new();
+        if let Err(WriteError::IoError(err)) = response.write(&mut out_buf) {
+            return Err(err);
+        }
+        log::debug!("RTSP answer:\n{}", String::from_utf8_lossy(&out_buf));
+        if let Some(capture) = &self.capture {
+            capture.rtsp(Side::Host, &out_buf);
+        }
+        if let Some(cipher) = self.cipher.as_mut() {
+            out_buf = cipher.encrypt(&out_buf).map_err(IoError::other)?;
+        }
+
+        self.stream.write_all(&out_buf).await?;
+        self.stream.flush().await
+    }
+
+    async fn handle_message(&mut self, message: Message<Vec<u8>>) -> Option<Response<Vec<u8>>> {
+        log::info!("RTSP message: {:?}", message);
+
+        match message {
+            Message::Request(request) => match request.typed_header::<headers::CSeq>() {
+                Ok(Some(cseq)) => Some(self.handle_request(&request, cseq).await),
+                _ => {
+                    log::warn!("RTSP request without valid CSeq: {:?}", request);
+                    Some(error_response(None, StatusCode::BadRequest))
+                }
+            },
+            x => {
+                log::warn!("Received unexpected RTSP message: {:?}", x);
+                None
+            }
+        }
+    }
+
+    async fn handle_request(
+        &mut self,
+        request: &Request<Vec<u8>>,
+        cseq: headers::CSeq,
+    ) -> Response<Vec<u8>> {
+        match request.method() {
+            Method::Options => response(cseq, StatusCode::Ok)
+                .typed_header(&public())
+                .build(Vec::new()),
+            Method::Describe => handle_describe(cseq),
+            Method::GetParameter => response(cseq, StatusCode::Ok).build(Vec::new()),
+            Method::Setup => self.handle_setup(request, cseq).await,
+            Method::Announce => self.handle_announce(request, cseq).await,
+            Method::Play => self.handle_play(cseq).await,
+            Method::Teardown => self.handle_teardown(cseq).await,
+            x => {
+                log::warn!("Unsupported RTSP method: {:?}", x);
+                response(cseq, StatusCode::NotImplemented)
+                    .typed_header(&public())
+                    .build(Vec::new())
+            }
+        }
+    }
+
+    async fn handle_setup(
+        &mut self,
+        request: &Request<Vec<u8>>,
+        cseq: headers::CSeq,
+    ) -> Response<Vec<u8>> {
+        if !matches!(self.phase, Phase::Init | Phase::Setup) {
+            return error_response(Some(cseq), StatusCode::MethodNotValidInThisState);
+        }
+        let ping_payload = match self.state.0.sessions.lock().await.get(&self.session_id) {
+            Some(session) => session.ping_payload,
+            None => return error_response(Some(cseq), StatusCode::SessionNotFound),
+        };
+
+        let target = request.request_uri().map(|uri| uri.path()).unwrap_or("");
+        let port = if target.contains("streamid=video") {
+            VIDEO_PORT
+        } else if target.contains("streamid=audio") {
+            AUDIO_PORT
+        } else if target.contains("streamid=control") {
+            CONTROL_PORT
+        } else {
+            log::warn!("SETUP for unknown stream: {}", target);
+            return error_response(Some(cseq), StatusCode::NotFound);
+        };
+
+        self.phase = Phase::Setup;
+        let mut response = response(cseq, StatusCode::Ok)
+            .typed_header(&headers::Session::with_timeout(
+                self.session_id.simple().to_string(),
+                SESSION_TIMEOUT,
+            ))
+            .header(headers::TRANSPORT, format!("server_port={}", port));
+        if port != CONTROL_PORT {
+            response = response.header(
+                headers::HeaderName::from_static_str(PING_PAYLOAD_HEADER).unwrap(),
+                ping_payload.as_str(),
+            );
+        }
+        response.build(Vec::new())
+    }
+
+    async fn handle_announce(
+        &mut self,
+        request: &Request<Vec<u8>>,
+        cseq: headers::CSeq,
+    ) -> Response<Vec<u8>> {
+        if self.phase != Phase::Setup {
+            return error_response(Some(cseq), StatusCode::MethodNotValidInThisState);
+        }
+
+        let config = match StreamConfig::from_announce(request.body()) {
+            Ok(config) => config,
+            Err(err) => {
+                log::warn!("Invalid ANNOUNCE payload: {}", err);
+                return error_response(Some(cseq), StatusCode::BadRequest);
+            }
+        };
+
+        match self.state.0.sessions.lock().await.get_mut(&self.session_id) {
+            Some(session) => {
+                log::info!("Negotiated stream config: {:?}", config);
+                session.stream_config = Some(config);
+                self.phase = Phase::Announced;
+                response(cseq, StatusCode::Ok).build(Vec::new())
+            }
+            None => error_response(Some(cseq), StatusCode::SessionNotFound),
+        }
+    }
+
+    async fn handle_play(&mut self, cseq: headers::CSeq) -> Response<Vec<u8>> {
+        if self.phase != Phase::Announced {
+            return error_response(Some(cseq), StatusCode::MethodNotValidInThisState);
+        }
+
+        // copied out, the sessions stay unlocked while the streams start
+        let play = match self.state.0.sessions.lock().await.get(&self.session_id) {
+            Some(session) => Play::new(session),
+            None => return error_response(Some(cseq), StatusCode::SessionNotFound),
+        };
+        let play = match play {
+            Ok(play) => play,
+            Err(status) => return error_response(Some(cseq), status),
+        };
+        let shutdown = play.shutdown.clone();
+        let started = match self.start(play).await {
+            Ok(started) => started,
+            Err(err) => {
+                log::error!("{:#}", err);
+                // whatever was started already stops along with the session
+                shutdown.cancel();
+                return error_response(Some(cseq), StatusCode::InternalServerError);
+            }
+        };
+
+        let mut sessions = self.state.0.sessions.lock().await;
+        let session = match sessions.get_mut(&self.session_id) {
+            Some(session) => session,
+            None => {
+                drop(sessions);
+                // ended meanwhile, which stopped all but the compositor and the app
+                if let Some(compositor) = started.compositor {
+                    let _ = task::spawn_blocking(move || drop(compositor)).await;
+                }
+                return error_response(Some(cseq), StatusCode::SessionNotFound);
+            }
+        };
+        session.control = Some(started.control);
+        session.control_thread = Some(started.control_thread);
+        session.tasks = started.tasks;
+        session.compositor = started.compositor;
+        session.process = started.process;
+
+        self.phase = Phase::Playing;
+        response(cseq, StatusCode::Ok).build(Vec::new())
+    }
+
+    /// Starts the control stream, the media streams and the input of a session and launches
+    /// its app. Everything started stops once the session is cancelled.
+    async fn start(&self, mut play: Play) -> anyhow::Result<Started> {
+        let host = self.state.0.clone();
+        let settings = &host.settings;
+        let capture = settings.video_capture;
+        let app = host.app(play.app);
+        let stream_config = &play.stream_config;
+        let hdr = if stream_config.hdr {
+            let hdr_metadata = app.and_then(|app| app.hdr);
+            if stream_config.codec == VideoCodec::H264 {
+                log::warn!("Client asked for HDR with H.264, streaming SDR");
+                None
+            } else if !capture.supports_hdr() {
+                log::warn!("Client asked for HDR, {:?} capture only does SDR", capture);
+                None
+            } else if hdr_metadata.is_none() {
+                log::warn!("Client asked for HDR, but the app has no HDR metadata");
+                None
+            } else {
+                hdr_metadata
+            }
+        } else {
+            None
+        };
+        // a session that can't be recorded is still streamed
+        let (video_track, audio_track) = match &settings.recording {
+            Some(recording) => {
+                match recorder::start(recording, self.session_id, stream_config, play.audio_config)
+                {
+                    Ok((video, audio)) => (Some(video), Some(audio)),
+                    Err(err) => {
+                        log::error!("Failed to start recording: {:#}", err);
+                        (None, None)
+                    }
+                }
+            }
+            None => (None, None),
+        };
+
+        // the client connects right after PLAY succeeded
+        let (input, packets) = tokio::sync::mpsc::unbounded_channel();
+        let (video, video_requests) = tokio::sync::mpsc::unbounded_channel();
+        let (audio, audio_requests) = tokio::sync::mpsc::unbounded_channel();
+        let components = crate::control::Components {
+            video: Some(video),
+            audio: Some(audio),
+            input: Some(input),
+        };
+        let control_address = settings.network.control_address;
+        let (cipher, capture, shutdown) = (
+            play.control_cipher.take(),
+            play.capture.clone(),
+            play.shutdown.clone(),
+        );
+        // waits for the thread to listen
+        let (control, control_thread) = task::spawn_blocking(move || {
+            crate::control::spawn(
+                control_address,
+                CONTROL_PORT,
+                components,
+                cipher,
+                capture,
+                shutdown,
+            )
+        })
+        .await?
+        .context("Failed to start control stream")?;
+
+        // apps run on the display of the pipeline's compositor or of our own, which is
+        // spawned last as dropping either blocks until its thread exited
+        let mut display: Option<(OsString, InputSender)> = None;
+        let (source, frames) = match settings.video_capture {
+            CaptureBackend::Pipeline => {
+                let encoder = settings.video_encoder.clone().unwrap_or_else(|| {
+                    video::pipeline::default_encoder(stream_config.codec, hdr.is_some()).into()
+                });
+                let config = stream_config.clone();
+                let (pipeline, socket_name) = task::spawn_blocking(move || {
+                    let pipeline = CapturePipeline::new(&config, &encoder, hdr.as_ref())?;
+                    let socket_name = pipeline.open_display()?;
+                    Ok::<_, anyhow::Error>((pipeline, socket_name))
+                })
+                .await??;
+                display = Some((socket_name, pipeline.input().into()));
+                (Source::Pipeline { pipeline, hdr }, None)
+            }
+            CaptureBackend::Compositor => {
+                let (frames, frame_receiver) = tokio::sync::mpsc::channel(FRAME_QUEUE);
+                (Source::Frames(frame_receiver), Some(frames))
+            }
+        };
+
+        let mut tasks = Vec::new();
+        let (stream, client) = video::start(
+            self.session_id,
+            settings.network.media_address,
+            stream_config,
+            &settings.rate_limits,
+            play.validator,
+            source,
+            control.clone(),
+            video_requests,
+            video_track,
+            play.capture.clone(),
+            play.shutdown.clone(),
         )
-        .build(Vec::new())
-}
+        .await
+        .context("Failed to start video stream")?;
+        tasks.push(stream);
+        task::spawn(record_endpoint(
+            self.state.clone(),
+            self.session_id,
+            client,
+            |endpoints| &mut endpoints.video,
+        ));
 
-fn handle_describe(request: &Request<&[u8]>) -> Response<Vec<u8>> {
-    let video_params = String::new();
-    let audio_params = format!(
-        "a=fmtp:97 surround-params={}{}{}{}",
-        2, // STEREO for now
-        1,
-        1,
-        "0010"
-    );
-
-    let payload = format!("{}\n{}\n", video_params, audio_params);
-    Response::builder(rtsp_types::Version::V1_0, rtsp_types::StatusCode::Ok)
-        .typed_header::<rtsp_types::headers::CSeq>(
-            &request
-                .typed_header::<rtsp_types::headers::CSeq>()
-                .unwrap()
-                .unwrap(),
+        let compositor = match frames {
+            Some(frames) => {
+                let config = stream_config.clone();
+                let compositor = task::spawn_blocking(move || Compositor::spawn(&config, frames))
+                    .await?
+                    .context("Failed to start compositor")?;
+                display = Some((compositor.socket_name().to_owned(), compositor.input()));
+                Some(compositor)
+            }
+            None => None,
+        };
+        let gamepads = crate::input::Gamepads::new(play.controllers, control.clone());
+        tasks.push(task::spawn(crate::input::run(
+            packets,
+            gamepads,
+            display.as_ref().map(|(_, input)| input.clone()),
+            play.shutdown.clone(),
+        )));
+
+        // the session goes on without audio if it can't be captured
+        let mut app_env = Vec::new();
+        match start_audio(
+            &play,
+            self.session_id,
+            settings.network.media_address,
+            &settings.audio_capture,
+            control.clone(),
+            audio_requests,
+            audio_track,
         )
-        .build(payload.into_bytes())
+        .await
+        {
+            Ok((env, stream, client)) => {
+                app_env = env;
+                tasks.push(stream);
+                task::spawn(record_endpoint(
+                    self.state.clone(),
+                    self.session_id,
+                    client,
+                    |endpoints| &mut endpoints.audio,
+                ));
+            }
+            Err(err) => log::error!("Failed to start audio stream: {:#}", err),
+        }
+
+        let mut process = None;
+        if let (Some((socket_name, _)), Some(app)) = (&display, app) {
+            match crate::compositor::launch(socket_name, &app.command, &app_env) {
+                Ok(child) => process = Some(child),
+                Err(err) => log::error!("{:#}", err),
+            }
+        }
+
+        Ok(Started {
+            control,
+            control_thread,
+            tasks,
+            compositor,
+            process,
+        })
+    }
+
+    async fn handle_teardown(&mut self, cseq: headers::CSeq) -> Response<Vec<u8>> {
+        // ended before answering, so that its ports are free once the client hears back
+        self.state.0.end_session(self.session_id).await;
+        self.phase = Phase::Closed;
+        response(cseq, StatusCode::Ok).build(Vec::new())
+    }
+}
+
+/// What PLAY needs of a session.
+struct Play {
+    app: AppId,
+    stream_config: StreamConfig,
+    audio_config: AudioConfig,
+    controllers: u16,
+    control_cipher: Option<ControlCipher>,
+    /// The launch key and its id, unusable ones only keep the audio from streaming.
+    audio_key: anyhow::Result<([u8; 16], u32)>,
+    validator: PingValidator,
+    capture: Option<Capture>,
+    shutdown: CancellationToken,
 }
 
-fn handle_setup(request: &Request<&[u8]>) -> Response<Vec<u8>> {
-    log::warn!("{:?}", request);
-    unimplemented!()
+impl Play {
+    fn new(session: &Session) -> Result<Play, StatusCode> {
+        let stream_config = session
+            .stream_config
+            .clone()
+            .ok_or(StatusCode::MethodNotValidInThisState)?;
+        let control_cipher = if session.control_encryption {
+            match session.aes_key() {
+                Ok(key) => Some(ControlCipher::new(key)),
+                Err(err) => {
+                    log::error!("Unable to setup control stream encryption: {}", err);
+                    return Err(StatusCode::InternalServerError);
+                }
+            }
+        } else {
+            None
+        };
+        let audio_key = session
+            .aes_key()
+            .and_then(|key| Ok((key, session.key_id()?)));
+        Ok(Play {
+            app: session.app,
+            audio_config: session.audio_config,
+            controllers: session.controllers,
+            control_cipher,
+            audio_key,
+            validator: session.ping_validator(),
+            capture: session.capture.clone(),
+            shutdown: session.shutdown.clone(),
+            stream_config,
+        })
+    }
 }
 
-fn handle_annouce(request: &Request<&[u8]>) -> Response<Vec<u8>> {
-    unimplemented!()
+/// What PLAY started, stored into the session once everything runs.
+struct Started {
+    control: ControlHandle,
+    control_thread: thread::JoinHandle<()>,
+    tasks: Vec<task::JoinHandle<()>>,
+    compositor: Option<Compositor>,
+    process: Option<Child>,
 }
 
-fn handle_play(request: &Request<&[u8]>) -> Response<Vec<u8>> {
-    unimplemented!()
+/// Starts the audio stream of a session, returning the environment that routes the audio
+/// of its app into the stream along with the stream's task.
+#[allow(clippy::too_many_arguments)]
+async fn start_audio(
+    play: &Play,
+    session_id: Uuid,
+    address: IpAddr,
+    backend: &AudioBackend,
+    control: ControlHandle,
+    requests: UnboundedReceiver<AudioRequest>,
+    recording: Option<AudioTrack>,
+) -> anyhow::Result<(
+    Vec<(String, String)>,
+    task::JoinHandle<()>,
+    oneshot::Receiver<SocketAddr>,
+)> {
+    let (key, key_id) = match &play.audio_key {
+        Ok(key) => *key,
+        Err(err) => anyhow::bail!("{:#}", err),
+    };
+    let (backend, config) = (backend.clone(), play.audio_config);
+    let name = session_id.simple().to_string();
+    // a PulseAudio sink is loaded into the sound server
+    let source =
+        task::spawn_blocking(move || audio::source::create(&backend, config, &name)).await??;
+    let app_env = source.app_env();
+    let packetizer = AudioPacketizer::new(key, key_id, play.stream_config.audio_packet_duration);
+    let (stream, client) = audio::start(
+        address,
+        config,
+        packetizer,
+        source,
+        play.validator,
+        control,
+        requests,
+        recording,
+        play.capture.clone(),
+        play.shutdown.clone(),
+    )
+    .await?;
+    Ok((app_env, stream, client))
 }
 
-async fn handle_message(
-    message: Message<&[u8]>,
-    stream: &mut TcpStream,
-    state: &SharedState,
-    id: &Uuid,
-) -> Result<(), IoError> {
-    log::info!("RTSP message: {:?}", message);
-
-    let resp = match message {
-        Message::Request(request) => match request.method() {
-            Method::Options => Some(handle_options(&request)),
-            Method::Describe => Some(handle_describe(&request)),
-            Method::Setup => Some(handle_setup(&request)),
-            Method::Announce => Some(handle_annouce(&request)),
-            Method::Play => Some(handle_play(&request)),
-            x => {
-                log::error!("Unknown RTSP method: {:?}", x);
-                None
+/// Records where the client receives a stream, once it pinged the stream's port.
+async fn record_endpoint(
+    state: SharedState,
+    session_id: Uuid,
+    client: oneshot::Receiver<SocketAddr>,
+    endpoint: fn(&mut Endpoints) -> &mut Option<SocketAddr>,
+) {
+    if let Ok(addr) = client.await {
+        if let Some(session) = state.0.sessions.lock().await.get_mut(&session_id) {
+            *endpoint(&mut session.endpoints) = Some(addr);
+        }
+    }
+}
+
+fn response(cseq: headers::CSeq, status: StatusCode) -> rtsp_types::ResponseBuilder {
+    Response::builder(Version::V1_0, status).typed_header(&cseq)
+}
+
+fn error_response(cseq: Option<headers::CSeq>, status: StatusCode) -> Response<Vec<u8>> {
+    match cseq {
+        Some(cseq) => response(cseq, status).build(Vec::new()),
+        None => Response::builder(Version::V1_0, status).build(Vec::new()),
+    }
+}
+
+fn public() -> headers::Public {
+    SUPPORTED_METHODS
+        .iter()
+        .cloned()
+        .fold(headers::Public::builder(), |builder, method| {
+            builder.method(method)
+        })
+        .build()
+}
+
+fn handle_describe(cseq: headers::CSeq) -> Response<Vec<u8>> {
+    // clients pick the layout matching the channel count they asked for at launch
+    let payload = crate::audio::SUPPORTED_CONFIGS
+        .iter()
+        .map(|config| format!("a=fmtp:97 surround-params={}\n", config.surround_params()))
+        .collect::<String>();
+    response(cseq, StatusCode::Ok).build(payload.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::state::Host;
+
+    const ANNOUNCE_BODY: &str = "v=0\r\n\
+        a=x-nv-video[0].clientViewportWd:1920 \r\n\
+        a=x-nv-video[0].clientViewportHt:1080 \r\n\
+        a=x-nv-video[0].maxFPS:60 \r\n\
+        a=x-nv-vqos[0].bw.maximumBitrateKbps:20000 \r\n";
+
+    /// Starts a connection of a new session, returning the client's end of it.
+    async fn connect() -> (SharedState, Uuid, TcpStream) {
+        let (host, _) = Host::new(crate::config::test_config());
+        let id = Uuid::new_v4();
+        host.sessions.lock().await.insert(id, Session::test());
+        let state = SharedState(Arc::new(host));
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        new_client(listener, stream, state.clone(), id).await;
+        (state, id, client)
+    }
+
+    /// Reads the next response, `None` once the host closed the connection.
+    async fn receive(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<Response<Vec<u8>>> {
+        loop {
+            match Message::<Vec<u8>>::parse(buffer) {
+                Ok((Message::Response(response), len)) => {
+                    buffer.drain(..len);
+                    return Some(response);
+                }
+                Ok((message, _)) => panic!("Host sent {:?}", message),
+                Err(ParseError::Incomplete) => {}
+                Err(ParseError::Error) => panic!("Host sent a malformed response"),
+            }
+            if stream.read_buf(buffer).await.unwrap() == 0 {
+                return None;
             }
-        },
-        x => {
-            log::warn!("Receive Response?: {:?}", x);
-            None
         }
-    };
+    }
 
-    tokio::time::sleep(Duration::from_millis(500)).await;
-    if let Some(resp) = resp {
-        let mut out_buf = Vec::new();
-        if let Err(WriteError::IoError(err)) = resp.write(&mut out_buf) {
-            return Err(err);
+    fn cseq(response: &Response<Vec<u8>>) -> Option<u32> {
+        response
+            .typed_header::<headers::CSeq>()
+            .unwrap()
+            .map(u32::from)
+    }
+
+    #[tokio::test]
+    async fn test_pipelined() {
+        let (state, id, mut client) = connect().await;
+        let requests = format!(
+            "OPTIONS rtsp://127.0.0.1:48010 RTSP/1.0\r\nCSeq: 1\r\n\r\n\
+             SETUP streamid=video/0/0 RTSP/1.0\r\nCSeq: 2\r\n\r\n\
+             ANNOUNCE streamid=control/13/0 RTSP/1.0\r\nCSeq: 3\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            ANNOUNCE_BODY.len(),
+            ANNOUNCE_BODY
+        );
+        client.write_all(requests.as_bytes()).await.unwrap();
+
+        let mut buffer = Vec::new();
+        for expected in 1..=3 {
+            let response = receive(&mut client, &mut buffer).await.unwrap();
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(cseq(&response), Some(expected));
         }
+        let sessions = state.0.sessions.lock().await;
+        let config = sessions[&id].stream_config.as_ref().unwrap();
+        assert_eq!((config.width, config.height, config.fps), (1920, 1080, 60));
+    }
+
+    #[tokio::test]
+    async fn test_not_implemented() {
+        let (_state, _id, mut client) = connect().await;
+        client
+            .write_all(b"RECORD * RTSP/1.0\r\nCSeq: 1\r\n\r\n")
+            .await
+            .unwrap();
 
-        let string = std::str::from_utf8(&out_buf).unwrap();
-        log::info!("RTSP answer:\n{}", string);
+        let response = receive(&mut client, &mut Vec::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NotImplemented);
+        assert_eq!(cseq(&response), Some(1));
+        let public = response.typed_header::<headers::Public>().unwrap().unwrap();
+        assert!(public.contains(&Method::Options));
+        assert!(public.contains(&Method::Play));
+        assert!(!public.contains(&Method::Record));
+    }
+
+    #[tokio::test]
+    async fn test_bad_request() {
+        let (_state, _id, mut client) = connect().await;
+        let mut buffer = Vec::new();
 
-        stream.write_all(&out_buf).await?;
-        stream.flush().await?;
+        // the connection survives a request it can't answer to
+        client
+            .write_all(b"OPTIONS * RTSP/1.0\r\n\r\nOPTIONS * RTSP/1.0\r\nCSeq: 2\r\n\r\n")
+            .await
+            .unwrap();
+        let response = receive(&mut client, &mut buffer).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert_eq!(cseq(&response), None);
+        let response = receive(&mut client, &mut buffer).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(cseq(&response), Some(2));
+
+        // but not garbage
+        client.write_all(b"\x00\x01 garbage\r\n\r\n").await.unwrap();
+        let response = receive(&mut client, &mut buffer).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert!(receive(&mut client, &mut buffer).await.is_none());
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_method_not_valid_in_this_state() {
+        let (state, id, mut client) = connect().await;
+        let mut buffer = Vec::new();
+
+        let requests = format!(
+            "PLAY / RTSP/1.0\r\nCSeq: 1\r\n\r\n\
+             ANNOUNCE streamid=control/13/0 RTSP/1.0\r\nCSeq: 2\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            ANNOUNCE_BODY.len(),
+            ANNOUNCE_BODY
+        );
+        client.write_all(requests.as_bytes()).await.unwrap();
+        for expected in 1..=2 {
+            let response = receive(&mut client, &mut buffer).await.unwrap();
+            assert_eq!(response.status(), StatusCode::MethodNotValidInThisState);
+            assert_eq!(cseq(&response), Some(expected));
+        }
+        assert!(state.0.sessions.lock().await[&id].stream_config.is_none());
+
+        // no more SETUP once the streams are announced
+        let requests = format!(
+            "SETUP streamid=audio/0/0 RTSP/1.0\r\nCSeq: 3\r\n\r\n\
+             ANNOUNCE streamid=control/13/0 RTSP/1.0\r\nCSeq: 4\r\n\
+             Content-Length: {}\r\n\r\n{}\
+             SETUP streamid=video/0/0 RTSP/1.0\r\nCSeq: 5\r\n\r\n",
+            ANNOUNCE_BODY.len(),
+            ANNOUNCE_BODY
+        );
+        client.write_all(requests.as_bytes()).await.unwrap();
+        let statuses = [
+            StatusCode::Ok,
+            StatusCode::Ok,
+            StatusCode::MethodNotValidInThisState,
+        ];
+        for status in statuses {
+            let response = receive(&mut client, &mut buffer).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
 }
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+/// Video codecs a client may request via `x-nv-vqos[0].bitStreamFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+}
+
+/// Stream parameters negotiated through the ANNOUNCE request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub packet_size: usize,
+    pub max_bitrate_kbps: u32,
+    pub min_fec_packets: u32,
+    pub codec: VideoCodec,
+    pub audio_channels: u8,
+    pub audio_channel_mask: u32,
+    pub audio_packet_duration: u32,
+    /// Whether the client asked for HDR10, which takes HEVC or AV1.
+    pub hdr: bool,
+}
+
+/// Parses the `a=<key>:<value>` attribute lines of an SDP payload.
+pub fn parse_attributes(payload: &[u8]) -> Result<HashMap<String, String>> {
+    let payload = std::str::from_utf8(payload).context("SDP payload is not valid UTF-8")?;
+    Ok(payload
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("a="))
+        .filter_map(|attr| attr.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect())
+}
+
+fn attribute<T: std::str::FromStr>(
+    attributes: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>> {
+    attributes
+        .get(key)
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", key, value))
+        })
+        .transpose()
+}
+
+impl StreamConfig {
+    pub fn from_announce(payload: &[u8]) -> Result<StreamConfig> {
+        let attributes = parse_attributes(payload)?;
+
+        let codec = match attribute::<u32>(&attributes, "x-nv-vqos[0].bitStreamFormat")? {
+            None | Some(0) => VideoCodec::H264,
+            Some(1) => VideoCodec::Hevc,
+            Some(2) => VideoCodec::Av1,
+            Some(x) => anyhow::bail!("Unknown bitstream format: {}", x),
+        };
+
+        Ok(StreamConfig {
+            width: attribute(&attributes, "x-nv-video[0].clientViewportWd")?
+                .context("Missing viewport width")?,
+            height: attribute(&attributes, "x-nv-video[0].clientViewportHt")?
+                .context("Missing viewport height")?,
+            fps: attribute(&attributes, "x-nv-video[0].maxFPS")?.context("Missing framerate")?,
+            packet_size: attribute(&attributes, "x-nv-video[0].packetSize")?.unwrap_or(1024),
+            max_bitrate_kbps: attribute(&attributes, "x-nv-vqos[0].bw.maximumBitrateKbps")?
+                .context("Missing maximum bitrate")?,
+            min_fec_packets: attribute(&attributes, "x-nv-vqos[0].fec.minRequiredFecPackets")?
+                .unwrap_or(0),
+            codec,
+            audio_channels: attribute(&attributes, "x-nv-audio.surround.numChannels")?.unwrap_or(2),
+            audio_channel_mask: attribute(&attributes, "x-nv-audio.surround.channelMask")?
+                .unwrap_or(0x3),
+            audio_packet_duration: attribute(&attributes, "x-nv-aqos.packetDuration")?.unwrap_or(5),
+            hdr: attribute::<u32>(&attributes, "x-nv-video[0].dynamicRangeMode")?.unwrap_or(0) == 1,
+        })
+    }
+}
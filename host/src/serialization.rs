@@ -109,12 +109,3 @@ pub mod key {
         }
     }
 }
-
-pub fn get_default_interface() -> default_net::Interface {
-    default_net::get_default_interface().unwrap_or_else(|_| {
-        default_net::get_interfaces()
-            .into_iter()
-            .next()
-            .expect("Failed to get network Interface")
-    })
-}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as SyncMutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use default_net::Interface;
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
+use tokio::sync::{oneshot, Mutex, Notify};
+use uuid::Uuid;
+
+use crate::{
+    audio, config::save_config, net, portmap, recorder, video, App, AppId, Client, ClientInfo,
+    Config, Session,
+};
+
+/// How long the config is left alone after a change before it is written, so pairing
+/// steps in quick succession are saved once.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// What the host serves from, split into parts that are locked on their own.
+///
+/// Identity, settings and apps never change while running and aren't locked at all. Clients
+/// are only locked for as long as a handler reads or updates them, never across a PIN
+/// prompt or another await, and written out by the [`Persister`].
+#[derive(Debug)]
+pub struct Host {
+    pub identity: Identity,
+    pub settings: Settings,
+    pub apps: Vec<App>,
+    pub clients: ClientRegistry,
+    pub sessions: Mutex<HashMap<Uuid, Session>>,
+    /// Set by the port mapper while the router forwards to the host.
+    pub external: portmap::ExternalAddress,
+}
+
+impl Host {
+    /// Splits a loaded config, the returned persister writes it back when clients change.
+    pub fn new(config: Config) -> (Host, Persister) {
+        let clients = ClientRegistry {
+            clients: Arc::new(SyncMutex::new(config.known_clients.clone())),
+            changed: Arc::new(Notify::new()),
+            unsaved: Arc::new(AtomicBool::new(false)),
+        };
+        let persister = Persister {
+            clients: clients.clients.clone(),
+            changed: clients.changed.clone(),
+            unsaved: clients.unsaved.clone(),
+            config: config.clone(),
+        };
+        let host = Host {
+            identity: Identity {
+                unique_id: config.unique_id,
+                server_cert: config.server_cert,
+                server_key: config.server_key,
+                hostname: config.hostname,
+                interface: net::advertised_interface(config.network.interface.as_deref()),
+                http_port: config.http_port,
+                https_port: config.https_port,
+            },
+            settings: Settings {
+                network: config.network,
+                max_sessions: config.max_sessions,
+                video_capture: config.video_capture,
+                video_encoder: config.video_encoder,
+                audio_capture: config.audio_capture,
+                rate_limits: config.rate_limits,
+                recording: config.recording,
+                port_mapping: config.port_mapping,
+                capture_directory: config.capture_directory,
+            },
+            apps: config.apps,
+            clients,
+            sessions: Mutex::new(HashMap::new()),
+            external: Default::default(),
+        };
+        (host, persister)
+    }
+
+    pub fn app(&self, id: AppId) -> Option<&App> {
+        self.apps.iter().find(|app| app.id == id)
+    }
+
+    /// Ends the session `id`, unless it already ended.
+    pub async fn end_session(&self, id: Uuid) {
+        // not held while ending, which takes until the app exited
+        let session = self.sessions.lock().await.remove(&id);
+        if let Some(session) = session {
+            log::info!("Ending session {}", id);
+            session.end().await;
+        }
+    }
+
+    /// Ends every session, for shutdown.
+    pub async fn end_sessions(&self) {
+        let sessions: Vec<_> = self.sessions.lock().await.drain().collect();
+        for (id, session) in sessions {
+            log::info!("Ending session {}", id);
+            session.end().await;
+        }
+    }
+}
+
+/// Who the host is to clients.
+#[derive(Debug)]
+pub struct Identity {
+    pub unique_id: Uuid,
+    pub server_cert: X509,
+    pub server_key: PKey<Private>,
+    pub hostname: String,
+    /// Advertised to clients, there may be none on a host without network.
+    pub interface: Option<Interface>,
+    pub http_port: u16,
+    pub https_port: u16,
+}
+
+/// How sessions are streamed.
+#[derive(Debug)]
+pub struct Settings {
+    pub network: net::NetworkConfig,
+    pub max_sessions: usize,
+    pub video_capture: video::CaptureBackend,
+    /// Encoder elements of the video pipeline, VA-API for the negotiated codec if unset.
+    pub video_encoder: Option<String>,
+    pub audio_capture: audio::AudioBackend,
+    pub rate_limits: video::RateLimits,
+    pub recording: Option<recorder::RecordingConfig>,
+    pub port_mapping: Option<portmap::PortMappingConfig>,
+    pub capture_directory: Option<PathBuf>,
+}
+
+/// The clients that paired or are pairing, every change is persisted.
+#[derive(Debug)]
+pub struct ClientRegistry {
+    clients: Arc<SyncMutex<HashMap<ClientInfo, Client>>>,
+    changed: Arc<Notify>,
+    unsaved: Arc<AtomicBool>,
+}
+
+impl ClientRegistry {
+    fn lock(&self) -> MutexGuard<'_, HashMap<ClientInfo, Client>> {
+        // a handler panicking mid update leaves nothing half written worth refusing
+        self.clients.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn get(&self, info: &ClientInfo) -> Option<Client> {
+        self.lock().get(info).cloned()
+    }
+
+    pub fn contains(&self, info: &ClientInfo) -> bool {
+        self.lock().contains_key(info)
+    }
+
+    /// Changes the clients and schedules saving them. Mustn't block, the registry is locked
+    /// while `update` runs.
+    pub fn update<R>(&self, update: impl FnOnce(&mut HashMap<ClientInfo, Client>) -> R) -> R {
+        let result = update(&mut self.lock());
+        self.schedule_save();
+        result
+    }
+
+    /// Like [`ClientRegistry::update`], but only schedules saving the clients if `update`
+    /// succeeded.
+    pub fn try_update<R, E>(
+        &self,
+        update: impl FnOnce(&mut HashMap<ClientInfo, Client>) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let result = update(&mut self.lock());
+        if result.is_ok() {
+            self.schedule_save();
+        }
+        result
+    }
+
+    fn schedule_save(&self) {
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    pub fn remove(&self, info: &ClientInfo) -> Option<Client> {
+        self.update(|clients| clients.remove(info))
+    }
+}
+
+/// Writes the config whenever clients changed, off the tasks serving requests.
+#[derive(Debug)]
+pub struct Persister {
+    clients: Arc<SyncMutex<HashMap<ClientInfo, Client>>>,
+    changed: Arc<Notify>,
+    unsaved: Arc<AtomicBool>,
+    /// The config as loaded, of which only the clients change.
+    config: Config,
+}
+
+impl Persister {
+    /// Saves changes until `stop` fires, then saves what is still unsaved.
+    pub async fn run(mut self, mut stop: oneshot::Receiver<()>) {
+        loop {
+            tokio::select! {
+                _ = self.changed.notified() => {},
+                _ = &mut stop => break,
+            }
+            // changes made while waiting are picked up by this save
+            tokio::select! {
+                _ = tokio::time::sleep(SAVE_DELAY) => {},
+                _ = &mut stop => break,
+            }
+            self.save().await;
+        }
+        if self.unsaved.load(Ordering::SeqCst) {
+            self.save().await;
+        }
+    }
+
+    async fn save(&mut self) {
+        // cleared first, a change while saving is saved again
+        self.unsaved.store(false, Ordering::SeqCst);
+        self.config.known_clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+
+        let config = self.config.clone();
+        match tokio::task::spawn_blocking(move || save_config(&config)).await {
+            Ok(Ok(())) => {
+                log::debug!("Saved config");
+                return;
+            }
+            Ok(Err(err)) => log::error!("Failed to save config: {:#}", err),
+            Err(err) => log::error!("Failed to save config: {}", err),
+        }
+        // retried with the next change, or on shutdown
+        self.unsaved.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    io,
+    net::TcpListener,
+    os::unix::io::{FromRawFd, RawFd},
+    time::Duration,
+};
+
+use sd_notify::NotifyState;
+
+/// Listeners systemd opened for the host, by the `FileDescriptorName=` of their socket.
+///
+/// Only the HTTP ports are taken over, those of RTSP and the streams are opened for each
+/// session.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    pub http: Option<TcpListener>,
+    pub https: Option<TcpListener>,
+}
+
+/// Takes the sockets passed by socket activation, none if the host wasn't activated.
+pub fn take_listeners() -> io::Result<Listeners> {
+    // unset, so that apps launched by sessions don't think they were activated
+    let fds = sd_notify::listen_fds_with_names(true)?;
+    Ok(listeners(fds))
+}
+
+fn listeners(fds: impl Iterator<Item = (RawFd, String)>) -> Listeners {
+    let mut listeners = Listeners::default();
+    for (fd, name) in fds {
+        // systemd passes the sockets to this process only, nothing else owns them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let slot = match name.as_str() {
+            "http" => &mut listeners.http,
+            "https" => &mut listeners.https,
+            _ => {
+                log::warn!("Closing unknown socket {:?} passed by systemd", name);
+                continue;
+            }
+        };
+        log::info!("Listening on {} socket passed by systemd", name);
+        *slot = Some(listener);
+    }
+    listeners
+}
+
+/// Tells systemd about the host, nothing happens unless it runs as a `Type=notify` service.
+pub fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        log::warn!("Failed to notify systemd: {}", err);
+    }
+}
+
+/// Keeps the watchdog of the service fed, for as long as the runtime is responsive.
+pub async fn watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // twice as often as required, as sd_watchdog_enabled(3) recommends
+    let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+    loop {
+        interval.tick().await;
+        notify(&[NotifyState::Watchdog]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::IntoRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_listeners() {
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = http.local_addr().unwrap();
+        let unknown = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let listeners = listeners(
+            [
+                (http.into_raw_fd(), "http".to_owned()),
+                (unknown.into_raw_fd(), "metrics".to_owned()),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(listeners.http.unwrap().local_addr().unwrap(), address);
+        assert!(listeners.https.is_none());
+    }
+}
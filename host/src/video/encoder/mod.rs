@@ -0,0 +1,321 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use super::{AccessUnit, FrameType};
+use crate::rtsp::{StreamConfig, VideoCodec};
+
+#[cfg(feature = "openh264")]
+mod openh264;
+#[cfg(feature = "openh264")]
+pub use self::openh264::OpenH264Encoder;
+
+/// Parameters an encoder is (re)configured with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub bitrate_kbps: u32,
+    pub codec: VideoCodec,
+}
+
+impl EncoderConfig {
+    pub fn new(config: &StreamConfig) -> EncoderConfig {
+        EncoderConfig {
+            width: config.width,
+            height: config.height,
+            fps: config.fps,
+            bitrate_kbps: config.max_bitrate_kbps,
+            codec: config.codec,
+        }
+    }
+}
+
+/// An uncompressed frame in I420, the planes stored back to back without padding.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// Presentation time relative to the start of the stream.
+    pub timestamp: Duration,
+}
+
+impl Frame {
+    /// Size of the chroma planes, rounded up for odd dimensions.
+    fn chroma_size(width: u32, height: u32) -> (usize, usize) {
+        (width.div_ceil(2) as usize, height.div_ceil(2) as usize)
+    }
+
+    /// Bytes of a frame with the given dimensions.
+    pub fn size(width: u32, height: u32) -> usize {
+        let (chroma_width, chroma_height) = Frame::chroma_size(width, height);
+        width as usize * height as usize + 2 * chroma_width * chroma_height
+    }
+
+    /// A black frame of the given size.
+    pub fn black(width: u32, height: u32, timestamp: Duration) -> Frame {
+        let luma = width as usize * height as usize;
+        let mut data = vec![128; Frame::size(width, height)];
+        data[..luma].fill(16);
+        Frame {
+            width,
+            height,
+            data,
+            timestamp,
+        }
+    }
+
+    /// Converts tightly packed RGBA pixels with BT.601 limited range coefficients.
+    ///
+    /// `flipped` pixels start with the bottom row, like OpenGL reads them back.
+    pub fn from_rgba(
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+        flipped: bool,
+        timestamp: Duration,
+    ) -> Frame {
+        let (w, h) = (width as usize, height as usize);
+        let (chroma_width, chroma_height) = Frame::chroma_size(width, height);
+        let mut data = vec![0; Frame::size(width, height)];
+        let (luma, chroma) = data.split_at_mut(w * h);
+        let (u, v) = chroma.split_at_mut(chroma_width * chroma_height);
+
+        let pixel = |x: usize, y: usize| {
+            let row = if flipped { h - 1 - y } else { y };
+            let offset = (row * w + x) * 4;
+            (
+                rgba[offset] as i32,
+                rgba[offset + 1] as i32,
+                rgba[offset + 2] as i32,
+            )
+        };
+        for y in 0..h {
+            for x in 0..w {
+                let (r, g, b) = pixel(x, y);
+                luma[y * w + x] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            }
+        }
+        // average each 2x2 block, odd dimensions repeat their last row or column
+        for cy in 0..chroma_height {
+            for cx in 0..chroma_width {
+                let (mut r, mut g, mut b) = (0, 0, 0);
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let p = pixel((cx * 2 + dx).min(w - 1), (cy * 2 + dy).min(h - 1));
+                    r += p.0;
+                    g += p.1;
+                    b += p.2;
+                }
+                let (r, g, b) = (r / 4, g / 4, b / 4);
+                u[cy * chroma_width + cx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v[cy * chroma_width + cx] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        }
+
+        Frame {
+            width,
+            height,
+            data,
+            timestamp,
+        }
+    }
+
+    pub fn y(&self) -> &[u8] {
+        &self.data[..self.width as usize * self.height as usize]
+    }
+
+    pub fn u(&self) -> &[u8] {
+        let luma = self.width as usize * self.height as usize;
+        let (chroma_width, chroma_height) = Frame::chroma_size(self.width, self.height);
+        &self.data[luma..luma + chroma_width * chroma_height]
+    }
+
+    pub fn v(&self) -> &[u8] {
+        let luma = self.width as usize * self.height as usize;
+        let (chroma_width, chroma_height) = Frame::chroma_size(self.width, self.height);
+        &self.data[luma + chroma_width * chroma_height..]
+    }
+}
+
+/// Counters an encoder keeps over its lifetime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderStats {
+    pub frames: u64,
+    pub idr_frames: u64,
+    /// Frames the rate control decided not to encode.
+    pub skipped_frames: u64,
+    pub bytes: u64,
+}
+
+impl EncoderStats {
+    pub fn record(&mut self, unit: Option<&AccessUnit>) {
+        match unit {
+            Some(unit) => {
+                self.frames += 1;
+                self.bytes += unit.data.len() as u64;
+                if unit.frame_type == FrameType::Idr {
+                    self.idr_frames += 1;
+                }
+            }
+            None => self.skipped_frames += 1,
+        }
+    }
+}
+
+/// A video encoder producing the access units sent to clients.
+pub trait VideoEncoder: Send {
+    fn name(&self) -> &'static str;
+
+    /// Applies a new configuration, which may restart the stream with an IDR frame.
+    fn configure(&mut self, config: &EncoderConfig) -> Result<()>;
+
+    /// Encodes a frame, `None` if the encoder skipped it.
+    fn encode(&mut self, frame: &Frame) -> Result<Option<AccessUnit>>;
+
+    /// Makes the next frame an IDR frame.
+    fn request_idr(&mut self);
+
+    /// Stops referencing the given frames, which the client failed to decode.
+    ///
+    /// Encoders without control over their reference frames recover with an IDR frame.
+    fn invalidate_reference_frames(&mut self, first_frame: i64, last_frame: i64) {
+        log::debug!(
+            "Invalidating frames {} to {} with an IDR frame",
+            first_frame,
+            last_frame
+        );
+        self.request_idr();
+    }
+
+    fn stats(&self) -> EncoderStats;
+}
+
+/// Creates the best available encoder for the given configuration.
+pub fn create(config: &EncoderConfig) -> Result<Box<dyn VideoEncoder>> {
+    match config.codec {
+        #[cfg(feature = "openh264")]
+        VideoCodec::H264 => Ok(Box::new(OpenH264Encoder::new(config)?)),
+        codec => bail!("No encoder available for {:?}", codec),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_planes() {
+        let frame = Frame::black(5, 3, Duration::ZERO);
+        assert_eq!(frame.data.len(), 15 + 2 * 3 * 2);
+        assert_eq!(frame.y().len(), 15);
+        assert!(frame.y().iter().all(|b| *b == 16));
+        assert_eq!(frame.u().len(), 6);
+        assert_eq!(frame.v().len(), 6);
+        assert!(frame.v().iter().all(|b| *b == 128));
+    }
+
+    #[test]
+    fn test_from_rgba() {
+        // a red top row above a white bottom row
+        let mut rgba = Vec::new();
+        for _ in 0..3 {
+            rgba.extend([255, 0, 0, 255]);
+        }
+        for _ in 0..3 {
+            rgba.extend([255, 255, 255, 255]);
+        }
+
+        let frame = Frame::from_rgba(3, 2, &rgba, false, Duration::ZERO);
+        assert_eq!(frame.y(), [82, 82, 82, 235, 235, 235]);
+        // chroma averages to pink
+        assert_eq!(frame.u(), [109, 109]);
+        assert_eq!(frame.v(), [184, 184]);
+
+        let flipped = Frame::from_rgba(3, 2, &rgba, true, Duration::ZERO);
+        assert_eq!(flipped.y(), [235, 235, 235, 82, 82, 82]);
+        assert_eq!(flipped.u(), frame.u());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = EncoderStats::default();
+        let unit = AccessUnit {
+            data: vec![0; 100],
+            timestamp: Duration::ZERO,
+            frame_type: FrameType::Idr,
+        };
+        stats.record(Some(&unit));
+        stats.record(None);
+        assert_eq!(
+            stats,
+            EncoderStats {
+                frames: 1,
+                idr_frames: 1,
+                skipped_frames: 1,
+                bytes: 100,
+            }
+        );
+    }
+
+    #[cfg(feature = "openh264")]
+    #[test]
+    fn test_software_encoder() {
+        let config = EncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 30,
+            bitrate_kbps: 1000,
+            codec: VideoCodec::H264,
+        };
+        let mut encoder = create(&config).unwrap();
+
+        let mut units = Vec::new();
+        for i in 0..5 {
+            let frame = Frame::black(320, 240, Duration::from_millis(33 * i));
+            units.extend(encoder.encode(&frame).unwrap());
+        }
+        encoder.request_idr();
+        let frame = Frame::black(320, 240, Duration::from_millis(33 * 5));
+        let last = encoder.encode(&frame).unwrap().unwrap();
+
+        assert_eq!(units[0].frame_type, FrameType::Idr);
+        // annex b start code
+        assert_eq!(&units[0].data[..4], [0, 0, 0, 1]);
+        assert_eq!(last.frame_type, FrameType::Idr);
+        assert_eq!(last.timestamp, Duration::from_millis(165));
+        assert!(encoder.stats().idr_frames >= 2);
+    }
+
+    #[cfg(feature = "openh264")]
+    #[test]
+    fn test_software_encoder_bitrate() {
+        let mut config = EncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 30,
+            bitrate_kbps: 1000,
+            codec: VideoCodec::H264,
+        };
+        let mut encoder = create(&config).unwrap();
+        let frame = Frame::black(320, 240, Duration::ZERO);
+        encoder.encode(&frame).unwrap();
+        let idr_frames = encoder.stats().idr_frames;
+
+        // only a new stream starts with an IDR frame, a new bitrate doesn't
+        config.bitrate_kbps = 500;
+        encoder.configure(&config).unwrap();
+        for i in 1..4 {
+            let frame = Frame::black(320, 240, Duration::from_millis(33 * i));
+            encoder.encode(&frame).unwrap();
+        }
+        assert_eq!(encoder.stats().idr_frames, idr_frames);
+
+        config.width = 640;
+        encoder.configure(&config).unwrap();
+        let frame = Frame::black(640, 240, Duration::from_millis(132));
+        let unit = encoder.encode(&frame).unwrap().unwrap();
+        assert_eq!(unit.frame_type, FrameType::Idr);
+    }
+}
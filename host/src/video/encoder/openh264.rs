@@ -0,0 +1,164 @@
+use std::{os::raw::c_int, ptr::addr_of_mut};
+
+use anyhow::{bail, Context, Result};
+use openh264::{
+    encoder::{Encoder, EncoderConfig as OpenH264Config, FrameType as OpenH264FrameType},
+    formats::YUVSource,
+};
+use openh264_sys2::{SBitrateInfo, ENCODER_OPTION_BITRATE, SPATIAL_LAYER_ALL};
+
+use super::{EncoderConfig, EncoderStats, Frame, VideoEncoder};
+use crate::{
+    rtsp::VideoCodec,
+    video::{AccessUnit, FrameType},
+};
+
+/// Software H.264 encoding through Cisco's openh264, available without any GPU.
+pub struct OpenH264Encoder {
+    encoder: Encoder,
+    config: EncoderConfig,
+    stats: EncoderStats,
+}
+
+fn create_encoder(config: &EncoderConfig) -> Result<Encoder> {
+    if config.codec != VideoCodec::H264 {
+        bail!("openh264 can't encode {:?}", config.codec);
+    }
+    let openh264_config = OpenH264Config::new(config.width, config.height)
+        .set_bitrate_bps(config.bitrate_kbps.saturating_mul(1000))
+        .max_frame_rate(config.fps as f32)
+        .enable_skip_frame(true);
+    Encoder::with_config(openh264_config).context("Failed to create openh264 encoder")
+}
+
+impl OpenH264Encoder {
+    pub fn new(config: &EncoderConfig) -> Result<OpenH264Encoder> {
+        Ok(OpenH264Encoder {
+            encoder: create_encoder(config)?,
+            config: *config,
+            stats: EncoderStats::default(),
+        })
+    }
+
+    /// Changes the target bitrate of the running encoder, which keeps its reference frames.
+    fn set_bitrate(&mut self, bitrate_kbps: u32) -> Result<()> {
+        let mut bitrate = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_ALL,
+            iBitrate: bitrate_kbps.saturating_mul(1000).min(c_int::MAX as u32) as c_int,
+        };
+        // openh264 copies the option before returning
+        let result = unsafe {
+            self.encoder
+                .raw_api()
+                .set_option(ENCODER_OPTION_BITRATE, addr_of_mut!(bitrate).cast())
+        };
+        if result != 0 {
+            bail!(
+                "openh264 refused a bitrate of {} kbps ({})",
+                bitrate_kbps,
+                result
+            );
+        }
+        Ok(())
+    }
+}
+
+struct FrameSource<'a>(&'a Frame);
+
+impl YUVSource for FrameSource<'_> {
+    fn width(&self) -> i32 {
+        self.0.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.0.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        self.0.y()
+    }
+
+    fn u(&self) -> &[u8] {
+        self.0.u()
+    }
+
+    fn v(&self) -> &[u8] {
+        self.0.v()
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.0.width as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.0.width.div_ceil(2) as i32
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.0.width.div_ceil(2) as i32
+    }
+}
+
+impl VideoEncoder for OpenH264Encoder {
+    fn name(&self) -> &'static str {
+        "openh264"
+    }
+
+    fn configure(&mut self, config: &EncoderConfig) -> Result<()> {
+        if *config == self.config {
+            return Ok(());
+        }
+        let same_stream = EncoderConfig {
+            bitrate_kbps: self.config.bitrate_kbps,
+            ..*config
+        } == self.config;
+        if same_stream {
+            self.set_bitrate(config.bitrate_kbps)?;
+        } else {
+            // anything but the bitrate needs a new encoder, which starts with an IDR frame
+            self.encoder = create_encoder(config)?;
+        }
+        self.config = *config;
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &Frame) -> Result<Option<AccessUnit>> {
+        if (frame.width, frame.height) != (self.config.width, self.config.height) {
+            bail!(
+                "Frame is {}x{}, encoder expects {}x{}",
+                frame.width,
+                frame.height,
+                self.config.width,
+                self.config.height
+            );
+        }
+
+        let bitstream = self
+            .encoder
+            .encode(&FrameSource(frame))
+            .context("Failed to encode frame")?;
+        let frame_type = match bitstream.frame_type() {
+            OpenH264FrameType::IDR => FrameType::Idr,
+            OpenH264FrameType::Skip | OpenH264FrameType::Invalid => {
+                self.stats.record(None);
+                return Ok(None);
+            }
+            _ => FrameType::P,
+        };
+        let unit = AccessUnit {
+            data: bitstream.to_vec(),
+            timestamp: frame.timestamp,
+            frame_type,
+        };
+        self.stats.record(Some(&unit));
+        Ok(Some(unit))
+    }
+
+    fn request_idr(&mut self) {
+        self.encoder.force_intra_frame();
+    }
+
+    fn stats(&self) -> EncoderStats {
+        self.stats
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+/// Static HDR10 metadata of an app, describing its mastering display (SMPTE ST 2086) and
+/// content light levels (CTA-861.3).
+///
+/// Fields left out of the config default to a BT.2020 display peaking at 1000 cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HdrMetadata {
+    /// Red, green and blue chromaticity coordinates in units of 0.00002.
+    pub display_primaries: [(u16, u16); 3],
+    /// In units of 0.00002.
+    pub white_point: (u16, u16),
+    /// In cd/m².
+    pub max_display_luminance: u16,
+    /// In units of 0.0001 cd/m².
+    pub min_display_luminance: u16,
+    /// MaxCLL in cd/m².
+    pub max_content_light_level: u16,
+    /// MaxFALL in cd/m².
+    pub max_frame_average_light_level: u16,
+}
+
+impl Default for HdrMetadata {
+    fn default() -> HdrMetadata {
+        HdrMetadata {
+            display_primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+            // D65
+            white_point: (15635, 16450),
+            max_display_luminance: 1000,
+            min_display_luminance: 50,
+            max_content_light_level: 1000,
+            max_frame_average_light_level: 400,
+        }
+    }
+}
+
+impl HdrMetadata {
+    /// The `mastering-display-info` caps field encoders write their SEI or OBU from.
+    pub fn mastering_display_info(&self) -> String {
+        let [red, green, blue] = self.display_primaries;
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}:{}:{}:{}",
+            red.0,
+            red.1,
+            green.0,
+            green.1,
+            blue.0,
+            blue.1,
+            self.white_point.0,
+            self.white_point.1,
+            // GStreamer wants both luminances in units of 0.0001 cd/m²
+            self.max_display_luminance as u32 * 10_000,
+            self.min_display_luminance,
+        )
+    }
+
+    /// The `content-light-level` caps field.
+    pub fn content_light_level(&self) -> String {
+        format!(
+            "{}:{}",
+            self.max_content_light_level, self.max_frame_average_light_level
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps_fields() {
+        let metadata = HdrMetadata::default();
+        assert_eq!(
+            metadata.mastering_display_info(),
+            "35400:14600:8500:39850:6550:2300:15635:16450:10000000:50"
+        );
+        assert_eq!(metadata.content_light_level(), "1000:400");
+    }
+}
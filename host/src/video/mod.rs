@@ -0,0 +1,512 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::mpsc as std_mpsc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::{self, JoinHandle},
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    capture::{Capture as ProtocolCapture, MediaStream},
+    control::{ControlHandle, ControlMessage, VideoRequest, TERMINATION_FAILURE},
+    ping::PingValidator,
+    recorder::VideoTrack,
+    rtsp::StreamConfig,
+};
+
+pub mod encoder;
+mod hdr;
+mod packetizer;
+pub mod pipeline;
+mod rate;
+#[cfg(test)]
+mod reassembler;
+pub use self::encoder::{EncoderConfig, Frame, VideoEncoder};
+pub use self::hdr::HdrMetadata;
+pub use self::packetizer::{PacketizeError, Packetizer, PacketizerConfig, DEFAULT_FEC_PERCENTAGE};
+pub use self::pipeline::CapturePipeline;
+pub use self::rate::{Decision, RateController, RateLimits, Reason};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    P,
+    Idr,
+}
+
+/// A single encoded frame.
+#[derive(Debug, Clone)]
+pub struct AccessUnit {
+    pub data: Vec<u8>,
+    /// Presentation time relative to the start of the stream.
+    pub timestamp: Duration,
+    pub frame_type: FrameType,
+}
+
+pub fn init(address: IpAddr) -> std::io::Result<UdpSocket> {
+    crate::net::bind_udp(SocketAddr::new(address, crate::rtsp::VIDEO_PORT))
+}
+
+/// Sends packetized frames to a client over UDP.
+#[derive(Debug)]
+pub struct VideoSender {
+    socket: UdpSocket,
+    client: Option<SocketAddr>,
+    packetizer: Packetizer,
+    /// Receives a copy of every frame sent.
+    recording: Option<VideoTrack>,
+    /// Receives the headers of every packet sent.
+    protocol: Option<ProtocolCapture>,
+}
+
+impl VideoSender {
+    pub fn new(socket: UdpSocket, config: PacketizerConfig) -> VideoSender {
+        VideoSender {
+            socket,
+            client: None,
+            packetizer: Packetizer::new(config),
+            recording: None,
+            protocol: None,
+        }
+    }
+
+    /// Records the frames sent from now on.
+    pub fn record(&mut self, track: VideoTrack) {
+        self.recording = Some(track);
+    }
+
+    /// Captures the headers of the packets sent from now on.
+    pub fn capture_protocol(&mut self, capture: ProtocolCapture) {
+        self.protocol = Some(capture);
+    }
+
+    /// Waits for the client to ping the video port, which tells us where to send the stream.
+    pub async fn wait_for_client(&mut self, validator: &PingValidator) -> Result<SocketAddr> {
+        let addr = validator.wait_for_client(&self.socket, "Video").await?;
+        self.client = Some(addr);
+        Ok(addr)
+    }
+
+    pub fn packetizer_mut(&mut self) -> &mut Packetizer {
+        &mut self.packetizer
+    }
+
+    pub async fn send(&mut self, unit: &AccessUnit) -> Result<()> {
+        let client = self.client.context("No video client connected yet")?;
+        for packet in self.packetizer.packetize(unit)? {
+            if let Some(capture) = &self.protocol {
+                capture.media(MediaStream::Video, &packet, packetizer::HEADER_LEN);
+            }
+            self.socket.send_to(&packet, client).await?;
+        }
+        if let Some(track) = &mut self.recording {
+            track.push(unit);
+        }
+        Ok(())
+    }
+}
+
+/// How sessions capture their screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureBackend {
+    /// Every session runs its own compositor, its frames are encoded in software.
+    #[default]
+    Compositor,
+    /// A `waylanddisplaysrc` pipeline, encoding with the configured GStreamer elements.
+    Pipeline,
+}
+
+impl CaptureBackend {
+    /// Whether sessions can be captured and encoded in 10 bit, the software encoder of the
+    /// compositor only does 8 bit H.264.
+    pub fn supports_hdr(self) -> bool {
+        self == CaptureBackend::Pipeline
+    }
+}
+
+/// Where the video of a session comes from.
+pub enum Source {
+    /// A `waylanddisplaysrc` pipeline, capturing in HDR10 if the app has metadata.
+    Pipeline {
+        pipeline: CapturePipeline,
+        hdr: Option<HdrMetadata>,
+    },
+    /// Frames rendered by the session's compositor.
+    Frames(Receiver<Frame>),
+}
+
+enum Capture {
+    Pipeline(CapturePipeline),
+    Frames(Receiver<Frame>, Box<dyn VideoEncoder>, EncoderConfig),
+}
+
+/// Changes the encoder thread applies before its next frame.
+enum EncoderRequest {
+    Idr,
+    InvalidateReferenceFrames { first_frame: i64, last_frame: i64 },
+    Bitrate(u32),
+}
+
+/// A running encoder, the target of the client's requests.
+enum Encoding {
+    Pipeline(CapturePipeline),
+    Thread(std_mpsc::Sender<EncoderRequest>, thread::JoinHandle<()>),
+}
+
+impl Encoding {
+    fn send(&self, request: EncoderRequest) {
+        match self {
+            Encoding::Pipeline(pipeline) => match request {
+                EncoderRequest::Idr | EncoderRequest::InvalidateReferenceFrames { .. } => {
+                    pipeline.request_idr()
+                }
+                EncoderRequest::Bitrate(bitrate_kbps) => pipeline.set_bitrate(bitrate_kbps),
+            },
+            // the thread outlives this as long as its units are received
+            Encoding::Thread(requests, _) => {
+                let _ = requests.send(request);
+            }
+        }
+    }
+}
+
+/// Starts capturing and streaming the video of a session.
+///
+/// Nothing is captured before the client pinged the video port, the returned receiver
+/// resolves to its address then. Failures of the capture, or a client that never pings, end
+/// the session by cancelling `shutdown` after telling the client through `control`. The
+/// bitrate and FEC adapt to the client's loss reports within `limits`.
+///
+/// The stream stops once `shutdown` is cancelled, the returned task finishes after the
+/// port and the encoder were released.
+#[allow(clippy::too_many_arguments)]
+pub async fn start(
+    session_id: Uuid,
+    address: IpAddr,
+    config: &StreamConfig,
+    limits: &RateLimits,
+    validator: PingValidator,
+    source: Source,
+    control: ControlHandle,
+    requests: UnboundedReceiver<VideoRequest>,
+    recording: Option<VideoTrack>,
+    protocol: Option<ProtocolCapture>,
+    shutdown: CancellationToken,
+) -> Result<(JoinHandle<()>, oneshot::Receiver<SocketAddr>)> {
+    let socket = match init(address) {
+        Ok(socket) => socket,
+        Err(err) => {
+            // stopping a pipeline joins its compositor
+            let _ = task::spawn_blocking(move || drop(source)).await;
+            return Err(err).context("Failed to bind video port");
+        }
+    };
+    let rate = RateController::new(limits, config, DEFAULT_FEC_PERCENTAGE);
+    let hdr = match &source {
+        Source::Pipeline { hdr, .. } => *hdr,
+        Source::Frames(_) => None,
+    };
+    let capture = {
+        let (config, bitrate_kbps) = (config.clone(), rate.bitrate_kbps());
+        // opening encoders and devices blocks
+        task::spawn_blocking(move || create_capture(&config, source, bitrate_kbps)).await??
+    };
+    let mut sender = VideoSender::new(socket, PacketizerConfig::new(config, rate.fec_percentage()));
+    if let Some(track) = recording {
+        sender.record(track);
+    }
+    if let Some(capture) = protocol {
+        sender.capture_protocol(capture);
+    }
+    let (client, client_addr) = oneshot::channel();
+    let mut stream = Stream {
+        session_id,
+        sender,
+        rate,
+        hdr,
+    };
+    let task = tokio::spawn(async move {
+        let result = async {
+            let waited = tokio::select! {
+                addr = stream.sender.wait_for_client(&validator) => addr.map(Some),
+                _ = shutdown.cancelled() => Ok(None),
+            };
+            let addr = match waited {
+                Ok(Some(addr)) => addr,
+                other => {
+                    let _ = task::spawn_blocking(move || drop(capture)).await;
+                    return other.map(|_| ());
+                }
+            };
+            let _ = client.send(addr);
+            stream
+                .run(capture, requests, control.clone(), &shutdown)
+                .await
+        };
+        if let Err(err) = result.await {
+            log::error!("Video stream failed: {:#}", err);
+            let _ = control.send(ControlMessage::Termination {
+                error_code: TERMINATION_FAILURE,
+            });
+            shutdown.cancel();
+        }
+    });
+    Ok((task, client_addr))
+}
+
+fn create_capture(config: &StreamConfig, source: Source, bitrate_kbps: u32) -> Result<Capture> {
+    Ok(match source {
+        Source::Pipeline { pipeline, .. } => {
+            pipeline.set_bitrate(bitrate_kbps);
+            Capture::Pipeline(pipeline)
+        }
+        Source::Frames(frames) => {
+            let encoder_config = EncoderConfig {
+                bitrate_kbps,
+                ..EncoderConfig::new(config)
+            };
+            let encoder = encoder::create(&encoder_config)?;
+            log::info!("Encoding video with {}", encoder.name());
+            Capture::Frames(frames, encoder, encoder_config)
+        }
+    })
+}
+
+/// Encodes frames on a thread of its own, the returned encoding takes its requests.
+///
+/// Requests are applied before the next frame, the thread ends along with either channel.
+fn spawn_encoder(
+    mut encoder: Box<dyn VideoEncoder>,
+    mut config: EncoderConfig,
+    mut frames: Receiver<Frame>,
+    units: UnboundedSender<AccessUnit>,
+    on_error: impl FnOnce(anyhow::Error) + Send + 'static,
+) -> Result<Encoding> {
+    let (requests, pending) = std_mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("video-encoder".into())
+        .spawn(move || {
+            while let Some(frame) = frames.blocking_recv() {
+                for request in pending.try_iter() {
+                    match request {
+                        EncoderRequest::Idr => encoder.request_idr(),
+                        EncoderRequest::InvalidateReferenceFrames {
+                            first_frame,
+                            last_frame,
+                        } => encoder.invalidate_reference_frames(first_frame, last_frame),
+                        EncoderRequest::Bitrate(bitrate_kbps) => {
+                            config.bitrate_kbps = bitrate_kbps;
+                            if let Err(err) = encoder.configure(&config) {
+                                on_error(err.context("Failed to change the bitrate"));
+                                return;
+                            }
+                        }
+                    }
+                }
+                match encoder.encode(&frame) {
+                    Ok(Some(unit)) => {
+                        if units.send(unit).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        on_error(err);
+                        return;
+                    }
+                }
+            }
+        })?;
+    Ok(Encoding::Thread(requests, thread))
+}
+
+/// The video stream of a session once its capture is set up.
+struct Stream {
+    session_id: Uuid,
+    sender: VideoSender,
+    rate: RateController,
+    /// Announced to the client once it starts the stream.
+    hdr: Option<HdrMetadata>,
+}
+
+impl Stream {
+    async fn run(
+        mut self,
+        capture: Capture,
+        mut requests: UnboundedReceiver<VideoRequest>,
+        control: ControlHandle,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let (units_sender, mut units) = unbounded_channel();
+        let on_error = {
+            let control = control.clone();
+            let shutdown = shutdown.clone();
+            move |err: anyhow::Error| {
+                log::error!("{:#}", err);
+                let _ = control.send(ControlMessage::Termination {
+                    error_code: TERMINATION_FAILURE,
+                });
+                shutdown.cancel();
+            }
+        };
+        let encoding = match capture {
+            Capture::Pipeline(pipeline) => match pipeline.start(units_sender, on_error) {
+                Ok(()) => Encoding::Pipeline(pipeline),
+                Err(err) => {
+                    let _ = task::spawn_blocking(move || drop(pipeline)).await;
+                    return Err(err);
+                }
+            },
+            Capture::Frames(frames, encoder, config) => {
+                spawn_encoder(encoder, config, frames, units_sender, on_error)?
+            }
+        };
+
+        let result = self
+            .forward(&encoding, &mut units, &mut requests, &control, shutdown)
+            .await;
+        // the encoder thread notices at its next frame, or once the frames close
+        drop(units);
+        // either way a thread is joined, the pipeline's compositor as it stops
+        match encoding {
+            Encoding::Thread(requests, thread) => {
+                drop(requests);
+                let _ = task::spawn_blocking(move || thread.join()).await;
+            }
+            Encoding::Pipeline(pipeline) => {
+                let _ = task::spawn_blocking(move || drop(pipeline)).await;
+            }
+        }
+        result
+    }
+
+    /// Sends encoded frames and handles the client's requests until the session ends.
+    async fn forward(
+        &mut self,
+        encoding: &Encoding,
+        units: &mut UnboundedReceiver<AccessUnit>,
+        requests: &mut UnboundedReceiver<VideoRequest>,
+        control: &ControlHandle,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        loop {
+            tokio::select! {
+                unit = units.recv() => match unit {
+                    Some(unit) => self.sender.send(&unit).await?,
+                    None => return Ok(()),
+                },
+                request = requests.recv() => match request {
+                    Some(request) => self.handle_request(encoding, control, request),
+                    // the control stream closed
+                    None => return Ok(()),
+                },
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        encoding: &Encoding,
+        control: &ControlHandle,
+        request: VideoRequest,
+    ) {
+        match request {
+            VideoRequest::Start => {
+                if let Some(metadata) = self.hdr {
+                    log::info!("Session {}: streaming in HDR10", self.session_id);
+                    let _ = control.send(ControlMessage::HdrMode {
+                        enabled: true,
+                        metadata: Some(metadata),
+                    });
+                }
+            }
+            VideoRequest::Idr => encoding.send(EncoderRequest::Idr),
+            VideoRequest::InvalidateReferenceFrames {
+                first_frame,
+                last_frame,
+            } => encoding.send(EncoderRequest::InvalidateReferenceFrames {
+                first_frame,
+                last_frame,
+            }),
+            VideoRequest::LossStats {
+                lost_frames,
+                interval_ms,
+            } => {
+                let interval = Duration::from_millis(interval_ms.max(0) as u64);
+                if let Some(decision) = self.rate.on_loss_stats(lost_frames, interval) {
+                    log::info!("Session {}: {}", self.session_id, decision);
+                    self.sender
+                        .packetizer_mut()
+                        .set_fec_percentage(decision.fec_percentage);
+                    encoding.send(EncoderRequest::Bitrate(decision.bitrate_kbps));
+                }
+            }
+            VideoRequest::RoundTripTime(rtt) => self.rate.on_rtt(rtt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reassembler::Reassembler;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_loopback_with_loss() {
+        let config = PacketizerConfig {
+            packet_size: 1024,
+            fec_percentage: DEFAULT_FEC_PERCENTAGE,
+            min_fec_packets: 0,
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(socket.local_addr().unwrap()).await.unwrap();
+
+        let mut sender = VideoSender::new(socket, config);
+        client.send(b"PING").await.unwrap();
+        let validator = PingValidator::new(
+            crate::ping::PingPayload::generate().unwrap(),
+            "127.0.0.1".parse().unwrap(),
+        );
+        sender.wait_for_client(&validator).await.unwrap();
+
+        let unit = AccessUnit {
+            data: (0..20_000).map(|i| (i * 7) as u8).collect(),
+            timestamp: Duration::from_millis(16),
+            frame_type: FrameType::Idr,
+        };
+        sender.send(&unit).await.unwrap();
+
+        let mut reassembler = Reassembler::new(config);
+        // as much as clients receive, anything larger is cut off
+        let mut buf = vec![0; config.packet_size + 16];
+        let mut received = 0;
+        let frame = loop {
+            let len = client.recv(&mut buf).await.unwrap();
+            received += 1;
+            // drop every fifth packet, parity has to make up for it
+            if received % 5 == 0 {
+                continue;
+            }
+            if let Some(frame) = reassembler.push(&buf[..len]) {
+                break frame;
+            }
+        };
+
+        assert_eq!(frame.frame_type, FrameType::Idr);
+        assert_eq!(&frame.data[..unit.data.len()], &unit.data[..]);
+        assert!(frame.data[unit.data.len()..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,356 @@
+use reed_solomon_erasure::{galois_8::ReedSolomon, Error as RsError};
+
+use super::{AccessUnit, FrameType};
+use crate::rtsp::StreamConfig;
+
+pub const RTP_HEADER_LEN: usize = 12;
+/// Reserved bytes following the RTP header, announced by the extension flag.
+pub const RTP_EXTENSION_LEN: usize = 4;
+/// Size of `NV_VIDEO_PACKET`.
+pub const VIDEO_PACKET_HEADER_LEN: usize = 16;
+pub const HEADER_LEN: usize = RTP_HEADER_LEN + RTP_EXTENSION_LEN + VIDEO_PACKET_HEADER_LEN;
+/// Short frame header prepended to every access unit.
+pub const FRAME_HEADER_LEN: usize = 8;
+
+pub const FLAG_CONTAINS_PIC_DATA: u8 = 0x1;
+pub const FLAG_EOF: u8 = 0x2;
+pub const FLAG_SOF: u8 = 0x4;
+
+const RTP_VERSION_WITH_EXTENSION: u8 = 0x90;
+const MULTI_FEC_FLAGS: u8 = 0x10;
+/// Data and parity shards of a single FEC block.
+const MAX_SHARDS: usize = 255;
+/// The block index is transmitted as two bits.
+const MAX_FEC_BLOCKS: usize = 4;
+
+pub const DEFAULT_FEC_PERCENTAGE: u8 = 20;
+
+#[derive(Debug)]
+pub enum PacketizeError {
+    /// The frame doesn't fit into the maximum amount of FEC blocks.
+    FrameTooLarge(usize),
+    Fec(RsError),
+}
+
+impl std::fmt::Display for PacketizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketizeError::FrameTooLarge(len) => write!(f, "Frame too large: {} bytes", len),
+            PacketizeError::Fec(err) => write!(f, "Failed to generate FEC shards: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for PacketizeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketizerConfig {
+    /// Negotiated `packetSize`, each datagram is `RTP_HEADER_LEN + RTP_EXTENSION_LEN` bytes
+    /// larger, which is all clients receive.
+    pub packet_size: usize,
+    pub fec_percentage: u8,
+    pub min_fec_packets: u8,
+}
+
+impl PacketizerConfig {
+    pub fn new(config: &StreamConfig, fec_percentage: u8) -> PacketizerConfig {
+        PacketizerConfig {
+            packet_size: config.packet_size,
+            fec_percentage,
+            min_fec_packets: config.min_fec_packets.min(u8::MAX as u32) as u8,
+        }
+    }
+
+    /// Size of every datagram on the wire.
+    pub fn datagram_len(&self) -> usize {
+        self.packet_size + RTP_HEADER_LEN + RTP_EXTENSION_LEN
+    }
+
+    /// Bytes of the frame carried in each datagram.
+    pub fn payload_len(&self) -> usize {
+        self.datagram_len() - HEADER_LEN
+    }
+}
+
+/// FEC parameters of one block, `parity_shards` always derives from `percentage` the same
+/// way the client computes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecParams {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub percentage: u8,
+}
+
+impl FecParams {
+    pub fn parity_shards(data_shards: usize, percentage: u8) -> usize {
+        (data_shards * percentage as usize).div_ceil(100)
+    }
+
+    fn new(data_shards: usize, percentage: u8, min_fec_packets: u8) -> FecParams {
+        let mut percentage = percentage as usize;
+        if percentage > 0 || min_fec_packets > 0 {
+            let min_fec_packets = min_fec_packets as usize;
+            let mut min_percentage = min_fec_packets * 100 / data_shards;
+            while FecParams::parity_shards(data_shards, min_percentage as u8) < min_fec_packets
+                && min_percentage < u8::MAX as usize
+            {
+                min_percentage += 1;
+            }
+            percentage = percentage.max(min_percentage).min(u8::MAX as usize);
+        }
+        let percentage = percentage as u8;
+
+        FecParams {
+            data_shards,
+            parity_shards: FecParams::parity_shards(data_shards, percentage),
+            percentage,
+        }
+    }
+}
+
+/// Splits access units into GameStream video packets.
+#[derive(Debug)]
+pub struct Packetizer {
+    config: PacketizerConfig,
+    sequence_number: u16,
+    frame_index: u32,
+}
+
+impl Packetizer {
+    pub fn new(config: PacketizerConfig) -> Packetizer {
+        Packetizer {
+            config,
+            sequence_number: 0,
+            frame_index: 0,
+        }
+    }
+
+    pub fn config(&self) -> &PacketizerConfig {
+        &self.config
+    }
+
+    pub fn set_fec_percentage(&mut self, fec_percentage: u8) {
+        self.config.fec_percentage = fec_percentage;
+    }
+
+    /// Finds the amount of data shards per block using as few FEC blocks as possible.
+    fn data_shards_per_block(&self, data_shards: usize) -> Option<usize> {
+        (1..=MAX_FEC_BLOCKS).find_map(|blocks| {
+            let per_block = data_shards.div_ceil(blocks);
+            let params = FecParams::new(
+                per_block,
+                self.config.fec_percentage,
+                self.config.min_fec_packets,
+            );
+            (params.data_shards + params.parity_shards <= MAX_SHARDS).then_some(per_block)
+        })
+    }
+
+    /// Returns all datagrams for the given access unit, data shards first.
+    pub fn packetize(&mut self, unit: &AccessUnit) -> Result<Vec<Vec<u8>>, PacketizeError> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + unit.data.len());
+        frame.push(0x01);
+        frame.extend_from_slice(&0u16.to_le_bytes());
+        frame.push(match unit.frame_type {
+            FrameType::P => 1,
+            FrameType::Idr => 2,
+        });
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&unit.data);
+
+        let payload_len = self.config.payload_len();
+        let chunks = frame.chunks(payload_len).collect::<Vec<_>>();
+        let per_block = self
+            .data_shards_per_block(chunks.len())
+            .ok_or(PacketizeError::FrameTooLarge(unit.data.len()))?;
+        let blocks = chunks.chunks(per_block).collect::<Vec<_>>();
+        let last_block = blocks.len() - 1;
+
+        let timestamp = (unit.timestamp.as_micros() * 90 / 1000) as u32;
+        let mut packets = Vec::new();
+        for (block_index, block) in blocks.iter().enumerate() {
+            let fec = FecParams::new(
+                block.len(),
+                self.config.fec_percentage,
+                self.config.min_fec_packets,
+            );
+
+            let mut shards = block
+                .iter()
+                .map(|chunk| {
+                    let mut shard = chunk.to_vec();
+                    shard.resize(payload_len, 0);
+                    shard
+                })
+                .collect::<Vec<_>>();
+            if fec.parity_shards > 0 {
+                shards.resize(fec.data_shards + fec.parity_shards, vec![0; payload_len]);
+                ReedSolomon::new(fec.data_shards, fec.parity_shards)
+                    .and_then(|rs| rs.encode(&mut shards))
+                    .map_err(PacketizeError::Fec)?;
+            }
+
+            for (shard_index, shard) in shards.iter().enumerate() {
+                let mut flags = 0;
+                if shard_index < fec.data_shards {
+                    flags |= FLAG_CONTAINS_PIC_DATA;
+                    if block_index == 0 && shard_index == 0 {
+                        flags |= FLAG_SOF;
+                    }
+                    if block_index == last_block && shard_index == fec.data_shards - 1 {
+                        flags |= FLAG_EOF;
+                    }
+                }
+
+                let mut packet = Vec::with_capacity(self.config.datagram_len());
+                self.write_header(
+                    &mut packet,
+                    timestamp,
+                    flags,
+                    ((block_index as u8) << 4) | ((last_block as u8) << 6),
+                    shard_index,
+                    &fec,
+                );
+                packet.extend_from_slice(shard);
+                packets.push(packet);
+            }
+        }
+
+        self.frame_index = self.frame_index.wrapping_add(1);
+        Ok(packets)
+    }
+
+    fn write_header(
+        &mut self,
+        packet: &mut Vec<u8>,
+        timestamp: u32,
+        flags: u8,
+        multi_fec_blocks: u8,
+        shard_index: usize,
+        fec: &FecParams,
+    ) {
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        // RTP header
+        packet.push(RTP_VERSION_WITH_EXTENSION);
+        packet.push(0);
+        packet.extend_from_slice(&sequence_number.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        packet.extend_from_slice(&[0; RTP_EXTENSION_LEN]);
+
+        // NV_VIDEO_PACKET
+        let fec_info = ((shard_index as u32) << 12)
+            | ((fec.data_shards as u32) << 22)
+            | ((fec.percentage as u32) << 4);
+        packet.extend_from_slice(&((sequence_number as u32) << 8).to_le_bytes());
+        packet.extend_from_slice(&self.frame_index.to_le_bytes());
+        packet.push(flags);
+        packet.push(0);
+        packet.push(MULTI_FEC_FLAGS);
+        packet.push(multi_fec_blocks);
+        packet.extend_from_slice(&fec_info.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(fec_percentage: u8) -> PacketizerConfig {
+        PacketizerConfig {
+            packet_size: 1024,
+            fec_percentage,
+            min_fec_packets: 0,
+        }
+    }
+
+    fn unit(len: usize) -> AccessUnit {
+        AccessUnit {
+            data: (0..len).map(|i| i as u8).collect(),
+            timestamp: Duration::from_millis(1000),
+            frame_type: FrameType::Idr,
+        }
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let mut packetizer = Packetizer::new(config(0));
+        let packets = packetizer.packetize(&unit(100)).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        // what moonlight-common-c reads, it receives packetSize + MAX_RTP_HEADER_SIZE (16)
+        let packet = &packets[0];
+        assert_eq!(packet.len(), 1024 + 16);
+        #[rustfmt::skip]
+        let header = [
+            // RTP: version 2 with extension, sequence number, 90 kHz timestamp, SSRC
+            0x90, 0x00, 0x00, 0x00, 0x00, 0x01, 0x5f, 0x90, 0x00, 0x00, 0x00, 0x00,
+            // reserved
+            0x00, 0x00, 0x00, 0x00,
+            // NV_VIDEO_PACKET: streamPacketIndex, frameIndex
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // flags PIC_DATA | EOF | SOF, extraFlags, multiFecFlags, multiFecBlocks
+            0x07, 0x00, 0x10, 0x00,
+            // fecInfo: shard 0 of 1 data shard at 0%
+            0x00, 0x00, 0x40, 0x00,
+        ];
+        assert_eq!(&packet[..32], &header);
+        // frame header, then the frame, in the remaining packetSize - 16 bytes
+        assert_eq!(&packet[32..36], &[0x01, 0, 0, 2]);
+        assert_eq!(&packet[40..140], &unit(100).data[..]);
+        assert!(packet[140..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let mut packetizer = Packetizer::new(config(20));
+        let first = packetizer.packetize(&unit(5000)).unwrap();
+        let second = packetizer.packetize(&unit(5000)).unwrap();
+
+        let sequence_numbers = first
+            .iter()
+            .chain(second.iter())
+            .map(|packet| u16::from_be_bytes([packet[2], packet[3]]))
+            .collect::<Vec<_>>();
+        assert!(sequence_numbers.windows(2).all(|w| w[1] == w[0] + 1));
+        assert_eq!(u32::from_le_bytes(second[0][20..24].try_into().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_fec_params() {
+        // 20% of 10 data shards
+        assert_eq!(FecParams::new(10, 20, 0).parity_shards, 2);
+        // min packets raise the percentage so the client derives the same amount
+        let fec = FecParams::new(3, 20, 2);
+        assert_eq!(fec.parity_shards, 2);
+        assert_eq!(FecParams::parity_shards(3, fec.percentage), 2);
+        // disabled fec
+        assert_eq!(FecParams::new(10, 0, 0).parity_shards, 0);
+    }
+
+    #[test]
+    fn test_multiple_blocks() {
+        let mut packetizer = Packetizer::new(config(20));
+        let payload_len = config(20).payload_len();
+        let packets = packetizer.packetize(&unit(300 * payload_len)).unwrap();
+        // clients read the block from bits 4-5 and the last block from bits 6-7
+        assert_eq!(packets[0][27], 0b0100_0000);
+        assert_eq!(packets.last().unwrap()[27], 0b0101_0000);
+        assert!(packets
+            .iter()
+            .all(|packet| packet[27] == 0b0100_0000 || packet[27] == 0b0101_0000));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut packetizer = Packetizer::new(config(20));
+        let payload_len = config(20).payload_len();
+        assert!(matches!(
+            packetizer.packetize(&unit(1024 * payload_len)),
+            Err(PacketizeError::FrameTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,440 @@
+use std::{ffi::OsString, thread, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{AccessUnit, FrameType, HdrMetadata};
+use crate::{
+    compositor::RemoteInput,
+    input::keymap,
+    rtsp::{StreamConfig, VideoCodec},
+};
+
+/// Name of the custom upstream events `waylanddisplaysrc` takes input from.
+const INPUT_STRUCTURE: &str = "remote-input";
+
+/// Encoder used unless the config names another, VA-API keeps the frames on the GPU.
+pub const DEFAULT_H264_ENCODER: &str = "vapostproc ! vah264enc";
+pub const DEFAULT_HEVC_ENCODER: &str = "vapostproc ! vah265enc";
+pub const DEFAULT_AV1_ENCODER: &str = "vapostproc ! vaav1enc";
+/// HDR sessions convert to 10 bit before encoding, H.264 has no 10 bit profile clients decode.
+pub const DEFAULT_HEVC_HDR_ENCODER: &str =
+    "vapostproc ! video/x-raw(memory:VAMemory),format=P010_10LE ! vah265enc";
+pub const DEFAULT_AV1_HDR_ENCODER: &str =
+    "vapostproc ! video/x-raw(memory:VAMemory),format=P010_10LE ! vaav1enc";
+
+pub fn default_encoder(codec: VideoCodec, hdr: bool) -> &'static str {
+    match (codec, hdr) {
+        (VideoCodec::H264, _) => DEFAULT_H264_ENCODER,
+        (VideoCodec::Hevc, false) => DEFAULT_HEVC_ENCODER,
+        (VideoCodec::Hevc, true) => DEFAULT_HEVC_HDR_ENCODER,
+        (VideoCodec::Av1, false) => DEFAULT_AV1_ENCODER,
+        (VideoCodec::Av1, true) => DEFAULT_AV1_HDR_ENCODER,
+    }
+}
+
+/// The parser and caps of an encoded stream, one access unit per buffer.
+pub fn encoded_format(codec: VideoCodec) -> (&'static str, &'static str) {
+    match codec {
+        // repeat parameter sets with every IDR frame, so clients can recover from any of them
+        VideoCodec::H264 => (
+            "h264parse config-interval=-1",
+            "video/x-h264,stream-format=byte-stream,alignment=au",
+        ),
+        VideoCodec::Hevc => (
+            "h265parse config-interval=-1",
+            "video/x-h265,stream-format=byte-stream,alignment=au",
+        ),
+        VideoCodec::Av1 => (
+            "av1parse",
+            "video/x-av1,stream-format=obu-stream,alignment=tu",
+        ),
+    }
+}
+
+/// Builds the `gst-launch` style description of a session's pipeline.
+///
+/// `encoder` is a fragment of one or more elements taking DMA-BUF frames of the compositor.
+/// With `hdr` the compositor renders 10 bit PQ frames, the encoders pick the metadata up
+/// from the caps of their input.
+pub fn description(config: &StreamConfig, encoder: &str, hdr: Option<&HdrMetadata>) -> String {
+    let (parser, caps) = encoded_format(config.codec);
+    let (raw_caps, caps) = match hdr {
+        Some(metadata) => (
+            format!(
+                ",format=BGR10A2_LE,colorimetry=bt2100-pq,mastering-display-info={},\
+                 content-light-level={}",
+                metadata.mastering_display_info(),
+                metadata.content_light_level()
+            ),
+            match config.codec {
+                VideoCodec::Hevc => format!("{},profile=main-10", caps),
+                _ => caps.to_string(),
+            },
+        ),
+        None => (String::new(), caps.to_string()),
+    };
+    format!(
+        "waylanddisplaysrc name=src ! \
+         video/x-raw(memory:DMABuf),width={},height={},framerate={}/1{} ! \
+         {} ! {} ! {} ! \
+         appsink name=sink sync=false max-buffers=2 drop=false emit-signals=false",
+        config.width, config.height, config.fps, raw_caps, encoder, parser, caps,
+    )
+}
+
+/// The event structures `waylanddisplaysrc` takes `input` as, it resolves keysyms against the
+/// layout of its own seat.
+fn input_structures(input: &RemoteInput) -> Vec<gst::Structure> {
+    let builder = gst::Structure::builder(INPUT_STRUCTURE);
+    let structure = match *input {
+        RemoteInput::PointerMotion { dx, dy } => builder
+            .field("type", "pointer-motion")
+            .field("dx", dx)
+            .field("dy", dy),
+        RemoteInput::PointerMotionAbsolute { x, y } => builder
+            .field("type", "pointer-motion-absolute")
+            .field("x", x)
+            .field("y", y),
+        RemoteInput::PointerButton { button, pressed } => builder
+            .field("type", "pointer-button")
+            .field("button", button)
+            .field("pressed", pressed),
+        RemoteInput::PointerAxis {
+            horizontal,
+            vertical,
+        } => builder
+            .field("type", "pointer-axis")
+            .field("horizontal", horizontal)
+            .field("vertical", vertical),
+        RemoteInput::Key {
+            keycode,
+            keysym,
+            pressed,
+        } => builder
+            .field("type", "key")
+            .field("keycode", keycode)
+            .field("keysym", keysym)
+            .field("pressed", pressed),
+        RemoteInput::Text(ref text) => {
+            return text
+                .chars()
+                .flat_map(|c| [true, false].map(|pressed| (keymap::char_to_keysym(c), pressed)))
+                .map(|(keysym, pressed)| {
+                    gst::Structure::builder(INPUT_STRUCTURE)
+                        .field("type", "keysym")
+                        .field("keysym", keysym)
+                        .field("pressed", pressed)
+                        .build()
+                })
+                .collect();
+        }
+    };
+    vec![structure.build()]
+}
+
+/// Feeds input into the compositor of a [`CapturePipeline`].
+#[derive(Debug, Clone)]
+pub struct PipelineInput {
+    src: gst::Pad,
+}
+
+impl PipelineInput {
+    pub fn send(&self, input: &RemoteInput) {
+        for structure in input_structures(input) {
+            // dropped once the pipeline stopped
+            self.src
+                .send_event(gst::event::CustomUpstream::new(structure));
+        }
+    }
+}
+
+/// Captures a session's compositor through `waylanddisplaysrc` and encodes its frames.
+///
+/// The pipeline stops once this is dropped.
+pub struct CapturePipeline {
+    pipeline: gst::Pipeline,
+    src: gst::Element,
+    sink: gst_app::AppSink,
+}
+
+impl CapturePipeline {
+    pub fn new(
+        config: &StreamConfig,
+        encoder: &str,
+        hdr: Option<&HdrMetadata>,
+    ) -> Result<CapturePipeline> {
+        gst::init().context("Failed to initialize GStreamer")?;
+
+        let description = description(config, encoder, hdr);
+        log::debug!("Video pipeline: {}", description);
+        let pipeline = gst::parse_launch(&description)
+            .context("Failed to create video pipeline")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow!("Video pipeline is not a pipeline"))?;
+        let src = pipeline
+            .by_name("src")
+            .context("Video pipeline has no waylanddisplaysrc")?;
+        let sink = pipeline
+            .by_name("sink")
+            .and_then(|sink| sink.downcast::<gst_app::AppSink>().ok())
+            .context("Video pipeline has no appsink")?;
+
+        Ok(CapturePipeline {
+            pipeline,
+            src,
+            sink,
+        })
+    }
+
+    /// Starts the pipeline's compositor without capturing yet, returning the
+    /// `WAYLAND_DISPLAY` of the session's apps.
+    pub fn open_display(&self) -> Result<OsString> {
+        // the element binds its socket as it starts
+        self.pipeline
+            .set_state(gst::State::Paused)
+            .context("Failed to start the compositor of the video pipeline")?;
+        self.src
+            .property::<Option<String>>("socket-name")
+            .map(OsString::from)
+            .context("waylanddisplaysrc has no Wayland socket")
+    }
+
+    /// Where to send the client's keyboard and mouse input.
+    pub fn input(&self) -> PipelineInput {
+        PipelineInput {
+            src: self
+                .src
+                .static_pad("src")
+                .expect("waylanddisplaysrc has a src pad"),
+        }
+    }
+
+    /// Starts capturing, encoded frames are handed to `units`.
+    ///
+    /// `on_error` is called from another thread once the pipeline failed or ended.
+    pub fn start(
+        &self,
+        units: UnboundedSender<AccessUnit>,
+        on_error: impl FnOnce(anyhow::Error) + Send + 'static,
+    ) -> Result<()> {
+        self.sink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let unit = AccessUnit {
+                        data: map.as_slice().to_vec(),
+                        timestamp: buffer
+                            .pts()
+                            .map(|pts| Duration::from_nanos(pts.nseconds()))
+                            .unwrap_or_default(),
+                        frame_type: if buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                            FrameType::P
+                        } else {
+                            FrameType::Idr
+                        },
+                    };
+                    // the session ended, stop pulling frames
+                    units.send(unit).map_err(|_| gst::FlowError::Flushing)?;
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        let bus = self.pipeline.bus().context("Video pipeline has no bus")?;
+        thread::Builder::new()
+            .name("video-pipeline".into())
+            .spawn(move || {
+                for message in bus.iter_timed(gst::ClockTime::NONE) {
+                    match message.view() {
+                        gst::MessageView::Error(err) => {
+                            on_error(anyhow!(
+                                "Video pipeline error from {}: {} ({:?})",
+                                err.src()
+                                    .map(|src| src.path_string().to_string())
+                                    .unwrap_or_default(),
+                                err.error(),
+                                err.debug()
+                            ));
+                            return;
+                        }
+                        gst::MessageView::Eos(_) => {
+                            on_error(anyhow!("Video pipeline ended"));
+                            return;
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+
+        self.pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to start video pipeline")?;
+        Ok(())
+    }
+
+    /// Asks the encoder to make the next frame an IDR frame.
+    pub fn request_idr(&self) {
+        let event = gst_video::UpstreamForceKeyUnitEvent::builder()
+            .all_headers(true)
+            .build();
+        if !self.sink.send_event(event) {
+            log::warn!("Video pipeline ignored IDR request");
+        }
+    }
+
+    /// Changes the target bitrate of the pipeline's encoders.
+    ///
+    /// Only encoders with a `bitrate` property in kbps are supported, which covers VA-API,
+    /// NVENC and x264.
+    pub fn set_bitrate(&self, bitrate_kbps: u32) {
+        let encoders = self
+            .pipeline
+            .iterate_recurse()
+            .into_iter()
+            .flatten()
+            .filter(|element| {
+                element
+                    .factory()
+                    .is_some_and(|factory| factory.has_type(gst::ElementFactoryType::VIDEO_ENCODER))
+            });
+        for encoder in encoders {
+            match encoder.find_property("bitrate") {
+                Some(property) if property.value_type() == u32::static_type() => {
+                    encoder.set_property("bitrate", bitrate_kbps)
+                }
+                _ => log::warn!("Can't change the bitrate of {}", encoder.name()),
+            }
+        }
+    }
+}
+
+impl Drop for CapturePipeline {
+    fn drop(&mut self) {
+        if let Err(err) = self.pipeline.set_state(gst::State::Null) {
+            log::warn!("Failed to stop video pipeline: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(codec: VideoCodec) -> StreamConfig {
+        StreamConfig {
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            packet_size: 1024,
+            max_bitrate_kbps: 20_000,
+            min_fec_packets: 0,
+            codec,
+            audio_channels: 2,
+            audio_channel_mask: 0x3,
+            audio_packet_duration: 5,
+            hdr: false,
+        }
+    }
+
+    #[test]
+    fn test_description() {
+        let h264 = description(&config(VideoCodec::H264), "x264enc", None);
+        assert!(h264.starts_with("waylanddisplaysrc name=src ! "));
+        assert!(
+            h264.contains("video/x-raw(memory:DMABuf),width=1920,height=1080,framerate=60/1 ! ")
+        );
+        assert!(h264.contains(" ! x264enc ! h264parse config-interval=-1 ! "));
+        assert!(h264.contains("appsink name=sink"));
+
+        let hevc = description(&config(VideoCodec::Hevc), DEFAULT_HEVC_ENCODER, None);
+        assert!(hevc.contains("vah265enc ! h265parse"));
+        assert!(hevc.contains("video/x-h265,stream-format=byte-stream"));
+        assert!(!hevc.contains("bt2100-pq"));
+    }
+
+    #[test]
+    fn test_hdr_description() {
+        let config = StreamConfig {
+            hdr: true,
+            ..config(VideoCodec::Hevc)
+        };
+        let metadata = HdrMetadata::default();
+        let hevc = description(
+            &config,
+            default_encoder(config.codec, config.hdr),
+            Some(&metadata),
+        );
+        assert!(hevc.contains(
+            "framerate=60/1,format=BGR10A2_LE,colorimetry=bt2100-pq,\
+             mastering-display-info=35400:14600:8500:39850:6550:2300:15635:16450:10000000:50,\
+             content-light-level=1000:400 ! "
+        ));
+        assert!(hevc.contains("format=P010_10LE ! vah265enc ! h265parse"));
+        assert!(hevc.contains("alignment=au,profile=main-10 ! appsink"));
+    }
+
+    #[test]
+    fn test_input_structure() {
+        gst::init().unwrap();
+
+        let motion =
+            input_structures(&RemoteInput::PointerMotionAbsolute { x: 0.25, y: 0.5 }).remove(0);
+        assert_eq!(motion.name(), INPUT_STRUCTURE);
+        assert_eq!(
+            motion.get::<&str>("type").unwrap(),
+            "pointer-motion-absolute"
+        );
+        assert_eq!(motion.get::<f64>("x").unwrap(), 0.25);
+        assert_eq!(motion.get::<f64>("y").unwrap(), 0.5);
+
+        let key = input_structures(&RemoteInput::Key {
+            keycode: 30,
+            keysym: 0x61,
+            pressed: true,
+        })
+        .remove(0);
+        assert_eq!(key.get::<&str>("type").unwrap(), "key");
+        assert_eq!(key.get::<u32>("keycode").unwrap(), 30);
+        assert!(key.get::<bool>("pressed").unwrap());
+        // typed instead if the element's layout lacks the key
+        assert_eq!(key.get::<u32>("keysym").unwrap(), 0x61);
+
+        let scroll = input_structures(&RemoteInput::PointerAxis {
+            horizontal: 0.0,
+            vertical: -1.0,
+        })
+        .remove(0);
+        assert_eq!(scroll.get::<&str>("type").unwrap(), "pointer-axis");
+        assert_eq!(scroll.get::<f64>("vertical").unwrap(), -1.0);
+
+        // the element finds or adds the keys, including for the snowman
+        let text = input_structures(&RemoteInput::Text("A\u{2603}b".into()));
+        assert!(text
+            .iter()
+            .all(|key| key.get::<&str>("type").unwrap() == "keysym"));
+        let keysyms: Vec<_> = text
+            .iter()
+            .map(|key| {
+                (
+                    key.get::<u32>("keysym").unwrap(),
+                    key.get::<bool>("pressed").unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            keysyms,
+            [
+                (0x41, true),
+                (0x41, false),
+                (0x100_2603, true),
+                (0x100_2603, false),
+                (0x62, true),
+                (0x62, false),
+            ]
+        );
+    }
+}
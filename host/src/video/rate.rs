@@ -0,0 +1,345 @@
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rtsp::StreamConfig;
+
+/// Frame loss in a report above which the controller reacts.
+const LOSS_THRESHOLD: f64 = 0.02;
+/// Time a change gets to show its effect before the next cut.
+const HOLD_OFF: Duration = Duration::from_secs(1);
+/// Loss free time after which the bitrate is raised again.
+const RECOVERY_PERIOD: Duration = Duration::from_secs(3);
+/// Share of the maximum bitrate added per recovery step.
+const INCREASE_FRACTION: f64 = 0.05;
+/// Cut to the bitrate per congestion step, more if more frames were lost.
+const MAX_DECREASE_FACTOR: f64 = 0.85;
+const MIN_DECREASE_FACTOR: f64 = 0.5;
+const FEC_STEP: u8 = 10;
+/// RTT growth over the lowest RTT seen, which points at queues filling up.
+const CONGESTION_RTT_GROWTH: Duration = Duration::from_millis(20);
+
+/// Bounds the bitrate and FEC are adapted within, configured per host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub min_bitrate_kbps: u32,
+    /// Caps the bitrate clients ask for, unlimited if unset.
+    pub max_bitrate_kbps: Option<u32>,
+    pub min_fec_percentage: u8,
+    pub max_fec_percentage: u8,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            min_bitrate_kbps: 1_000,
+            max_bitrate_kbps: None,
+            min_fec_percentage: 10,
+            max_fec_percentage: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Frames got lost while the RTT grew, the link can't take the bitrate.
+    Congestion,
+    /// Frames got lost without signs of congestion, more parity repairs them.
+    Loss,
+    /// No frames got lost for a while.
+    Recovery,
+}
+
+/// A change the stream has to apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub bitrate_kbps: u32,
+    pub fec_percentage: u8,
+    pub reason: Reason,
+    /// Share of frames lost in the report that caused this.
+    pub loss: f64,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {:.1}% frame loss, bitrate {} kbps, FEC {}%",
+            self.reason,
+            self.loss * 100.0,
+            self.bitrate_kbps,
+            self.fec_percentage
+        )
+    }
+}
+
+/// Adapts the bitrate and FEC of a stream to the client's loss reports and RTT.
+///
+/// Random loss is met with more parity first, loss along with a growing RTT means
+/// congestion and cuts the bitrate. Loss free periods slowly win the bitrate back.
+#[derive(Debug, Clone)]
+pub struct RateController {
+    min_bitrate_kbps: u32,
+    max_bitrate_kbps: u32,
+    min_fec_percentage: u8,
+    max_fec_percentage: u8,
+    fps: u32,
+
+    bitrate_kbps: u32,
+    fec_percentage: u8,
+    base_rtt: Option<Duration>,
+    rtt: Option<Duration>,
+    since_change: Duration,
+    loss_free: Duration,
+}
+
+impl RateController {
+    /// Starts at the bitrate the client asked for.
+    pub fn new(limits: &RateLimits, config: &StreamConfig, fec_percentage: u8) -> RateController {
+        let max_bitrate_kbps = limits
+            .max_bitrate_kbps
+            .map_or(config.max_bitrate_kbps, |max| {
+                max.min(config.max_bitrate_kbps)
+            });
+        let max_fec_percentage = limits.max_fec_percentage.min(100);
+        let min_fec_percentage = limits.min_fec_percentage.min(max_fec_percentage);
+        RateController {
+            min_bitrate_kbps: limits.min_bitrate_kbps.min(max_bitrate_kbps),
+            max_bitrate_kbps,
+            min_fec_percentage,
+            max_fec_percentage,
+            fps: config.fps.max(1),
+
+            bitrate_kbps: max_bitrate_kbps,
+            fec_percentage: fec_percentage.clamp(min_fec_percentage, max_fec_percentage),
+            base_rtt: None,
+            rtt: None,
+            since_change: HOLD_OFF,
+            loss_free: Duration::ZERO,
+        }
+    }
+
+    pub fn bitrate_kbps(&self) -> u32 {
+        self.bitrate_kbps
+    }
+
+    pub fn fec_percentage(&self) -> u8 {
+        self.fec_percentage
+    }
+
+    /// Records the RTT of the control stream.
+    pub fn on_rtt(&mut self, rtt: Duration) {
+        self.base_rtt = Some(self.base_rtt.map_or(rtt, |base| base.min(rtt)));
+        self.rtt = Some(rtt);
+    }
+
+    fn congested(&self) -> bool {
+        match (self.rtt, self.base_rtt) {
+            (Some(rtt), Some(base)) => rtt > base * 2 && rtt - base > CONGESTION_RTT_GROWTH,
+            _ => false,
+        }
+    }
+
+    /// Handles a loss report of the client, returning the change to apply if any.
+    pub fn on_loss_stats(&mut self, lost_frames: i32, interval: Duration) -> Option<Decision> {
+        if interval.is_zero() {
+            return None;
+        }
+        self.since_change += interval;
+        let expected_frames = (interval.as_secs_f64() * self.fps as f64).max(1.0);
+        let loss = (lost_frames.max(0) as f64 / expected_frames).min(1.0);
+
+        let (bitrate_kbps, fec_percentage, reason) = if loss >= LOSS_THRESHOLD {
+            self.loss_free = Duration::ZERO;
+            if self.since_change < HOLD_OFF {
+                return None;
+            }
+            if !self.congested() && self.fec_percentage < self.max_fec_percentage {
+                let fec_percentage = self.fec_percentage.saturating_add(FEC_STEP);
+                (self.bitrate_kbps, fec_percentage, Reason::Loss)
+            } else {
+                let factor = (1.0 - loss).clamp(MIN_DECREASE_FACTOR, MAX_DECREASE_FACTOR);
+                let bitrate_kbps = (self.bitrate_kbps as f64 * factor) as u32;
+                (bitrate_kbps, self.fec_percentage, Reason::Congestion)
+            }
+        } else if loss == 0.0 {
+            self.loss_free += interval;
+            if self.loss_free < RECOVERY_PERIOD {
+                return None;
+            }
+            self.loss_free = Duration::ZERO;
+            let step = ((self.max_bitrate_kbps as f64 * INCREASE_FRACTION) as u32).max(1);
+            (
+                self.bitrate_kbps.saturating_add(step),
+                self.fec_percentage.saturating_sub(FEC_STEP / 2),
+                Reason::Recovery,
+            )
+        } else {
+            // a little loss is normal, but not a reason to raise the bitrate either
+            self.loss_free = Duration::ZERO;
+            return None;
+        };
+
+        let bitrate_kbps = bitrate_kbps.clamp(self.min_bitrate_kbps, self.max_bitrate_kbps);
+        let fec_percentage = fec_percentage.clamp(self.min_fec_percentage, self.max_fec_percentage);
+        if (bitrate_kbps, fec_percentage) == (self.bitrate_kbps, self.fec_percentage) {
+            return None;
+        }
+        self.bitrate_kbps = bitrate_kbps;
+        self.fec_percentage = fec_percentage;
+        self.since_change = Duration::ZERO;
+        Some(Decision {
+            bitrate_kbps,
+            fec_percentage,
+            reason,
+            loss,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtsp::VideoCodec;
+
+    /// Moonlight reports every 50 ms.
+    const REPORT_INTERVAL: Duration = Duration::from_millis(50);
+
+    fn config() -> StreamConfig {
+        StreamConfig {
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            packet_size: 1024,
+            max_bitrate_kbps: 20_000,
+            min_fec_packets: 0,
+            codec: VideoCodec::H264,
+            audio_channels: 2,
+            audio_channel_mask: 0x3,
+            audio_packet_duration: 5,
+            hdr: false,
+        }
+    }
+
+    fn controller() -> RateController {
+        RateController::new(&RateLimits::default(), &config(), 20)
+    }
+
+    /// Feeds `seconds` of reports losing `lost` of every 3 frames sent per report.
+    fn feed(controller: &mut RateController, seconds: u32, lost: i32) -> Vec<Decision> {
+        (0..seconds * 20)
+            .filter_map(|_| controller.on_loss_stats(lost, REPORT_INTERVAL))
+            .collect()
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = RateLimits {
+            max_bitrate_kbps: Some(10_000),
+            min_fec_percentage: 30,
+            ..Default::default()
+        };
+        let controller = RateController::new(&limits, &config(), 20);
+        assert_eq!(controller.bitrate_kbps(), 10_000);
+        assert_eq!(controller.fec_percentage(), 30);
+
+        // clients asking for less than the configured minimum get what they asked for
+        let limits = RateLimits {
+            min_bitrate_kbps: 50_000,
+            ..Default::default()
+        };
+        let mut controller = RateController::new(&limits, &config(), 20);
+        controller.on_rtt(Duration::from_millis(10));
+        controller.on_rtt(Duration::from_millis(100));
+        assert!(feed(&mut controller, 10, 3).is_empty());
+        assert_eq!(controller.bitrate_kbps(), 20_000);
+    }
+
+    #[test]
+    fn test_clean_trace() {
+        let mut controller = controller();
+        controller.on_rtt(Duration::from_millis(10));
+        let decisions = feed(&mut controller, 30, 0);
+
+        // FEC falls back to its minimum, the bitrate already is at the maximum
+        assert_eq!(decisions.len(), 2);
+        assert!(decisions.iter().all(|d| d.reason == Reason::Recovery));
+        assert_eq!(controller.fec_percentage(), 10);
+        assert_eq!(controller.bitrate_kbps(), 20_000);
+    }
+
+    #[test]
+    fn test_random_loss() {
+        let mut controller = controller();
+        controller.on_rtt(Duration::from_millis(10));
+        let decisions = feed(&mut controller, 5, 1);
+
+        // parity is raised once per hold off until it maxes out, then the bitrate goes down
+        let reasons = decisions.iter().map(|d| d.reason).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            [
+                Reason::Loss,
+                Reason::Loss,
+                Reason::Loss,
+                Reason::Congestion,
+                Reason::Congestion
+            ]
+        );
+        assert_eq!(decisions[2].fec_percentage, 50);
+        assert_eq!(decisions[2].bitrate_kbps, 20_000);
+        assert_eq!(decisions[3].bitrate_kbps, 13_333);
+        assert!(decisions[3].loss > 0.3);
+    }
+
+    #[test]
+    fn test_congestion() {
+        let mut controller = controller();
+        controller.on_rtt(Duration::from_millis(10));
+        controller.on_rtt(Duration::from_millis(80));
+        let decisions = feed(&mut controller, 60, 2);
+
+        // straight to cutting the bitrate, but never below the minimum
+        assert!(decisions.iter().all(|d| d.reason == Reason::Congestion));
+        assert_eq!(decisions[0].fec_percentage, 20);
+        assert_eq!(decisions[0].bitrate_kbps, 10_000);
+        assert_eq!(controller.bitrate_kbps(), 1_000);
+        assert!(feed(&mut controller, 5, 2).is_empty());
+    }
+
+    #[test]
+    fn test_recovery() {
+        let mut controller = controller();
+        controller.on_rtt(Duration::from_millis(10));
+        controller.on_rtt(Duration::from_millis(80));
+        feed(&mut controller, 3, 3);
+        let low = controller.bitrate_kbps();
+        assert!(low < 10_000);
+
+        // the queues drained
+        controller.on_rtt(Duration::from_millis(12));
+        let decisions = feed(&mut controller, 120, 0);
+        assert!(decisions.iter().all(|d| d.reason == Reason::Recovery));
+        assert!(decisions
+            .windows(2)
+            .all(|w| w[0].bitrate_kbps <= w[1].bitrate_kbps));
+        assert_eq!(controller.bitrate_kbps(), 20_000);
+
+        assert_eq!(controller.fec_percentage(), 10);
+    }
+
+    #[test]
+    fn test_minor_loss() {
+        let mut controller = controller();
+        controller.bitrate_kbps = 5_000;
+        controller.on_rtt(Duration::from_millis(10));
+
+        // a frame per second is below the threshold, but keeps the bitrate from rising
+        let decisions = (0..30)
+            .filter_map(|_| controller.on_loss_stats(1, Duration::from_secs(1)))
+            .collect::<Vec<_>>();
+        assert!(decisions.is_empty());
+        assert_eq!(controller.bitrate_kbps(), 5_000);
+    }
+}
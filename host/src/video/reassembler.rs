@@ -0,0 +1,111 @@
+//! Client side of the video stream, used to verify what the packetizer puts on the wire.
+//!
+//! Parses packets the way moonlight-common-c does, with its own constants rather than the
+//! packetizer's, so both can't share a mistake.
+
+use std::collections::HashMap;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use super::packetizer::FecParams;
+use super::{FrameType, PacketizerConfig};
+
+/// `MAX_RTP_HEADER_SIZE`, clients receive this much beyond `packetSize`.
+const MAX_RTP_HEADER_SIZE: usize = 16;
+/// RTP header, reserved bytes and `NV_VIDEO_PACKET`.
+const HEADER_LEN: usize = 12 + 4 + 16;
+const FRAME_HEADER_LEN: usize = 8;
+
+pub struct Frame {
+    pub frame_type: FrameType,
+    /// Frame data including the padding of the last shard.
+    pub data: Vec<u8>,
+}
+
+struct Block {
+    data_shards: usize,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl Block {
+    fn received(&self) -> usize {
+        self.shards.iter().filter(|shard| shard.is_some()).count()
+    }
+}
+
+pub struct Reassembler {
+    config: PacketizerConfig,
+    frame_index: Option<u32>,
+    blocks: HashMap<u8, Block>,
+}
+
+impl Reassembler {
+    pub fn new(config: PacketizerConfig) -> Reassembler {
+        Reassembler {
+            config,
+            frame_index: None,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Feeds a datagram, returns the frame once enough shards of every block arrived.
+    pub fn push(&mut self, packet: &[u8]) -> Option<Frame> {
+        assert_eq!(packet.len(), self.config.packet_size + MAX_RTP_HEADER_SIZE);
+        let frame_index = u32::from_le_bytes(packet[20..24].try_into().unwrap());
+        if self.frame_index != Some(frame_index) {
+            self.frame_index = Some(frame_index);
+            self.blocks.clear();
+        }
+
+        let multi_fec_blocks = packet[27];
+        let fec_info = u32::from_le_bytes(packet[28..32].try_into().unwrap());
+        let shard_index = ((fec_info >> 12) & 0x3ff) as usize;
+        let data_shards = ((fec_info >> 22) & 0x3ff) as usize;
+        let percentage = ((fec_info >> 4) & 0xff) as u8;
+        let parity_shards = FecParams::parity_shards(data_shards, percentage);
+
+        let block = self
+            .blocks
+            .entry((multi_fec_blocks >> 4) & 0x3)
+            .or_insert_with(|| Block {
+                data_shards,
+                shards: vec![None; data_shards + parity_shards],
+            });
+        block.shards[shard_index] = Some(packet[HEADER_LEN..].to_vec());
+
+        let last_block = (multi_fec_blocks >> 6) & 0x3;
+        let complete = (0..=last_block).all(|index| {
+            self.blocks
+                .get(&index)
+                .map(|block| block.received() >= block.data_shards)
+                .unwrap_or(false)
+        });
+        if !complete {
+            return None;
+        }
+
+        let mut data = Vec::new();
+        for index in 0..=last_block {
+            let block = self.blocks.get_mut(&index).unwrap();
+            if block.shards.len() > block.data_shards {
+                ReedSolomon::new(block.data_shards, block.shards.len() - block.data_shards)
+                    .unwrap()
+                    .reconstruct_data(&mut block.shards)
+                    .unwrap();
+            }
+            for shard in &block.shards[..block.data_shards] {
+                data.extend_from_slice(shard.as_ref().unwrap());
+            }
+        }
+        self.blocks.clear();
+
+        let frame_type = match data[3] {
+            2 => FrameType::Idr,
+            _ => FrameType::P,
+        };
+        Some(Frame {
+            frame_type,
+            data: data.split_off(FRAME_HEADER_LEN),
+        })
+    }
+}
@@ -134,6 +134,20 @@ impl<'a> From<&'a MethodRef<'a>> for &'a str {
     }
 }
 
+/// Parses a request URI.
+///
+/// Some clients (e.g. GameStream clients) send relative request targets like
+/// `streamid=video/0/0`. These are resolved against `rtsp://localhost/` instead
+/// of failing the whole message.
+fn parse_request_uri(uri: &str) -> Result<Url, url::ParseError> {
+    match Url::parse(uri) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            Url::parse("rtsp://localhost/").and_then(|base| base.join(uri))
+        }
+        res => res,
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct RequestRef<'a> {
     pub(crate) method: MethodRef<'a>,
@@ -149,7 +163,7 @@ impl<'a> RequestRef<'a> {
             method: self.method.to_owned(),
             request_uri: self
                 .request_uri
-                .map(Url::parse)
+                .map(parse_request_uri)
                 .transpose()
                 .map_err(|_| ParseError::Error)?,
             version: self.version,
@@ -556,6 +556,26 @@ REMAINDER"
         ));
     }
 
+    #[test]
+    fn test_relative_request_uri() {
+        assert_eq!(
+            request(
+                b"SETUP streamid=video/0/0 RTSP/1.0\r\n\
+CSeq: 3\r\n\
+\r\n\
+REMAINDER"
+            )
+            .map(|(rem, req)| (rem, RequestRef::to_owned(&req).unwrap())),
+            Ok((
+                &b"REMAINDER"[..],
+                Request::builder(Method::Setup, Version::V1_0)
+                    .request_uri(Url::parse("rtsp://localhost/streamid=video/0/0").unwrap())
+                    .header(crate::headers::CSEQ, "3")
+                    .build(Vec::<u8>::new())
+            ))
+        );
+    }
+
     #[test]
     fn test_data() {
         assert_eq!(